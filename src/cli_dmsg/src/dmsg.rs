@@ -74,12 +74,16 @@ impl DMsgAgent {
             .blocks
             .into_iter()
             .flat_map(|b| match b.block {
+                // blocks archived before typed encoding
                 ICRC3GenericBlock::Blob(blob) => {
                     let res: Result<NameBlock, String> =
                         from_reader(blob.as_slice()).map_err(format_error);
                     res
                 }
-                _ => panic!("unexpected block type"),
+                value => {
+                    let height = u64::try_from(&b.id.0).map_err(format_error)?;
+                    NameBlock::from_value(height, &value)
+                }
             })
             .collect();
 
@@ -1,4 +1,5 @@
 use candid::{CandidType, Principal};
+use lib_panda::{heap_memory_size, CanisterMetrics};
 use serde::Deserialize;
 use std::collections::BTreeSet;
 
@@ -74,6 +75,16 @@ fn get_state() -> store::State {
     store::state::with(|s| s.clone())
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> CanisterMetrics {
+    CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: heap_memory_size(),
+        timestamp: ic_cdk::api::time() / 1_000_000,
+    }
+}
+
 #[ic_cdk::update]
 fn try_prepare(miner: Principal, payer: Principal) -> bool {
     if store::state::with(|s| !s.preparers.contains(&ic_cdk::api::msg_caller())) {
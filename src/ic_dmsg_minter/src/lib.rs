@@ -3,6 +3,7 @@ use icrc_ledger_types::icrc1::{
     account::Account,
     transfer::{Memo, TransferArg, TransferError},
 };
+use lib_panda::CanisterMetrics;
 use serde_bytes::ByteBuf;
 
 mod api;
@@ -1,86 +1,354 @@
 use candid::{Nat, Principal};
-use ic_cose_types::validate_principals;
+use ic_cose_types::{validate_principals, validate_str, MILLISECONDS};
+use ic_message_types::profile::UserInfo;
 use icrc_ledger_types::icrc1::account::Account;
+use lib_panda::audit;
 use num_traits::cast::ToPrimitive;
-use std::collections::BTreeSet;
+use serde_bytes::{ByteArray, ByteBuf};
+use std::{collections::BTreeSet, time::Duration};
 
 use crate::{is_controller, store, token_transfer_to, types};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    let mut args = args;
-    store::state::with_mut(|s| {
-        s.managers.append(&mut args);
-        Ok(())
+    store::AUDIT.with("admin_add_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        let mut args = args;
+        store::state::with_mut(|s| {
+            s.managers.append(&mut args);
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_remove_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    store::state::with_mut(|s| {
-        s.managers.retain(|p| !args.contains(p));
-        Ok(())
+    store::AUDIT.with("admin_remove_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        store::state::with_mut(|s| {
+            s.managers.retain(|p| !args.contains(p));
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_canister(kind: types::CanisterKind, id: Principal) -> Result<(), String> {
-    validate_admin_add_canister(kind, id)?;
-    store::state::with_mut(|s| {
-        match kind {
-            types::CanisterKind::Cose => {
-                s.cose_canisters.push(id);
+    store::AUDIT.with("admin_add_canister", audit::digest((&kind, &id)), || {
+        validate_admin_add_canister(kind, id)?;
+        store::state::with_mut(|s| {
+            match kind {
+                types::CanisterKind::Cose => {
+                    s.cose_canisters.push(id);
+                }
+                types::CanisterKind::Profile => {
+                    s.profile_canisters.push(id);
+                }
+                types::CanisterKind::Channel => {
+                    s.channel_canisters.push(id);
+                }
             }
-            types::CanisterKind::Profile => {
-                s.profile_canisters.push(id);
+            Ok(())
+        })
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_update_price(args: types::UpdatePriceInput) -> Result<(), String> {
+    store::AUDIT.with("admin_update_price", audit::digest((&args,)), || {
+        validate_admin_update_price(args.clone())?;
+        store::state::with_mut(|s| {
+            if let Some(price) = args.channel {
+                s.price.channel = price;
             }
-            types::CanisterKind::Channel => {
-                s.channel_canisters.push(id);
+            if let Some(price) = args.name_l7 {
+                s.price.name_l7 = price;
             }
-        }
-        Ok(())
+            if let Some(price) = args.name_l5 {
+                s.price.name_l5 = price;
+            }
+            if let Some(price) = args.name_l3 {
+                s.price.name_l3 = price;
+            }
+            if let Some(price) = args.name_l2 {
+                s.price.name_l2 = price;
+            }
+            if let Some(price) = args.name_l1 {
+                s.price.name_l1 = price;
+            }
+            if let Some(percent) = args.renewal_percent {
+                s.price.renewal_percent = percent;
+            }
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
-fn admin_update_price(args: types::UpdatePriceInput) -> Result<(), String> {
-    validate_admin_update_price(args.clone())?;
-    store::state::with_mut(|s| {
-        if let Some(price) = args.channel {
-            s.price.channel = price;
-        }
-        if let Some(price) = args.name_l7 {
-            s.price.name_l7 = price;
-        }
-        if let Some(price) = args.name_l5 {
-            s.price.name_l5 = price;
-        }
-        if let Some(price) = args.name_l3 {
-            s.price.name_l3 = price;
-        }
-        if let Some(price) = args.name_l2 {
-            s.price.name_l2 = price;
-        }
-        if let Some(price) = args.name_l1 {
-            s.price.name_l1 = price;
-        }
+async fn admin_update_pricing(args: types::PricingConfig) -> Result<(), String> {
+    let digest = audit::digest((&args,));
+    store::AUDIT
+        .with_async("admin_update_pricing", digest, async {
+            args.validate()?;
+            let currency = args.currency.is_some();
+            store::pricing::update(args);
+            if currency {
+                store::pricing::refresh(ic_cdk::api::time() / MILLISECONDS).await?;
+            }
+            Ok(())
+        })
+        .await
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_update_signing_config(args: types::SigningConfig) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_update_signing_config",
+        audit::digest((&args,)),
+        || {
+            args.validate()?;
+            store::state::with_mut(|s| {
+                s.signing = args;
+                Ok(())
+            })
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_canister_wasm(kind: types::CanisterKind, wasm: ByteBuf) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_set_canister_wasm",
+        audit::digest((&kind, &wasm)),
+        || {
+            validate_provision_wasm(kind, &wasm)?;
+            store::provision::set_wasm(kind, wasm.into_vec())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_update_provisioning_config(args: types::ProvisioningConfig) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_update_provisioning_config",
+        audit::digest((&args,)),
+        || {
+            args.validate()?;
+            store::state::with_mut(|s| {
+                s.provisioning = args;
+                Ok(())
+            })
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_provision_canister(kind: types::CanisterKind) -> Result<Principal, String> {
+    let digest = audit::digest((&kind,));
+    store::AUDIT
+        .with_async("admin_provision_canister", digest, async {
+            validate_admin_provision_canister(kind)?;
+            store::provision::create(kind).await
+        })
+        .await
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_begin_wasm_upload(args: types::WasmUploadInput) -> Result<(), String> {
+    store::AUDIT.with("admin_begin_wasm_upload", audit::digest((&args,)), || {
+        args.validate()?;
+        store::upgrade::begin_upload(args)
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_upload_wasm_chunk(kind: types::CanisterKind, chunk: ByteBuf) -> Result<u64, String> {
+    store::AUDIT.with(
+        "admin_upload_wasm_chunk",
+        audit::digest((&kind, &chunk)),
+        || store::upgrade::upload_chunk(kind, chunk.into_vec()),
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_commit_wasm_upload(kind: types::CanisterKind) -> Result<ByteArray<32>, String> {
+    store::AUDIT.with("admin_commit_wasm_upload", audit::digest((&kind,)), || {
+        store::upgrade::commit_upload(kind)
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_start_fleet_upgrade(args: types::StartUpgradeInput) -> Result<u64, String> {
+    store::AUDIT.with("admin_start_fleet_upgrade", audit::digest((&args,)), || {
+        let id = store::upgrade::start(
+            ic_cdk::api::msg_caller(),
+            args,
+            ic_cdk::api::time() / MILLISECONDS,
+        )?;
+        ic_cdk_timers::set_timer(Duration::from_secs(0), store::upgrade::run());
+        Ok(id)
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_halt_fleet_upgrade(id: u64, reason: String) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_halt_fleet_upgrade",
+        audit::digest((&id, &reason)),
+        || {
+            validate_admin_halt_fleet_upgrade(id, reason.clone())?;
+            store::upgrade::halt(id, reason, ic_cdk::api::time() / MILLISECONDS)
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_update_cycles_monitor(args: types::CyclesMonitorConfig) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_update_cycles_monitor",
+        audit::digest((&args,)),
+        || {
+            args.validate()?;
+            store::state::with_mut(|s| {
+                s.cycles_monitor = args;
+                Ok(())
+            })
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_update_auction_config(args: types::AuctionConfig) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_update_auction_config",
+        audit::digest((&args,)),
+        || {
+            args.validate()?;
+            store::state::with_mut(|s| {
+                s.auction = args;
+                Ok(())
+            })
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_attach_archive(id: Principal) -> Result<(), String> {
+    store::AUDIT.with("admin_attach_archive", audit::digest((&id,)), || {
+        validate_admin_attach_archive(id)?;
+        store::archive::attach(id)
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_add_reserved_names(args: Vec<types::ReserveNameInput>) -> Result<(), String> {
+    store::AUDIT.with("admin_add_reserved_names", audit::digest((&args,)), || {
+        validate_admin_add_reserved_names(args.clone())?;
+        store::reserved::add(args, ic_cdk::api::time() / MILLISECONDS);
         Ok(())
     })
 }
 
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_remove_reserved_names(args: Vec<String>) -> Result<(), String> {
+    store::AUDIT.with(
+        "admin_remove_reserved_names",
+        audit::digest((&args,)),
+        || {
+            validate_admin_remove_reserved_names(args.clone())?;
+            store::reserved::remove(args);
+            Ok(())
+        },
+    )
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_allocate_reserved_name(name: String, user: Principal) -> Result<UserInfo, String> {
+    let digest = audit::digest((&name, &user));
+    store::AUDIT
+        .with_async("admin_allocate_reserved_name", digest, async {
+            validate_admin_allocate_reserved_name(name.clone(), user)?;
+            store::reserved::allocate(user, name, ic_cdk::api::time() / MILLISECONDS).await
+        })
+        .await
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_resolve_name_dispute(
+    id: u64,
+    status: types::DisputeStatus,
+    resolution: String,
+) -> Result<types::NameDispute, String> {
+    store::AUDIT.with(
+        "admin_resolve_name_dispute",
+        audit::digest((&id, &status, &resolution)),
+        || {
+            validate_admin_resolve_name_dispute(id, status.clone(), resolution.clone())?;
+            store::reserved::resolve(id, status, resolution, ic_cdk::api::time() / MILLISECONDS)
+        },
+    )
+}
+
 #[ic_cdk::update(guard = "is_controller")]
 async fn admin_collect_token(user: Account, amount: Nat) -> Result<(), String> {
+    let digest = audit::digest((&user, &amount));
     let amount64 = amount.0.to_u64().unwrap_or_default();
-    token_transfer_to(user, amount, "COLLECT".to_string())
+    store::AUDIT
+        .with_async("admin_collect_token", digest, async {
+            token_transfer_to(user, amount, "COLLECT".to_string())
+                .await
+                .map(|blk| {
+                    store::treasury::record(
+                        types::TreasuryKind::Collection,
+                        user.owner,
+                        amount64,
+                        Some(blk),
+                        format!("COLLECT: {}", user),
+                        ic_cdk::api::time() / MILLISECONDS,
+                    );
+                })
+                .map_err(|err| format!("failed to collect token, {}", err))
+        })
         .await
-        .map_err(|err| format!("failed to collect token, {}", err))?;
+}
 
-    store::state::with_mut(|s| {
-        s.transfer_out_total += amount64 as u128;
-    });
-    Ok(())
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_resolve_payout(id: u64, paid: bool) -> Result<(), String> {
+    store::AUDIT
+        .with_async(
+            "admin_resolve_payout",
+            audit::digest((&id, &paid)),
+            store::payout::resolve(id, paid, ic_cdk::api::time() / MILLISECONDS),
+        )
+        .await
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_register_channel_canisters() -> Result<(), String> {
+    store::AUDIT
+        .with_async(
+            "admin_register_channel_canisters",
+            audit::digest(()),
+            async {
+                validate_admin_register_channel_canisters()?;
+                store::provision::register_channels().await
+            },
+        )
+        .await
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+async fn admin_sync_profile_names(
+    prev: Option<Principal>,
+    take: u32,
+) -> Result<Option<Principal>, String> {
+    let digest = audit::digest((&prev, &take));
+    store::AUDIT
+        .with_async("admin_sync_profile_names", digest, async {
+            validate_admin_sync_profile_names(prev, take)?;
+            store::user::sync_profile_names(prev, take as usize).await
+        })
+        .await
 }
 
 #[ic_cdk::update]
@@ -144,6 +412,9 @@ fn validate2_admin_add_canister(
 fn validate_admin_update_price(args: types::UpdatePriceInput) -> Result<(), String> {
     args.validate()?;
     store::state::with(|s| {
+        if s.pricing.currency.is_some() {
+            Err("prices are denominated in a currency, update the pricing config".to_string())?;
+        }
         if let Some(price) = args.name_l5 {
             if price <= args.name_l7.unwrap_or(s.price.name_l7) {
                 Err("name_l5 must be greater than name_l7".to_string())?;
@@ -174,6 +445,270 @@ fn validate2_admin_update_price(args: types::UpdatePriceInput) -> Result<String,
     Ok("ok".to_string())
 }
 
+#[ic_cdk::update]
+fn validate_admin_update_pricing(args: types::PricingConfig) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_update_pricing(args: types::PricingConfig) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_update_signing_config(args: types::SigningConfig) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_update_signing_config(args: types::SigningConfig) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_set_canister_wasm(
+    kind: types::CanisterKind,
+    wasm: ByteBuf,
+) -> Result<(), String> {
+    validate_provision_wasm(kind, &wasm)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_set_canister_wasm(
+    kind: types::CanisterKind,
+    wasm: ByteBuf,
+) -> Result<String, String> {
+    validate_provision_wasm(kind, &wasm)?;
+    Ok("ok".to_string())
+}
+
+fn validate_provision_wasm(kind: types::CanisterKind, wasm: &[u8]) -> Result<(), String> {
+    if kind == types::CanisterKind::Cose {
+        return Err("COSE canisters are not provisioned".to_string());
+    }
+    types::validate_canister_wasm(wasm)
+}
+
+#[ic_cdk::update]
+fn validate_admin_update_provisioning_config(
+    args: types::ProvisioningConfig,
+) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_update_provisioning_config(
+    args: types::ProvisioningConfig,
+) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_provision_canister(kind: types::CanisterKind) -> Result<(), String> {
+    if store::provision::wasm_hash(kind).is_none() {
+        return Err("no wasm for the canister kind".to_string());
+    }
+    if store::state::with(|s| s.provisioning.cycles) < types::MIN_PROVISION_CYCLES {
+        return Err(format!(
+            "cycles should be at least {}",
+            types::MIN_PROVISION_CYCLES
+        ));
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_provision_canister(kind: types::CanisterKind) -> Result<String, String> {
+    validate_admin_provision_canister(kind)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_begin_wasm_upload(args: types::WasmUploadInput) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_begin_wasm_upload(args: types::WasmUploadInput) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_upload_wasm_chunk(
+    kind: types::CanisterKind,
+    chunk: ByteBuf,
+) -> Result<(), String> {
+    store::upgrade::check_chunk(kind, &chunk)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_upload_wasm_chunk(
+    kind: types::CanisterKind,
+    chunk: ByteBuf,
+) -> Result<String, String> {
+    store::upgrade::check_chunk(kind, &chunk)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_commit_wasm_upload(kind: types::CanisterKind) -> Result<(), String> {
+    store::upgrade::check_commit(kind)?;
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_commit_wasm_upload(kind: types::CanisterKind) -> Result<String, String> {
+    store::upgrade::check_commit(kind)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_start_fleet_upgrade(args: types::StartUpgradeInput) -> Result<(), String> {
+    store::upgrade::check_start(&args)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_start_fleet_upgrade(args: types::StartUpgradeInput) -> Result<String, String> {
+    store::upgrade::check_start(&args)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_halt_fleet_upgrade(_id: u64, reason: String) -> Result<(), String> {
+    validate_str(&reason)?;
+    if reason.len() > types::MAX_RESERVED_REASON_SIZE {
+        return Err("reason is too long".to_string());
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_halt_fleet_upgrade(id: u64, reason: String) -> Result<String, String> {
+    validate_admin_halt_fleet_upgrade(id, reason)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_update_cycles_monitor(args: types::CyclesMonitorConfig) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_update_cycles_monitor(
+    args: types::CyclesMonitorConfig,
+) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_update_auction_config(args: types::AuctionConfig) -> Result<(), String> {
+    args.validate()
+}
+
+#[ic_cdk::update]
+fn validate2_admin_update_auction_config(args: types::AuctionConfig) -> Result<String, String> {
+    args.validate()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_attach_archive(id: Principal) -> Result<(), String> {
+    if id == Principal::anonymous() || id == ic_cdk::api::canister_self() {
+        return Err("invalid archive canister".to_string());
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_attach_archive(id: Principal) -> Result<String, String> {
+    validate_admin_attach_archive(id)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_add_reserved_names(args: Vec<types::ReserveNameInput>) -> Result<(), String> {
+    if args.is_empty() || args.len() > types::MAX_RESERVED_NAMES_PER_CALL {
+        Err(format!(
+            "names should be between 1 and {}",
+            types::MAX_RESERVED_NAMES_PER_CALL
+        ))?;
+    }
+    for input in &args {
+        input.validate()?;
+        validate_str(&input.name.to_ascii_lowercase())?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_add_reserved_names(
+    args: Vec<types::ReserveNameInput>,
+) -> Result<String, String> {
+    validate_admin_add_reserved_names(args)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_remove_reserved_names(args: Vec<String>) -> Result<(), String> {
+    if args.is_empty() || args.len() > types::MAX_RESERVED_NAMES_PER_CALL {
+        Err(format!(
+            "names should be between 1 and {}",
+            types::MAX_RESERVED_NAMES_PER_CALL
+        ))?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_remove_reserved_names(args: Vec<String>) -> Result<String, String> {
+    validate_admin_remove_reserved_names(args)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_allocate_reserved_name(name: String, user: Principal) -> Result<(), String> {
+    if user == Principal::anonymous() {
+        Err("anonymous user is not allowed".to_string())?;
+    }
+    store::reserved::check_allocate(&name.to_lowercase(), &user)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_allocate_reserved_name(name: String, user: Principal) -> Result<String, String> {
+    validate_admin_allocate_reserved_name(name, user)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_resolve_name_dispute(
+    id: u64,
+    status: types::DisputeStatus,
+    resolution: String,
+) -> Result<(), String> {
+    if status == types::DisputeStatus::Open {
+        Err("invalid dispute status".to_string())?;
+    }
+    if resolution.len() > types::MAX_DISPUTE_CLAIM_SIZE {
+        Err("resolution is too long".to_string())?;
+    }
+    store::reserved::check_resolve(id)
+}
+
+#[ic_cdk::update]
+fn validate2_admin_resolve_name_dispute(
+    id: u64,
+    status: types::DisputeStatus,
+    resolution: String,
+) -> Result<String, String> {
+    validate_admin_resolve_name_dispute(id, status, resolution)?;
+    Ok("ok".to_string())
+}
+
 #[ic_cdk::update]
 fn validate_admin_collect_token(_user: Account, amount: Nat) -> Result<(), String> {
     if amount < types::TOKEN_1 {
@@ -188,3 +723,54 @@ fn validate2_admin_collect_token(user: Account, amount: Nat) -> Result<String, S
     validate_admin_collect_token(user, amount)?;
     Ok("ok".to_string())
 }
+
+#[ic_cdk::update]
+fn validate_admin_resolve_payout(id: u64, _paid: bool) -> Result<(), String> {
+    store::payout::list(Some(id.saturating_sub(1)), 1)
+        .first()
+        .filter(|p| p.id == id && p.needs_review)
+        .ok_or_else(|| "payout does not need review".to_string())?;
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_resolve_payout(id: u64, paid: bool) -> Result<String, String> {
+    validate_admin_resolve_payout(id, paid)?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_register_channel_canisters() -> Result<(), String> {
+    store::state::with(|s| {
+        if s.profile_canisters.is_empty() {
+            Err("no profile canister".to_string())?;
+        }
+        if s.channel_canisters.is_empty() && s.matured_channel_canisters.is_empty() {
+            Err("no channel canister".to_string())?;
+        }
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn validate2_admin_register_channel_canisters() -> Result<String, String> {
+    validate_admin_register_channel_canisters()?;
+    Ok("ok".to_string())
+}
+
+#[ic_cdk::update]
+fn validate_admin_sync_profile_names(_prev: Option<Principal>, take: u32) -> Result<(), String> {
+    if take == 0 || take > 1000 {
+        Err("take must be between 1 and 1000".to_string())?;
+    }
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_sync_profile_names(
+    prev: Option<Principal>,
+    take: u32,
+) -> Result<String, String> {
+    validate_admin_sync_profile_names(prev, take)?;
+    Ok("ok".to_string())
+}
@@ -0,0 +1,84 @@
+use ic_http_certification::HttpRequest;
+use lib_panda::{HttpResponse, MetricsWriter};
+
+use crate::store;
+
+#[ic_cdk::query(hidden = true)]
+fn http_request(request: HttpRequest<'static>) -> HttpResponse {
+    let path = request.get_path().unwrap_or_default();
+    lib_panda::http_metrics(request.method().as_str(), &path, metrics)
+}
+
+fn metrics() -> Vec<u8> {
+    let mut w = MetricsWriter::new();
+    w.gauge(
+        "ic_message_cycles_balance",
+        "Cycles balance of the canister.",
+        ic_cdk::api::canister_cycle_balance(),
+    )
+    .gauge(
+        "ic_message_stable_memory_bytes",
+        "Size of the stable memory in bytes.",
+        ic_cdk::stable::stable_size() * 65536,
+    )
+    .gauge(
+        "ic_message_heap_memory_bytes",
+        "Size of the heap memory in bytes.",
+        lib_panda::heap_memory_size(),
+    );
+    let (cose, profile, channel, matured) = store::state::with(|s| {
+        w.counter(
+            "ic_message_incoming_tokens_total",
+            "PANDA tokens received for names and channels, in e8s.",
+            s.incoming_total,
+        )
+        .counter(
+            "ic_message_transfer_out_tokens_total",
+            "PANDA tokens transferred out, in e8s.",
+            s.transfer_out_total,
+        )
+        .gauge(
+            "ic_message_next_block_height",
+            "Height of the next name block.",
+            s.next_block_height,
+        )
+        .gauge(
+            "ic_message_indexed_blocks",
+            "Name blocks indexed in the name history.",
+            s.indexed_blocks,
+        );
+        (
+            s.cose_canisters.len(),
+            s.profile_canisters.len(),
+            s.channel_canisters.len(),
+            s.matured_channel_canisters.len(),
+        )
+    });
+    w.gauge(
+        "ic_message_users",
+        "Registered users.",
+        store::user::users_total(),
+    )
+    .gauge(
+        "ic_message_names",
+        "Registered usernames.",
+        store::user::names_total(),
+    )
+    .gauge("ic_message_cose_canisters", "COSE canisters.", cose)
+    .gauge(
+        "ic_message_profile_canisters",
+        "Profile canisters.",
+        profile,
+    )
+    .gauge(
+        "ic_message_channel_canisters",
+        "Open channel canisters.",
+        channel,
+    )
+    .gauge(
+        "ic_message_matured_channel_canisters",
+        "Matured channel canisters.",
+        matured,
+    );
+    w.into_bytes()
+}
@@ -1,4 +1,5 @@
 use candid::{CandidType, Principal};
+use ic_cose_types::MILLISECONDS;
 use serde::Deserialize;
 use std::{collections::BTreeSet, time::Duration};
 
@@ -34,8 +35,10 @@ fn init(args: Option<ChainArgs>) {
             name_l3: 50_000 * types::TOKEN_1,
             name_l5: 20_000 * types::TOKEN_1,
             name_l7: 5000 * types::TOKEN_1,
+            renewal_percent: 0,
         };
         s.schnorr_key_name = "dfx_test_key".to_string();
+        s.typed_blocks_from = Some(0);
     });
 
     match args {
@@ -55,6 +58,7 @@ fn init(args: Option<ChainArgs>) {
     }
 
     ic_cdk_timers::set_timer(Duration::from_secs(0), store::state::try_init_public_key());
+    set_timers();
 }
 
 #[ic_cdk::pre_upgrade]
@@ -93,5 +97,47 @@ fn post_upgrade(args: Option<ChainArgs>) {
             s.latest_usernames
                 .extend(s.short_usernames.iter().take(20).cloned());
         }
+        // blocks before this height were chained with the sha3 hash of their CBOR encoding
+        if s.typed_blocks_from.is_none() {
+            s.typed_blocks_from = Some(s.next_block_height);
+        }
+        ic_cdk::api::certified_data_set(s.root_hash().as_slice());
+    });
+
+    store::user::load_expiry_queue();
+    set_timers();
+}
+
+// set_timers registers the background jobs, timers are cleared by an upgrade.
+fn set_timers() {
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(3600), || async {
+        store::user::expire_names(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(600), || async {
+        store::auction::settle_due(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(3600), || async {
+        store::archive::run().await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || async {
+        store::history::sync().await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(600), || async {
+        store::pricing::refresh_due(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(300), || async {
+        store::pending::retry_due(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(300), || async {
+        store::payout::retry_due(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(1800), || async {
+        store::provision::run(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || async {
+        store::upgrade::run().await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(3600), || async {
+        store::cycles::run(ic_cdk::api::time() / MILLISECONDS).await;
     });
 }
@@ -1,11 +1,16 @@
 use candid::Principal;
 use ic_cdk::management_canister::{canister_status, CanisterStatusArgs, CanisterStatusResult};
-use ic_cose_types::{format_error, to_cbor_bytes};
-use ic_message_types::profile::UserInfo;
+use ic_cose_types::{format_error, to_cbor_bytes, MILLISECONDS};
+use ic_message_types::{
+    profile::{ProfileInfo, UserInfo},
+    BTYPE_NAME_REGISTER, BTYPE_NAME_RELEASE, BTYPE_NAME_RENEW, BTYPE_NAME_TRANSFER,
+    BTYPE_SUBNAME_ISSUE, BTYPE_SUBNAME_REVOKE,
+};
 use icrc_ledger_types::icrc3::{
     archive::{GetArchivesArgs, GetArchivesResult},
     blocks::{GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate, SupportedBlockType},
 };
+use lib_panda::CanisterMetrics;
 use serde_bytes::ByteBuf;
 use std::collections::BTreeSet;
 
@@ -27,10 +32,38 @@ fn get_state() -> Result<types::StateInfo, String> {
         transfer_out_total: s.transfer_out_total,
         next_block_height: s.next_block_height,
         next_block_phash: s.next_block_phash,
+        typed_blocks_from: s.typed_blocks_from,
+        indexed_blocks: s.indexed_blocks,
         latest_usernames: s.latest_usernames.clone().into(),
+        auction: s.auction.clone(),
+        pricing: s.pricing.clone(),
+        signing: s.signing.clone(),
+        provisioning: s.provisioning.clone(),
+        canister_loads: s.canister_loads.clone(),
+        cycles_monitor: s.cycles_monitor.clone(),
     }))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> CanisterMetrics {
+    store::cycles::self_metrics(ic_cdk::api::time() / MILLISECONDS)
+}
+
+// the latest metrics of the fleet polled by the cycles monitor
+#[ic_cdk::query]
+fn get_fleet_metrics() -> Result<Vec<(Principal, CanisterMetrics)>, String> {
+    Ok(store::cycles::metrics())
+}
+
+#[ic_cdk::query]
+fn list_cycles_alerts(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::CyclesAlert>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::cycles::list_alerts(prev, take as usize))
+}
+
 #[ic_cdk::query]
 async fn get_canister_status() -> Result<CanisterStatusResult, String> {
     store::state::is_manager(&ic_cdk::api::msg_caller())?;
@@ -45,10 +78,20 @@ async fn get_canister_status() -> Result<CanisterStatusResult, String> {
 
 #[ic_cdk::query]
 pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
-    vec![SupportedBlockType {
-        block_type: "ic-message".to_string(),
+    [
+        BTYPE_NAME_REGISTER,
+        BTYPE_NAME_TRANSFER,
+        BTYPE_NAME_RENEW,
+        BTYPE_NAME_RELEASE,
+        BTYPE_SUBNAME_ISSUE,
+        BTYPE_SUBNAME_REVOKE,
+    ]
+    .into_iter()
+    .map(|btype| SupportedBlockType {
+        block_type: btype.to_string(),
         url: "https://github.com/ldclabs/ic-panda/tree/main/src/ic_message".to_string(),
-    }]
+    })
+    .collect()
 }
 
 #[ic_cdk::query]
@@ -63,8 +106,8 @@ pub fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
 }
 
 #[ic_cdk::query]
-pub fn icrc3_get_archives(_args: GetArchivesArgs) -> GetArchivesResult {
-    vec![] // TODO: implement
+pub fn icrc3_get_archives(args: GetArchivesArgs) -> GetArchivesResult {
+    store::archive::list(args.from)
 }
 
 #[ic_cdk::query]
@@ -77,11 +120,190 @@ fn search_username(prefix: String) -> Result<Vec<String>, String> {
     Ok(store::user::search_username(prefix))
 }
 
+#[ic_cdk::query(composite = true)]
+async fn search_users(
+    query: String,
+    prev: Option<u64>,
+    take: Option<u32>,
+    prev_id: Option<Principal>,
+) -> Result<Vec<(UserInfo, ProfileInfo)>, String> {
+    if query.len() > 256 {
+        Err("query is too long".to_string())?;
+    }
+    let take = take.unwrap_or(20).min(100);
+    store::user::search(query, prev, prev_id, take).await
+}
+
+#[ic_cdk::query(composite = true, guard = "is_authenticated")]
+async fn get_my_kv(namespace: String, keys: BTreeSet<String>) -> Result<types::KVsOutput, String> {
+    types::validate_kv_namespace(&namespace)?;
+    store::user::get_my_kvs(ic_cdk::api::msg_caller(), namespace, keys).await
+}
+
 #[ic_cdk::query]
 fn get_by_username(username: String) -> Result<UserInfo, String> {
     store::user::get_by_username(username.to_ascii_lowercase())
 }
 
+#[ic_cdk::query]
+fn get_price() -> Result<types::PriceQuote, String> {
+    Ok(store::pricing::quote())
+}
+
+#[ic_cdk::query]
+fn get_price_history(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<(u64, types::PriceQuote)>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::pricing::history(prev, take as usize))
+}
+
+#[ic_cdk::query]
+fn get_username_term(name: String) -> Result<types::UsernameTerm, String> {
+    store::user::get_term(&name)
+}
+
+#[ic_cdk::query]
+fn get_name_history(
+    name: String,
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::NameHistory>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::history::name_history(
+        &name.to_ascii_lowercase(),
+        prev,
+        take as usize,
+    ))
+}
+
+#[ic_cdk::query]
+fn get_account_name_history(
+    account: Principal,
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::NameHistory>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::history::account_history(
+        account,
+        prev,
+        take as usize,
+    ))
+}
+
+#[ic_cdk::query]
+fn list_subnames(parent: String) -> Result<Vec<types::SubnameInfo>, String> {
+    Ok(store::subname::list(&parent.to_ascii_lowercase()))
+}
+
+#[ic_cdk::query]
+fn resolve_username(name: String, keys: Vec<String>) -> Result<types::ResolvedName, String> {
+    store::resolver::resolve(&name, keys, ic_cdk::api::time() / MILLISECONDS)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn username_record_message(key: String) -> Result<types::AddressMessage, String> {
+    store::resolver::address_message(
+        &ic_cdk::api::msg_caller(),
+        &key,
+        &ic_cdk::api::canister_self(),
+        ic_cdk::api::time() / MILLISECONDS,
+    )
+}
+
+#[ic_cdk::query]
+fn get_username_auction(name: String) -> Option<types::UsernameAuction> {
+    store::auction::get(&name)
+}
+
+#[ic_cdk::query]
+fn list_username_auctions(
+    prev: Option<String>,
+    take: Option<u32>,
+) -> Result<Vec<types::UsernameAuction>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::auction::list(
+        prev.map(|p| p.to_ascii_lowercase()),
+        take as usize,
+    ))
+}
+
+#[ic_cdk::query]
+fn get_reserved_name(name: String) -> Option<types::ReservedName> {
+    store::reserved::get(&name)
+}
+
+#[ic_cdk::query]
+fn list_reserved_names(
+    prev: Option<String>,
+    take: Option<u32>,
+) -> Result<Vec<types::ReservedName>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::reserved::list(
+        prev.map(|p| p.to_ascii_lowercase()),
+        take as usize,
+    ))
+}
+
+#[ic_cdk::query]
+fn list_name_disputes(
+    name: Option<String>,
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::NameDispute>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::reserved::list_disputes(name, prev, take as usize))
+}
+
+#[ic_cdk::query]
+fn get_username_listing(name: String) -> Option<types::UsernameListing> {
+    let ln = name.to_ascii_lowercase();
+    store::market::get(&ln).map(|listing| listing.into_info(ln))
+}
+
+#[ic_cdk::query]
+fn list_username_listings(
+    prev: Option<String>,
+    take: Option<u32>,
+) -> Result<Vec<types::UsernameListing>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::market::list_listings(
+        prev.map(|p| p.to_ascii_lowercase()),
+        take as usize,
+    ))
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_pending_operations() -> Result<Vec<types::PendingOperation>, String> {
+    Ok(store::pending::list_of(&ic_cdk::api::msg_caller()))
+}
+
+#[ic_cdk::query]
+fn list_pending_operations(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::PendingOperation>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller())?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::pending::list(prev, take as usize))
+}
+
+#[ic_cdk::query]
+fn list_pending_payouts(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::PendingPayout>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller())?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::payout::list(prev, take as usize))
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn my_username_offers() -> Result<Vec<types::UsernameListing>, String> {
+    Ok(store::market::offers_of(&ic_cdk::api::msg_caller()))
+}
+
 #[ic_cdk::query]
 fn get_user(user: Option<Principal>) -> Result<UserInfo, String> {
     store::user::get(user.unwrap_or(ic_cdk::api::msg_caller()))
@@ -97,3 +319,59 @@ fn my_iv() -> Result<ByteBuf, String> {
     let pk = store::state::ed25519_public_key(&ic_cdk::api::msg_caller())?;
     Ok(pk.public_key)
 }
+
+#[ic_cdk::query]
+fn get_attestation_public_key() -> Result<ByteBuf, String> {
+    store::signing::attestation_public_key()
+}
+
+// the derived Ed25519 public key that verifies signatures from `sign_with_my_key`
+#[ic_cdk::query]
+fn get_signing_public_key(user: Principal) -> Result<ByteBuf, String> {
+    let pk = store::state::ed25519_public_key(&user)?;
+    Ok(pk.public_key)
+}
+
+#[ic_cdk::query]
+fn get_canister_wasms() -> Result<Vec<types::WasmInfo>, String> {
+    Ok(store::upgrade::wasm_info())
+}
+
+#[ic_cdk::query]
+fn get_fleet_upgrade(id: u64) -> Option<types::FleetUpgrade> {
+    store::upgrade::get(id)
+}
+
+#[ic_cdk::query]
+fn list_fleet_upgrades(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::FleetUpgrade>, String> {
+    let take = take.unwrap_or(10).min(100);
+    Ok(store::upgrade::list(prev, take as usize))
+}
+
+#[ic_cdk::query]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller()).or_else(|_| crate::is_controller())?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
+}
+
+#[ic_cdk::query]
+fn list_treasury_entries(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<types::TreasuryEntry>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::treasury::list(prev, take as usize))
+}
+
+// the treasury aggregates by UTC day, `from` and `to` are timestamps in milliseconds
+#[ic_cdk::query]
+fn treasury_report(from: u64, to: u64) -> Result<types::TreasuryReport, String> {
+    store::treasury::report(from, to)
+}
@@ -47,6 +47,195 @@ async fn transfer_username(to: Principal) -> Result<(), String> {
     store::user::transfer_username(caller, to, now_ms).await
 }
 
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn renew_username(years: u64) -> Result<u64, String> {
+    if years == 0 || years > types::MAX_RENEWAL_YEARS {
+        Err(format!(
+            "years should be between 1 and {}",
+            types::MAX_RENEWAL_YEARS
+        ))?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::user::renew_username(caller, years, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn sign_with_my_key(message: ByteBuf) -> Result<ByteBuf, String> {
+    if message.is_empty() || message.len() > types::MAX_SIGN_MESSAGE_SIZE {
+        Err(format!(
+            "message should be between 1 and {} bytes",
+            types::MAX_SIGN_MESSAGE_SIZE
+        ))?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::signing::sign(caller, message.into_vec(), now_ms).await
+}
+
+// attest_username returns a signed NameAttestation of the name's current owner,
+// verifiable offline against `get_attestation_public_key`.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn attest_username(name: String) -> Result<ByteBuf, String> {
+    if name.is_empty() || name.len() > 2 * types::MAX_USER_NAME_SIZE + 1 {
+        Err("invalid username".to_string())?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::signing::attest(caller, name, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn retry_pending_operation(id: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let op = store::pending::get(id).ok_or_else(|| "pending operation not found".to_string())?;
+    if op.user != caller {
+        store::state::is_manager(&caller)?;
+    }
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::pending::run(id, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn file_name_dispute(name: String, claim: String) -> Result<types::NameDispute, String> {
+    if name.is_empty() || name.len() > types::MAX_USER_NAME_SIZE {
+        Err("invalid username".to_string())?;
+    }
+    if claim.is_empty() || claim.len() > types::MAX_DISPUTE_CLAIM_SIZE {
+        Err(format!(
+            "claim should be between 1 and {} bytes",
+            types::MAX_DISPUTE_CLAIM_SIZE
+        ))?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::reserved::file_dispute(caller, name, claim, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn create_subname(label: String, user: Principal) -> Result<types::SubnameInfo, String> {
+    if label.len() > types::MAX_USER_NAME_SIZE {
+        Err("label is too long".to_string())?;
+    }
+    validate_str(&label)?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::subname::create(caller, label, user, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn revoke_subname(label: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::subname::revoke(caller, label, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn update_username_records(input: types::UpdateResolverInput) -> Result<(), String> {
+    input.validate()?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::resolver::update(caller, input, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn verify_username_record(key: String, signature: ByteBuf, issued_at: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::resolver::verify_address(
+        caller,
+        key,
+        &signature,
+        ic_cdk::api::canister_self(),
+        issued_at,
+        now_ms,
+    )
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn commit_username_bid(
+    name: String,
+    commitment: ByteArray<32>,
+    deposit: u64,
+) -> Result<types::UsernameAuction, String> {
+    if name.len() > types::MAX_USER_NAME_SIZE {
+        Err("name is too long".to_string())?;
+    }
+    validate_str(&name.to_ascii_lowercase())?;
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::auction::commit_bid(caller, name, commitment, deposit, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn reveal_username_bid(name: String, amount: u64, salt: ByteBuf) -> Result<(), String> {
+    if salt.len() > 64 {
+        Err("salt is too long".to_string())?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::auction::reveal_bid(caller, name, amount, &salt, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn list_username(price: u64) -> Result<types::UsernameListing, String> {
+    if price < types::MIN_NAME_PRICE {
+        Err("price is too low".to_string())?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::list_username(caller, price, now_ms)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn cancel_username_listing(name: Option<String>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::cancel_listing(caller, name, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn buy_username(name: String, price: u64) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::buy(caller, name, price, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn offer_username(name: String, amount: u64) -> Result<types::UsernameListing, String> {
+    if amount < types::TOKEN_1 {
+        Err("offer is too low".to_string())?;
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::offer(caller, name, amount, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn withdraw_username_offer(name: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::withdraw_offer(caller, name, now_ms).await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn accept_username_offer(buyer: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::market::accept_offer(caller, buyer, now_ms).await
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 async fn update_my_name(name: String) -> Result<UserInfo, String> {
     if name.is_empty() {
@@ -60,7 +249,8 @@ async fn update_my_name(name: String) -> Result<UserInfo, String> {
     }
 
     let caller = ic_cdk::api::msg_caller();
-    store::user::update_name(caller, name).await
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::user::update_name(caller, now_ms, name).await
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
@@ -102,6 +292,19 @@ async fn update_my_kv(input: UpdateKVInput) -> Result<(), String> {
     store::user::update_my_kv(caller, input).await
 }
 
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn update_my_kvs(input: types::UpdateKVsInput) -> Result<u32, String> {
+    input.validate()?;
+    for value in input.upsert.values() {
+        if let types::KVValue::Encrypted(data) = value {
+            try_decode_encrypt0(data)?;
+        }
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    store::user::update_my_kvs(caller, input).await
+}
+
 #[ic_cdk::update(guard = "is_authenticated")]
 async fn create_channel(input: CreateChannelInput) -> Result<ChannelInfo, String> {
     let caller = ic_cdk::api::msg_caller();
@@ -118,7 +321,8 @@ async fn topup_channel(input: ChannelTopupInput) -> Result<ChannelInfo, String>
     input.validate()?;
 
     let caller = ic_cdk::api::msg_caller();
-    store::channel::topup_channel(caller, input).await
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::channel::topup_channel(caller, now_ms, input).await
 }
 
 // DEPRECATED
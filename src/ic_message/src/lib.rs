@@ -4,7 +4,7 @@ use ic_cdk::management_canister::CanisterStatusResult;
 use ic_cose_types::ANONYMOUS;
 use ic_message_types::{
     channel::{ChannelInfo, ChannelKEKInput, ChannelTopupInput, CreateChannelInput},
-    profile::{UpdateKVInput, UserInfo},
+    profile::{ProfileInfo, UpdateKVInput, UserInfo},
 };
 use icrc_ledger_types::icrc3::{
     archive::{GetArchivesArgs, GetArchivesResult},
@@ -17,10 +17,12 @@ use icrc_ledger_types::{
     },
     icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
+use lib_panda::CanisterMetrics;
 use serde_bytes::{ByteArray, ByteBuf};
 use std::collections::BTreeSet;
 
 mod api_admin;
+mod api_http;
 mod api_init;
 mod api_query;
 mod api_update;
@@ -32,6 +34,10 @@ use crate::api_init::ChainArgs;
 
 // "druyg-tyaaa-aaaaq-aactq-cai" PANDA token canister id
 static TOKEN_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 0, 0, 167, 1, 1]);
+// "ryjl3-tyaaa-aaaaa-aaaba-cai" ICP ledger canister id
+static ICP_LEDGER_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 2, 1, 1]);
+// "rkp4c-7iaaa-aaaaa-aaaca-cai" cycles minting canister id
+static CMC_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 0, 0, 0, 4, 1, 1]);
 // "dwv6s-6aaaa-aaaaq-aacta-cai" ICPanda DAO canister id
 static DAO_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 0, 0, 166, 1, 1]);
 // "ql553-iqaaa-aaaap-anuyq-cai" dMsg minter canister id
@@ -80,6 +86,24 @@ where
     })
 }
 
+// calls from a composite query can not be bounded wait calls
+async fn query_call<In, Out>(id: Principal, method: &str, args: In) -> Result<Out, String>
+where
+    In: ArgumentEncoder + Send,
+    Out: candid::CandidType + for<'a> candid::Deserialize<'a>,
+{
+    let res = ic_cdk::call::Call::unbounded_wait(id, method)
+        .with_args(&args)
+        .await
+        .map_err(|err| format!("failed to call {} on {:?}, error: {:?}", method, &id, err))?;
+    res.candid().map_err(|err| {
+        format!(
+            "failed to decode response from {} on {:?}, error: {:?}",
+            method, &id, err
+        )
+    })
+}
+
 async fn token_transfer_to(user: Account, amount: Nat, memo: String) -> Result<Nat, String> {
     let res: Result<Nat, TransferError> = call(
         TOKEN_CANISTER,
@@ -98,6 +122,37 @@ async fn token_transfer_to(user: Account, amount: Nat, memo: String) -> Result<N
     res.map_err(|err| format!("failed to transfer tokens, error: {:?}", err))
 }
 
+// token_payout transfers tokens with a fixed created_at_time, so that the ledger deduplicates
+// the retries of the same payout. The error is `(rejected, message)`, `rejected` is true if the
+// ledger rejected the transfer, otherwise the outcome of the call is unknown.
+async fn token_payout(
+    user: Account,
+    amount: Nat,
+    memo: String,
+    created_at_time: u64,
+) -> Result<Nat, (bool, String)> {
+    let res: Result<Nat, TransferError> = call(
+        TOKEN_CANISTER,
+        "icrc1_transfer",
+        (TransferArg {
+            from_subaccount: None,
+            to: user,
+            fee: None,
+            created_at_time: Some(created_at_time),
+            memo: Some(Memo(ByteBuf::from(memo.into_bytes()))),
+            amount,
+        },),
+        0,
+    )
+    .await
+    .map_err(|err| (false, err))?;
+    match res {
+        Ok(blk) => Ok(blk),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of),
+        Err(err) => Err((true, format!("failed to transfer tokens, error: {:?}", err))),
+    }
+}
+
 async fn token_transfer_from(user: Principal, amount: Nat, memo: String) -> Result<Nat, String> {
     let res: Result<Nat, TransferFromError> = call(
         TOKEN_CANISTER,
@@ -51,3 +51,25 @@ pub async fn schnorr_public_key(
         chain_code: ByteBuf::from(rt.chain_code),
     })
 }
+
+pub async fn sign_with_schnorr(
+    key_name: String,
+    alg: mgt::SchnorrAlgorithm,
+    derivation_path: Vec<Vec<u8>>,
+    message: Vec<u8>,
+) -> Result<ByteBuf, String> {
+    let args = mgt::SignWithSchnorrArgs {
+        message,
+        derivation_path,
+        key_id: mgt::SchnorrKeyId {
+            algorithm: alg,
+            name: key_name,
+        },
+        aux: None,
+    };
+
+    let rt = mgt::sign_with_schnorr(&args)
+        .await
+        .map_err(|err| format!("sign_with_schnorr failed {:?}", err))?;
+    Ok(ByteBuf::from(rt.signature))
+}
@@ -2,7 +2,7 @@ use candid::{Nat, Principal};
 use ciborium::{from_reader, from_reader_with_buffer, into_writer};
 use ic_certification::{HashTreeNode, Label};
 use ic_cose_types::types::{
-    namespace::CreateNamespaceInput,
+    namespace::{CreateNamespaceInput, UpdateNamespaceInput},
     setting::{
         CreateSettingInput, CreateSettingOutput, SettingInfo, SettingPath, UpdateSettingOutput,
         UpdateSettingPayloadInput,
@@ -10,24 +10,27 @@ use ic_cose_types::types::{
     PublicKeyOutput, SchnorrAlgorithm,
 };
 use ic_message_types::{
-    profile::{UpdateKVInput, UserInfo},
-    NameBlock,
+    profile::{ProfileInfo, UpdateKVInput, UserInfo},
+    NameBlock, NameOp,
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
     DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
+use lib_panda::audit::{AuditLog, AuditLogs};
+use lib_panda::CanisterMetrics;
 use serde::{Deserialize, Serialize};
 use serde_bytes::{ByteArray, ByteBuf};
 use std::{
     borrow::Cow,
     cell::RefCell,
     collections::{BTreeMap, BTreeSet, VecDeque},
+    thread::LocalKey,
 };
 
 use crate::schnorr::{derive_25519_public_key, schnorr_public_key};
-use crate::{call, get_name_principal, token_transfer_from, types};
+use crate::{call, get_name_principal, query_call, token_transfer_from, types};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -41,7 +44,7 @@ pub struct State {
     #[serde(default)]
     pub matured_channel_canisters: BTreeSet<Principal>,
     pub short_usernames: BTreeSet<String>, // names that length <= 7
-    pub price: types::Price,
+    pub price: types::Price,               // fixed PANDA prices, used when there is no quote
     pub incoming_total: u128,
     pub transfer_out_total: u128,
     pub next_block_height: u64,
@@ -54,6 +57,60 @@ pub struct State {
     pub init_vector: ByteArray<32>, // initialization vector should not be exposed
     #[serde(default)]
     pub latest_usernames: VecDeque<String>, // 20 latest registered usernames
+    #[serde(default)]
+    pub auction: types::AuctionConfig,
+    #[serde(default)]
+    pub archives: Vec<ArchiveRange>, // the last used one receives new archived blocks
+    #[serde(default)]
+    pub archived_blocks: u64, // blocks lower than this height are in archives
+    #[serde(default)]
+    pub blocks_offset: u64, // height of the first block in NAME_BLOCKS
+    #[serde(default)]
+    pub blocks_alt: bool, // NAME_BLOCKS is stored in the alternate log memories
+    #[serde(default)]
+    pub typed_blocks_from: Option<u64>, // lower blocks are CBOR blobs chained with sha3_256 hashes
+    #[serde(default)]
+    pub indexed_blocks: u64, // blocks lower than this height are in the history indexes
+    #[serde(default)]
+    pub pricing: types::PricingConfig,
+    #[serde(default)]
+    pub price_quote: Option<types::PriceQuote>, // the latest quote in the pricing currency
+    #[serde(default)]
+    pub signing: types::SigningConfig,
+    #[serde(default)]
+    pub sign_rates: BTreeMap<Principal, (u64, u32)>, // user -> (window start, signatures in the window)
+    #[serde(default)]
+    pub sign_rate_total: (u64, u32), // (window start, signatures of all users in the window)
+    #[serde(default)]
+    pub provisioning: types::ProvisioningConfig,
+    #[serde(default)]
+    pub canister_loads: BTreeMap<Principal, types::CanisterLoad>, // channel and profile canisters
+    #[serde(default)]
+    pub provisioning_canister: Option<(types::CanisterKind, Principal)>, // created, not set up yet
+    #[serde(default)]
+    pub cycles_monitor: types::CyclesMonitorConfig,
+    #[serde(default)]
+    pub canister_metrics: BTreeMap<Principal, CanisterMetrics>,
+    #[serde(default)]
+    pub pending_top_ups: BTreeMap<Principal, u64>, // canister -> ICP block index not notified to the CMC yet
+    #[serde(default)]
+    pub wasm_uploads: BTreeMap<String, WasmUpload>, // wasm key -> the upload in progress
+}
+
+// WasmUpload is a wasm module being uploaded in chunks to WASM_CHUNK_STORE.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct WasmUpload {
+    pub hash: ByteArray<32>, // expected SHA-256 of the whole wasm module
+    pub size: u64,           // expected size of the whole wasm module
+    pub uploaded: u64,
+    pub chunks: u32,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ArchiveRange {
+    pub canister: Principal,
+    pub start: u64,
+    pub end: u64, // exclusive
 }
 
 impl State {
@@ -61,8 +118,10 @@ impl State {
         self.hash_tree().digest()
     }
 
+    // hash_tree certifies the ICRC-3 tip (`last_block_index`, `last_block_hash`)
+    // along with the next block height and phash, with labels in sorted order.
     pub fn hash_tree(&self) -> HashTreeNode {
-        HashTreeNode::Fork(Box::new((
+        let next = HashTreeNode::Fork(Box::new((
             HashTreeNode::Labeled(
                 Label::from("next_block_height"),
                 Box::new(HashTreeNode::Leaf(
@@ -73,7 +132,35 @@ impl State {
                 Label::from("next_block_phash"),
                 Box::new(HashTreeNode::Leaf(self.next_block_phash.to_vec())),
             ),
-        )))
+        )));
+        if self.next_block_height == 0 {
+            return next;
+        }
+
+        let last = HashTreeNode::Fork(Box::new((
+            HashTreeNode::Labeled(
+                Label::from("last_block_hash"),
+                Box::new(HashTreeNode::Leaf(self.next_block_phash.to_vec())),
+            ),
+            HashTreeNode::Labeled(
+                Label::from("last_block_index"),
+                Box::new(HashTreeNode::Leaf(leb128(self.next_block_height - 1))),
+            ),
+        )));
+        HashTreeNode::Fork(Box::new((last, next)))
+    }
+}
+
+fn leb128(mut v: u64) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(10);
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(b);
+            return buf;
+        }
+        buf.push(b | 0x80);
     }
 }
 
@@ -144,873 +231,5951 @@ impl Storable for User {
     }
 }
 
-const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
-const NAME_MEMORY_ID: MemoryId = MemoryId::new(1);
-const USER_MEMORY_ID: MemoryId = MemoryId::new(2);
-const NAME_BLK_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
-const NAME_BLK_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Listing {
+    #[serde(rename = "s")]
+    pub seller: Principal,
+    #[serde(rename = "p")]
+    pub price: u64,
+    #[serde(rename = "c")]
+    pub created_at: u64,
+    #[serde(rename = "o")]
+    pub offers: BTreeMap<Principal, (u64, u64)>, // buyer -> (escrowed amount, created_at)
+}
 
-thread_local! {
-    static STATE: RefCell<State> = RefCell::new(State::default());
+impl Listing {
+    pub fn into_info(self, name: String) -> types::UsernameListing {
+        types::UsernameListing {
+            name,
+            seller: self.seller,
+            price: self.price,
+            created_at: self.created_at,
+            offers: self
+                .offers
+                .into_iter()
+                .map(|(buyer, (amount, created_at))| types::UsernameOffer {
+                    buyer,
+                    amount,
+                    created_at,
+                })
+                .collect(),
+        }
+    }
+}
 
-    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
-        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+impl Storable for Listing {
+    const BOUND: Bound = Bound::Unbounded;
 
-    static STATE_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
-        StableCell::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
-            Vec::new()
-        )
-    );
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Listing data");
+        buf
+    }
 
-    static NAME_STORE: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_MEMORY_ID)),
-        )
-    );
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Listing data");
+        Cow::Owned(buf)
+    }
 
-    static USER_STORE: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(
-        StableBTreeMap::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(USER_MEMORY_ID)),
-        )
-    );
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Listing data")
+    }
+}
 
-    static NAME_BLOCKS: RefCell<StableLog<Vec<u8>, Memory, Memory>> = RefCell::new(
-        StableLog::init(
-            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_INDEX_MEMORY_ID)),
-            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_DATA_MEMORY_ID)),
-        )
-    );
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Auction {
+    #[serde(rename = "u")]
+    pub username: String,
+    #[serde(rename = "s")]
+    pub started_at: u64,
+    #[serde(rename = "c")]
+    pub commit_end: u64,
+    #[serde(rename = "r")]
+    pub reveal_end: u64,
+    #[serde(rename = "b")]
+    pub bids: BTreeMap<Principal, Bid>,
 }
 
-pub mod state {
-    use super::*;
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Bid {
+    #[serde(rename = "h")]
+    pub commitment: ByteArray<32>,
+    #[serde(rename = "d")]
+    pub deposit: u64,
+    #[serde(rename = "a")]
+    pub amount: Option<u64>, // revealed amount
+    #[serde(rename = "c")]
+    pub created_at: u64,
+}
 
-    pub fn with<R>(f: impl FnOnce(&State) -> R) -> R {
-        STATE.with_borrow(f)
+impl Auction {
+    pub fn into_info(self, name: String, reserve_price: u64) -> types::UsernameAuction {
+        types::UsernameAuction {
+            name,
+            reserve_price,
+            started_at: self.started_at,
+            commit_end: self.commit_end,
+            reveal_end: self.reveal_end,
+            bids: self
+                .bids
+                .into_iter()
+                .map(|(bidder, bid)| types::AuctionBid {
+                    bidder,
+                    deposit: bid.deposit,
+                    amount: bid.amount,
+                    created_at: bid.created_at,
+                })
+                .collect(),
+        }
     }
+}
 
-    pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
-        STATE.with_borrow_mut(f)
-    }
+impl Storable for Auction {
+    const BOUND: Bound = Bound::Unbounded;
 
-    pub fn is_manager(caller: &Principal) -> Result<(), String> {
-        STATE.with_borrow(|r| match r.managers.contains(caller) {
-            true => Ok(()),
-            false => Err("caller is not a manager".to_string()),
-        })
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Auction data");
+        buf
     }
 
-    pub fn load() {
-        let mut scratch = [0; 4096];
-        STATE_STORE.with_borrow(|r| {
-            STATE.with_borrow_mut(|h| {
-                let v: State = from_reader_with_buffer(&r.get()[..], &mut scratch)
-                    .expect("failed to decode STATE_STORE data");
-                *h = v;
-            });
-        });
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Auction data");
+        Cow::Owned(buf)
     }
 
-    pub fn save() {
-        STATE.with_borrow(|h| {
-            STATE_STORE.with_borrow_mut(|r| {
-                let mut buf = vec![];
-                into_writer(h, &mut buf).expect("failed to encode STATE_STORE data");
-                r.set(buf);
-            });
-        });
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Auction data")
     }
+}
 
-    pub fn ed25519_public_key(caller: &Principal) -> Result<PublicKeyOutput, String> {
-        STATE.with_borrow(|s| {
-            let pk = s
-                .ed25519_public_key
-                .as_ref()
-                .ok_or("no schnorr ed25519 public key")?;
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Resolver {
+    #[serde(rename = "o")]
+    pub owner: Principal, // records are dropped when the username changes owner
+    #[serde(rename = "r")]
+    pub records: BTreeMap<String, (types::ResolverRecord, Option<u64>)>, // key -> (record, verified_at)
+    #[serde(rename = "u")]
+    pub updated_at: u64,
+    #[serde(default, rename = "n")]
+    pub nonce: u64, // bumped by each address verification, so a signed message is used once
+}
 
-            let path: Vec<Vec<u8>> = vec![
-                b"ICPanda_IV".to_vec(),
-                caller.to_bytes().to_vec(),
-                s.init_vector.to_vec(),
-            ];
-            derive_25519_public_key(pk, path)
-        })
-    }
+impl Storable for Resolver {
+    const BOUND: Bound = Bound::Unbounded;
 
-    pub async fn try_init_public_key() {
-        let (schnorr_key_name, ed25519_public_key) =
-            STATE.with_borrow(|s| (s.schnorr_key_name.clone(), s.ed25519_public_key.clone()));
-        if schnorr_key_name.is_empty() || ed25519_public_key.is_some() {
-            return;
-        }
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Resolver data");
+        buf
+    }
 
-        let ed25519_public_key =
-            schnorr_public_key(schnorr_key_name.clone(), SchnorrAlgorithm::Ed25519, vec![])
-                .await
-                .map_err(|err| {
-                    ic_cdk::api::debug_print(format!(
-                        "failed to retrieve Schnorr Ed25519 public key: {err}"
-                    ))
-                })
-                .ok();
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Resolver data");
+        Cow::Owned(buf)
+    }
 
-        let mut data = ic_cdk::management_canister::raw_rand()
-            .await
-            .expect("failed to generate IV");
-        data.truncate(32);
-        let iv: [u8; 32] = data.try_into().expect("failed to generate IV");
-        STATE.with_borrow_mut(|r| {
-            r.ed25519_public_key = ed25519_public_key;
-            r.init_vector = iv.into();
-        });
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Resolver data")
     }
 }
 
-pub mod user {
-    use ic_cose_types::{cose::sha3_256, to_cbor_bytes};
-    use icrc_ledger_types::icrc3::blocks::{
-        BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock,
-    };
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Subname {
+    #[serde(rename = "u")]
+    pub user: Principal,
+    #[serde(rename = "i")]
+    pub issuer: Principal, // the parent owner, subnames are invalid after the parent changes owner
+    #[serde(rename = "c")]
+    pub created_at: u64,
+}
 
-    use super::*;
+impl Storable for Subname {
+    const BOUND: Bound = Bound::Unbounded;
 
-    pub fn names_total() -> u64 {
-        NAME_STORE.with_borrow(|r| r.len())
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Subname data");
+        buf
     }
 
-    pub fn users_total() -> u64 {
-        USER_STORE.with_borrow(|r| r.len())
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Subname data");
+        Cow::Owned(buf)
     }
 
-    pub async fn update_name(caller: Principal, name: String) -> Result<UserInfo, String> {
-        let profile_canister = state::with(|s| s.profile_canisters.last().cloned());
-        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Subname data")
+    }
+}
 
-        let (new_profile, info) = USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
-            Some(mut user) => {
-                user.name = name;
-                r.insert(caller, user.clone());
-                (false, user.into_info(caller))
-            }
-            None => {
-                let user = User {
-                    name,
-                    image: "".to_string(),
-                    profile_canister,
-                    cose_canister: None,
-                    username: None,
-                };
-                r.insert(caller, user.clone());
-                (true, user.into_info(caller))
-            }
-        });
+#[derive(Clone, Deserialize, Serialize)]
+pub struct NameEvent {
+    #[serde(rename = "h")]
+    pub height: u64,
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "u")]
+    pub user: Principal,
+    #[serde(rename = "f")]
+    pub from: Option<Principal>,
+    #[serde(rename = "v")]
+    pub value: u64,
+    #[serde(rename = "t")]
+    pub timestamp: u64,
+    #[serde(default, rename = "o")]
+    pub op: Option<NameOp>,
+}
 
-        if new_profile {
-            let _: Result<(), String> = call(
-                info.profile_canister,
-                "admin_upsert_profile",
-                (caller, None::<(Principal, u64)>),
-                0,
-            )
-            .await?;
+impl From<&NameBlock> for NameEvent {
+    fn from(blk: &NameBlock) -> Self {
+        Self {
+            height: blk.height,
+            name: blk.name.clone(),
+            user: blk.user,
+            from: blk.from,
+            value: blk.value,
+            timestamp: blk.timestamp,
+            op: blk.op,
         }
-        Ok(info)
     }
+}
 
-    pub fn update_username(caller: Principal, username: String) -> Result<UserInfo, String> {
-        USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
-            Some(mut user) => {
-                let ln = username.to_lowercase();
-                NAME_STORE.with_borrow(|r| match r.get(&ln) {
-                    Some(id) if id == caller => Ok(()),
-                    _ => Err("username not match".to_string()),
-                })?;
-                user.username = Some(username);
-                r.insert(caller, user.clone());
-                Ok(user.into_info(caller))
-            }
-            None => Err("user not found".to_string()),
-        })
+impl NameEvent {
+    pub fn into_info(self) -> types::NameHistory {
+        let blk = NameBlock {
+            height: self.height,
+            phash: [0u8; 32].into(),
+            name: self.name,
+            user: self.user,
+            from: self.from,
+            value: self.value,
+            timestamp: self.timestamp,
+            op: self.op,
+        };
+        types::NameHistory {
+            height: blk.height,
+            btype: blk.btype().to_string(),
+            name: blk.name,
+            user: blk.user,
+            from: blk.from,
+            value: blk.value,
+            timestamp: blk.timestamp,
+        }
     }
+}
 
-    pub fn update_image(caller: Principal, image: String) -> Result<(), String> {
-        USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
+impl Storable for NameEvent {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode NameEvent data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode NameEvent data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode NameEvent data")
+    }
+}
+
+// (name, height) key of NAME_HISTORY_STORE
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NameHeight(String, u64);
+
+impl Storable for NameHeight {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode NameHeight data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode NameHeight data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode NameHeight data")
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Reserved {
+    #[serde(rename = "r")]
+    pub reason: String,
+    #[serde(rename = "a")]
+    pub allocated_to: Option<Principal>,
+    #[serde(rename = "c")]
+    pub created_at: u64,
+}
+
+impl Reserved {
+    pub fn into_info(self, name: String) -> types::ReservedName {
+        types::ReservedName {
+            name,
+            reason: self.reason,
+            allocated_to: self.allocated_to,
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl Storable for Reserved {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Reserved data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Reserved data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Reserved data")
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Dispute {
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "c")]
+    pub claimant: Principal,
+    #[serde(rename = "h")]
+    pub holder: Option<Principal>, // the owner when the dispute was filed
+    #[serde(rename = "m")]
+    pub claim: String,
+    #[serde(rename = "s")]
+    pub status: types::DisputeStatus,
+    #[serde(rename = "r")]
+    pub resolution: String,
+    #[serde(rename = "ca")]
+    pub created_at: u64,
+    #[serde(rename = "ra")]
+    pub resolved_at: u64,
+}
+
+impl Dispute {
+    pub fn into_info(self, id: u64) -> types::NameDispute {
+        types::NameDispute {
+            id,
+            name: self.name,
+            claimant: self.claimant,
+            holder: self.holder,
+            claim: self.claim,
+            status: self.status,
+            resolution: self.resolution,
+            created_at: self.created_at,
+            resolved_at: self.resolved_at,
+        }
+    }
+}
+
+impl Storable for Dispute {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Dispute data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Dispute data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Dispute data")
+    }
+}
+
+// Upgrade is a fleet upgrade of all registered canisters of a kind.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Upgrade {
+    #[serde(rename = "k")]
+    pub kind: types::CanisterKind,
+    #[serde(rename = "h")]
+    pub wasm_hash: ByteArray<32>,
+    #[serde(rename = "r")]
+    pub rollback_wasm_hash: Option<ByteArray<32>>,
+    #[serde(rename = "b")]
+    pub batch_size: u32,
+    #[serde(rename = "a")]
+    pub arg: ByteBuf,
+    #[serde(rename = "s")]
+    pub status: types::UpgradeStatus,
+    #[serde(rename = "c")]
+    pub canisters: Vec<UpgradeTarget>,
+    #[serde(rename = "e")]
+    pub error: Option<String>,
+    #[serde(rename = "u")]
+    pub created_by: Principal,
+    #[serde(rename = "ca")]
+    pub created_at: u64,
+    #[serde(rename = "ua")]
+    pub updated_at: u64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct UpgradeTarget {
+    #[serde(rename = "c")]
+    pub canister: Principal,
+    #[serde(rename = "s")]
+    pub status: types::CanisterUpgradeStatus,
+    #[serde(rename = "p")]
+    pub prev_module_hash: Option<ByteBuf>,
+    #[serde(rename = "e")]
+    pub error: Option<String>,
+    #[serde(rename = "t")]
+    pub updated_at: u64,
+}
+
+impl Upgrade {
+    pub fn into_info(self, id: u64) -> types::FleetUpgrade {
+        types::FleetUpgrade {
+            id,
+            kind: self.kind,
+            wasm_hash: self.wasm_hash,
+            rollback_wasm_hash: self.rollback_wasm_hash,
+            batch_size: self.batch_size,
+            status: self.status,
+            canisters: self
+                .canisters
+                .into_iter()
+                .map(|c| types::CanisterUpgrade {
+                    canister: c.canister,
+                    status: c.status,
+                    prev_module_hash: c.prev_module_hash,
+                    error: c.error,
+                    updated_at: c.updated_at,
+                })
+                .collect(),
+            error: self.error,
+            created_by: self.created_by,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+impl Storable for Upgrade {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Upgrade data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Upgrade data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Upgrade data")
+    }
+}
+
+// Alert is recorded when the cycles monitor cannot top up a canister.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Alert {
+    #[serde(rename = "c")]
+    pub canister: Principal,
+    #[serde(rename = "b")]
+    pub cycles_balance: Option<u128>,
+    #[serde(rename = "m")]
+    pub message: String,
+    #[serde(rename = "ca")]
+    pub created_at: u64,
+}
+
+impl Alert {
+    pub fn into_info(self, id: u64) -> types::CyclesAlert {
+        types::CyclesAlert {
+            id,
+            canister: self.canister,
+            cycles_balance: self.cycles_balance,
+            message: self.message,
+            created_at: self.created_at,
+        }
+    }
+}
+
+impl Storable for Alert {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Alert data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Alert data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Alert data")
+    }
+}
+
+// PendingOp is a registration whose cross-canister steps are not done yet.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct PendingOp {
+    #[serde(rename = "u")]
+    pub user: Principal,
+    #[serde(rename = "n")]
+    pub username: String,
+    #[serde(rename = "d")]
+    pub desc: String,
+    #[serde(rename = "cc")]
+    pub cose_canister: Principal,
+    #[serde(rename = "s")]
+    pub steps: u8, // remaining steps
+    #[serde(rename = "a")]
+    pub attempts: u32,
+    #[serde(rename = "e")]
+    pub error: Option<String>,
+    #[serde(rename = "ca")]
+    pub created_at: u64,
+    #[serde(rename = "ua")]
+    pub updated_at: u64,
+}
+
+impl PendingOp {
+    pub fn into_info(self, id: u64) -> types::PendingOperation {
+        types::PendingOperation {
+            id,
+            user: self.user,
+            kind: "register_username".to_string(),
+            username: self.username,
+            pending_steps: pending::step_names(self.steps),
+            attempts: self.attempts,
+            error: self.error,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+impl Storable for PendingOp {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode PendingOp data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode PendingOp data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode PendingOp data")
+    }
+}
+
+// Payout is a token transfer out of the canister, it is retried until the ledger accepts it.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Payout {
+    #[serde(rename = "to")]
+    pub to: Principal,
+    #[serde(rename = "a")]
+    pub amount: u64,
+    #[serde(rename = "m")]
+    pub memo: String,
+    #[serde(rename = "ct")]
+    pub created_at_time: u64, // nanoseconds, the ledger deduplicates the retries with it
+    #[serde(rename = "at")]
+    pub attempts: u32,
+    #[serde(rename = "e")]
+    pub error: Option<String>,
+    #[serde(rename = "ca")]
+    pub created_at: u64,
+    #[serde(rename = "ua")]
+    pub updated_at: u64,
+    #[serde(default, rename = "k")]
+    pub kind: Option<types::TreasuryKind>, // recorded in the treasury ledger when paid
+    #[serde(default, rename = "u")]
+    pub uncertain: bool, // the last attempt has an unknown outcome, the ledger maybe executed it
+}
+
+impl Payout {
+    // needs_review returns true if the payout can not be retried safely: an earlier attempt
+    // maybe executed and the ledger no longer deduplicates its created_at_time.
+    pub fn needs_review(&self, now_ns: u64) -> bool {
+        self.uncertain && now_ns.saturating_sub(self.created_at_time) > payout::DEDUP_WINDOW_NS
+    }
+
+    pub fn into_info(self, id: u64, now_ns: u64) -> types::PendingPayout {
+        let needs_review = self.needs_review(now_ns);
+        types::PendingPayout {
+            id,
+            to: self.to,
+            amount: self.amount,
+            memo: self.memo,
+            attempts: self.attempts,
+            error: self.error,
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            needs_review,
+        }
+    }
+}
+
+impl Storable for Payout {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode Payout data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode Payout data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode Payout data")
+    }
+}
+
+// TreasuryRecord is an itemized movement of the DAO treasury.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct TreasuryRecord {
+    #[serde(rename = "k")]
+    pub kind: types::TreasuryKind,
+    #[serde(rename = "u")]
+    pub account: Principal,
+    #[serde(rename = "a")]
+    pub amount: u64,
+    #[serde(rename = "b")]
+    pub block: Option<u64>,
+    #[serde(rename = "m")]
+    pub memo: String,
+    #[serde(rename = "t")]
+    pub timestamp: u64,
+}
+
+impl TreasuryRecord {
+    pub fn into_info(self, id: u64) -> types::TreasuryEntry {
+        types::TreasuryEntry {
+            id,
+            kind: self.kind,
+            account: self.account,
+            amount: self.amount,
+            block: self.block,
+            memo: self.memo,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+impl Storable for TreasuryRecord {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode TreasuryRecord data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode TreasuryRecord data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode TreasuryRecord data")
+    }
+}
+
+// TreasuryDay aggregates the treasury records of a UTC day by kind.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct TreasuryDay {
+    #[serde(rename = "t")]
+    pub totals: BTreeMap<types::TreasuryKind, (u64, u128)>, // (count, amount)
+}
+
+impl Storable for TreasuryDay {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode TreasuryDay data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode TreasuryDay data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode TreasuryDay data")
+    }
+}
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const NAME_MEMORY_ID: MemoryId = MemoryId::new(1);
+const USER_MEMORY_ID: MemoryId = MemoryId::new(2);
+const NAME_BLK_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const NAME_BLK_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
+const MARKET_MEMORY_ID: MemoryId = MemoryId::new(5);
+const NAME_EXPIRY_MEMORY_ID: MemoryId = MemoryId::new(6);
+const AUCTION_MEMORY_ID: MemoryId = MemoryId::new(7);
+const RESOLVER_MEMORY_ID: MemoryId = MemoryId::new(8);
+const SUBNAME_MEMORY_ID: MemoryId = MemoryId::new(9);
+const NAME_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(10);
+const ACCOUNT_HISTORY_MEMORY_ID: MemoryId = MemoryId::new(11);
+const PRICE_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(12);
+const PRICE_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(13);
+const RESERVED_MEMORY_ID: MemoryId = MemoryId::new(14);
+const DISPUTE_MEMORY_ID: MemoryId = MemoryId::new(15);
+const PENDING_MEMORY_ID: MemoryId = MemoryId::new(16);
+const WASM_MEMORY_ID: MemoryId = MemoryId::new(17);
+const UPGRADE_MEMORY_ID: MemoryId = MemoryId::new(18);
+const ALERT_MEMORY_ID: MemoryId = MemoryId::new(19);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(20);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(21);
+const TREASURY_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(22);
+const TREASURY_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(23);
+const TREASURY_DAILY_MEMORY_ID: MemoryId = MemoryId::new(24);
+const PAYOUT_MEMORY_ID: MemoryId = MemoryId::new(25);
+const NAME_BLK_ALT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(26);
+const NAME_BLK_ALT_DATA_MEMORY_ID: MemoryId = MemoryId::new(27);
+const OPEN_DISPUTE_MEMORY_ID: MemoryId = MemoryId::new(28);
+const WASM_CHUNK_MEMORY_ID: MemoryId = MemoryId::new(29);
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+
+    // usernames in trading, to prevent concurrent trades
+    static MARKET_LOCKS: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+
+    // an archiving is in progress
+    static ARCHIVING: RefCell<bool> = const { RefCell::new(false) };
+
+    // a provisioning is in progress
+    static PROVISIONING: RefCell<bool> = const { RefCell::new(false) };
+
+    // a fleet upgrade is in progress
+    static UPGRADING: RefCell<bool> = const { RefCell::new(false) };
+
+    // a cycles monitoring round is in progress
+    static MONITORING: RefCell<bool> = const { RefCell::new(false) };
+
+    // pending operations being run
+    static PENDING_LOCKS: RefCell<BTreeSet<u64>> = const { RefCell::new(BTreeSet::new()) };
+
+    // a history indexing is in progress
+    static INDEXING: RefCell<bool> = const { RefCell::new(false) };
+
+    // (expires_at, name), rebuilt from NAME_EXPIRY_STORE after upgrade
+    static EXPIRY_QUEUE: RefCell<BTreeSet<(u64, String)>> = const { RefCell::new(BTreeSet::new()) };
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            Vec::new()
+        )
+    );
+
+    static NAME_STORE: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_MEMORY_ID)),
+        )
+    );
+
+    static USER_STORE: RefCell<StableBTreeMap<Principal, User, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(USER_MEMORY_ID)),
+        )
+    );
+
+    static NAME_BLOCKS: RefCell<StableLog<Vec<u8>, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_DATA_MEMORY_ID)),
+        )
+    );
+
+    static RESERVED_STORE: RefCell<StableBTreeMap<String, Reserved, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(RESERVED_MEMORY_ID)),
+        )
+    );
+
+    static DISPUTE_STORE: RefCell<StableBTreeMap<u64, Dispute, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(DISPUTE_MEMORY_ID)),
+        )
+    );
+
+    // (claimant, dispute id) of the open disputes
+    static OPEN_DISPUTE_STORE: RefCell<StableBTreeMap<(Principal, u64), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(OPEN_DISPUTE_MEMORY_ID)),
+        )
+    );
+
+    static PENDING_STORE: RefCell<StableBTreeMap<u64, PendingOp, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PENDING_MEMORY_ID)),
+        )
+    );
+
+    static PAYOUT_STORE: RefCell<StableBTreeMap<u64, Payout, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PAYOUT_MEMORY_ID)),
+        )
+    );
+
+    // "channel" or "profile" -> wasm module to install new canisters
+    static WASM_STORE: RefCell<StableBTreeMap<String, Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(WASM_MEMORY_ID)),
+        )
+    );
+
+    // (canister kind, index) -> chunk of the wasm being uploaded
+    static WASM_CHUNK_STORE: RefCell<StableBTreeMap<(u8, u32), Vec<u8>, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(WASM_CHUNK_MEMORY_ID)),
+        )
+    );
+
+    static ALERT_STORE: RefCell<StableBTreeMap<u64, Alert, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ALERT_MEMORY_ID)),
+        )
+    );
+
+    static UPGRADE_STORE: RefCell<StableBTreeMap<u64, Upgrade, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(UPGRADE_MEMORY_ID)),
+        )
+    );
+
+    // quotes of the pricing oracle, CBOR encoded PriceQuote
+    static PRICE_LOG: RefCell<StableLog<Vec<u8>, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRICE_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(PRICE_LOG_DATA_MEMORY_ID)),
+        )
+    );
+
+    static MARKET_STORE: RefCell<StableBTreeMap<String, Listing, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(MARKET_MEMORY_ID)),
+        )
+    );
+
+    // name -> expires_at, names without an entry are perpetual
+    static NAME_EXPIRY_STORE: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_EXPIRY_MEMORY_ID)),
+        )
+    );
+
+    static AUCTION_STORE: RefCell<StableBTreeMap<String, Auction, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUCTION_MEMORY_ID)),
+        )
+    );
+
+    static RESOLVER_STORE: RefCell<StableBTreeMap<String, Resolver, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(RESOLVER_MEMORY_ID)),
+        )
+    );
+
+    // "parent/label" -> Subname, so that the subnames of a parent are adjacent
+    static SUBNAME_STORE: RefCell<StableBTreeMap<String, Subname, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SUBNAME_MEMORY_ID)),
+        )
+    );
+
+    // (name, height) -> event of the name
+    static NAME_HISTORY_STORE: RefCell<StableBTreeMap<NameHeight, NameEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(NAME_HISTORY_MEMORY_ID)),
+        )
+    );
+
+    // (account, height) -> event, for both the `user` and the `from` of a block
+    static ACCOUNT_HISTORY_STORE: RefCell<StableBTreeMap<(Principal, u64), NameEvent, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(ACCOUNT_HISTORY_MEMORY_ID)),
+        )
+    );
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
+
+    static TREASURY_LOG: RefCell<StableLog<TreasuryRecord, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TREASURY_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(TREASURY_LOG_DATA_MEMORY_ID)),
+        )
+    );
+
+    static TREASURY_DAILY_STORE: RefCell<StableBTreeMap<u64, TreasuryDay, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TREASURY_DAILY_MEMORY_ID)),
+        )
+    );
+}
+
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
+// FlagGuard sets a flag while a background job runs. The flag is cleared on drop,
+// also when a callback of the job traps.
+struct FlagGuard(&'static LocalKey<RefCell<bool>>);
+
+impl FlagGuard {
+    fn new(flag: &'static LocalKey<RefCell<bool>>) -> Option<Self> {
+        flag.with_borrow_mut(|r| {
+            if *r {
+                return None;
+            }
+            *r = true;
+            Some(FlagGuard(flag))
+        })
+    }
+}
+
+impl Drop for FlagGuard {
+    fn drop(&mut self) {
+        self.0.with_borrow_mut(|r| *r = false);
+    }
+}
+
+pub mod state {
+    use super::*;
+
+    pub fn with<R>(f: impl FnOnce(&State) -> R) -> R {
+        STATE.with_borrow(f)
+    }
+
+    pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
+        STATE.with_borrow_mut(f)
+    }
+
+    pub fn is_manager(caller: &Principal) -> Result<(), String> {
+        STATE.with_borrow(|r| match r.managers.contains(caller) {
+            true => Ok(()),
+            false => Err("caller is not a manager".to_string()),
+        })
+    }
+
+    pub fn load() {
+        let mut scratch = [0; 4096];
+        STATE_STORE.with_borrow(|r| {
+            STATE.with_borrow_mut(|h| {
+                let v: State = from_reader_with_buffer(&r.get()[..], &mut scratch)
+                    .expect("failed to decode STATE_STORE data");
+                *h = v;
+            });
+        });
+        if with(|s| s.blocks_alt) {
+            NAME_BLOCKS.with_borrow_mut(|r| {
+                *r = StableLog::init(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_ALT_INDEX_MEMORY_ID)),
+                    MEMORY_MANAGER.with_borrow(|m| m.get(NAME_BLK_ALT_DATA_MEMORY_ID)),
+                );
+            });
+        }
+    }
+
+    pub fn save() {
+        STATE.with_borrow(|h| {
+            STATE_STORE.with_borrow_mut(|r| {
+                let mut buf = vec![];
+                into_writer(h, &mut buf).expect("failed to encode STATE_STORE data");
+                r.set(buf);
+            });
+        });
+    }
+
+    // user_derivation_path returns the Schnorr derivation path of the user's key.
+    pub fn user_derivation_path(s: &State, user: &Principal) -> Vec<Vec<u8>> {
+        vec![
+            b"ICPanda_IV".to_vec(),
+            user.to_bytes().to_vec(),
+            s.init_vector.to_vec(),
+        ]
+    }
+
+    pub fn ed25519_public_key(caller: &Principal) -> Result<PublicKeyOutput, String> {
+        STATE.with_borrow(|s| {
+            let pk = s
+                .ed25519_public_key
+                .as_ref()
+                .ok_or("no schnorr ed25519 public key")?;
+
+            derive_25519_public_key(pk, user_derivation_path(s, caller))
+        })
+    }
+
+    pub async fn try_init_public_key() {
+        let (schnorr_key_name, ed25519_public_key) =
+            STATE.with_borrow(|s| (s.schnorr_key_name.clone(), s.ed25519_public_key.clone()));
+        if schnorr_key_name.is_empty() || ed25519_public_key.is_some() {
+            return;
+        }
+
+        let ed25519_public_key =
+            schnorr_public_key(schnorr_key_name.clone(), SchnorrAlgorithm::Ed25519, vec![])
+                .await
+                .map_err(|err| {
+                    ic_cdk::api::debug_print(format!(
+                        "failed to retrieve Schnorr Ed25519 public key: {err}"
+                    ))
+                })
+                .ok();
+
+        let mut data = ic_cdk::management_canister::raw_rand()
+            .await
+            .expect("failed to generate IV");
+        data.truncate(32);
+        let iv: [u8; 32] = data.try_into().expect("failed to generate IV");
+        STATE.with_borrow_mut(|r| {
+            r.ed25519_public_key = ed25519_public_key;
+            r.init_vector = iv.into();
+        });
+    }
+}
+
+pub mod user {
+    use ic_cose_types::{to_cbor_bytes, MILLISECONDS};
+    use icrc_ledger_types::icrc3::{
+        archive::QueryArchiveFn,
+        blocks::{
+            ArchivedBlocks, BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock,
+        },
+    };
+
+    use super::*;
+
+    pub fn names_total() -> u64 {
+        NAME_STORE.with_borrow(|r| r.len())
+    }
+
+    pub fn users_total() -> u64 {
+        USER_STORE.with_borrow(|r| r.len())
+    }
+
+    pub async fn update_name(
+        caller: Principal,
+        now_ms: u64,
+        name: String,
+    ) -> Result<UserInfo, String> {
+        let profile_canister = state::with(|s| s.profile_canisters.last().cloned());
+        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+
+        let (new_profile, info) = USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
+            Some(mut user) => {
+                user.name = name;
+                r.insert(caller, user.clone());
+                (false, user.into_info(caller))
+            }
+            None => {
+                let user = User {
+                    name,
+                    image: "".to_string(),
+                    profile_canister,
+                    cose_canister: None,
+                    username: None,
+                };
+                r.insert(caller, user.clone());
+                (true, user.into_info(caller))
+            }
+        });
+
+        // the name is saved, syncing it to the profile is retried until done
+        let mut steps = pending::STEP_PROFILE_NAME;
+        if new_profile {
+            steps |= pending::STEP_PROFILE;
+        }
+        let id = pending::add(PendingOp {
+            user: caller,
+            username: info.username.clone().unwrap_or_default(),
+            desc: "update name".to_string(),
+            cose_canister: info.cose_canister.unwrap_or(Principal::anonymous()),
+            steps,
+            attempts: 0,
+            error: None,
+            created_at: now_ms,
+            updated_at: now_ms,
+        });
+        if let Err(err) = pending::run(id, now_ms).await {
+            ic_cdk::api::debug_print(format!("pending operation {} failed: {}", id, err));
+        }
+        Ok(info)
+    }
+
+    // sync_profile_name updates the display name in the profile canister's search index
+    pub async fn sync_profile_name(info: &UserInfo) -> Result<(), String> {
+        let _: Result<(), String> = call(
+            info.profile_canister,
+            "admin_update_profile_names",
+            (vec![(info.id, info.name.clone())],),
+            0,
+        )
+        .await?;
+        Ok(())
+    }
+
+    // sync_profile_names syncs the display names of users after `prev` to their profile canisters,
+    // returns the last user synced.
+    pub async fn sync_profile_names(
+        prev: Option<Principal>,
+        take: usize,
+    ) -> Result<Option<Principal>, String> {
+        let mut last: Option<Principal> = None;
+        let mut names: BTreeMap<Principal, Vec<(Principal, String)>> = BTreeMap::new();
+        USER_STORE.with_borrow(|r| {
+            let iter = match prev {
+                Some(prev) => r.range(prev..),
+                None => r.range(..),
+            };
+            for e in iter.filter(|e| Some(*e.key()) != prev).take(take) {
+                let user = e.value();
+                last = Some(*e.key());
+                names
+                    .entry(user.profile_canister)
+                    .or_default()
+                    .push((*e.key(), user.name));
+            }
+        });
+
+        for (canister, names) in names {
+            let _: Result<(), String> =
+                call(canister, "admin_update_profile_names", (names,), 0).await?;
+        }
+        Ok(last)
+    }
+
+    pub async fn search(
+        query: String,
+        prev: Option<u64>,
+        prev_id: Option<Principal>,
+        take: u32,
+    ) -> Result<Vec<(UserInfo, ProfileInfo)>, String> {
+        let canisters = state::with(|s| s.profile_canisters.clone());
+        let mut profiles: Vec<ProfileInfo> = Vec::new();
+        for canister in canisters {
+            let res: Result<Vec<ProfileInfo>, String> = query_call(
+                canister,
+                "search_profiles",
+                (query.clone(), prev, Some(take), prev_id),
+            )
+            .await?;
+            profiles.extend(res?);
+        }
+
+        profiles.sort_by_key(|p| std::cmp::Reverse((p.active_at, p.id)));
+        profiles.truncate(take as usize);
+        Ok(USER_STORE.with_borrow(|r| {
+            profiles
+                .into_iter()
+                .filter_map(|p| r.get(&p.id).map(|u| (u.into_info(p.id), p)))
+                .collect()
+        }))
+    }
+
+    pub fn update_username(caller: Principal, username: String) -> Result<UserInfo, String> {
+        USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
+            Some(mut user) => {
+                let ln = username.to_lowercase();
+                NAME_STORE.with_borrow(|r| match r.get(&ln) {
+                    Some(id) if id == caller => Ok(()),
+                    _ => Err("username not match".to_string()),
+                })?;
+                user.username = Some(username);
+                r.insert(caller, user.clone());
+                Ok(user.into_info(caller))
+            }
+            None => Err("user not found".to_string()),
+        })
+    }
+
+    pub fn update_image(caller: Principal, image: String) -> Result<(), String> {
+        USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
+            Some(mut user) => {
+                user.image = image;
+                r.insert(caller, user);
+                Ok(())
+            }
+            None => Err("user not found".to_string()),
+        })
+    }
+
+    pub async fn update_my_ecdh(
+        caller: Principal,
+        ecdh_pub: ByteArray<32>,
+        encrypted_ecdh: ByteBuf,
+    ) -> Result<(), String> {
+        let (cose_canister, profile_canister) = USER_STORE
+            .with_borrow(|r| {
+                r.get(&caller)
+                    .map(|u| (u.cose_canister, u.profile_canister))
+            })
+            .ok_or_else(|| "user not found".to_string())?;
+        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
+        let mut sp = SettingPath {
+            ns: caller.to_text().replace("-", "_"),
+            user_owned: false,
+            subject: Some(caller),
+            key: b"StaticECDH".to_vec().into(),
+            version: 0,
+        };
+        let res: Result<SettingInfo, String> =
+            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
+        let res = match res {
+            Ok(info) => {
+                sp.version = info.version;
+                let res: Result<UpdateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_update_payload",
+                    (
+                        sp,
+                        UpdateSettingPayloadInput {
+                            payload: None,
+                            status: None,
+                            deprecate_current: None,
+                            dek: Some(encrypted_ecdh),
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+            Err(_) => {
+                let res: Result<CreateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_create",
+                    (
+                        sp,
+                        CreateSettingInput {
+                            payload: None,
+                            desc: None,
+                            status: None,
+                            tags: None,
+                            dek: Some(encrypted_ecdh),
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+        };
+
+        if res.is_ok() {
+            let _: Result<(), String> = call(
+                profile_canister,
+                "admin_update_profile_ecdh_pub",
+                (caller, ecdh_pub),
+                0,
+            )
+            .await?;
+        }
+        res
+    }
+
+    pub async fn update_my_kv(caller: Principal, input: UpdateKVInput) -> Result<(), String> {
+        let cose_canister = USER_STORE
+            .with_borrow(|r| r.get(&caller).map(|u| u.cose_canister))
+            .ok_or_else(|| "user not found".to_string())?;
+        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
+        let mut sp = SettingPath {
+            ns: caller.to_text().replace("-", "_"),
+            user_owned: false,
+            subject: Some(caller),
+            key: b"KV".to_vec().into(),
+            version: 0,
+        };
+        let res: Result<SettingInfo, String> =
+            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
+
+        match res {
+            Ok(info) => {
+                sp.version = info.version;
+                let mut payload: BTreeMap<String, ByteBuf> = info
+                    .payload
+                    .map(|p| from_reader(&p[..]).expect("failed to decode payload"))
+                    .unwrap_or_default();
+                if !input.remove_kv.is_empty() {
+                    payload.retain(|k, _| !input.remove_kv.contains(k));
+                }
+                if !input.upsert_kv.is_empty() {
+                    payload.extend(input.upsert_kv);
+                }
+
+                let res: Result<UpdateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_update_payload",
+                    (
+                        sp,
+                        UpdateSettingPayloadInput {
+                            payload: Some(to_cbor_bytes(&payload).into()),
+                            status: None,
+                            deprecate_current: None,
+                            dek: None,
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+            Err(_) => {
+                if !input.remove_kv.is_empty() {
+                    Err("cannot remove KV from non-existing setting".to_string())?;
+                }
+                let payload = to_cbor_bytes(&input.upsert_kv);
+                let res: Result<CreateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_create",
+                    (
+                        sp,
+                        CreateSettingInput {
+                            payload: Some(payload.into()),
+                            desc: None,
+                            status: None,
+                            tags: None,
+                            dek: None,
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+        }
+    }
+
+    // kv_setting_path returns the COSE setting path of a user's KV namespace,
+    // each namespace is a setting with its own version.
+    fn kv_setting_path(caller: &Principal, namespace: &str) -> SettingPath {
+        SettingPath {
+            ns: caller.to_text().replace("-", "_"),
+            user_owned: false,
+            subject: Some(*caller),
+            key: format!("KV:{}", namespace).into_bytes().into(),
+            version: 0,
+        }
+    }
+
+    // is_not_found returns true if the COSE error is about a missing setting.
+    fn is_not_found(err: &str) -> bool {
+        err.contains("not found")
+    }
+
+    // raise_payload_size raises the payload limit of the user's COSE namespace
+    // to fit the KV settings, it is idempotent.
+    async fn raise_payload_size(
+        cose_canister: Principal,
+        caller: &Principal,
+    ) -> Result<(), String> {
+        let res: Result<(), String> = call(
+            cose_canister,
+            "namespace_update_info",
+            (UpdateNamespaceInput {
+                name: caller.to_text().replace("-", "_"),
+                max_payload_size: Some(types::MAX_KV_PAYLOAD_SIZE),
+                desc: None,
+                status: None,
+                visibility: None,
+                session_expires_in_ms: None,
+            },),
+            0,
+        )
+        .await?;
+        res
+    }
+
+    fn user_cose_canister(caller: &Principal) -> Result<Principal, String> {
+        USER_STORE
+            .with_borrow(|r| r.get(caller).map(|u| u.cose_canister))
+            .ok_or_else(|| "user not found".to_string())?
+            .ok_or_else(|| "user has no COSE service".to_string())
+    }
+
+    // get_my_kvs returns the values of the keys in the namespace, or all values if keys is empty.
+    pub async fn get_my_kvs(
+        caller: Principal,
+        namespace: String,
+        keys: BTreeSet<String>,
+    ) -> Result<types::KVsOutput, String> {
+        let cose_canister = user_cose_canister(&caller)?;
+        let sp = kv_setting_path(&caller, &namespace);
+        let res: Result<SettingInfo, String> =
+            query_call(cose_canister, "setting_get", (sp,)).await?;
+        let (version, mut kv) = match res {
+            Ok(info) => {
+                let kv: BTreeMap<String, types::KVValue> = match info.payload {
+                    Some(p) => from_reader(&p[..]).map_err(|err| format!("{:?}", err))?,
+                    None => BTreeMap::new(),
+                };
+                (info.version, kv)
+            }
+            // the namespace does not exist yet
+            Err(err) if is_not_found(&err) => (0, BTreeMap::new()),
+            Err(err) => return Err(err),
+        };
+        if !keys.is_empty() {
+            kv.retain(|k, _| keys.contains(k));
+        }
+        Ok(types::KVsOutput {
+            namespace,
+            version,
+            kv,
+        })
+    }
+
+    // update_my_kvs merges the changes into the namespace if it is still at the version
+    // read by the client, returns the new version.
+    pub async fn update_my_kvs(
+        caller: Principal,
+        input: types::UpdateKVsInput,
+    ) -> Result<u32, String> {
+        let cose_canister = user_cose_canister(&caller)?;
+        let mut sp = kv_setting_path(&caller, &input.namespace);
+        let res: Result<SettingInfo, String> =
+            call(cose_canister, "setting_get", (sp.clone(),), 0).await?;
+
+        let info = match res {
+            Ok(info) => Some(info),
+            Err(err) if is_not_found(&err) => None,
+            Err(err) => return Err(err),
+        };
+        let mut kv: BTreeMap<String, types::KVValue> = match info.as_ref().map(|i| &i.payload) {
+            Some(Some(p)) => from_reader(&p[..]).map_err(|err| format!("{:?}", err))?,
+            _ => BTreeMap::new(),
+        };
+        kv.retain(|k, _| !input.remove.contains(k));
+        kv.extend(input.upsert);
+        let payload = to_cbor_bytes(&kv);
+        if payload.len() as u64 > types::MAX_KV_PAYLOAD_SIZE {
+            return Err(format!(
+                "KVs size {} exceeds {}",
+                payload.len(),
+                types::MAX_KV_PAYLOAD_SIZE
+            ));
+        }
+        // namespaces are created with a smaller payload limit
+        if payload.len() as u64 > types::COSE_NAMESPACE_PAYLOAD_SIZE {
+            raise_payload_size(cose_canister, &caller).await?;
+        }
+
+        match info {
+            Some(info) => {
+                if info.version != input.version {
+                    return Err(format!(
+                        "version conflict, expected {}, got {}",
+                        info.version, input.version
+                    ));
+                }
+                sp.version = info.version;
+                // COSE also rejects the update if the setting version changed
+                let res: Result<UpdateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_update_payload",
+                    (
+                        sp,
+                        UpdateSettingPayloadInput {
+                            payload: Some(payload.into()),
+                            status: None,
+                            deprecate_current: None,
+                            dek: None,
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|out| out.version)
+            }
+            None => {
+                if input.version != 0 {
+                    return Err(format!(
+                        "version conflict, expected 0, got {}",
+                        input.version
+                    ));
+                }
+                let res: Result<CreateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_create",
+                    (
+                        sp,
+                        CreateSettingInput {
+                            payload: Some(payload.into()),
+                            desc: None,
+                            status: None,
+                            tags: None,
+                            dek: None,
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|out| out.version)
+            }
+        }
+    }
+
+    pub async fn register_username(
+        caller: Principal,
+        username: String,
+        name: String,
+        now_ms: u64,
+    ) -> Result<UserInfo, String> {
+        state::with(|s| {
+            if s.cose_canisters.is_empty() {
+                return Err("no COSE canister".to_string());
+            }
+            if s.profile_canisters.is_empty() {
+                return Err("no profile canister".to_string());
+            }
+            Ok(())
+        })?;
+        let price = pricing::current(now_ms).await;
+
+        let ln = username.to_lowercase();
+        let amount = price.get(ln.len()).saturating_sub(types::TOKEN_FEE);
+        if amount == 0 {
+            return Err("invalid username length".to_string());
+        }
+
+        let has_username = USER_STORE.with_borrow(|r| {
+            r.get(&caller)
+                .map(|u| u.username.is_some())
+                .unwrap_or_default()
+        });
+
+        if has_username {
+            return Err("caller already has username".to_string());
+        }
+        if auction::is_auctioned(&ln) {
+            return Err("username should be registered by auction".to_string());
+        }
+        if reserved::is_reserved(&ln) {
+            return Err("username is reserved".to_string());
+        }
+
+        release_if_expired(&ln, now_ms).await?;
+        NAME_STORE.with_borrow_mut(|r| match r.get(&ln) {
+            Some(_) => Err("username already registered".to_string()),
+            None => {
+                r.insert(ln.clone(), caller);
+                Ok(())
+            }
+        })?;
+
+        let blk =
+            match token_transfer_from(caller, amount.into(), format!("RU: {}", username)).await {
+                Err(err) => {
+                    NAME_STORE.with_borrow_mut(|r| {
+                        r.remove(&ln);
+                    });
+                    return Err(err);
+                }
+                Ok(blk) => blk,
+            };
+
+        let desc = format!("name: {}, $PANDA block: {}", username, blk);
+        register_paid(caller, username, name, amount, Some(blk), desc, now_ms).await
+    }
+
+    // release_if_expired releases the name if its grace period is over,
+    // so that it can be registered again.
+    pub async fn release_if_expired(ln: &str, now_ms: u64) -> Result<(), String> {
+        if is_released(ln, now_ms) {
+            if MARKET_LOCKS.with_borrow(|r| r.contains(ln)) {
+                return Err("username is in trading".to_string());
+            }
+            if let Some(listing) = release(ln, now_ms) {
+                market::refund_offers(ln, listing.offers, now_ms).await;
+            }
+        }
+        Ok(())
+    }
+
+    // register_paid completes a paid registration, the name should be reserved
+    // for the caller in NAME_STORE already.
+    pub async fn register_paid(
+        caller: Principal,
+        username: String,
+        name: String,
+        amount: u64,
+        block: Option<Nat>,
+        desc: String,
+        now_ms: u64,
+    ) -> Result<UserInfo, String> {
+        let (cose_canister, profile_canister) = state::with(|s| {
+            (
+                s.cose_canisters.last().cloned(),
+                s.profile_canisters.last().cloned(),
+            )
+        });
+        let renewal_percent = pricing::quote().price.renewal_percent;
+        let cose_canister = cose_canister.ok_or_else(|| "no COSE canister".to_string())?;
+        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+
+        let ln = username.to_lowercase();
+        if renewal_percent > 0 {
+            set_expiry(&ln, now_ms + types::NAME_TERM_MS);
+        }
+        state::with_mut(|s| {
+            if ln.len() <= 7 {
+                s.short_usernames.insert(ln.clone());
+            }
+            s.latest_usernames.push_front(username.clone());
+            if s.latest_usernames.len() > 20 {
+                s.latest_usernames.pop_back();
+            }
+            append_block(s, NameOp::Register, ln, caller, None, amount, now_ms);
+        });
+        treasury::record(
+            types::TreasuryKind::NameRegistration,
+            caller,
+            amount,
+            block,
+            desc.clone(),
+            now_ms,
+        );
+
+        let (new_profile, info) = USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
             Some(mut user) => {
-                user.image = image;
-                r.insert(caller, user);
+                if user.cose_canister.is_none() {
+                    user.cose_canister = Some(cose_canister);
+                }
+                user.username = Some(username);
+                r.insert(caller, user.clone());
+                (false, user.into_info(caller))
+            }
+            None => {
+                let user = User {
+                    name,
+                    image: "".to_string(),
+                    profile_canister,
+                    cose_canister: Some(cose_canister),
+                    username: Some(username),
+                };
+                r.insert(caller, user.clone());
+                (true, user.into_info(caller))
+            }
+        });
+
+        // the remaining steps call other canisters, they are retried until done
+        let mut steps = pending::STEP_NAMESPACE;
+        if new_profile {
+            steps |= pending::STEP_PROFILE | pending::STEP_PROFILE_NAME;
+        }
+        let id = pending::add(PendingOp {
+            user: caller,
+            username: info.username.clone().unwrap_or_default(),
+            desc,
+            cose_canister,
+            steps,
+            attempts: 0,
+            error: None,
+            created_at: now_ms,
+            updated_at: now_ms,
+        });
+        if let Err(err) = pending::run(id, now_ms).await {
+            ic_cdk::api::debug_print(format!("pending operation {} failed: {}", id, err));
+        }
+        Ok(info)
+    }
+
+    // transferable_username returns the caller's username if it can be transferred.
+    pub fn transferable_username(caller: &Principal) -> Result<String, String> {
+        let username = USER_STORE.with_borrow(|r| {
+            r.get(caller)
+                .ok_or_else(|| "caller not found".to_string())?
+                .username
+                .ok_or_else(|| "caller has no username".to_string())
+        })?;
+
+        let ln = username.to_lowercase();
+        if get_name_principal(&ln) == *caller {
+            return Err("cannot transfer username".to_string());
+        }
+        if is_released(&ln, ic_cdk::api::time() / MILLISECONDS) {
+            return Err("username expired".to_string());
+        }
+        Ok(username)
+    }
+
+    pub async fn transfer_username(
+        caller: Principal,
+        to: Principal,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let username = transferable_username(&caller)?;
+        if market::get(&username.to_lowercase()).is_some() {
+            return Err("username is listed in the market".to_string());
+        }
+        let moved = move_username(caller, to, username, 0, now_ms)?;
+        moved.init_services().await
+    }
+
+    // the recipient of a moved username, with the services that should be created for it
+    pub struct MovedUsername {
+        pub to: Principal,
+        pub username: String,
+        pub new_profile: Option<Principal>, // profile canister
+        pub new_cose: Option<Principal>,    // COSE canister
+    }
+
+    // move_username moves the username from the caller to the recipient and records a NameBlock.
+    pub fn move_username(
+        caller: Principal,
+        to: Principal,
+        username: String,
+        value: u64,
+        now_ms: u64,
+    ) -> Result<MovedUsername, String> {
+        let (cose_canister, profile_canister) = state::with(|s| {
+            (
+                s.cose_canisters.last().cloned(),
+                s.profile_canisters.last().cloned(),
+            )
+        });
+        let cose_canister = cose_canister.ok_or_else(|| "no COSE canister".to_string())?;
+        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+
+        let ln = username.to_lowercase();
+        let (new_profile, new_cose) = NAME_STORE.with_borrow_mut(|r| match r.get(&ln) {
+            Some(owner) => {
+                if owner != caller {
+                    Err("username not owned by caller".to_string())
+                } else {
+                    let rt = USER_STORE.with_borrow_mut(|rr| {
+                        let mut new_cose = false;
+                        let mut new_profile = false;
+                        match rr.get(&to) {
+                            Some(mut user) => {
+                                if let Some(username) = user.username {
+                                    Err(format!(
+                                        "{} already has username {}",
+                                        to.to_text(),
+                                        username
+                                    ))?;
+                                }
+                                user.username = Some(username.clone());
+                                if user.cose_canister.is_none() {
+                                    new_cose = true;
+                                    user.cose_canister = Some(cose_canister);
+                                }
+                                rr.insert(to, user);
+                            }
+                            None => {
+                                new_cose = true;
+                                new_profile = true;
+                                rr.insert(
+                                    to,
+                                    User {
+                                        name: username.clone(),
+                                        image: "".to_string(),
+                                        profile_canister,
+                                        cose_canister: Some(cose_canister),
+                                        username: Some(username.clone()),
+                                    },
+                                );
+                            }
+                        }
+
+                        if let Some(mut user) = rr.get(&caller) {
+                            user.username = None;
+                            rr.insert(caller, user.clone());
+                        }
+                        Ok::<(bool, bool), String>((new_profile, new_cose))
+                    })?;
+                    r.insert(ln.clone(), to);
+                    Ok(rt)
+                }
+            }
+            None => Err("username not found".to_string()),
+        })?;
+
+        state::with_mut(|s| {
+            append_block(s, NameOp::Transfer, ln, to, Some(caller), value, now_ms);
+        });
+
+        Ok(MovedUsername {
+            to,
+            username,
+            new_profile: if new_profile {
+                Some(profile_canister)
+            } else {
+                None
+            },
+            new_cose: if new_cose { Some(cose_canister) } else { None },
+        })
+    }
+
+    impl MovedUsername {
+        pub async fn init_services(self) -> Result<(), String> {
+            let to = self.to;
+            if let Some(profile_canister) = self.new_profile {
+                let _: Result<(), String> = call(
+                    profile_canister,
+                    "admin_upsert_profile",
+                    (to, None::<(Principal, u64)>),
+                    0,
+                )
+                .await?;
+            }
+
+            if let Some(cose_canister) = self.new_cose {
+                let _: Result<types::NamespaceInfo, String> = call(
+                    cose_canister,
+                    "admin_create_namespace",
+                    (CreateNamespaceInput {
+                        name: to.to_text().replace("-", "_"),
+                        visibility: 0,
+                        desc: Some(format!("name: {}", self.username)),
+                        max_payload_size: Some(types::COSE_NAMESPACE_PAYLOAD_SIZE),
+                        managers: BTreeSet::from([ic_cdk::api::canister_self()]),
+                        auditors: BTreeSet::from([to]),
+                        users: BTreeSet::from([to]),
+                        session_expires_in_ms: None,
+                    },),
+                    0,
+                )
+                .await?;
+            }
+
+            Ok(())
+        }
+    }
+
+    pub fn search_username(prefix: String) -> Vec<String> {
+        if prefix.contains('.') {
+            return subname::search(&prefix);
+        }
+        state::with(|s| {
+            if prefix.len() <= 7 {
+                s.short_usernames
+                    .iter()
+                    .filter(|n| n.starts_with(&prefix))
+                    .cloned()
+                    .collect()
+            } else {
+                NAME_STORE.with_borrow(|r| {
+                    if r.contains_key(&prefix) {
+                        vec![prefix]
+                    } else {
+                        vec![]
+                    }
+                })
+            }
+        })
+    }
+
+    pub fn get_by_username(username: String) -> Result<UserInfo, String> {
+        if username.contains('.') {
+            let id = subname::get(&username).ok_or_else(|| "user not found".to_string())?;
+            return get(id);
+        }
+        state::with(|s| {
+            if username.len() <= 7 && !s.short_usernames.contains(&username) {
+                None
+            } else {
+                NAME_STORE.with_borrow(|r| match r.get(&username) {
+                    Some(id) => USER_STORE.with_borrow(|rr| rr.get(&id).map(|u| u.into_info(id))),
+                    None => None,
+                })
+            }
+        })
+        .ok_or_else(|| "user not found".to_string())
+    }
+
+    pub fn get(user: Principal) -> Result<UserInfo, String> {
+        USER_STORE.with_borrow(|r| {
+            r.get(&user)
+                .map(|u| u.into_info(user))
+                .ok_or_else(|| "user not found".to_string())
+        })
+    }
+
+    // append_block appends a NameBlock of the op to the log and certifies the new tip.
+    // A registration has no `from`, a renewal has `from` equal to `user`,
+    // a release (expiry or subname revocation) is recorded with the anonymous principal as `user`,
+    // and a subname block has the parent owner as `from`.
+    pub fn append_block(
+        s: &mut State,
+        op: NameOp,
+        name: String,
+        user: Principal,
+        from: Option<Principal>,
+        value: u64,
+        now_ms: u64,
+    ) {
+        let blk = NameBlock {
+            height: s.next_block_height,
+            phash: s.next_block_phash,
+            name,
+            user,
+            from,
+            value,
+            timestamp: now_ms,
+            op: Some(op),
+        };
+        let phash = blk.to_value().hash();
+        if s.indexed_blocks == blk.height {
+            history::add(&blk);
+            s.indexed_blocks += 1;
+        }
+        let blk = to_cbor_bytes(&blk);
+        s.next_block_height += 1;
+        s.next_block_phash = phash.into();
+        NAME_BLOCKS.with_borrow_mut(|r| {
+            r.append(&blk).expect("failed to append NameBlock");
+        });
+        ic_cdk::api::certified_data_set(s.root_hash().as_slice());
+    }
+
+    pub fn load_expiry_queue() {
+        NAME_EXPIRY_STORE.with_borrow(|r| {
+            EXPIRY_QUEUE.with_borrow_mut(|q| {
+                q.clear();
+                for e in r.iter() {
+                    q.insert((e.value(), e.key().clone()));
+                }
+            })
+        });
+    }
+
+    fn set_expiry(ln: &str, expires_at: u64) {
+        let prev = NAME_EXPIRY_STORE.with_borrow_mut(|r| r.insert(ln.to_string(), expires_at));
+        EXPIRY_QUEUE.with_borrow_mut(|q| {
+            if let Some(prev) = prev {
+                q.remove(&(prev, ln.to_string()));
+            }
+            q.insert((expires_at, ln.to_string()));
+        });
+    }
+
+    fn clear_expiry(ln: &str) {
+        if let Some(prev) = NAME_EXPIRY_STORE.with_borrow_mut(|r| r.remove(&ln.to_string())) {
+            EXPIRY_QUEUE.with_borrow_mut(|q| q.remove(&(prev, ln.to_string())));
+        }
+    }
+
+    // is_released returns true if the name has expired and its grace period is over.
+    pub fn is_released(ln: &str, now_ms: u64) -> bool {
+        NAME_EXPIRY_STORE
+            .with_borrow(|r| r.get(&ln.to_string()))
+            .is_some_and(|expires_at| expires_at + types::NAME_GRACE_MS < now_ms)
+    }
+
+    pub fn get_term(name: &str) -> Result<types::UsernameTerm, String> {
+        let ln = name.to_lowercase();
+        let owner = NAME_STORE
+            .with_borrow(|r| r.get(&ln))
+            .ok_or_else(|| "username not found".to_string())?;
+        let expires_at = NAME_EXPIRY_STORE.with_borrow(|r| r.get(&ln));
+        let renewal_fee = pricing::quote().price.renewal(ln.len());
+        Ok(types::UsernameTerm {
+            name: ln,
+            owner,
+            expires_at,
+            grace_until: expires_at.map(|t| t + types::NAME_GRACE_MS),
+            renewal_fee,
+        })
+    }
+
+    // check_renewal returns the new expiry of the name if the caller can renew it.
+    fn check_renewal(caller: &Principal, ln: &str, years: u64, now_ms: u64) -> Result<u64, String> {
+        if NAME_STORE.with_borrow(|r| r.get(&ln.to_string())) != Some(*caller) {
+            return Err("username not owned by caller".to_string());
+        }
+        let expires_at = NAME_EXPIRY_STORE
+            .with_borrow(|r| r.get(&ln.to_string()))
+            .ok_or_else(|| "username does not expire".to_string())?;
+        if expires_at + types::NAME_GRACE_MS < now_ms {
+            return Err("username expired".to_string());
+        }
+        let expires_at = expires_at + years * types::NAME_TERM_MS;
+        if expires_at > now_ms + types::MAX_RENEWAL_YEARS * types::NAME_TERM_MS {
+            return Err("renewal exceeds the maximum term".to_string());
+        }
+        Ok(expires_at)
+    }
+
+    pub async fn renew_username(caller: Principal, years: u64, now_ms: u64) -> Result<u64, String> {
+        let username = USER_STORE
+            .with_borrow(|r| r.get(&caller).and_then(|u| u.username))
+            .ok_or_else(|| "caller has no username".to_string())?;
+        let ln = username.to_lowercase();
+        check_renewal(&caller, &ln, years, now_ms)?;
+        let amount = pricing::current(now_ms).await.renewal(ln.len()) * years;
+        let amount = amount.saturating_sub(types::TOKEN_FEE);
+        if amount == 0 {
+            return Err("username renewal is not enabled".to_string());
+        }
+
+        let blk = token_transfer_from(caller, amount.into(), format!("RN: {}", username)).await?;
+        // the username maybe transferred or renewed during the payment
+        let expires_at = match check_renewal(&caller, &ln, years, now_ms) {
+            Ok(expires_at) => expires_at,
+            Err(err) => {
+                payout::pay(
+                    caller,
+                    amount.saturating_sub(types::TOKEN_FEE),
+                    format!("RF: {}", username),
+                    None,
+                    now_ms,
+                )
+                .await;
+                return Err(err);
+            }
+        };
+
+        set_expiry(&ln, expires_at);
+        state::with_mut(|s| {
+            append_block(s, NameOp::Renew, ln, caller, Some(caller), amount, now_ms);
+        });
+        treasury::record(
+            types::TreasuryKind::NameRenewal,
+            caller,
+            amount,
+            Some(blk),
+            format!("RN: {}", username),
+            now_ms,
+        );
+        Ok(expires_at)
+    }
+
+    // release takes back a name whose grace period is over and records a NameBlock,
+    // returns the market listing of the name if any.
+    fn release(ln: &str, now_ms: u64) -> Option<Listing> {
+        let owner = NAME_STORE.with_borrow_mut(|r| r.remove(&ln.to_string()))?;
+        clear_expiry(ln);
+        reserved::set_allocation(ln, None);
+        USER_STORE.with_borrow_mut(|r| {
+            if let Some(mut user) = r.get(&owner) {
+                if user.username.as_ref().map(|u| u.to_lowercase()).as_deref() == Some(ln) {
+                    user.username = None;
+                    r.insert(owner, user);
+                }
+            }
+        });
+        state::with_mut(|s| {
+            s.short_usernames.remove(ln);
+            s.latest_usernames.retain(|u| u.to_lowercase() != ln);
+            append_block(
+                s,
+                NameOp::Release,
+                ln.to_string(),
+                Principal::anonymous(),
+                Some(owner),
+                0,
+                now_ms,
+            );
+        });
+        MARKET_STORE.with_borrow_mut(|r| r.remove(&ln.to_string()))
+    }
+
+    // expire_names releases the names whose grace period is over, called by a timer.
+    pub async fn expire_names(now_ms: u64) {
+        let names: Vec<String> = EXPIRY_QUEUE.with_borrow(|q| {
+            q.iter()
+                .take_while(|(expires_at, _)| expires_at + types::NAME_GRACE_MS < now_ms)
+                .take(types::MAX_EXPIRED_NAMES_PER_SWEEP)
+                .map(|(_, ln)| ln.clone())
+                .collect()
+        });
+
+        for ln in names {
+            // the name maybe renewed or traded while refunding offers
+            if !is_released(&ln, now_ms) || MARKET_LOCKS.with_borrow(|r| r.contains(&ln)) {
+                continue;
+            }
+            if let Some(listing) = release(&ln, now_ms) {
+                market::refund_offers(&ln, listing.offers, now_ms).await;
+            }
+        }
+    }
+
+    pub fn has_username(user: &Principal) -> bool {
+        USER_STORE.with_borrow(|r| r.get(user).is_some_and(|u| u.username.is_some()))
+    }
+
+    pub fn batch_get(ids: BTreeSet<Principal>) -> Vec<UserInfo> {
+        USER_STORE.with_borrow(|r| {
+            ids.iter()
+                .filter_map(|id| r.get(id).map(|u| u.into_info(*id)))
+                .collect()
+        })
+    }
+
+    // block_value returns the ICRC-3 value of a stored NameBlock. Blocks lower than
+    // `typed_from` are served in their original encoding, a Blob of the CBOR encoded block,
+    // as they were chained with the sha3_256 hash of these bytes. The first typed block
+    // carries the sha3_256 hash of the last legacy block as phash, and later blocks carry
+    // the ICRC-3 representation-independent hash of the previous typed value.
+    pub fn block_value(typed_from: u64, height: u64, data: &[u8]) -> ICRC3GenericBlock {
+        if height < typed_from {
+            return ICRC3GenericBlock::Blob(data.to_vec().into());
+        }
+        let blk: NameBlock = from_reader(data).expect("failed to decode NameBlock");
+        blk.to_value()
+    }
+
+    pub fn get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+        const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+        let (next_block_height, archived_blocks, blocks_offset, typed_from, archives) =
+            state::with(|s| {
+                (
+                    s.next_block_height,
+                    s.archived_blocks,
+                    s.blocks_offset,
+                    s.typed_blocks_from.unwrap_or_default(),
+                    s.archives.clone(),
+                )
+            });
+
+        NAME_BLOCKS.with_borrow(|logs| {
+            let mut blocks = vec![];
+            let mut archived = vec![];
+            for arg in args {
+                let (start, length) = arg
+                    .as_start_and_length()
+                    .unwrap_or_else(|msg| ic_cdk::api::trap(&msg));
+                let end = start.saturating_add(length).min(next_block_height);
+
+                // blocks that have been moved to archive canisters
+                for archive in &archives {
+                    let s = start.max(archive.start);
+                    let e = end.min(archive.end).min(archived_blocks);
+                    if s < e {
+                        archived.push(ArchivedBlocks {
+                            args: vec![GetBlocksRequest {
+                                start: Nat::from(s),
+                                length: Nat::from(e - s),
+                            }],
+                            callback: QueryArchiveFn::new(archive.canister, "icrc3_get_blocks"),
+                        });
+                    }
+                }
+
+                let max_length = MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
+                let start = start.max(archived_blocks);
+                let end = end.min(start.saturating_add(max_length));
+                for height in start..end {
+                    match logs.get(height - blocks_offset) {
+                        None => break,
+                        Some(block) => {
+                            blocks.push(BlockWithId {
+                                id: Nat::from(height),
+                                block: block_value(typed_from, height, &block),
+                            });
+                        }
+                    }
+                }
+            }
+
+            GetBlocksResult {
+                log_length: Nat::from(next_block_height),
+                blocks,
+                archived_blocks: archived,
+            }
+        })
+    }
+}
+
+pub mod pricing {
+    use super::*;
+    use ic_cose_types::to_cbor_bytes;
+
+    // quote returns the PANDA prices with their validity window.
+    pub fn quote() -> types::PriceQuote {
+        state::with(|s| match (&s.pricing.currency, &s.price_quote) {
+            (None, _) => types::PriceQuote {
+                currency: None,
+                rate: None,
+                price: s.price.clone(),
+                valid_from: 0,
+                valid_until: None,
+            },
+            (Some(_), Some(quote)) => quote.clone(),
+            // no quote yet, the prices are valid until refreshed
+            (Some(currency), None) => types::PriceQuote {
+                currency: Some(currency.clone()),
+                rate: None,
+                price: s.price.clone(),
+                valid_from: 0,
+                valid_until: Some(0),
+            },
+        })
+    }
+
+    // current returns the PANDA prices for a payment, refreshing an expired quote.
+    // When the refresh fails, the prices of the last quote are used, or the fixed prices
+    // when there is no quote yet.
+    pub async fn current(now_ms: u64) -> types::Price {
+        let quote = quote();
+        match quote.valid_until {
+            Some(valid_until) if valid_until <= now_ms => match refresh(now_ms).await {
+                Ok(q) => q.price,
+                Err(err) => {
+                    ic_cdk::api::debug_print(format!("failed to refresh price quote: {}", err));
+                    quote.price
+                }
+            },
+            _ => quote.price,
+        }
+    }
+
+    // refresh_due refreshes the quote when half of its validity window has passed.
+    pub async fn refresh_due(now_ms: u64) {
+        let due = state::with(|s| match (&s.pricing.currency, &s.price_quote) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(_), Some(quote)) => {
+                quote.valid_until.unwrap_or_default() <= now_ms + s.pricing.ttl_ms / 2
+            }
+        });
+        if due {
+            if let Err(err) = refresh(now_ms).await {
+                ic_cdk::api::debug_print(format!("failed to refresh price quote: {}", err));
+            }
+        }
+    }
+
+    // refresh fetches the exchange rate from the price source, converts the base prices
+    // and records the quote in the price history.
+    pub async fn refresh(now_ms: u64) -> Result<types::PriceQuote, String> {
+        let cfg = state::with(|s| s.pricing.clone());
+        let currency = cfg
+            .currency
+            .clone()
+            .ok_or_else(|| "prices are not denominated in a currency".to_string())?;
+        let source = cfg
+            .source
+            .ok_or_else(|| "no price source canister".to_string())?;
+        let res: Result<types::ExchangeRate, String> = call(
+            source,
+            "get_exchange_rate",
+            (types::GetExchangeRateRequest {
+                base_asset: "PANDA".to_string(),
+                quote_asset: currency.clone(),
+            },),
+            0,
+        )
+        .await?;
+        let rate = res?;
+        if rate.decimals > 18 {
+            return Err(format!("invalid rate decimals: {}", rate.decimals));
+        }
+        let r = rate.rate as u128 * types::TOKEN_1 as u128 / 10u128.pow(rate.decimals);
+        let r = u64::try_from(r).map_err(|_| format!("rate overflow: {}", r))?;
+        if r < cfg.min_rate || r > cfg.max_rate {
+            return Err(format!(
+                "rate {} out of bounds [{}, {}]",
+                r, cfg.min_rate, cfg.max_rate
+            ));
+        }
+        let valid_until = rate
+            .timestamp
+            .checked_mul(1000)
+            .ok_or_else(|| format!("invalid rate timestamp: {}", rate.timestamp))?
+            .min(now_ms)
+            .saturating_add(cfg.ttl_ms);
+        if valid_until <= now_ms {
+            return Err(format!("stale rate at {}", rate.timestamp));
+        }
+
+        let quote = types::PriceQuote {
+            currency: Some(currency),
+            rate: Some(r),
+            price: cfg.base.convert(r)?,
+            valid_from: now_ms,
+            valid_until: Some(valid_until),
+        };
+        state::with_mut(|s| {
+            // the pricing config maybe updated during the call
+            if s.pricing != cfg {
+                return Err("pricing config changed".to_string());
+            }
+            s.price_quote = Some(quote.clone());
+            Ok(())
+        })?;
+        PRICE_LOG.with_borrow_mut(|r| {
+            r.append(&to_cbor_bytes(&quote))
+                .expect("failed to append PriceQuote");
+        });
+        Ok(quote)
+    }
+
+    // update sets the pricing config, the quote will be refreshed with the new config.
+    pub fn update(cfg: types::PricingConfig) {
+        state::with_mut(|s| {
+            s.pricing = cfg;
+            s.price_quote = None;
+        });
+    }
+
+    // history returns quotes in descending order, starting below the index `prev`.
+    pub fn history(prev: Option<u64>, take: usize) -> Vec<(u64, types::PriceQuote)> {
+        PRICE_LOG.with_borrow(|r| {
+            let end = prev.unwrap_or(u64::MAX).min(r.len());
+            (0..end)
+                .rev()
+                .take(take)
+                .filter_map(|i| {
+                    r.get(i).map(|data| {
+                        let quote: types::PriceQuote =
+                            from_reader(&data[..]).expect("failed to decode PriceQuote");
+                        (i, quote)
+                    })
+                })
+                .collect()
+        })
+    }
+}
+
+pub mod channel {
+    use super::*;
+    use crate::MINTER_CANISTER;
+    use ic_message_types::channel::{
+        channel_kek_key, ChannelInfo, ChannelKEKInput, ChannelTopupInput, CreateChannelInput,
+    };
+
+    pub async fn create_channel(
+        caller: Principal,
+        now_ms: u64,
+        mut input: CreateChannelInput,
+    ) -> Result<ChannelInfo, String> {
+        let (channel_canister, profile_canister) = state::with_mut(|s| {
+            (
+                provision::pick_channel_canister(s),
+                s.profile_canisters.last().cloned(),
+            )
+        });
+
+        let channel_canister = channel_canister.ok_or_else(|| "no channel canister".to_string())?;
+        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+        let price = pricing::current(now_ms).await;
+
+        let amount = price.channel.saturating_sub(types::TOKEN_FEE);
+
+        let (user_profile_canister, is_new) =
+            USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
+                Some(user) => (user.profile_canister, false),
+                None => {
+                    let user = User {
+                        name: input.name.clone(),
+                        image: "".to_string(),
+                        profile_canister,
+                        cose_canister: None,
+                        username: None,
+                    };
+                    r.insert(caller, user.clone());
+                    (profile_canister, true)
+                }
+            });
+
+        if is_new {
+            let _: Result<(), String> = call(
+                user_profile_canister,
+                "admin_upsert_profile",
+                (caller, None::<(Principal, u64)>),
+                0,
+            )
+            .await?;
+        }
+
+        if amount > 0 {
+            let blk = token_transfer_from(caller, amount.into(), "CC".to_string()).await?;
+            treasury::record(
+                types::TreasuryKind::ChannelCreation,
+                caller,
+                amount,
+                Some(blk),
+                "CC".to_string(),
+                now_ms,
+            );
+        }
+
+        input.paid = amount;
+        let miner = input.get_miner();
+        let res: Result<ChannelInfo, String> =
+            call(channel_canister, "admin_create_channel", (input,), 0).await?;
+
+        // try to mint $DMSG for the miner
+        if res.is_ok() {
+            if let Some(miner) = miner {
+                if user::has_username(&miner) {
+                    let _: Result<bool, String> =
+                        call(MINTER_CANISTER, "try_prepare", (miner, caller), 0).await;
+                }
+            }
+        }
+
+        res
+    }
+
+    pub async fn topup_channel(
+        caller: Principal,
+        now_ms: u64,
+        mut input: ChannelTopupInput,
+    ) -> Result<ChannelInfo, String> {
+        input.payer = caller;
+        state::with(|s| {
+            if !s.channel_canisters.contains(&input.canister)
+                && !s.matured_channel_canisters.contains(&input.canister)
+            {
+                Err("channel canister not found".to_string())
+            } else {
                 Ok(())
             }
-            None => Err("user not found".to_string()),
-        })
+        })?;
+        let amount = input.amount.saturating_sub(types::TOKEN_FEE);
+        let blk = token_transfer_from(caller, amount.into(), "TC".to_string()).await?;
+        treasury::record(
+            types::TreasuryKind::ChannelTopup,
+            caller,
+            amount,
+            Some(blk),
+            "TC".to_string(),
+            now_ms,
+        );
+        let res: Result<ChannelInfo, String> =
+            call(input.canister, "admin_topup_channel", (input,), 0).await?;
+        res
+    }
+
+    pub async fn save_channel_kek(caller: Principal, input: ChannelKEKInput) -> Result<(), String> {
+        let cose_canister = USER_STORE
+            .with_borrow(|r| r.get(&caller).map(|u| u.cose_canister))
+            .ok_or_else(|| "user not found".to_string())?;
+        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
+        let mut sp = SettingPath {
+            ns: caller.to_text().replace("-", "_"),
+            user_owned: false,
+            subject: Some(caller),
+            key: channel_kek_key(&input.canister, input.id),
+            version: 0,
+        };
+        let res: Result<SettingInfo, String> =
+            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
+        let res = match res {
+            Ok(info) => {
+                sp.version = info.version;
+                let res: Result<UpdateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_update_payload",
+                    (
+                        sp,
+                        UpdateSettingPayloadInput {
+                            payload: None,
+                            status: None,
+                            deprecate_current: None,
+                            dek: Some(input.kek),
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+            Err(_) => {
+                let res: Result<CreateSettingOutput, String> = call(
+                    cose_canister,
+                    "setting_create",
+                    (
+                        sp,
+                        CreateSettingInput {
+                            payload: None,
+                            desc: None,
+                            status: None,
+                            tags: None,
+                            dek: Some(input.kek),
+                        },
+                    ),
+                    0,
+                )
+                .await?;
+                res.map(|_| ())
+            }
+        };
+
+        res.map(|_| ())
+    }
+}
+
+pub mod market {
+    use super::*;
+
+    struct TradeLock(String);
+
+    impl TradeLock {
+        fn new(name: &str) -> Result<Self, String> {
+            MARKET_LOCKS.with_borrow_mut(|r| {
+                if !r.insert(name.to_string()) {
+                    return Err("username is in trading".to_string());
+                }
+                Ok(TradeLock(name.to_string()))
+            })
+        }
+    }
+
+    impl Drop for TradeLock {
+        fn drop(&mut self) {
+            MARKET_LOCKS.with_borrow_mut(|r| {
+                r.remove(&self.0);
+            });
+        }
+    }
+
+    pub fn get(name: &str) -> Option<Listing> {
+        MARKET_STORE.with_borrow(|r| r.get(&name.to_string()))
+    }
+
+    pub fn list_listings(prev: Option<String>, take: usize) -> Vec<types::UsernameListing> {
+        MARKET_STORE.with_borrow(|r| {
+            let iter = match prev {
+                Some(ref prev) => r.range(prev.clone()..),
+                None => r.range(..),
+            };
+            iter.filter(|e| Some(e.key()) != prev.as_ref())
+                .take(take)
+                .map(|e| e.value().into_info(e.key().clone()))
+                .collect()
+        })
+    }
+
+    pub fn offers_of(buyer: &Principal) -> Vec<types::UsernameListing> {
+        MARKET_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| e.value().offers.contains_key(buyer))
+                .map(|e| e.value().into_info(e.key().clone()))
+                .collect()
+        })
+    }
+
+    pub fn list_username(
+        caller: Principal,
+        price: u64,
+        now_ms: u64,
+    ) -> Result<types::UsernameListing, String> {
+        let username = user::transferable_username(&caller)?;
+        let ln = username.to_lowercase();
+        MARKET_LOCKS.with_borrow(|r| {
+            if r.contains(&ln) {
+                return Err("username is in trading".to_string());
+            }
+            Ok(())
+        })?;
+
+        MARKET_STORE.with_borrow_mut(|r| {
+            let listing = match r.get(&ln) {
+                Some(mut listing) => {
+                    // the name maybe listed by its previous owner
+                    if listing.seller != caller {
+                        listing.seller = caller;
+                        listing.created_at = now_ms;
+                    }
+                    listing.price = price;
+                    listing
+                }
+                None => Listing {
+                    seller: caller,
+                    price,
+                    created_at: now_ms,
+                    offers: BTreeMap::new(),
+                },
+            };
+            r.insert(ln.clone(), listing.clone());
+            Ok(listing.into_info(ln))
+        })
+    }
+
+    // cancel_listing removes the listing of the caller's username, or of the given name,
+    // and refunds the offers. A listing whose seller no longer owns the name can be
+    // cancelled by anyone, so that its offers are not stranded.
+    pub async fn cancel_listing(
+        caller: Principal,
+        name: Option<String>,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let ln = match name {
+            Some(name) => name.to_lowercase(),
+            None => USER_STORE
+                .with_borrow(|r| r.get(&caller).and_then(|u| u.username))
+                .ok_or_else(|| "caller has no username".to_string())?
+                .to_lowercase(),
+        };
+        let _lock = TradeLock::new(&ln)?;
+        let listing = get(&ln).ok_or_else(|| "username is not listed".to_string())?;
+        let seller_owns = NAME_STORE.with_borrow(|r| r.get(&ln)) == Some(listing.seller);
+        if listing.seller != caller && seller_owns {
+            return Err("caller is not the seller".to_string());
+        }
+
+        MARKET_STORE.with_borrow_mut(|r| r.remove(&ln));
+        refund_offers(&ln, listing.offers, now_ms).await;
+        Ok(())
+    }
+
+    pub async fn buy(
+        caller: Principal,
+        name: String,
+        price: u64,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let ln = name.to_lowercase();
+        if user::has_username(&caller) {
+            return Err("caller already has username".to_string());
+        }
+        let _lock = TradeLock::new(&ln)?;
+        let listing = get(&ln).ok_or_else(|| "username is not listed".to_string())?;
+        if listing.seller == caller {
+            return Err("cannot buy own username".to_string());
+        }
+        if listing.price != price {
+            return Err("price mismatch".to_string());
+        }
+
+        let blk = token_transfer_from(caller, price.into(), format!("BU: {}", ln)).await?;
+        treasury::record(
+            types::TreasuryKind::MarketPurchase,
+            caller,
+            price,
+            Some(blk),
+            format!("BU: {}", ln),
+            now_ms,
+        );
+        // the lock ensures that the listing was not changed during the payment,
+        // but the buyer maybe registered a username in the meantime.
+        if let Err(err) = settle(
+            listing.seller,
+            caller,
+            ln.clone(),
+            price,
+            listing.offers,
+            now_ms,
+        )
+        .await
+        {
+            refund(&ln, caller, price, now_ms).await;
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub async fn offer(
+        caller: Principal,
+        name: String,
+        amount: u64,
+        now_ms: u64,
+    ) -> Result<types::UsernameListing, String> {
+        let ln = name.to_lowercase();
+        if user::has_username(&caller) {
+            return Err("caller already has username".to_string());
+        }
+        let _lock = TradeLock::new(&ln)?;
+        let listing = get(&ln).ok_or_else(|| "username is not listed".to_string())?;
+        if listing.seller == caller {
+            return Err("cannot offer own username".to_string());
+        }
+        if amount >= listing.price {
+            return Err("offer should be lower than the price, buy it instead".to_string());
+        }
+        if listing.offers.contains_key(&caller) {
+            return Err("caller already has an offer".to_string());
+        }
+        if listing.offers.len() >= types::MAX_USERNAME_OFFERS {
+            return Err("too many offers".to_string());
+        }
+
+        let blk = token_transfer_from(caller, amount.into(), format!("OU: {}", ln)).await?;
+        treasury::record(
+            types::TreasuryKind::MarketOffer,
+            caller,
+            amount,
+            Some(blk),
+            format!("OU: {}", ln),
+            now_ms,
+        );
+        // the listing maybe removed during the payment, refund the offer then.
+        let res = MARKET_STORE.with_borrow_mut(|r| {
+            let mut listing = r
+                .get(&ln)
+                .ok_or_else(|| "username is not listed".to_string())?;
+            listing.offers.insert(caller, (amount, now_ms));
+            r.insert(ln.clone(), listing.clone());
+            Ok(listing.into_info(ln.clone()))
+        });
+        if res.is_err() {
+            refund(&ln, caller, amount, now_ms).await;
+        }
+        res
+    }
+
+    // withdraw_offer removes the caller's offer, the refund is retried until it succeeds.
+    pub async fn withdraw_offer(
+        caller: Principal,
+        name: String,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let ln = name.to_lowercase();
+        let _lock = TradeLock::new(&ln)?;
+        let (amount, _) = MARKET_STORE.with_borrow_mut(|r| {
+            let mut listing = r
+                .get(&ln)
+                .ok_or_else(|| "username is not listed".to_string())?;
+            let offer = listing
+                .offers
+                .remove(&caller)
+                .ok_or_else(|| "offer not found".to_string())?;
+            r.insert(ln.clone(), listing);
+            Ok::<(u64, u64), String>(offer)
+        })?;
+        refund(&ln, caller, amount, now_ms).await;
+        Ok(())
+    }
+
+    pub async fn accept_offer(
+        caller: Principal,
+        buyer: Principal,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let username = user::transferable_username(&caller)?;
+        let ln = username.to_lowercase();
+        let _lock = TradeLock::new(&ln)?;
+        let mut listing = get(&ln).ok_or_else(|| "username is not listed".to_string())?;
+        let (amount, _) = listing
+            .offers
+            .remove(&buyer)
+            .ok_or_else(|| "offer not found".to_string())?;
+        if user::has_username(&buyer) {
+            return Err("buyer already has username".to_string());
+        }
+
+        settle(caller, buyer, ln, amount, listing.offers, now_ms).await
+    }
+
+    // settle moves the username to the buyer, pays the seller minus the DAO fee,
+    // and refunds the remaining offers. Nothing is changed if it fails.
+    async fn settle(
+        seller: Principal,
+        buyer: Principal,
+        ln: String,
+        amount: u64,
+        offers: BTreeMap<Principal, (u64, u64)>,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let username = user::transferable_username(&seller)?;
+        // the seller maybe changed the username since it was listed
+        if username.to_lowercase() != ln || NAME_STORE.with_borrow(|r| r.get(&ln)) != Some(seller) {
+            return Err("seller no longer owns the username".to_string());
+        }
+        let moved = user::move_username(seller, buyer, username, amount, now_ms)?;
+        MARKET_STORE.with_borrow_mut(|r| r.remove(&ln));
+        let fee = amount * types::USERNAME_MARKET_FEE_PERCENT / 100;
+        treasury::release(
+            types::TreasuryKind::MarketFee,
+            buyer,
+            fee,
+            format!("SU: {}", ln),
+            now_ms,
+        );
+
+        if let Err(err) = moved.init_services().await {
+            ic_cdk::api::debug_print(format!("failed to init services for {}: {}", buyer, err));
+        }
+
+        payout::pay_escrow(
+            seller,
+            amount - fee,
+            format!("SU: {}", ln),
+            types::TreasuryKind::MarketPayout,
+            types::TreasuryKind::MarketFee,
+            now_ms,
+        )
+        .await;
+        refund_offers(&ln, offers, now_ms).await;
+        Ok(())
+    }
+
+    // refund_offers refunds the offers, failed refunds are retried by the payout queue.
+    pub async fn refund_offers(ln: &str, offers: BTreeMap<Principal, (u64, u64)>, now_ms: u64) {
+        for (buyer, (amount, _)) in offers {
+            refund(ln, buyer, amount, now_ms).await;
+        }
+    }
+
+    async fn refund(ln: &str, buyer: Principal, amount: u64, now_ms: u64) {
+        payout::pay_escrow(
+            buyer,
+            amount,
+            format!("RO: {}", ln),
+            types::TreasuryKind::MarketRefund,
+            types::TreasuryKind::MarketFee,
+            now_ms,
+        )
+        .await;
+    }
+}
+
+pub mod auction {
+    use super::*;
+    use ic_cose_types::{cose::sha3_256, MILLISECONDS};
+
+    const MAX_AUCTIONS_PER_SETTLE: usize = 10;
+
+    // is_auctioned returns true if the name can only be registered by auction.
+    pub fn is_auctioned(ln: &str) -> bool {
+        state::with(|s| s.auction.lengths.contains(&(ln.len() as u8)))
+            || AUCTION_STORE.with_borrow(|r| r.contains_key(&ln.to_string()))
+    }
+
+    pub fn commitment(ln: &str, amount: u64, salt: &[u8], bidder: &Principal) -> [u8; 32] {
+        let mut data = Vec::with_capacity(ln.len() + 8 + salt.len() + 29);
+        data.extend_from_slice(ln.as_bytes());
+        data.extend_from_slice(&amount.to_be_bytes());
+        data.extend_from_slice(salt);
+        data.extend_from_slice(bidder.as_slice());
+        sha3_256(&data)
+    }
+
+    fn reserve_price(ln: &str) -> u64 {
+        pricing::quote().price.get(ln.len())
+    }
+
+    pub fn get(name: &str) -> Option<types::UsernameAuction> {
+        let ln = name.to_lowercase();
+        AUCTION_STORE
+            .with_borrow(|r| r.get(&ln))
+            .map(|a| a.into_info(ln.clone(), reserve_price(&ln)))
+    }
+
+    pub fn list(prev: Option<String>, take: usize) -> Vec<types::UsernameAuction> {
+        AUCTION_STORE.with_borrow(|r| {
+            let iter = match prev {
+                Some(ref prev) => r.range(prev.clone()..),
+                None => r.range(..),
+            };
+            iter.filter(|e| Some(e.key()) != prev.as_ref())
+                .take(take)
+                .map(|e| e.value().into_info(e.key().clone(), reserve_price(e.key())))
+                .collect()
+        })
+    }
+
+    // check_commit checks that the caller can commit a bid with the deposit.
+    fn check_commit(caller: &Principal, ln: &str, deposit: u64, now_ms: u64) -> Result<(), String> {
+        if user::has_username(caller) {
+            return Err("caller already has username".to_string());
+        }
+        match AUCTION_STORE.with_borrow(|r| r.get(&ln.to_string())) {
+            Some(auction) => {
+                if now_ms >= auction.commit_end {
+                    return Err("commit phase is over".to_string());
+                }
+                if auction.bids.contains_key(caller) {
+                    return Err("caller already has a bid".to_string());
+                }
+                if auction.bids.len() >= types::MAX_AUCTION_BIDS {
+                    return Err("too many bids".to_string());
+                }
+            }
+            None => {
+                if !state::with(|s| s.auction.lengths.contains(&(ln.len() as u8))) {
+                    return Err("username is not auctioned".to_string());
+                }
+                if reserved::is_reserved(ln) {
+                    return Err("username is reserved".to_string());
+                }
+                if NAME_STORE.with_borrow(|r| r.contains_key(&ln.to_string())) {
+                    return Err("username already registered".to_string());
+                }
+            }
+        }
+        if deposit < reserve_price(ln) {
+            return Err("deposit is lower than the reserve price".to_string());
+        }
+        Ok(())
+    }
+
+    // commit_bid escrows the deposit and records the sealed bid,
+    // the auction starts with its first bid.
+    // The deposit is public and is an upper bound of the sealed amount, bidders can hide their
+    // amount by depositing more, the excess is refunded when the auction is settled.
+    pub async fn commit_bid(
+        caller: Principal,
+        username: String,
+        commitment: ByteArray<32>,
+        deposit: u64,
+        now_ms: u64,
+    ) -> Result<types::UsernameAuction, String> {
+        let ln = username.to_lowercase();
+        user::release_if_expired(&ln, now_ms).await?;
+        check_commit(&caller, &ln, deposit, now_ms)?;
+        let blk = token_transfer_from(caller, deposit.into(), format!("AB: {}", ln)).await?;
+        treasury::record(
+            types::TreasuryKind::AuctionDeposit,
+            caller,
+            deposit,
+            Some(blk),
+            format!("AB: {}", ln),
+            now_ms,
+        );
+
+        // the auction maybe changed during the payment
+        let now_ms = ic_cdk::api::time() / MILLISECONDS;
+        if let Err(err) = check_commit(&caller, &ln, deposit, now_ms) {
+            refund(caller, deposit, format!("AR: {}", ln), now_ms).await;
+            return Err(err);
+        }
+
+        let (commit_ms, reveal_ms) = state::with(|s| (s.auction.commit_ms, s.auction.reveal_ms));
+        let auction = AUCTION_STORE.with_borrow_mut(|r| {
+            let mut auction = r.get(&ln).unwrap_or_else(|| Auction {
+                username,
+                started_at: now_ms,
+                commit_end: now_ms + commit_ms,
+                reveal_end: now_ms + commit_ms + reveal_ms,
+                bids: BTreeMap::new(),
+            });
+            auction.bids.insert(
+                caller,
+                Bid {
+                    commitment,
+                    deposit,
+                    amount: None,
+                    created_at: now_ms,
+                },
+            );
+            r.insert(ln.clone(), auction.clone());
+            auction
+        });
+        Ok(auction.into_info(ln.clone(), reserve_price(&ln)))
+    }
+
+    pub fn reveal_bid(
+        caller: Principal,
+        name: String,
+        amount: u64,
+        salt: &[u8],
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let ln = name.to_lowercase();
+        let reserve = reserve_price(&ln);
+        AUCTION_STORE.with_borrow_mut(|r| {
+            let mut auction = r.get(&ln).ok_or_else(|| "auction not found".to_string())?;
+            if now_ms < auction.commit_end || now_ms >= auction.reveal_end {
+                return Err("auction is not in reveal phase".to_string());
+            }
+            let bid = auction
+                .bids
+                .get_mut(&caller)
+                .ok_or_else(|| "bid not found".to_string())?;
+            if bid.amount.is_some() {
+                return Err("bid already revealed".to_string());
+            }
+            if commitment(&ln, amount, salt, &caller) != *bid.commitment {
+                return Err("commitment mismatch".to_string());
+            }
+            if amount > bid.deposit {
+                return Err("amount exceeds the deposit".to_string());
+            }
+            if amount < reserve {
+                return Err("amount is lower than the reserve price".to_string());
+            }
+            bid.amount = Some(amount);
+            r.insert(ln.clone(), auction);
+            Ok(())
+        })
+    }
+
+    // settle_due settles the auctions whose reveal phase is over, called by a timer.
+    pub async fn settle_due(now_ms: u64) {
+        let due: Vec<String> = AUCTION_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| e.value().reveal_end <= now_ms)
+                .take(MAX_AUCTIONS_PER_SETTLE)
+                .map(|e| e.key().clone())
+                .collect()
+        });
+
+        for ln in due {
+            settle(ln, now_ms).await;
+        }
+    }
+
+    // settle registers the name to the highest revealed bidder without a username,
+    // refunds the other revealed bids and forfeits the unrevealed ones.
+    async fn settle(ln: String, now_ms: u64) {
+        let auction = match AUCTION_STORE.with_borrow_mut(|r| r.remove(&ln)) {
+            Some(auction) => auction,
+            None => return,
+        };
+
+        let mut revealed: Vec<(Principal, u64, u64)> = auction
+            .bids
+            .iter()
+            .filter_map(|(bidder, bid)| bid.amount.map(|amount| (*bidder, amount, bid.created_at)))
+            .collect();
+        revealed.sort_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+
+        // the name is reserved for the winner before any inter-canister call
+        let winner = revealed.into_iter().find(|(bidder, _, _)| {
+            !user::has_username(bidder)
+                && NAME_STORE.with_borrow_mut(|r| {
+                    if r.contains_key(&ln) {
+                        return false;
+                    }
+                    r.insert(ln.clone(), *bidder);
+                    true
+                })
+        });
+
+        let forfeited: u64 = auction
+            .bids
+            .values()
+            .filter(|bid| bid.amount.is_none())
+            .map(|bid| bid.deposit)
+            .sum();
+        treasury::release(
+            types::TreasuryKind::AuctionForfeit,
+            ic_cdk::api::canister_self(),
+            forfeited,
+            format!("AB: {}", ln),
+            now_ms,
+        );
+
+        let mut winner = winner;
+        if let Some((bidder, amount, _)) = winner {
+            let desc = format!(
+                "name: {}, auction: {}",
+                auction.username, auction.started_at
+            );
+            // register_paid records the winning bid as income
+            match user::register_paid(
+                bidder,
+                auction.username.clone(),
+                auction.username.clone(),
+                amount,
+                None,
+                desc,
+                now_ms,
+            )
+            .await
+            {
+                Ok(_) => treasury::record(
+                    types::TreasuryKind::EscrowRelease,
+                    bidder,
+                    amount,
+                    None,
+                    format!("AB: {}", ln),
+                    now_ms,
+                ),
+                Err(err) => {
+                    // the name is released and the winner gets the whole deposit back
+                    ic_cdk::api::debug_print(format!(
+                        "failed to register {} for {}: {}",
+                        ln, bidder, err
+                    ));
+                    NAME_STORE.with_borrow_mut(|r| {
+                        if r.get(&ln) == Some(bidder) {
+                            r.remove(&ln);
+                        }
+                    });
+                    winner = None;
+                }
+            }
+        }
+
+        for (bidder, bid) in auction.bids {
+            let amount = match (bid.amount, winner) {
+                (None, _) => continue,
+                (Some(amount), Some((winner, _, _))) if winner == bidder => bid.deposit - amount,
+                _ => bid.deposit,
+            };
+            refund(bidder, amount, format!("AR: {}", ln), now_ms).await;
+        }
+    }
+
+    // refund returns the amount minus the ledger fee, failed refunds are retried by the
+    // payout queue.
+    async fn refund(to: Principal, amount: u64, memo: String, now_ms: u64) {
+        payout::pay_escrow(
+            to,
+            amount,
+            memo,
+            types::TreasuryKind::AuctionRefund,
+            types::TreasuryKind::AuctionForfeit,
+            now_ms,
+        )
+        .await;
+    }
+}
+
+pub mod resolver {
+    use super::*;
+    use crate::types::{ResolvedName, ResolvedRecord, ResolverRecord, UpdateResolverInput};
+    use lib_panda::{recover_eth_address, verify_sol_signature};
+
+    // owned_username returns the lowercase username owned by the caller.
+    fn owned_username(caller: &Principal) -> Result<String, String> {
+        let username = USER_STORE
+            .with_borrow(|r| r.get(caller).and_then(|u| u.username))
+            .ok_or_else(|| "caller has no username".to_string())?;
+        let ln = username.to_lowercase();
+        match NAME_STORE.with_borrow(|r| r.get(&ln)) {
+            Some(owner) if owner == *caller => Ok(ln),
+            _ => Err("username not owned by caller".to_string()),
+        }
+    }
+
+    pub fn update(
+        caller: Principal,
+        input: UpdateResolverInput,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let ln = owned_username(&caller)?;
+        RESOLVER_STORE.with_borrow_mut(|r| {
+            let mut resolver = match r.get(&ln) {
+                Some(resolver) if resolver.owner == caller => resolver,
+                _ => Resolver {
+                    owner: caller,
+                    records: BTreeMap::new(),
+                    updated_at: now_ms,
+                    nonce: 0,
+                },
+            };
+
+            for key in input.remove {
+                resolver.records.remove(&key);
+            }
+            for record in input.upsert {
+                let key = record.key();
+                match resolver.records.get(&key) {
+                    Some((prev, _)) if *prev == record => {}
+                    _ => {
+                        resolver.records.insert(key, (record, None));
+                    }
+                }
+            }
+            if resolver.records.len() > types::MAX_RESOLVER_TEXT_RECORDS + 4 {
+                return Err("too many records".to_string());
+            }
+
+            resolver.updated_at = now_ms;
+            r.insert(ln, resolver);
+            Ok(())
+        })
+    }
+
+    pub fn resolve(name: &str, keys: Vec<String>, now_ms: u64) -> Result<ResolvedName, String> {
+        let ln = name.to_lowercase();
+        let owner = NAME_STORE
+            .with_borrow(|r| r.get(&ln))
+            .ok_or_else(|| "username not found".to_string())?;
+        if NAME_EXPIRY_STORE
+            .with_borrow(|r| r.get(&ln))
+            .is_some_and(|t| t < now_ms)
+        {
+            return Err("username expired".to_string());
+        }
+        let (records, updated_at) = match RESOLVER_STORE.with_borrow(|r| r.get(&ln)) {
+            Some(resolver) if resolver.owner == owner => (
+                resolver
+                    .records
+                    .into_iter()
+                    .filter(|(key, _)| keys.is_empty() || keys.contains(key))
+                    .map(|(_, (record, verified_at))| ResolvedRecord {
+                        record,
+                        verified_at,
+                    })
+                    .collect(),
+                resolver.updated_at,
+            ),
+            _ => (vec![], 0),
+        };
+
+        Ok(ResolvedName {
+            name: ln,
+            owner,
+            records,
+            updated_at,
+        })
+    }
+
+    // address_message returns the message that the evm or sol address should sign
+    // to prove that it is linked to the caller's username. The message is bound to the
+    // registry canister and the resolver's nonce, and expires ADDRESS_MESSAGE_TTL_MS
+    // after `issued_at`.
+    pub fn address_message(
+        caller: &Principal,
+        key: &str,
+        registry: &Principal,
+        issued_at: u64,
+    ) -> Result<types::AddressMessage, String> {
+        let ln = owned_username(caller)?;
+        let (record, nonce) = RESOLVER_STORE
+            .with_borrow(|r| r.get(&ln))
+            .filter(|resolver| resolver.owner == *caller)
+            .and_then(|mut resolver| {
+                resolver
+                    .records
+                    .remove(key)
+                    .map(|(record, _)| (record, resolver.nonce))
+            })
+            .ok_or_else(|| format!("record {} not found", key))?;
+
+        let expires_at = issued_at + types::ADDRESS_MESSAGE_TTL_MS;
+        match record {
+            ResolverRecord::Evm(addr) | ResolverRecord::Sol(addr) => Ok(types::AddressMessage {
+                message: format!(
+                    "I link the address {} to the ICPanda username {} owned by {}.\n\nRegistry: {}\nNonce: {}\nIssued At: {}\nExpires At: {}",
+                    addr,
+                    ln,
+                    caller.to_text(),
+                    registry.to_text(),
+                    nonce,
+                    issued_at,
+                    expires_at
+                ),
+                issued_at,
+                expires_at,
+            }),
+            _ => Err(format!("record {} cannot be verified", key)),
+        }
+    }
+
+    // verify_address verifies the signature of the address message issued at `issued_at`,
+    // a message can be used once and only before it expires.
+    pub fn verify_address(
+        caller: Principal,
+        key: String,
+        signature: &[u8],
+        registry: Principal,
+        issued_at: u64,
+        now_ms: u64,
+    ) -> Result<(), String> {
+        let msg = address_message(&caller, &key, &registry, issued_at)?;
+        // allow the clock of the replica that issued the message to be a minute ahead
+        if issued_at > now_ms + 60 * 1000 || msg.expires_at < now_ms {
+            return Err("address message expired".to_string());
+        }
+        let message = msg.message;
+        let ln = owned_username(&caller)?;
+        RESOLVER_STORE.with_borrow_mut(|r| {
+            let mut resolver = r.get(&ln).ok_or_else(|| "records not found".to_string())?;
+            let (record, verified_at) = resolver
+                .records
+                .get_mut(&key)
+                .ok_or_else(|| format!("record {} not found", key))?;
+            match record {
+                ResolverRecord::Evm(addr) => {
+                    let addr = types::evm_address(addr)?;
+                    if recover_eth_address(&message, signature)? != addr {
+                        return Err("signature does not match the address".to_string());
+                    }
+                }
+                ResolverRecord::Sol(addr) => {
+                    let pubkey = types::sol_address(addr)?;
+                    let signature: [u8; 64] = signature
+                        .try_into()
+                        .map_err(|_| "invalid signature".to_string())?;
+                    verify_sol_signature(&pubkey, message.as_bytes(), &signature)?;
+                }
+                _ => return Err(format!("record {} cannot be verified", key)),
+            }
+
+            *verified_at = Some(now_ms);
+            resolver.nonce += 1;
+            r.insert(ln, resolver);
+            Ok(())
+        })
+    }
+}
+
+pub mod subname {
+    use super::*;
+
+    const MAX_SEARCH_RESULTS: usize = 20;
+
+    // subname_key returns the store key "parent/label" of a full subname "label.parent".
+    fn subname_key(name: &str) -> Option<(String, String)> {
+        let (label, parent) = name.split_once('.')?;
+        Some((parent.to_string(), format!("{}/{}", parent, label)))
+    }
+
+    fn is_valid(parent: &str, subname: &Subname) -> bool {
+        NAME_STORE.with_borrow(|r| r.get(&parent.to_string())) == Some(subname.issuer)
+    }
+
+    // get returns the principal that a full subname points at.
+    pub fn get(name: &str) -> Option<Principal> {
+        let (parent, key) = subname_key(name)?;
+        SUBNAME_STORE
+            .with_borrow(|r| r.get(&key))
+            .filter(|s| is_valid(&parent, s))
+            .map(|s| s.user)
+    }
+
+    fn list_by_key(parent: &str, prefix: &str, take: usize) -> Vec<types::SubnameInfo> {
+        SUBNAME_STORE.with_borrow(|r| {
+            r.range(prefix.to_string()..)
+                .take_while(|e| e.key().starts_with(prefix))
+                .filter(|e| is_valid(parent, &e.value()))
+                .take(take)
+                .map(|e| {
+                    let v = e.value();
+                    let label = &e.key()[parent.len() + 1..];
+                    types::SubnameInfo {
+                        name: format!("{}.{}", label, parent),
+                        user: v.user,
+                        created_at: v.created_at,
+                    }
+                })
+                .collect()
+        })
+    }
+
+    pub fn search(prefix: &str) -> Vec<String> {
+        match prefix.split_once('.') {
+            Some((label, parent)) => {
+                list_by_key(parent, &format!("{}/{}", parent, label), MAX_SEARCH_RESULTS)
+                    .into_iter()
+                    .map(|s| s.name)
+                    .collect()
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn list(parent: &str) -> Vec<types::SubnameInfo> {
+        list_by_key(
+            parent,
+            &format!("{}/", parent),
+            types::MAX_SUBNAMES_PER_NAME,
+        )
+    }
+
+    // owned_parent returns the lowercase username owned by the caller.
+    fn owned_parent(caller: &Principal) -> Result<String, String> {
+        let username = USER_STORE
+            .with_borrow(|r| r.get(caller).and_then(|u| u.username))
+            .ok_or_else(|| "caller has no username".to_string())?;
+        let ln = username.to_lowercase();
+        match NAME_STORE.with_borrow(|r| r.get(&ln)) {
+            Some(owner) if owner == *caller => Ok(ln),
+            _ => Err("username not owned by caller".to_string()),
+        }
+    }
+
+    pub fn create(
+        caller: Principal,
+        label: String,
+        user: Principal,
+        now_ms: u64,
+    ) -> Result<types::SubnameInfo, String> {
+        // labels starting with '_' are reserved, like usernames
+        if label.is_empty() || label.starts_with('_') {
+            return Err("invalid label".to_string());
+        }
+        let parent = owned_parent(&caller)?;
+        if !USER_STORE.with_borrow(|r| r.contains_key(&user)) {
+            return Err("user not found".to_string());
+        }
+        if list(&parent).len() >= types::MAX_SUBNAMES_PER_NAME {
+            return Err("too many subnames".to_string());
+        }
+
+        let name = format!("{}.{}", label, parent);
+        let key = format!("{}/{}", parent, label);
+        SUBNAME_STORE.with_borrow_mut(|r| {
+            if let Some(prev) = r.get(&key) {
+                if is_valid(&parent, &prev) {
+                    return Err("subname already exists".to_string());
+                }
+            }
+            r.insert(
+                key,
+                Subname {
+                    user,
+                    issuer: caller,
+                    created_at: now_ms,
+                },
+            );
+            Ok(())
+        })?;
+
+        state::with_mut(|s| {
+            user::append_block(
+                s,
+                NameOp::SubnameIssue,
+                name.clone(),
+                user,
+                Some(caller),
+                0,
+                now_ms,
+            );
+        });
+        Ok(types::SubnameInfo {
+            name,
+            user,
+            created_at: now_ms,
+        })
+    }
+
+    pub fn revoke(caller: Principal, label: String, now_ms: u64) -> Result<(), String> {
+        let parent = owned_parent(&caller)?;
+        let key = format!("{}/{}", parent, label);
+        let subname = SUBNAME_STORE
+            .with_borrow_mut(|r| r.remove(&key))
+            .ok_or_else(|| "subname not found".to_string())?;
+
+        state::with_mut(|s| {
+            user::append_block(
+                s,
+                NameOp::SubnameRevoke,
+                format!("{}.{}", label, parent),
+                Principal::anonymous(),
+                Some(subname.user),
+                0,
+                now_ms,
+            );
+        });
+        Ok(())
+    }
+}
+
+pub mod archive {
+    use super::*;
+    use icrc_ledger_types::icrc3::{archive::ICRC3ArchiveInfo, blocks::ICRC3GenericBlock};
+
+    pub fn attach(canister: Principal) -> Result<(), String> {
+        state::with_mut(|s| {
+            if s.archives.iter().any(|a| a.canister == canister) {
+                return Err("archive canister already attached".to_string());
+            }
+            s.archives.push(ArchiveRange {
+                canister,
+                start: s.archived_blocks,
+                end: s.archived_blocks,
+            });
+            Ok(())
+        })
+    }
+
+    pub fn list(from: Option<Principal>) -> Vec<ICRC3ArchiveInfo> {
+        state::with(|s| {
+            let skip = match from {
+                Some(from) => s
+                    .archives
+                    .iter()
+                    .position(|a| a.canister == from)
+                    .map(|i| i + 1)
+                    .unwrap_or(s.archives.len()),
+                None => 0,
+            };
+            s.archives
+                .iter()
+                .skip(skip)
+                .filter(|a| a.end > a.start)
+                .map(|a| ICRC3ArchiveInfo {
+                    canister_id: a.canister,
+                    start: Nat::from(a.start),
+                    end: Nat::from(a.end - 1),
+                })
+                .collect()
+        })
+    }
+
+    // run moves old blocks to the current archive canister when the local log
+    // exceeds the threshold, then compacts the local log. It is called by a timer.
+    pub async fn run() {
+        let Some(_guard) = FlagGuard::new(&ARCHIVING) else {
+            return;
+        };
+        if let Err(err) = archive_blocks().await {
+            ic_cdk::api::debug_print(format!("failed to archive blocks: {}", err));
+        }
+        compact();
+    }
+
+    // target returns the archive canister receiving new blocks. It is the last used one,
+    // or the next attached one when that is full.
+    fn target(s: &mut State) -> Result<Principal, String> {
+        let i = s
+            .archives
+            .iter()
+            .rposition(|a| a.end > a.start)
+            .unwrap_or(0);
+        let i = match s.archives.get(i) {
+            None => return Err("no archive canister".to_string()),
+            Some(a) if a.end - a.start >= types::ARCHIVE_MAX_BLOCKS => i + 1,
+            Some(_) => i,
+        };
+        let archived = s.archived_blocks;
+        match s.archives.get_mut(i) {
+            Some(a) => {
+                if a.end == a.start {
+                    a.start = archived;
+                    a.end = archived;
+                }
+                Ok(a.canister)
+            }
+            None => Err("archive canisters are full, attach a new one".to_string()),
+        }
+    }
+
+    async fn archive_blocks() -> Result<(), String> {
+        let local = state::with(|s| s.next_block_height - s.archived_blocks);
+        if local <= types::ARCHIVE_TRIGGER_BLOCKS {
+            return Ok(());
+        }
+
+        loop {
+            let (canister, start, offset, typed_from, local) = state::with_mut(|s| {
+                Ok::<_, String>((
+                    target(s)?,
+                    s.archived_blocks,
+                    s.blocks_offset,
+                    s.typed_blocks_from.unwrap_or_default(),
+                    s.next_block_height - s.archived_blocks,
+                ))
+            })?;
+            if local <= types::ARCHIVE_KEEP_BLOCKS {
+                return Ok(());
+            }
+
+            let room = state::with(|s| {
+                s.archives
+                    .iter()
+                    .find(|a| a.canister == canister)
+                    .map(|a| types::ARCHIVE_MAX_BLOCKS - (a.end - a.start))
+                    .unwrap_or_default()
+            });
+            let n = (local - types::ARCHIVE_KEEP_BLOCKS)
+                .min(types::ARCHIVE_BATCH_BLOCKS)
+                .min(room);
+            let blocks: Vec<ICRC3GenericBlock> = NAME_BLOCKS.with_borrow(|r| {
+                (start..start + n)
+                    .filter_map(|h| r.get(h - offset).map(|b| (h, b)))
+                    .map(|(h, b)| user::block_value(typed_from, h, &b))
+                    .collect()
+            });
+            let res: Result<u64, String> =
+                call(canister, "append_blocks", (start, blocks), 0).await?;
+            let end = res?;
+            if end != start + n {
+                return Err(format!(
+                    "unexpected archive height {}, expected {}",
+                    end,
+                    start + n
+                ));
+            }
+
+            state::with_mut(|s| {
+                s.archived_blocks = end;
+                if let Some(a) = s.archives.iter_mut().find(|a| a.canister == canister) {
+                    a.end = end;
+                }
+            });
+        }
+    }
+
+    // compact drops the archived blocks from the local log. The blocks not archived yet,
+    // at most ARCHIVE_KEEP_BLOCKS after archiving, are copied one by one to a new log in
+    // the other pair of log memories, so the heap never holds more than one block.
+    fn compact() {
+        let (archived, offset, alt) =
+            state::with(|s| (s.archived_blocks, s.blocks_offset, s.blocks_alt));
+        if archived == offset {
+            return;
+        }
+
+        let (index_id, data_id) = if alt {
+            (NAME_BLK_INDEX_MEMORY_ID, NAME_BLK_DATA_MEMORY_ID)
+        } else {
+            (NAME_BLK_ALT_INDEX_MEMORY_ID, NAME_BLK_ALT_DATA_MEMORY_ID)
+        };
+        let prev = NAME_BLOCKS.with_borrow_mut(|r| {
+            std::mem::replace(
+                r,
+                StableLog::new(
+                    MEMORY_MANAGER.with_borrow(|m| m.get(index_id)),
+                    MEMORY_MANAGER.with_borrow(|m| m.get(data_id)),
+                ),
+            )
+        });
+        NAME_BLOCKS.with_borrow_mut(|r| {
+            for i in archived - offset..prev.len() {
+                if let Some(blk) = prev.get(i) {
+                    r.append(&blk).expect("failed to append NameBlock");
+                }
+            }
+        });
+        state::with_mut(|s| {
+            s.blocks_offset = archived;
+            s.blocks_alt = !alt;
+        });
+    }
+}
+
+pub mod history {
+    use super::*;
+    use icrc_ledger_types::icrc3::blocks::{GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock};
+
+    const SYNC_BATCH_BLOCKS: u64 = 500;
+    const MAX_SYNC_BATCHES: usize = 10;
+
+    // add indexes a block by its name and by the accounts involved in it.
+    pub fn add(blk: &NameBlock) {
+        let event = NameEvent::from(blk);
+        NAME_HISTORY_STORE.with_borrow_mut(|r| {
+            r.insert(NameHeight(blk.name.clone(), blk.height), event.clone());
+        });
+        ACCOUNT_HISTORY_STORE.with_borrow_mut(|r| {
+            if blk.user != Principal::anonymous() {
+                r.insert((blk.user, blk.height), event.clone());
+            }
+            if let Some(from) = blk.from {
+                if from != blk.user {
+                    r.insert((from, blk.height), event);
+                }
+            }
+        });
+    }
+
+    // name_history returns the events of a name in descending height order,
+    // starting below `prev`.
+    pub fn name_history(name: &str, prev: Option<u64>, take: usize) -> Vec<types::NameHistory> {
+        NAME_HISTORY_STORE.with_borrow(|r| {
+            r.range(
+                NameHeight(name.to_string(), 0)
+                    ..NameHeight(name.to_string(), prev.unwrap_or(u64::MAX)),
+            )
+            .rev()
+            .take(take)
+            .map(|e| e.value().into_info())
+            .collect()
+        })
+    }
+
+    // account_history returns the events involving an account in descending height order,
+    // starting below `prev`.
+    pub fn account_history(
+        account: Principal,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Vec<types::NameHistory> {
+        ACCOUNT_HISTORY_STORE.with_borrow(|r| {
+            r.range((account, 0)..(account, prev.unwrap_or(u64::MAX)))
+                .rev()
+                .take(take)
+                .map(|e| e.value().into_info())
+                .collect()
+        })
+    }
+
+    // sync indexes the blocks that were appended before the indexes existed,
+    // fetching the archived ones from archive canisters.
+    pub async fn sync() {
+        if state::with(|s| s.indexed_blocks >= s.next_block_height) {
+            return;
+        }
+        let Some(_guard) = FlagGuard::new(&INDEXING) else {
+            return;
+        };
+        if let Err(err) = sync_blocks().await {
+            ic_cdk::api::debug_print(format!("failed to index blocks: {}", err));
+        }
+    }
+
+    async fn sync_blocks() -> Result<(), String> {
+        for _ in 0..MAX_SYNC_BATCHES {
+            let (start, next, archived, offset, archives) = state::with(|s| {
+                (
+                    s.indexed_blocks,
+                    s.next_block_height,
+                    s.archived_blocks,
+                    s.blocks_offset,
+                    s.archives.clone(),
+                )
+            });
+            if start >= next {
+                return Ok(());
+            }
+
+            let end = start.saturating_add(SYNC_BATCH_BLOCKS).min(next);
+            let blocks: Vec<NameBlock> = if start < archived {
+                let archive = archives
+                    .iter()
+                    .find(|a| a.start <= start && start < a.end)
+                    .ok_or_else(|| format!("no archive canister for block {}", start))?;
+                let end = end.min(archive.end);
+                let res: GetBlocksResult = query_call(
+                    archive.canister,
+                    "icrc3_get_blocks",
+                    (vec![GetBlocksRequest {
+                        start: Nat::from(start),
+                        length: Nat::from(end - start),
+                    }],),
+                )
+                .await?;
+                res.blocks
+                    .into_iter()
+                    .map(|b| {
+                        let height =
+                            u64::try_from(&b.id.0).map_err(|_| "invalid block id".to_string())?;
+                        decode_block(height, &b.block)
+                    })
+                    .collect::<Result<_, _>>()?
+            } else {
+                NAME_BLOCKS.with_borrow(|r| {
+                    (start..end)
+                        .filter_map(|h| r.get(h - offset))
+                        .map(|b| from_reader(&b[..]).map_err(|err| format!("{:?}", err)))
+                        .collect::<Result<_, _>>()
+                })?
+            };
+            if blocks.is_empty() {
+                return Err(format!("no blocks from {}", start));
+            }
+
+            state::with_mut(|s| {
+                for blk in &blocks {
+                    // new blocks may be indexed by `append_block` in the meantime
+                    if blk.height != s.indexed_blocks {
+                        break;
+                    }
+                    add(blk);
+                    s.indexed_blocks += 1;
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // decode_block decodes a typed block, or a legacy CBOR encoded block.
+    fn decode_block(height: u64, block: &ICRC3GenericBlock) -> Result<NameBlock, String> {
+        match block {
+            ICRC3GenericBlock::Blob(data) => {
+                from_reader(data.as_slice()).map_err(|err| format!("{:?}", err))
+            }
+            value => NameBlock::from_value(height, value),
+        }
+    }
+}
+
+pub mod reserved {
+    use super::*;
+
+    pub fn is_reserved(ln: &str) -> bool {
+        RESERVED_STORE.with_borrow(|r| r.contains_key(&ln.to_string()))
+    }
+
+    pub fn get(name: &str) -> Option<types::ReservedName> {
+        let ln = name.to_lowercase();
+        RESERVED_STORE
+            .with_borrow(|r| r.get(&ln))
+            .map(|v| v.into_info(ln))
+    }
+
+    pub fn list(prev: Option<String>, take: usize) -> Vec<types::ReservedName> {
+        RESERVED_STORE.with_borrow(|r| {
+            let iter = match prev {
+                Some(ref prev) => r.range(prev.clone()..),
+                None => r.range(..),
+            };
+            iter.filter(|e| Some(e.key()) != prev.as_ref())
+                .take(take)
+                .map(|e| e.value().into_info(e.key().clone()))
+                .collect()
+        })
+    }
+
+    pub fn add(names: Vec<types::ReserveNameInput>, now_ms: u64) {
+        RESERVED_STORE.with_borrow_mut(|r| {
+            for input in names {
+                let ln = input.name.to_lowercase();
+                let allocated_to = r.get(&ln).and_then(|v| v.allocated_to);
+                r.insert(
+                    ln,
+                    Reserved {
+                        reason: input.reason,
+                        allocated_to,
+                        created_at: now_ms,
+                    },
+                );
+            }
+        });
+    }
+
+    pub fn remove(names: Vec<String>) {
+        RESERVED_STORE.with_borrow_mut(|r| {
+            for name in names {
+                r.remove(&name.to_lowercase());
+            }
+        });
+    }
+
+    pub fn check_allocate(ln: &str, user: &Principal) -> Result<(), String> {
+        match RESERVED_STORE.with_borrow(|r| r.get(&ln.to_string())) {
+            None => return Err("username is not reserved".to_string()),
+            Some(v) if v.allocated_to.is_some() => {
+                return Err("username already allocated".to_string())
+            }
+            _ => {}
+        }
+        if NAME_STORE.with_borrow(|r| r.contains_key(&ln.to_string())) {
+            return Err("username already registered".to_string());
+        }
+        if user::has_username(user) {
+            return Err("user already has username".to_string());
+        }
+        Ok(())
+    }
+
+    // allocate registers a reserved name to the user without payment,
+    // the name stays in the reserved list with the allocation recorded.
+    pub async fn allocate(user: Principal, name: String, now_ms: u64) -> Result<UserInfo, String> {
+        let ln = name.to_lowercase();
+        check_allocate(&ln, &user)?;
+        NAME_STORE.with_borrow_mut(|r| r.insert(ln.clone(), user));
+        set_allocation(&ln, Some(user));
+        let desc = format!("name: {}, reserved", name);
+        match user::register_paid(user, name.clone(), name, 0, None, desc, now_ms).await {
+            Ok(info) => Ok(info),
+            Err(err) => {
+                NAME_STORE.with_borrow_mut(|r| r.remove(&ln));
+                set_allocation(&ln, None);
+                Err(err)
+            }
+        }
+    }
+
+    // set_allocation records the holder of an allocated reserved name, it is cleared
+    // when the allocation fails or the name is released.
+    pub fn set_allocation(ln: &str, user: Option<Principal>) {
+        RESERVED_STORE.with_borrow_mut(|r| {
+            if let Some(mut v) = r.get(&ln.to_string()) {
+                if v.allocated_to != user {
+                    v.allocated_to = user;
+                    r.insert(ln.to_string(), v);
+                }
+            }
+        });
+    }
+
+    pub fn file_dispute(
+        caller: Principal,
+        name: String,
+        claim: String,
+        now_ms: u64,
+    ) -> Result<types::NameDispute, String> {
+        let ln = name.to_lowercase();
+        let holder = NAME_STORE.with_borrow(|r| r.get(&ln));
+        if holder.is_none() && !is_reserved(&ln) {
+            return Err("username is not registered or reserved".to_string());
+        }
+        if holder == Some(caller) {
+            return Err("caller is the holder".to_string());
+        }
+
+        let open =
+            OPEN_DISPUTE_STORE.with_borrow(|r| r.range((caller, 0)..=(caller, u64::MAX)).count());
+        if open >= types::MAX_OPEN_DISPUTES_PER_USER {
+            return Err("too many open disputes".to_string());
+        }
+
+        DISPUTE_STORE.with_borrow_mut(|r| {
+            let id = r.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            let dispute = Dispute {
+                name: ln,
+                claimant: caller,
+                holder,
+                claim,
+                status: types::DisputeStatus::Open,
+                resolution: "".to_string(),
+                created_at: now_ms,
+                resolved_at: 0,
+            };
+            r.insert(id, dispute.clone());
+            OPEN_DISPUTE_STORE.with_borrow_mut(|r| r.insert((caller, id), ()));
+            Ok(dispute.into_info(id))
+        })
+    }
+
+    pub fn check_resolve(id: u64) -> Result<(), String> {
+        match DISPUTE_STORE.with_borrow(|r| r.get(&id)) {
+            None => Err("dispute not found".to_string()),
+            Some(v) if v.status != types::DisputeStatus::Open => {
+                Err("dispute already resolved".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn resolve(
+        id: u64,
+        status: types::DisputeStatus,
+        resolution: String,
+        now_ms: u64,
+    ) -> Result<types::NameDispute, String> {
+        check_resolve(id)?;
+        DISPUTE_STORE.with_borrow_mut(|r| {
+            let mut v = r.get(&id).ok_or_else(|| "dispute not found".to_string())?;
+            v.status = status;
+            v.resolution = resolution;
+            v.resolved_at = now_ms;
+            r.insert(id, v.clone());
+            OPEN_DISPUTE_STORE.with_borrow_mut(|r| r.remove(&(v.claimant, id)));
+            Ok(v.into_info(id))
+        })
+    }
+
+    // list_disputes returns disputes in descending id order, starting below `prev`.
+    pub fn list_disputes(
+        name: Option<String>,
+        prev: Option<u64>,
+        take: usize,
+    ) -> Vec<types::NameDispute> {
+        let name = name.map(|n| n.to_lowercase());
+        DISPUTE_STORE.with_borrow(|r| {
+            r.range(..prev.unwrap_or(u64::MAX))
+                .rev()
+                .filter(|e| name.as_ref().is_none_or(|n| &e.value().name == n))
+                .take(take)
+                .map(|e| e.value().into_info(*e.key()))
+                .collect()
+        })
+    }
+}
+
+pub mod payout {
+    use super::*;
+    use crate::token_payout;
+    use icrc_ledger_types::icrc1::account::Account;
+
+    const MAX_PAYOUTS_PER_ROUND: usize = 10;
+    // the ledger rejects transactions created more than 24 hours ago
+    pub(super) const DEDUP_WINDOW_NS: u64 = 20 * 3600 * 1_000_000_000;
+
+    // pay transfers the amount to the recipient. The payout is saved before the transfer and
+    // retried by a timer until the ledger accepts it, so a failed transfer never loses tokens.
+    // Returns the ledger block index if it is paid now.
+    pub async fn pay(
+        to: Principal,
+        amount: u64,
+        memo: String,
+        kind: Option<types::TreasuryKind>,
+        now_ms: u64,
+    ) -> Option<Nat> {
+        if amount == 0 {
+            return None;
+        }
+        let id = PAYOUT_STORE.with_borrow_mut(|r| {
+            let id = r.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            r.insert(
+                id,
+                Payout {
+                    to,
+                    amount,
+                    memo,
+                    // payouts created in the same call get distinct times
+                    created_at_time: ic_cdk::api::time() + id % 1000,
+                    attempts: 0,
+                    error: None,
+                    created_at: now_ms,
+                    updated_at: now_ms,
+                    kind,
+                    uncertain: false,
+                },
+            );
+            id
+        });
+        match run(id, now_ms).await {
+            Ok(blk) => Some(blk),
+            Err(err) => {
+                ic_cdk::api::debug_print(format!("payout {} failed: {}", id, err));
+                None
+            }
+        }
+    }
+
+    // pay_escrow returns escrowed tokens, the recipient gets the amount minus the ledger fee.
+    // An amount that does not cover the ledger fee is released to the treasury as `dust`.
+    pub async fn pay_escrow(
+        to: Principal,
+        amount: u64,
+        memo: String,
+        kind: types::TreasuryKind,
+        dust: types::TreasuryKind,
+        now_ms: u64,
+    ) {
+        if amount <= types::TOKEN_FEE {
+            treasury::release(dust, to, amount, memo, now_ms);
+            return;
+        }
+        pay(to, amount - types::TOKEN_FEE, memo, Some(kind), now_ms).await;
+    }
+
+    // record records a paid payout in the treasury ledger. The amount includes the ledger
+    // fee, so that the entries add up to the balance of the canister.
+    pub(super) fn record(p: Payout, block: Option<Nat>, now_ms: u64) {
+        if let Some(kind) = p.kind {
+            treasury::record(
+                kind,
+                p.to,
+                p.amount + types::TOKEN_FEE,
+                block,
+                p.memo,
+                now_ms,
+            );
+        }
+    }
+
+    // run transfers a queued payout, the payout is removed and recorded in the treasury ledger
+    // when done. Concurrent runs of the same payout are deduplicated by the ledger.
+    async fn run(id: u64, now_ms: u64) -> Result<Nat, String> {
+        let mut p = PAYOUT_STORE
+            .with_borrow(|r| r.get(&id))
+            .ok_or_else(|| "payout not found".to_string())?;
+        let now_ns = ic_cdk::api::time();
+        if p.needs_review(now_ns) {
+            return Err("payout needs manual review".to_string());
+        }
+        // every earlier attempt was rejected by the ledger, it is safe to reissue the payout
+        if now_ns.saturating_sub(p.created_at_time) > DEDUP_WINDOW_NS {
+            p.created_at_time = now_ns;
+        }
+
+        let res = token_payout(
+            Account {
+                owner: p.to,
+                subaccount: None,
+            },
+            p.amount.into(),
+            p.memo.clone(),
+            p.created_at_time,
+        )
+        .await;
+        match res {
+            Ok(ref blk) => {
+                // a concurrent run may have paid and recorded it already
+                if PAYOUT_STORE.with_borrow_mut(|r| r.remove(&id)).is_some() {
+                    record(p, Some(blk.clone()), now_ms);
+                }
+            }
+            Err((rejected, ref err)) => {
+                // a rejection with the same created_at_time also proves that no earlier
+                // attempt was executed, the ledger would report it as a duplicate.
+                p.uncertain = !rejected;
+                p.attempts += 1;
+                p.error = Some(err.clone());
+                p.updated_at = now_ms;
+                PAYOUT_STORE.with_borrow_mut(|r| {
+                    if r.contains_key(&id) {
+                        r.insert(id, p);
+                    }
+                });
+            }
+        }
+        res.map_err(|(_, err)| err)
+    }
+
+    // resolve settles a payout that needs manual review after its transfer was looked up in
+    // the ledger: a paid payout is removed, otherwise it is reissued.
+    pub async fn resolve(id: u64, paid: bool, now_ms: u64) -> Result<(), String> {
+        let mut p = PAYOUT_STORE
+            .with_borrow(|r| r.get(&id))
+            .ok_or_else(|| "payout not found".to_string())?;
+        if !p.needs_review(ic_cdk::api::time()) {
+            return Err("payout does not need review".to_string());
+        }
+
+        if paid {
+            PAYOUT_STORE.with_borrow_mut(|r| r.remove(&id));
+            record(p, None, now_ms);
+            return Ok(());
+        }
+
+        p.uncertain = false;
+        p.created_at_time = ic_cdk::api::time();
+        p.updated_at = now_ms;
+        PAYOUT_STORE.with_borrow_mut(|r| r.insert(id, p));
+        run(id, now_ms).await.map(|_| ())
+    }
+
+    // retry_due retries failed payouts with an exponential backoff.
+    pub async fn retry_due(now_ms: u64) {
+        let now_ns = ic_cdk::api::time();
+        let ids: Vec<u64> = PAYOUT_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| {
+                    let p = e.value();
+                    let backoff = (60_000u64 << p.attempts.min(10)).min(6 * 3600 * 1000);
+                    p.updated_at + backoff <= now_ms && !p.needs_review(now_ns)
+                })
+                .take(MAX_PAYOUTS_PER_ROUND)
+                .map(|e| *e.key())
+                .collect()
+        });
+        for id in ids {
+            if let Err(err) = run(id, now_ms).await {
+                ic_cdk::api::debug_print(format!("payout {} failed: {}", id, err));
+            }
+        }
+    }
+
+    pub fn list(prev: Option<u64>, take: usize) -> Vec<types::PendingPayout> {
+        let now_ns = ic_cdk::api::time();
+        PAYOUT_STORE.with_borrow(|r| {
+            r.range(prev.map(|p| p + 1).unwrap_or(0)..)
+                .take(take)
+                .map(|e| e.value().into_info(*e.key(), now_ns))
+                .collect()
+        })
+    }
+}
+
+pub mod pending {
+    use super::*;
+
+    // idempotent steps of a registration
+    pub const STEP_NAMESPACE: u8 = 1; // create the user's namespace in the COSE canister
+    pub const STEP_PROFILE: u8 = 2; // create the user's profile
+    pub const STEP_PROFILE_NAME: u8 = 4; // sync the display name to the profile
+
+    const MAX_RETRIES_PER_ROUND: usize = 10;
+
+    pub fn step_names(steps: u8) -> Vec<String> {
+        [
+            (STEP_NAMESPACE, "namespace"),
+            (STEP_PROFILE, "profile"),
+            (STEP_PROFILE_NAME, "profile_name"),
+        ]
+        .into_iter()
+        .filter(|(step, _)| steps & step != 0)
+        .map(|(_, name)| name.to_string())
+        .collect()
+    }
+
+    pub fn add(op: PendingOp) -> u64 {
+        PENDING_STORE.with_borrow_mut(|r| {
+            let id = r.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            r.insert(id, op);
+            id
+        })
+    }
+
+    pub fn get(id: u64) -> Option<PendingOp> {
+        PENDING_STORE.with_borrow(|r| r.get(&id))
+    }
+
+    pub fn list_of(user: &Principal) -> Vec<types::PendingOperation> {
+        PENDING_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| &e.value().user == user)
+                .map(|e| e.value().into_info(*e.key()))
+                .collect()
+        })
+    }
+
+    pub fn list(prev: Option<u64>, take: usize) -> Vec<types::PendingOperation> {
+        PENDING_STORE.with_borrow(|r| {
+            r.range(prev.map(|p| p + 1).unwrap_or(0)..)
+                .take(take)
+                .map(|e| e.value().into_info(*e.key()))
+                .collect()
+        })
+    }
+
+    // PendingLock marks a pending operation as running, it is released on drop,
+    // also when a callback of the operation traps.
+    struct PendingLock(u64);
+
+    impl PendingLock {
+        fn new(id: u64) -> Result<Self, String> {
+            PENDING_LOCKS.with_borrow_mut(|r| {
+                if !r.insert(id) {
+                    return Err("pending operation is running".to_string());
+                }
+                Ok(PendingLock(id))
+            })
+        }
+    }
+
+    impl Drop for PendingLock {
+        fn drop(&mut self) {
+            PENDING_LOCKS.with_borrow_mut(|r| {
+                r.remove(&self.0);
+            });
+        }
+    }
+
+    // run runs the remaining steps of a pending operation, the operation is removed when done.
+    pub async fn run(id: u64, now_ms: u64) -> Result<(), String> {
+        let mut op = get(id).ok_or_else(|| "pending operation not found".to_string())?;
+        let _lock = PendingLock::new(id)?;
+
+        let res = run_steps(&mut op).await;
+        op.attempts += 1;
+        op.updated_at = now_ms;
+        PENDING_STORE.with_borrow_mut(|r| match res {
+            Ok(_) => {
+                r.remove(&id);
+            }
+            Err(ref err) => {
+                op.error = Some(err.clone());
+                r.insert(id, op);
+            }
+        });
+        res
+    }
+
+    async fn run_steps(op: &mut PendingOp) -> Result<(), String> {
+        if op.steps & STEP_NAMESPACE != 0 {
+            // the user's namespace maybe exists
+            let res: Result<types::NamespaceInfo, String> = call(
+                op.cose_canister,
+                "admin_create_namespace",
+                (CreateNamespaceInput {
+                    name: op.user.to_text().replace("-", "_"),
+                    visibility: 0,
+                    desc: Some(op.desc.clone()),
+                    max_payload_size: Some(types::COSE_NAMESPACE_PAYLOAD_SIZE),
+                    managers: BTreeSet::from([ic_cdk::api::canister_self()]),
+                    auditors: BTreeSet::from([op.user]),
+                    users: BTreeSet::from([op.user]),
+                    session_expires_in_ms: None,
+                },),
+                0,
+            )
+            .await?;
+            match res {
+                Ok(_) => {}
+                Err(err) if err.contains("already exists") => {}
+                Err(err) => return Err(err),
+            }
+            op.steps &= !STEP_NAMESPACE;
+        }
+
+        if op.steps & (STEP_PROFILE | STEP_PROFILE_NAME) == 0 {
+            return Ok(());
+        }
+        let info = USER_STORE
+            .with_borrow(|r| r.get(&op.user))
+            .map(|u| u.into_info(op.user))
+            .ok_or_else(|| "user not found".to_string())?;
+        if op.steps & STEP_PROFILE != 0 {
+            let res: Result<(), String> = call(
+                info.profile_canister,
+                "admin_upsert_profile",
+                (op.user, None::<(Principal, u64)>),
+                0,
+            )
+            .await?;
+            res?;
+            op.steps &= !STEP_PROFILE;
+        }
+        if op.steps & STEP_PROFILE_NAME != 0 {
+            user::sync_profile_name(&info).await?;
+            op.steps &= !STEP_PROFILE_NAME;
+        }
+        Ok(())
+    }
+
+    // retry_due retries failed operations with an exponential backoff.
+    pub async fn retry_due(now_ms: u64) {
+        let ids: Vec<u64> = PENDING_STORE.with_borrow(|r| {
+            r.iter()
+                .filter(|e| {
+                    let op = e.value();
+                    let backoff = (60_000u64 << op.attempts.min(10)).min(6 * 3600 * 1000);
+                    op.attempts < types::MAX_PENDING_ATTEMPTS && op.updated_at + backoff <= now_ms
+                })
+                .take(MAX_RETRIES_PER_ROUND)
+                .map(|e| *e.key())
+                .collect()
+        });
+        for id in ids {
+            if let Err(err) = run(id, now_ms).await {
+                ic_cdk::api::debug_print(format!("pending operation {} failed: {}", id, err));
+            }
+        }
+    }
+}
+
+pub mod signing {
+    use super::*;
+    use crate::schnorr::sign_with_schnorr;
+    use ic_cose_types::to_cbor_bytes;
+    use lib_panda::NameAttestation;
+
+    const RATE_WINDOW_MS: u64 = 3600 * 1000;
+
+    // check_rate counts a signature of the user, and of all users, in the current window.
+    // The counters are kept in the state so they survive upgrades.
+    fn check_rate(user: &Principal, cfg: &types::SigningConfig, now_ms: u64) -> Result<(), String> {
+        state::with_mut(|s| {
+            s.sign_rates
+                .retain(|_, (start, _)| *start + RATE_WINDOW_MS > now_ms);
+            if s.sign_rate_total.0 + RATE_WINDOW_MS <= now_ms {
+                s.sign_rate_total = (now_ms, 0);
+            }
+            if s.sign_rate_total.1 >= cfg.max_total_per_hour {
+                return Err("too many signatures, try again later".to_string());
+            }
+            let (_, count) = s.sign_rates.entry(*user).or_insert((now_ms, 0));
+            if *count >= cfg.max_per_hour {
+                return Err("too many signatures, try again later".to_string());
+            }
+            *count += 1;
+            s.sign_rate_total.1 += 1;
+            Ok(())
+        })
+    }
+
+    // uncount_rate gives back the quota of a signature that failed.
+    fn uncount_rate(user: &Principal) {
+        state::with_mut(|s| {
+            if let Some((_, count)) = s.sign_rates.get_mut(user) {
+                *count = count.saturating_sub(1);
+            }
+            s.sign_rate_total.1 = s.sign_rate_total.1.saturating_sub(1);
+        });
+    }
+
+    // sign signs the message with the user's derived Ed25519 key.
+    pub async fn sign(caller: Principal, message: Vec<u8>, now_ms: u64) -> Result<ByteBuf, String> {
+        let path = state::with(|s| state::user_derivation_path(s, &caller));
+        sign_paid(caller, path, message, now_ms).await
+    }
+
+    // attestation_public_key returns the derived Ed25519 public key that signs attestations.
+    pub fn attestation_public_key() -> Result<ByteBuf, String> {
+        state::with(|s| {
+            let pk = s
+                .ed25519_public_key
+                .as_ref()
+                .ok_or("no schnorr ed25519 public key")?;
+            let pk = derive_25519_public_key(pk, attestation_path())?;
+            Ok(pk.public_key)
+        })
+    }
+
+    fn attestation_path() -> Vec<Vec<u8>> {
+        vec![b"ICPanda_Attestation".to_vec()]
+    }
+
+    // attest signs a NameAttestation of the current owner of the name,
+    // it can be verified offline with `lib_panda::verify_name_attestation`.
+    // A subname expires with its parent name.
+    pub async fn attest(caller: Principal, name: String, now_ms: u64) -> Result<ByteBuf, String> {
+        let ln = name.to_lowercase();
+        let (owner, parent) = match ln.split_once('.') {
+            Some((_, parent)) => (subname::get(&ln), parent.to_string()),
+            None => (NAME_STORE.with_borrow(|r| r.get(&ln)), ln.clone()),
+        };
+        let owner = owner.ok_or_else(|| "username not found".to_string())?;
+        let expires_at = NAME_EXPIRY_STORE.with_borrow(|r| r.get(&parent));
+        if expires_at.is_some_and(|t| t < now_ms) {
+            return Err("username expired".to_string());
+        }
+
+        let att = NameAttestation {
+            username: ln,
+            owner,
+            registry: ic_cdk::api::canister_self(),
+            issued_at: now_ms,
+            expires_at,
+        };
+        let data = to_cbor_bytes(&att);
+        let sig = sign_paid(caller, attestation_path(), data.clone(), now_ms).await?;
+        // the same encoding as lib_panda::Ed25519Message
+        Ok(ByteBuf::from(to_cbor_bytes(&[ByteBuf::from(data), sig])))
+    }
+
+    // sign_paid signs the message with the derived key under the caller's rate limit,
+    // the fee is refunded if the signing fails.
+    async fn sign_paid(
+        caller: Principal,
+        path: Vec<Vec<u8>>,
+        message: Vec<u8>,
+        now_ms: u64,
+    ) -> Result<ByteBuf, String> {
+        let (key_name, cfg) = state::with(|s| {
+            if s.schnorr_key_name.is_empty() || s.ed25519_public_key.is_none() {
+                return Err("no schnorr ed25519 public key".to_string());
+            }
+            Ok((s.schnorr_key_name.clone(), s.signing.clone()))
+        })?;
+        check_rate(&caller, &cfg, now_ms)?;
+
+        let fee = cfg.fee.saturating_sub(types::TOKEN_FEE);
+        if fee > 0 {
+            match token_transfer_from(caller, fee.into(), "SG".to_string()).await {
+                Ok(blk) => treasury::record(
+                    types::TreasuryKind::SigningFee,
+                    caller,
+                    fee,
+                    Some(blk),
+                    "SG".to_string(),
+                    now_ms,
+                ),
+                Err(err) => {
+                    uncount_rate(&caller);
+                    return Err(err);
+                }
+            }
+        }
+
+        match sign_with_schnorr(key_name, SchnorrAlgorithm::Ed25519, path, message).await {
+            Ok(signature) => Ok(signature),
+            Err(err) => {
+                uncount_rate(&caller);
+                // failed refunds are retried by the payout queue
+                payout::pay(
+                    caller,
+                    fee.saturating_sub(types::TOKEN_FEE),
+                    "RSG".to_string(),
+                    Some(types::TreasuryKind::SigningRefund),
+                    now_ms,
+                )
+                .await;
+                Err(err)
+            }
+        }
     }
+}
 
-    pub async fn update_my_ecdh(
-        caller: Principal,
-        ecdh_pub: ByteArray<32>,
-        encrypted_ecdh: ByteBuf,
-    ) -> Result<(), String> {
-        let (cose_canister, profile_canister) = USER_STORE
-            .with_borrow(|r| {
-                r.get(&caller)
-                    .map(|u| (u.cose_canister, u.profile_canister))
+pub mod provision {
+    use super::*;
+    use ic_cdk::management_canister as mgt;
+    use ic_cose_types::format_error;
+
+    pub fn wasm_key(kind: types::CanisterKind) -> Result<String, String> {
+        match kind {
+            types::CanisterKind::Channel => Ok("channel".to_string()),
+            types::CanisterKind::Profile => Ok("profile".to_string()),
+            types::CanisterKind::Cose => Ok("cose".to_string()),
+        }
+    }
+
+    // pick_channel_canister returns the open channel canister with the least load.
+    // The cached load of the canister is bumped, so the channels created before the next
+    // refresh of the loads are spread over the canisters.
+    pub fn pick_channel_canister(s: &mut State) -> Option<Principal> {
+        let canister = s
+            .channel_canisters
+            .iter()
+            .min_by_key(|c| {
+                s.canister_loads
+                    .get(*c)
+                    .map(|l| (l.items_total, l.messages_total))
+                    .unwrap_or_default()
             })
-            .ok_or_else(|| "user not found".to_string())?;
-        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
-        let mut sp = SettingPath {
-            ns: caller.to_text().replace("-", "_"),
-            user_owned: false,
-            subject: Some(caller),
-            key: b"StaticECDH".to_vec().into(),
-            version: 0,
+            .cloned()?;
+        if let Some(l) = s.canister_loads.get_mut(&canister) {
+            l.items_total += 1;
+        }
+        Some(canister)
+    }
+
+    pub fn set_wasm(kind: types::CanisterKind, wasm: Vec<u8>) -> Result<(), String> {
+        let key = wasm_key(kind)?;
+        upgrade::replace_wasm(&key, wasm);
+        Ok(())
+    }
+
+    pub fn wasm_hash(kind: types::CanisterKind) -> Option<ByteArray<32>> {
+        let key = wasm_key(kind).ok()?;
+        WASM_STORE.with_borrow(|r| r.get(&key).map(|w| lib_panda::sha256(&w).into()))
+    }
+
+    // run refreshes the loads of channel and profile canisters, matures full channel canisters
+    // and creates new canisters when capacity runs low.
+    pub async fn run(now_ms: u64) {
+        let Some(_guard) = FlagGuard::new(&PROVISIONING) else {
+            return;
         };
-        let res: Result<SettingInfo, String> =
-            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
-        let res = match res {
-            Ok(info) => {
-                sp.version = info.version;
-                let res: Result<UpdateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_update_payload",
-                    (
-                        sp,
-                        UpdateSettingPayloadInput {
-                            payload: None,
-                            status: None,
-                            deprecate_current: None,
-                            dek: Some(encrypted_ecdh),
+        refresh_loads(now_ms).await;
+        if let Err(err) = balance().await {
+            ic_cdk::api::debug_print(format!("failed to provision canisters: {}", err));
+        }
+    }
+
+    async fn refresh_loads(now_ms: u64) {
+        let (channels, profiles) =
+            state::with(|s| (s.channel_canisters.clone(), s.profile_canisters.clone()));
+        for canister in channels {
+            let res: Result<Result<types::ChannelCanisterState, String>, String> =
+                call(canister, "get_state", (), 0).await;
+            match res {
+                Ok(Ok(st)) => state::with_mut(|s| {
+                    s.canister_loads.insert(
+                        canister,
+                        types::CanisterLoad {
+                            items_total: st.channels_total,
+                            messages_total: st.messages_total,
+                            stable_memory_size: st.stable_memory_size.unwrap_or_default(),
+                            updated_at: now_ms,
                         },
-                    ),
-                    0,
-                )
-                .await?;
-                res.map(|_| ())
+                    );
+                }),
+                Ok(Err(err)) | Err(err) => ic_cdk::api::debug_print(format!(
+                    "failed to get state of {}: {}",
+                    canister, err
+                )),
             }
-            Err(_) => {
-                let res: Result<CreateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_create",
-                    (
-                        sp,
-                        CreateSettingInput {
-                            payload: None,
-                            desc: None,
-                            status: None,
-                            tags: None,
-                            dek: Some(encrypted_ecdh),
+        }
+        for canister in profiles {
+            let res: Result<Result<types::ProfileCanisterState, String>, String> =
+                call(canister, "get_state", (), 0).await;
+            match res {
+                Ok(Ok(st)) => state::with_mut(|s| {
+                    s.canister_loads.insert(
+                        canister,
+                        types::CanisterLoad {
+                            items_total: st.profiles_total,
+                            messages_total: 0,
+                            stable_memory_size: st.stable_memory_size.unwrap_or_default(),
+                            updated_at: now_ms,
                         },
-                    ),
-                    0,
-                )
-                .await?;
-                res.map(|_| ())
+                    );
+                }),
+                Ok(Err(err)) | Err(err) => ic_cdk::api::debug_print(format!(
+                    "failed to get state of {}: {}",
+                    canister, err
+                )),
             }
-        };
+        }
+    }
 
-        if res.is_ok() {
-            let _: Result<(), String> = call(
-                profile_canister,
-                "admin_update_profile_ecdh_pub",
-                (caller, ecdh_pub),
-                0,
-            )
-            .await?;
+    async fn balance() -> Result<(), String> {
+        // resume the canister that failed to be set up
+        if let Some((kind, _)) = state::with(|s| s.provisioning_canister) {
+            create_canister(kind).await?;
+        }
+
+        let (open, profile_full, cfg) = state::with(|s| {
+            let cfg = &s.provisioning;
+            let open = s
+                .channel_canisters
+                .iter()
+                .filter(|c| {
+                    !s.canister_loads
+                        .get(*c)
+                        .is_some_and(|l| cfg.is_channel_full(l))
+                })
+                .count();
+            let profile_full = s
+                .profile_canisters
+                .last()
+                .and_then(|c| s.canister_loads.get(c))
+                .is_some_and(|l| cfg.is_profile_full(l));
+            (open, profile_full, cfg.clone())
+        });
+
+        if cfg.min_open_channel_canisters > 0 {
+            for _ in open..cfg.min_open_channel_canisters as usize {
+                create_canister(types::CanisterKind::Channel).await?;
+            }
+            if profile_full {
+                create_canister(types::CanisterKind::Profile).await?;
+            }
+        }
+
+        // full channel canisters only serve existing channels,
+        // the last open one is kept until a new one is added
+        state::with_mut(|s| {
+            let full: Vec<Principal> = s
+                .channel_canisters
+                .iter()
+                .filter(|c| {
+                    s.canister_loads
+                        .get(*c)
+                        .is_some_and(|l| s.provisioning.is_channel_full(l))
+                })
+                .cloned()
+                .collect();
+            for canister in full {
+                if s.channel_canisters.len() <= 1 {
+                    break;
+                }
+                s.channel_canisters.retain(|c| c != &canister);
+                s.matured_channel_canisters.insert(canister);
+            }
+        });
+        Ok(())
+    }
+
+    // install installs the wasm module, through the chunk store of the canister when it is
+    // too large for a single message.
+    pub async fn install(
+        canister: Principal,
+        mode: mgt::CanisterInstallMode,
+        wasm: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<(), String> {
+        if wasm.len() <= types::MAX_WASM_CHUNK_SIZE {
+            return mgt::install_code(&mgt::InstallCodeArgs {
+                mode,
+                canister_id: canister,
+                wasm_module: wasm,
+                arg,
+            })
+            .await
+            .map_err(format_error);
+        }
+
+        mgt::clear_chunk_store(&mgt::ClearChunkStoreArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(format_error)?;
+        let mut chunk_hashes_list = Vec::new();
+        for chunk in wasm.chunks(types::MAX_WASM_CHUNK_SIZE) {
+            let hash = mgt::upload_chunk(&mgt::UploadChunkArgs {
+                canister_id: canister,
+                chunk: chunk.to_vec(),
+            })
+            .await
+            .map_err(format_error)?;
+            chunk_hashes_list.push(hash);
         }
+        let res = mgt::install_chunked_code(&mgt::InstallChunkedCodeArgs {
+            mode,
+            target_canister: canister,
+            store_canister: None,
+            chunk_hashes_list,
+            wasm_module_hash: lib_panda::sha256(&wasm).to_vec(),
+            arg,
+        })
+        .await
+        .map_err(format_error);
+        let _ = mgt::clear_chunk_store(&mgt::ClearChunkStoreArgs {
+            canister_id: canister,
+        })
+        .await;
         res
     }
 
-    pub async fn update_my_kv(caller: Principal, input: UpdateKVInput) -> Result<(), String> {
-        let cose_canister = USER_STORE
-            .with_borrow(|r| r.get(&caller).map(|u| u.cose_canister))
-            .ok_or_else(|| "user not found".to_string())?;
-        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
-        let mut sp = SettingPath {
-            ns: caller.to_text().replace("-", "_"),
-            user_owned: false,
-            subject: Some(caller),
-            key: b"KV".to_vec().into(),
-            version: 0,
-        };
-        let res: Result<SettingInfo, String> =
-            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
+    // new_canister creates an empty canister controlled by this canister and the configured
+    // controllers.
+    async fn new_canister(cfg: &types::ProvisioningConfig) -> Result<Principal, String> {
+        let mut controllers = cfg.controllers.clone();
+        controllers.insert(ic_cdk::api::canister_self());
+        let res = mgt::create_canister_with_extra_cycles(
+            &mgt::CreateCanisterArgs {
+                settings: Some(mgt::CanisterSettings {
+                    controllers: Some(controllers.into_iter().collect()),
+                    ..Default::default()
+                }),
+            },
+            cfg.cycles,
+        )
+        .await
+        .map_err(format_error)?;
+        Ok(res.canister_id)
+    }
 
+    // added treats an "already added" error as done, so the setup can be resumed.
+    fn added(res: Result<(), String>) -> Result<(), String> {
         match res {
-            Ok(info) => {
-                sp.version = info.version;
-                let mut payload: BTreeMap<String, ByteBuf> = info
-                    .payload
-                    .map(|p| from_reader(&p[..]).expect("failed to decode payload"))
-                    .unwrap_or_default();
-                if !input.remove_kv.is_empty() {
-                    payload.retain(|k, _| !input.remove_kv.contains(k));
-                }
-                if !input.upsert_kv.is_empty() {
-                    payload.extend(input.upsert_kv);
-                }
+            Err(err) if err.contains("already added") => Ok(()),
+            res => res,
+        }
+    }
 
-                let res: Result<UpdateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_update_payload",
-                    (
-                        sp,
-                        UpdateSettingPayloadInput {
-                            payload: Some(to_cbor_bytes(&payload).into()),
-                            status: None,
-                            deprecate_current: None,
-                            dek: None,
-                        },
-                    ),
+    // register_channels makes every channel canister a manager of every profile canister and
+    // adds the profile canisters to every channel canister. Channels check blocked users on the
+    // profile canisters and fail closed until they are registered, so it is run once after
+    // upgrading channel canisters from a version without profile canisters. It can be resumed.
+    pub async fn register_channels() -> Result<(), String> {
+        let _guard = FlagGuard::new(&PROVISIONING)
+            .ok_or_else(|| "provisioning is in progress".to_string())?;
+        let (channels, profiles) = state::with(|s| {
+            let mut channels = s.channel_canisters.clone();
+            channels.extend(s.matured_channel_canisters.iter().cloned());
+            (channels, s.profile_canisters.clone())
+        });
+        let managers: BTreeSet<Principal> = channels.iter().cloned().collect();
+        for profile in &profiles {
+            let res: Result<(), String> =
+                call(*profile, "admin_add_managers", (managers.clone(),), 0).await?;
+            res.map_err(|err| format!("failed to add managers to {}: {}", profile, err))?;
+        }
+        for channel in channels {
+            for profile in &profiles {
+                let res: Result<(), String> = call(
+                    channel,
+                    "admin_add_canister",
+                    (types::ChannelCanisterKind::Profile, *profile),
                     0,
                 )
                 .await?;
-                res.map(|_| ())
+                added(res).map_err(|err| {
+                    format!(
+                        "failed to add profile canister {} to {}: {}",
+                        profile, channel, err
+                    )
+                })?;
             }
-            Err(_) => {
-                if !input.remove_kv.is_empty() {
-                    Err("cannot remove KV from non-existing setting".to_string())?;
-                }
-                let payload = to_cbor_bytes(&input.upsert_kv);
-                let res: Result<CreateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_create",
-                    (
-                        sp,
-                        CreateSettingInput {
-                            payload: Some(payload.into()),
-                            desc: None,
-                            status: None,
-                            tags: None,
-                            dek: None,
-                        },
-                    ),
-                    0,
+        }
+        Ok(())
+    }
+
+    // create creates a canister on demand, not concurrently with a provisioning round.
+    pub async fn create(kind: types::CanisterKind) -> Result<Principal, String> {
+        let _guard = FlagGuard::new(&PROVISIONING)
+            .ok_or_else(|| "provisioning is in progress".to_string())?;
+        create_canister(kind).await
+    }
+
+    // create_canister creates and installs a channel or profile canister from the stored wasm,
+    // copies the configuration of the latest canister of the kind, and adds it to the state.
+    // The canister id is recorded once created, a failed setup is resumed from it by the next
+    // call instead of creating another canister.
+    async fn create_canister(kind: types::CanisterKind) -> Result<Principal, String> {
+        if kind == types::CanisterKind::Cose {
+            return Err("COSE canisters are not provisioned".to_string());
+        }
+        let key = wasm_key(kind)?;
+        let wasm = WASM_STORE
+            .with_borrow(|r| r.get(&key))
+            .ok_or_else(|| format!("no {} wasm", key))?;
+        let (cfg, template) = state::with(|s| {
+            let template = match kind {
+                types::CanisterKind::Channel => s
+                    .channel_canisters
+                    .last()
+                    .or(s.matured_channel_canisters.last())
+                    .cloned(),
+                _ => s.profile_canisters.last().cloned(),
+            };
+            (s.provisioning.clone(), template)
+        });
+        let template = template.ok_or_else(|| format!("no {} canister to copy from", key))?;
+        let self_id = ic_cdk::api::canister_self();
+
+        let (name, mut managers, oss_cluster, oss_buckets, profiles) = match kind {
+            types::CanisterKind::Channel => {
+                let st: Result<types::ChannelCanisterState, String> =
+                    call(template, "get_state", (), 0).await?;
+                let st = st?;
+                (
+                    st.name,
+                    st.managers,
+                    st.ic_oss_cluster,
+                    st.ic_oss_buckets,
+                    st.profile_canisters,
                 )
-                .await?;
-                res.map(|_| ())
+            }
+            _ => {
+                let st: Result<types::ProfileCanisterState, String> =
+                    call(template, "get_state", (), 0).await?;
+                let st = st?;
+                (
+                    st.name,
+                    st.managers,
+                    st.ic_oss_cluster,
+                    st.ic_oss_buckets,
+                    vec![],
+                )
+            }
+        };
+        managers.insert(self_id);
+
+        let canister = match state::with(|s| s.provisioning_canister) {
+            Some((k, canister)) if k == kind => canister,
+            Some((k, canister)) => {
+                return Err(format!(
+                    "{:?} canister {} is being provisioned",
+                    k, canister
+                ));
+            }
+            None => {
+                let canister = new_canister(&cfg).await?;
+                state::with_mut(|s| s.provisioning_canister = Some((kind, canister)));
+                canister
+            }
+        };
+
+        let status = mgt::canister_status(&mgt::CanisterStatusArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(format_error)?;
+        if status.module_hash.is_none() {
+            let arg = candid::encode_one(Some(types::CanisterChainArgs::Init(
+                types::CanisterInitArgs { name, managers },
+            )))
+            .map_err(format_error)?;
+            install(canister, mgt::CanisterInstallMode::Install, wasm, arg).await?;
+        }
+
+        match kind {
+            types::CanisterKind::Channel => {
+                let mut args: Vec<(types::ChannelCanisterKind, Principal)> = vec![];
+                args.extend(oss_cluster.map(|c| (types::ChannelCanisterKind::OssCluster, c)));
+                args.extend(
+                    oss_buckets
+                        .into_iter()
+                        .map(|c| (types::ChannelCanisterKind::OssBucket, c)),
+                );
+                args.extend(
+                    profiles
+                        .into_iter()
+                        .map(|c| (types::ChannelCanisterKind::Profile, c)),
+                );
+                for arg in args {
+                    let res: Result<(), String> =
+                        call(canister, "admin_add_canister", arg, 0).await?;
+                    added(res)?;
+                }
+                // channel canisters call profile canisters as managers
+                let profiles = state::with(|s| s.profile_canisters.clone());
+                for profile in profiles {
+                    let res: Result<(), String> = call(
+                        profile,
+                        "admin_add_managers",
+                        (BTreeSet::from([canister]),),
+                        0,
+                    )
+                    .await?;
+                    res.map_err(|err| {
+                        format!("failed to add manager {} to {}: {}", canister, profile, err)
+                    })?;
+                }
+                state::with_mut(|s| {
+                    s.channel_canisters.push(canister);
+                    s.provisioning_canister = None;
+                });
+            }
+            _ => {
+                let mut args: Vec<(types::ProfileCanisterKind, Principal)> = vec![];
+                args.extend(oss_cluster.map(|c| (types::ProfileCanisterKind::OssCluster, c)));
+                args.extend(
+                    oss_buckets
+                        .into_iter()
+                        .map(|c| (types::ProfileCanisterKind::OssBucket, c)),
+                );
+                for arg in args {
+                    let res: Result<(), String> =
+                        call(canister, "admin_add_canister", arg, 0).await?;
+                    added(res)?;
+                }
+                let channels = state::with(|s| {
+                    let mut channels = s.channel_canisters.clone();
+                    channels.extend(s.matured_channel_canisters.iter().cloned());
+                    channels
+                });
+                for channel in channels {
+                    let res: Result<(), String> = call(
+                        channel,
+                        "admin_add_canister",
+                        (types::ChannelCanisterKind::Profile, canister),
+                        0,
+                    )
+                    .await?;
+                    added(res).map_err(|err| {
+                        format!(
+                            "failed to add profile canister {} to {}: {}",
+                            canister, channel, err
+                        )
+                    })?;
+                }
+                state::with_mut(|s| {
+                    s.profile_canisters.push(canister);
+                    s.provisioning_canister = None;
+                });
             }
         }
+        Ok(canister)
     }
+}
 
-    pub async fn register_username(
-        caller: Principal,
-        username: String,
-        name: String,
-        now_ms: u64,
-    ) -> Result<UserInfo, String> {
-        let (cose_canister, profile_canister, price) = state::with(|s| {
-            (
-                s.cose_canisters.last().cloned(),
-                s.profile_canisters.last().cloned(),
-                s.price.clone(),
+pub mod upgrade {
+    use super::*;
+    use ic_cdk::management_canister as mgt;
+    use ic_cose_types::{format_error, MILLISECONDS};
+
+    fn prev_key(key: &str) -> String {
+        format!("{}.prev", key)
+    }
+
+    fn wasm_by_hash(kind: types::CanisterKind, hash: &ByteArray<32>) -> Option<Vec<u8>> {
+        let key = provision::wasm_key(kind).ok()?;
+        WASM_STORE.with_borrow(|r| {
+            [r.get(&key), r.get(&prev_key(&key))]
+                .into_iter()
+                .flatten()
+                .find(|w| lib_panda::sha256(w) == **hash)
+        })
+    }
+
+    pub fn wasm_info() -> Vec<types::WasmInfo> {
+        [
+            types::CanisterKind::Cose,
+            types::CanisterKind::Profile,
+            types::CanisterKind::Channel,
+        ]
+        .into_iter()
+        .filter_map(|kind| {
+            let key = provision::wasm_key(kind).ok()?;
+            WASM_STORE.with_borrow(|r| {
+                let wasm = r.get(&key)?;
+                Some(types::WasmInfo {
+                    kind,
+                    hash: lib_panda::sha256(&wasm).into(),
+                    size: wasm.len() as u64,
+                    prev_hash: r.get(&prev_key(&key)).map(|w| lib_panda::sha256(&w).into()),
+                })
+            })
+        })
+        .collect()
+    }
+
+    // begin_upload discards any unfinished upload of the kind and expects a new one.
+    pub fn begin_upload(input: types::WasmUploadInput) -> Result<(), String> {
+        let key = provision::wasm_key(input.kind)?;
+        clear_chunks(input.kind);
+        state::with_mut(|s| {
+            s.wasm_uploads.insert(
+                key,
+                WasmUpload {
+                    hash: input.hash,
+                    size: input.size,
+                    uploaded: 0,
+                    chunks: 0,
+                },
             )
         });
-        let cose_canister = cose_canister.ok_or_else(|| "no COSE canister".to_string())?;
-        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+        Ok(())
+    }
 
-        let ln = username.to_lowercase();
-        let amount = price.get(ln.len()).saturating_sub(types::TOKEN_FEE);
-        if amount == 0 {
-            return Err("invalid username length".to_string());
+    fn clear_chunks(kind: types::CanisterKind) {
+        let k = kind as u8;
+        WASM_CHUNK_STORE.with_borrow_mut(|r| {
+            let indexes: Vec<u32> = r.range((k, 0)..=(k, u32::MAX)).map(|e| e.key().1).collect();
+            for i in indexes {
+                r.remove(&(k, i));
+            }
+        });
+    }
+
+    fn upload(key: &str) -> Result<WasmUpload, String> {
+        state::with(|s| s.wasm_uploads.get(key).cloned())
+            .ok_or_else(|| format!("no {} wasm upload in progress", key))
+    }
+
+    pub fn check_chunk(kind: types::CanisterKind, chunk: &[u8]) -> Result<(), String> {
+        if chunk.is_empty() || chunk.len() > types::MAX_WASM_CHUNK_SIZE {
+            return Err(format!("invalid chunk size: {}", chunk.len()));
+        }
+        let key = provision::wasm_key(kind)?;
+        let upload = upload(&key)?;
+        if upload.uploaded + chunk.len() as u64 > upload.size {
+            return Err(format!("wasm exceeds the expected size {}", upload.size));
         }
+        Ok(())
+    }
 
-        let has_username = USER_STORE.with_borrow(|r| {
-            r.get(&caller)
-                .map(|u| u.username.is_some())
-                .unwrap_or_default()
+    // upload_chunk stores a chunk under the next index and returns the uploaded size.
+    pub fn upload_chunk(kind: types::CanisterKind, chunk: Vec<u8>) -> Result<u64, String> {
+        check_chunk(kind, &chunk)?;
+        let key = provision::wasm_key(kind)?;
+        let mut upload = upload(&key)?;
+        upload.uploaded += chunk.len() as u64;
+        WASM_CHUNK_STORE.with_borrow_mut(|r| r.insert((kind as u8, upload.chunks), chunk));
+        upload.chunks += 1;
+        let uploaded = upload.uploaded;
+        state::with_mut(|s| s.wasm_uploads.insert(key, upload));
+        Ok(uploaded)
+    }
+
+    // replace_wasm makes the wasm the current one of the key, the replaced wasm is kept
+    // for rollback.
+    pub fn replace_wasm(key: &str, wasm: Vec<u8>) {
+        WASM_STORE.with_borrow_mut(|r| {
+            if let Some(prev) = r.insert(key.to_string(), wasm) {
+                r.insert(prev_key(key), prev);
+            }
         });
+    }
 
-        if has_username {
-            return Err("caller already has username".to_string());
-        }
+    // commit_upload verifies the uploaded wasm and makes it the current one of the kind.
+    pub fn commit_upload(kind: types::CanisterKind) -> Result<ByteArray<32>, String> {
+        let (key, hash, wasm) = check_commit(kind)?;
+        replace_wasm(&key, wasm);
+        clear_chunks(kind);
+        state::with_mut(|s| s.wasm_uploads.remove(&key));
+        Ok(hash)
+    }
 
-        NAME_STORE.with_borrow_mut(|r| match r.get(&ln) {
-            Some(_) => Err("username already registered".to_string()),
-            None => {
-                r.insert(ln.clone(), caller);
-                Ok(())
+    // check_commit concatenates the uploaded chunks and verifies the wasm.
+    pub fn check_commit(
+        kind: types::CanisterKind,
+    ) -> Result<(String, ByteArray<32>, Vec<u8>), String> {
+        let key = provision::wasm_key(kind)?;
+        let upload = upload(&key)?;
+        if upload.uploaded != upload.size {
+            return Err(format!(
+                "wasm size mismatch, expected {}, got {}",
+                upload.size, upload.uploaded
+            ));
+        }
+        let mut wasm = Vec::with_capacity(upload.size as usize);
+        WASM_CHUNK_STORE.with_borrow(|r| {
+            for i in 0..upload.chunks {
+                let chunk = r
+                    .get(&(kind as u8, i))
+                    .ok_or_else(|| format!("wasm chunk {} not found", i))?;
+                wasm.extend(chunk);
             }
+            Ok::<(), String>(())
         })?;
+        if wasm.len() as u64 != upload.size {
+            return Err(format!(
+                "wasm size mismatch, expected {}, got {}",
+                upload.size,
+                wasm.len()
+            ));
+        }
+        if lib_panda::sha256(&wasm) != *upload.hash {
+            return Err("wasm hash mismatch".to_string());
+        }
+        types::validate_canister_wasm(&wasm)?;
+        Ok((key, upload.hash, wasm))
+    }
 
-        let blk =
-            match token_transfer_from(caller, amount.into(), format!("RU: {}", username)).await {
-                Err(err) => {
-                    NAME_STORE.with_borrow_mut(|r| {
-                        r.remove(&ln);
-                    });
-                    return Err(err);
-                }
-                Ok(blk) => blk,
-            };
+    pub fn get(id: u64) -> Option<types::FleetUpgrade> {
+        UPGRADE_STORE.with_borrow(|r| r.get(&id).map(|u| u.into_info(id)))
+    }
 
-        state::with_mut(|s| {
-            if ln.len() <= 7 {
-                s.short_usernames.insert(ln.clone());
-            }
-            s.latest_usernames.push_front(username.clone());
-            if s.latest_usernames.len() > 20 {
-                s.latest_usernames.pop_back();
-            }
-            s.incoming_total += amount as u128;
-            let blk = NameBlock {
-                height: s.next_block_height,
-                phash: s.next_block_phash,
-                name: ln,
-                user: caller,
-                from: None,
-                value: amount,
-                timestamp: now_ms,
-            };
-            let blk = to_cbor_bytes(&blk);
-            s.next_block_height += 1;
-            s.next_block_phash = sha3_256(&blk).into();
-            NAME_BLOCKS.with_borrow_mut(|r| {
-                r.append(&blk).expect("failed to append NameBlock");
-            });
-            ic_cdk::api::certified_data_set(s.root_hash().as_slice());
-        });
+    pub fn list(prev: Option<u64>, take: usize) -> Vec<types::FleetUpgrade> {
+        UPGRADE_STORE.with_borrow(|r| {
+            r.range(..prev.unwrap_or(u64::MAX))
+                .rev()
+                .take(take)
+                .map(|e| e.value().into_info(*e.key()))
+                .collect()
+        })
+    }
 
-        // the user's namespace maybe exists, but we don't care about the result
-        let _: Result<types::NamespaceInfo, String> = call(
-            cose_canister,
-            "admin_create_namespace",
-            (CreateNamespaceInput {
-                name: caller.to_text().replace("-", "_"),
-                visibility: 0,
-                desc: Some(format!("name: {}, $PANDA block: {}", username, blk)),
-                max_payload_size: Some(1024),
-                managers: BTreeSet::from([ic_cdk::api::canister_self()]),
-                auditors: BTreeSet::from([caller]),
-                users: BTreeSet::from([caller]),
-                session_expires_in_ms: None,
-            },),
-            0,
-        )
-        .await?;
+    fn running() -> Option<u64> {
+        UPGRADE_STORE.with_borrow(|r| {
+            r.iter()
+                .rev()
+                .find(|e| e.value().status == types::UpgradeStatus::Running)
+                .map(|e| *e.key())
+        })
+    }
 
-        let (new_profile, info) = USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
-            Some(mut user) => {
-                if user.cose_canister.is_none() {
-                    user.cose_canister = Some(cose_canister);
-                }
-                user.username = Some(username);
-                r.insert(caller, user.clone());
-                (false, user.into_info(caller))
-            }
-            None => {
-                let user = User {
-                    name,
-                    image: "".to_string(),
-                    profile_canister,
-                    cose_canister: Some(cose_canister),
-                    username: Some(username),
-                };
-                r.insert(caller, user.clone());
-                (true, user.into_info(caller))
+    fn targets(kind: types::CanisterKind) -> Vec<Principal> {
+        state::with(|s| match kind {
+            types::CanisterKind::Cose => s.cose_canisters.clone(),
+            types::CanisterKind::Profile => s.profile_canisters.clone(),
+            types::CanisterKind::Channel => {
+                let mut canisters = s.channel_canisters.clone();
+                canisters.extend(s.matured_channel_canisters.iter().cloned());
+                canisters
             }
-        });
+        })
+    }
 
-        if new_profile {
-            let _: Result<(), String> = call(
-                info.profile_canister,
-                "admin_upsert_profile",
-                (caller, None::<(Principal, u64)>),
-                0,
-            )
-            .await?;
+    pub fn check_start(input: &types::StartUpgradeInput) -> Result<(), String> {
+        input.validate()?;
+        if let Some(id) = running() {
+            return Err(format!("upgrade {} is running", id));
         }
-        Ok(info)
+        if wasm_by_hash(input.kind, &input.wasm_hash).is_none() {
+            return Err("wasm not found".to_string());
+        }
+        if targets(input.kind).is_empty() {
+            return Err("no canister to upgrade".to_string());
+        }
+        Ok(())
     }
 
-    pub async fn transfer_username(
+    // start records a fleet upgrade, the other uploaded wasm of the kind is recorded for rollback.
+    pub fn start(
         caller: Principal,
-        to: Principal,
+        input: types::StartUpgradeInput,
         now_ms: u64,
-    ) -> Result<(), String> {
-        let (cose_canister, profile_canister) = state::with(|s| {
-            (
-                s.cose_canisters.last().cloned(),
-                s.profile_canisters.last().cloned(),
-            )
-        });
-        let cose_canister = cose_canister.ok_or_else(|| "no COSE canister".to_string())?;
-        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+    ) -> Result<u64, String> {
+        check_start(&input)?;
+        let rollback_wasm_hash = wasm_info()
+            .into_iter()
+            .find(|w| w.kind == input.kind)
+            .and_then(|w| {
+                if w.hash == input.wasm_hash {
+                    w.prev_hash
+                } else {
+                    Some(w.hash)
+                }
+            });
+        let arg = match input.arg {
+            Some(arg) => arg,
+            None => ByteBuf::from(candid::encode_args(()).map_err(format_error)?),
+        };
+        let upgrade = Upgrade {
+            kind: input.kind,
+            wasm_hash: input.wasm_hash,
+            rollback_wasm_hash,
+            batch_size: input.batch_size,
+            arg,
+            status: types::UpgradeStatus::Running,
+            canisters: targets(input.kind)
+                .into_iter()
+                .map(|canister| UpgradeTarget {
+                    canister,
+                    status: types::CanisterUpgradeStatus::Pending,
+                    prev_module_hash: None,
+                    error: None,
+                    updated_at: now_ms,
+                })
+                .collect(),
+            error: None,
+            created_by: caller,
+            created_at: now_ms,
+            updated_at: now_ms,
+        };
+        UPGRADE_STORE.with_borrow_mut(|r| {
+            let id = r.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            r.insert(id, upgrade);
+            Ok(id)
+        })
+    }
 
-        let username = USER_STORE.with_borrow(|r| {
-            r.get(&caller)
-                .ok_or_else(|| "caller not found".to_string())?
-                .username
-                .ok_or_else(|| "caller has no username".to_string())
-        })?;
+    pub fn halt(id: u64, reason: String, now_ms: u64) -> Result<(), String> {
+        UPGRADE_STORE.with_borrow_mut(|r| {
+            let mut upgrade = r
+                .get(&id)
+                .ok_or_else(|| format!("upgrade {} not found", id))?;
+            if upgrade.status != types::UpgradeStatus::Running {
+                return Err(format!("upgrade {} is not running", id));
+            }
+            upgrade.status = types::UpgradeStatus::Halted;
+            upgrade.error = Some(reason);
+            upgrade.updated_at = now_ms;
+            r.insert(id, upgrade);
+            Ok(())
+        })
+    }
 
-        let ln = username.to_lowercase();
-        if get_name_principal(&ln) == caller {
-            return Err("cannot transfer username".to_string());
+    // run upgrades a batch of pending canisters of the running upgrade, and halts on the first
+    // failure. It is called by a timer, one batch per round, so an upgrade can be halted
+    // between batches. The canisters of a batch are upgraded one at a time.
+    pub async fn run() {
+        let Some(_guard) = FlagGuard::new(&UPGRADING) else {
+            return;
+        };
+        if let Some(id) = running() {
+            if let Err(err) = run_batch(id).await {
+                ic_cdk::api::debug_print(format!("failed to run upgrade {}: {}", id, err));
+            }
         }
+    }
 
-        let (new_profile, new_cose) = NAME_STORE.with_borrow_mut(|r| match r.get(&ln) {
-            Some(owner) => {
-                if owner != caller {
-                    Err("username not owned by caller".to_string())
-                } else {
-                    let rt = USER_STORE.with_borrow_mut(|rr| {
-                        let mut new_cose = false;
-                        let mut new_profile = false;
-                        match rr.get(&to) {
-                            Some(mut user) => {
-                                if let Some(username) = user.username {
-                                    Err(format!(
-                                        "{} already has username {}",
-                                        to.to_text(),
-                                        username
-                                    ))?;
-                                }
-                                user.username = Some(username.clone());
-                                if user.cose_canister.is_none() {
-                                    new_cose = true;
-                                    user.cose_canister = Some(cose_canister);
-                                }
-                                rr.insert(to, user);
-                            }
-                            None => {
-                                new_cose = true;
-                                new_profile = true;
-                                rr.insert(
-                                    to,
-                                    User {
-                                        name: username.clone(),
-                                        image: "".to_string(),
-                                        profile_canister,
-                                        cose_canister: Some(cose_canister),
-                                        username: Some(username.clone()),
-                                    },
-                                );
-                            }
-                        }
+    // run_batch returns true if there are more canisters to upgrade.
+    async fn run_batch(id: u64) -> Result<bool, String> {
+        let upgrade = UPGRADE_STORE
+            .with_borrow(|r| r.get(&id))
+            .ok_or_else(|| format!("upgrade {} not found", id))?;
+        if upgrade.status != types::UpgradeStatus::Running {
+            return Ok(false);
+        }
+        let batch: Vec<usize> = upgrade
+            .canisters
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.status == types::CanisterUpgradeStatus::Pending)
+            .map(|(i, _)| i)
+            .take(upgrade.batch_size as usize)
+            .collect();
+        if batch.is_empty() {
+            update(id, |u| u.status = types::UpgradeStatus::Completed);
+            return Ok(false);
+        }
+        let wasm = match wasm_by_hash(upgrade.kind, &upgrade.wasm_hash) {
+            Some(wasm) => wasm,
+            None => {
+                update(id, |u| {
+                    u.status = types::UpgradeStatus::Halted;
+                    u.error = Some("wasm not found".to_string());
+                });
+                return Ok(false);
+            }
+        };
 
-                        if let Some(mut user) = rr.get(&caller) {
-                            user.username = None;
-                            rr.insert(caller, user.clone());
-                        }
-                        Ok::<(bool, bool), String>((new_profile, new_cose))
-                    })?;
-                    r.insert(ln.clone(), to);
-                    Ok(rt)
+        for i in batch {
+            // stop here if the upgrade was halted while waiting for the previous canister
+            if UPGRADE_STORE.with_borrow(|r| r.get(&id)).map(|u| u.status)
+                != Some(types::UpgradeStatus::Running)
+            {
+                return Ok(false);
+            }
+
+            let canister = upgrade.canisters[i].canister;
+            let res = upgrade_canister(canister, wasm.clone(), upgrade.arg.to_vec()).await;
+            let now_ms = ic_cdk::api::time() / MILLISECONDS;
+            match res {
+                Ok(prev_module_hash) => update(id, |u| {
+                    let c = &mut u.canisters[i];
+                    c.status = types::CanisterUpgradeStatus::Upgraded;
+                    c.prev_module_hash = prev_module_hash;
+                    c.updated_at = now_ms;
+                }),
+                Err(err) => {
+                    update(id, |u| {
+                        let c = &mut u.canisters[i];
+                        c.status = types::CanisterUpgradeStatus::Failed;
+                        c.error = Some(err.clone());
+                        c.updated_at = now_ms;
+                        u.status = types::UpgradeStatus::Halted;
+                        u.error = Some(format!("failed to upgrade {}: {}", canister, err));
+                    });
+                    return Ok(false);
                 }
             }
-            None => Err("username not found".to_string()),
-        })?;
+        }
+        Ok(true)
+    }
 
-        state::with_mut(|s| {
-            let blk = NameBlock {
-                height: s.next_block_height,
-                phash: s.next_block_phash,
-                name: ln,
-                user: to,
-                from: Some(caller),
-                value: 0,
-                timestamp: now_ms,
-            };
-            let blk = to_cbor_bytes(&blk);
-            s.next_block_height += 1;
-            s.next_block_phash = sha3_256(&blk).into();
-            NAME_BLOCKS.with_borrow_mut(|r| {
-                r.append(&blk).expect("failed to append NameBlock");
-            });
-            ic_cdk::api::certified_data_set(s.root_hash().as_slice());
+    // upgrade_canister stops the canister, upgrades it and starts it again, so no message is
+    // processed in the middle of the upgrade. It returns the module hash before upgrading.
+    async fn upgrade_canister(
+        canister: Principal,
+        wasm: Vec<u8>,
+        arg: Vec<u8>,
+    ) -> Result<Option<ByteBuf>, String> {
+        let status = mgt::canister_status(&mgt::CanisterStatusArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(format_error)?;
+        mgt::stop_canister(&mgt::StopCanisterArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(format_error)?;
+        let res =
+            provision::install(canister, mgt::CanisterInstallMode::Upgrade(None), wasm, arg).await;
+        // the canister is started again also when the upgrade failed
+        mgt::start_canister(&mgt::StartCanisterArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(format_error)?;
+        res?;
+        Ok(status.module_hash.map(ByteBuf::from))
+    }
+
+    fn update(id: u64, f: impl FnOnce(&mut Upgrade)) {
+        UPGRADE_STORE.with_borrow_mut(|r| {
+            if let Some(mut upgrade) = r.get(&id) {
+                f(&mut upgrade);
+                upgrade.updated_at = ic_cdk::api::time() / MILLISECONDS;
+                r.insert(id, upgrade);
+            }
         });
+    }
+}
 
-        if new_profile {
-            let _: Result<(), String> = call(
-                profile_canister,
-                "admin_upsert_profile",
-                (to, None::<(Principal, u64)>),
-                0,
-            )
-            .await?;
-        }
+pub mod cycles {
+    use super::*;
+    use ic_cdk::management_canister as mgt;
+    use icrc_ledger_types::icrc1::{
+        account::Account,
+        transfer::{Memo, TransferArg, TransferError},
+    };
+    use num_traits::cast::ToPrimitive;
 
-        if new_cose {
-            let _: Result<types::NamespaceInfo, String> = call(
-                cose_canister,
-                "admin_create_namespace",
-                (CreateNamespaceInput {
-                    name: to.to_text().replace("-", "_"),
-                    visibility: 0,
-                    desc: Some(format!("name: {}", username)),
-                    max_payload_size: Some(1024),
-                    managers: BTreeSet::from([ic_cdk::api::canister_self()]),
-                    auditors: BTreeSet::from([to]),
-                    users: BTreeSet::from([to]),
-                    session_expires_in_ms: None,
-                },),
-                0,
-            )
-            .await?;
-        }
+    use crate::{CMC_CANISTER, ICP_LEDGER_CANISTER, MINTER_CANISTER, NAME_IDENTITY_CANISTER};
 
-        Ok(())
-    }
+    const MEMO_TOP_UP_CANISTER: u64 = 0x50555054; // "TPUP"
 
-    pub fn search_username(prefix: String) -> Vec<String> {
+    pub fn metrics() -> Vec<(Principal, CanisterMetrics)> {
         state::with(|s| {
-            if prefix.len() <= 7 {
-                s.short_usernames
-                    .iter()
-                    .filter(|n| n.starts_with(&prefix))
-                    .cloned()
-                    .collect()
-            } else {
-                NAME_STORE.with_borrow(|r| {
-                    if r.contains_key(&prefix) {
-                        vec![prefix]
-                    } else {
-                        vec![]
-                    }
-                })
-            }
+            s.canister_metrics
+                .iter()
+                .map(|(k, v)| (*k, v.clone()))
+                .collect()
         })
     }
 
-    pub fn get_by_username(username: String) -> Result<UserInfo, String> {
-        state::with(|s| {
-            if username.len() <= 7 && !s.short_usernames.contains(&username) {
-                None
-            } else {
-                NAME_STORE.with_borrow(|r| match r.get(&username) {
-                    Some(id) => USER_STORE.with_borrow(|rr| rr.get(&id).map(|u| u.into_info(id))),
-                    None => None,
-                })
+    pub fn list_alerts(prev: Option<u64>, take: usize) -> Vec<types::CyclesAlert> {
+        ALERT_STORE.with_borrow(|r| {
+            r.range(..prev.unwrap_or(u64::MAX))
+                .rev()
+                .take(take)
+                .map(|e| e.value().into_info(*e.key()))
+                .collect()
+        })
+    }
+
+    fn alert(canister: Principal, cycles_balance: Option<u128>, message: String, now_ms: u64) {
+        ic_cdk::api::debug_print(format!("cycles alert for {}: {}", canister, message));
+        ALERT_STORE.with_borrow_mut(|r| {
+            let id = r.last_key_value().map(|(k, _)| k + 1).unwrap_or(1);
+            r.insert(
+                id,
+                Alert {
+                    canister,
+                    cycles_balance,
+                    message,
+                    created_at: now_ms,
+                },
+            );
+            // keep the latest alerts only
+            while r.len() > types::MAX_CYCLES_ALERTS {
+                r.pop_first();
             }
-        })
-        .ok_or_else(|| "user not found".to_string())
+        });
     }
 
-    pub fn get(user: Principal) -> Result<UserInfo, String> {
-        USER_STORE.with_borrow(|r| {
-            r.get(&user)
-                .map(|u| u.into_info(user))
-                .ok_or_else(|| "user not found".to_string())
+    fn targets() -> BTreeSet<Principal> {
+        state::with(|s| {
+            let mut canisters = s.cycles_monitor.canisters.clone();
+            canisters.insert(ic_cdk::api::canister_self());
+            canisters.insert(MINTER_CANISTER);
+            canisters.insert(NAME_IDENTITY_CANISTER);
+            canisters.extend(s.cose_canisters.iter().cloned());
+            canisters.extend(s.profile_canisters.iter().cloned());
+            canisters.extend(s.channel_canisters.iter().cloned());
+            canisters.extend(s.matured_channel_canisters.iter().cloned());
+            canisters
         })
     }
 
-    pub fn has_username(user: &Principal) -> bool {
-        USER_STORE.with_borrow(|r| r.get(user).is_some_and(|u| u.username.is_some()))
+    pub fn self_metrics(now_ms: u64) -> CanisterMetrics {
+        CanisterMetrics {
+            cycles_balance: ic_cdk::api::canister_cycle_balance(),
+            stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+            heap_memory_size: lib_panda::heap_memory_size(),
+            timestamp: now_ms,
+        }
     }
 
-    pub fn batch_get(ids: BTreeSet<Principal>) -> Vec<UserInfo> {
-        USER_STORE.with_borrow(|r| {
-            ids.iter()
-                .filter_map(|id| r.get(id).map(|u| u.into_info(*id)))
-                .collect()
+    // get_metrics asks the canister for its metrics, and falls back to the canister status
+    // for canisters controlled by this canister that do not report metrics.
+    async fn get_metrics(canister: Principal, now_ms: u64) -> Result<CanisterMetrics, String> {
+        if canister == ic_cdk::api::canister_self() {
+            return Ok(self_metrics(now_ms));
+        }
+        let err = match call(canister, "get_canister_metrics", (), 0).await {
+            Ok(metrics) => return Ok(metrics),
+            Err(err) => err,
+        };
+        let status = mgt::canister_status(&mgt::CanisterStatusArgs {
+            canister_id: canister,
+        })
+        .await
+        .map_err(|_| err)?;
+        Ok(CanisterMetrics {
+            cycles_balance: status.cycles.0.to_u128().unwrap_or(u128::MAX),
+            stable_memory_size: status
+                .memory_metrics
+                .stable_memory_size
+                .0
+                .to_u64()
+                .unwrap_or_default(),
+            heap_memory_size: status
+                .memory_metrics
+                .wasm_memory_size
+                .0
+                .to_u64()
+                .unwrap_or_default(),
+            timestamp: now_ms,
         })
     }
 
-    pub fn get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
-        const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
-
-        let next_block_height = state::with(|s| s.next_block_height);
+    // run polls the fleet and tops up canisters below the threshold, an alert is recorded
+    // for each canister that cannot be polled or topped up.
+    pub async fn run(now_ms: u64) {
+        let cfg = state::with(|s| s.cycles_monitor.clone());
+        if cfg.min_cycles == 0 {
+            return;
+        }
+        let Some(_guard) = FlagGuard::new(&MONITORING) else {
+            return;
+        };
 
-        NAME_BLOCKS.with_borrow(|logs| {
-            let logs_len = logs.len();
-            let mut blocks = vec![];
-            for arg in args {
-                let (start, length) = arg
-                    .as_start_and_length()
-                    .unwrap_or_else(|msg| ic_cdk::api::trap(&msg));
+        // retry the notifications of ICP already sent to the CMC
+        let pending = state::with(|s| s.pending_top_ups.clone());
+        for (canister, block_index) in pending {
+            if let Err(err) = notify_top_up(canister, block_index).await {
+                alert(canister, None, err, now_ms);
+            }
+        }
 
-                let max_length = MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
-                if max_length == 0 {
-                    break;
+        for canister in targets() {
+            let metrics = match get_metrics(canister, now_ms).await {
+                Ok(metrics) => metrics,
+                Err(err) => {
+                    alert(
+                        canister,
+                        None,
+                        format!("failed to get metrics: {}", err),
+                        now_ms,
+                    );
+                    continue;
                 }
+            };
+            let balance = metrics.cycles_balance;
+            state::with_mut(|s| s.canister_metrics.insert(canister, metrics));
+            if balance >= cfg.min_cycles {
+                continue;
+            }
+            if let Err(err) = top_up(&cfg, canister).await {
+                alert(canister, Some(balance), err, now_ms);
+            }
+        }
 
-                let length = max_length.min(length).min(logs_len - start);
-                for i in start..start + length {
-                    match logs.get(i) {
-                        None => break,
-                        Some(block) => {
-                            blocks.push(BlockWithId {
-                                id: Nat::from(i),
-                                block: ICRC3GenericBlock::Blob(block.into()),
-                            });
-                        }
-                    }
-                }
+        // drop the metrics of removed canisters
+        let canisters = targets();
+        state::with_mut(|s| s.canister_metrics.retain(|k, _| canisters.contains(k)));
+    }
 
-                if blocks.len() as u64 >= MAX_BLOCKS_PER_RESPONSE {
-                    break;
+    // top_up deposits cycles from this canister's balance above the reserve,
+    // or converts ICP to cycles by the CMC.
+    async fn top_up(cfg: &types::CyclesMonitorConfig, canister: Principal) -> Result<(), String> {
+        let mut errors: Vec<String> = vec![];
+        if canister != ic_cdk::api::canister_self() {
+            let balance = ic_cdk::api::canister_cycle_balance();
+            if balance >= cfg.reserve_cycles.saturating_add(cfg.top_up_cycles) {
+                match mgt::deposit_cycles(
+                    &mgt::DepositCyclesArgs {
+                        canister_id: canister,
+                    },
+                    cfg.top_up_cycles,
+                )
+                .await
+                {
+                    Ok(_) => return Ok(()),
+                    Err(err) => errors.push(format!("failed to deposit cycles: {:?}", err)),
                 }
+            } else {
+                errors.push(format!("insufficient cycles: {}", balance));
             }
+        }
 
-            GetBlocksResult {
-                log_length: Nat::from(next_block_height),
-                blocks,
-                archived_blocks: vec![],
+        if cfg.icp_top_up_e8s > 0 {
+            match cmc_top_up(canister, cfg.icp_top_up_e8s).await {
+                Ok(_) => return Ok(()),
+                Err(err) => errors.push(err),
             }
+        } else {
+            errors.push("ICP top-up is disabled".to_string());
+        }
+        Err(errors.join("; "))
+    }
+
+    // cmc_top_up sends ICP to the CMC and notifies it to mint cycles to the canister.
+    // The block index is kept until the CMC handles the notification, so a failed
+    // notification is retried instead of sending ICP again.
+    async fn cmc_top_up(canister: Principal, amount_e8s: u64) -> Result<Nat, String> {
+        if let Some(block_index) = state::with(|s| s.pending_top_ups.get(&canister).copied()) {
+            return notify_top_up(canister, block_index).await;
+        }
+
+        let mut subaccount = [0u8; 32];
+        let id = canister.as_slice();
+        subaccount[0] = id.len() as u8;
+        subaccount[1..1 + id.len()].copy_from_slice(id);
+
+        let res: Result<Nat, TransferError> = call(
+            ICP_LEDGER_CANISTER,
+            "icrc1_transfer",
+            (TransferArg {
+                from_subaccount: None,
+                to: Account {
+                    owner: CMC_CANISTER,
+                    subaccount: Some(subaccount),
+                },
+                fee: None,
+                created_at_time: None,
+                memo: Some(Memo(ByteBuf::from(
+                    MEMO_TOP_UP_CANISTER.to_le_bytes().to_vec(),
+                ))),
+                amount: amount_e8s.into(),
+            },),
+            0,
+        )
+        .await?;
+        let block_index = res
+            .map_err(|err| format!("failed to transfer ICP, error: {:?}", err))?
+            .0
+            .to_u64()
+            .ok_or_else(|| "invalid block index".to_string())?;
+        state::with_mut(|s| s.pending_top_ups.insert(canister, block_index));
+        notify_top_up(canister, block_index).await
+    }
+
+    // notify_top_up notifies the CMC of the ICP transfer, the pending block index is dropped
+    // once the CMC has minted the cycles or will never do so.
+    async fn notify_top_up(canister: Principal, block_index: u64) -> Result<Nat, String> {
+        let res: Result<Nat, types::NotifyError> = call(
+            CMC_CANISTER,
+            "notify_top_up",
+            (types::NotifyTopUpArg {
+                block_index,
+                canister_id: canister,
+            },),
+            0,
+        )
+        .await?;
+        let done = match &res {
+            Ok(_) => true,
+            Err(types::NotifyError::Refunded { .. })
+            | Err(types::NotifyError::InvalidTransaction(_))
+            | Err(types::NotifyError::TransactionTooOld(_)) => true,
+            Err(types::NotifyError::Processing) | Err(types::NotifyError::Other { .. }) => false,
+        };
+        if done {
+            state::with_mut(|s| s.pending_top_ups.remove(&canister));
+        }
+        res.map_err(|err| {
+            format!(
+                "failed to notify top-up of block {}, error: {:?}",
+                block_index, err
+            )
         })
     }
 }
 
-pub mod channel {
+pub mod treasury {
     use super::*;
-    use crate::MINTER_CANISTER;
-    use ic_message_types::channel::{
-        channel_kek_key, ChannelInfo, ChannelKEKInput, ChannelTopupInput, CreateChannelInput,
-    };
+    use num_traits::cast::ToPrimitive;
 
-    pub async fn create_channel(
-        caller: Principal,
+    // record appends an entry to the treasury ledger and updates the daily
+    // aggregates and the lifetime counters. Entries with their own transfer keep
+    // the $PANDA ledger block index for reconciliation.
+    pub fn record(
+        kind: types::TreasuryKind,
+        account: Principal,
+        amount: u64,
+        block: Option<Nat>,
+        memo: String,
         now_ms: u64,
-        mut input: CreateChannelInput,
-    ) -> Result<ChannelInfo, String> {
-        let (channel_canister, profile_canister, price) = state::with(|s| {
-            let i = if s.channel_canisters.len() > 1 {
-                now_ms % s.channel_canisters.len() as u64
-            } else {
-                0
-            };
-            (
-                s.channel_canisters.get(i as usize).cloned(),
-                s.profile_canisters.last().cloned(),
-                s.price.clone(),
-            )
+    ) {
+        if amount == 0 {
+            return;
+        }
+
+        if !kind.is_escrow() {
+            state::with_mut(|s| {
+                if kind.is_income() {
+                    s.incoming_total += amount as u128;
+                } else {
+                    s.transfer_out_total += amount as u128;
+                }
+            });
+        }
+        TREASURY_LOG.with_borrow_mut(|r| {
+            r.append(&TreasuryRecord {
+                kind,
+                account,
+                amount,
+                block: block.and_then(|b| b.0.to_u64()),
+                memo,
+                timestamp: now_ms,
+            })
+            .expect("failed to append TreasuryRecord");
         });
 
-        let channel_canister = channel_canister.ok_or_else(|| "no channel canister".to_string())?;
-        let profile_canister = profile_canister.ok_or_else(|| "no profile canister".to_string())?;
+        let day = types::treasury_day(now_ms);
+        TREASURY_DAILY_STORE.with_borrow_mut(|r| {
+            let mut daily = r.get(&day).unwrap_or_default();
+            let total = daily.totals.entry(kind).or_default();
+            total.0 += 1;
+            total.1 += amount as u128;
+            r.insert(day, daily);
+        });
+    }
 
-        let amount = price.channel.saturating_sub(types::TOKEN_FEE);
+    // release moves escrowed tokens to the treasury, they leave the escrow as EscrowRelease
+    // and are recorded as income of the given kind.
+    pub fn release(
+        kind: types::TreasuryKind,
+        account: Principal,
+        amount: u64,
+        memo: String,
+        now_ms: u64,
+    ) {
+        record(
+            types::TreasuryKind::EscrowRelease,
+            account,
+            amount,
+            None,
+            memo.clone(),
+            now_ms,
+        );
+        record(kind, account, amount, None, memo, now_ms);
+    }
 
-        let (user_profile_canister, is_new) =
-            USER_STORE.with_borrow_mut(|r| match r.get(&caller) {
-                Some(user) => (user.profile_canister, false),
-                None => {
-                    let user = User {
-                        name: input.name.clone(),
-                        image: "".to_string(),
-                        profile_canister,
-                        cose_canister: None,
-                        username: None,
-                    };
-                    r.insert(caller, user.clone());
-                    (profile_canister, true)
-                }
-            });
+    // list returns the entries before `prev` in reverse order.
+    pub fn list(prev: Option<u64>, take: usize) -> Vec<types::TreasuryEntry> {
+        TREASURY_LOG.with_borrow(|r| {
+            let end = prev.unwrap_or(u64::MAX).min(r.len());
+            let start = end.saturating_sub(take as u64);
+            (start..end)
+                .rev()
+                .filter_map(|i| r.get(i).map(|rec| rec.into_info(i)))
+                .collect()
+        })
+    }
 
-        if is_new {
-            let _: Result<(), String> = call(
-                user_profile_canister,
-                "admin_upsert_profile",
-                (caller, None::<(Principal, u64)>),
-                0,
-            )
-            .await?;
-        }
+    // report sums the daily aggregates of the days in [from_ms, to_ms).
+    pub fn report(from_ms: u64, to_ms: u64) -> Result<types::TreasuryReport, String> {
+        let (from, to) = types::treasury_days(from_ms, to_ms)?;
 
-        if amount > 0 {
-            token_transfer_from(caller, amount.into(), "CC".to_string()).await?;
+        let mut income: u128 = 0;
+        let mut expense: u128 = 0;
+        let mut escrow_in: u128 = 0;
+        let mut escrow_out: u128 = 0;
+        let mut totals: BTreeMap<types::TreasuryKind, types::TreasuryTotal> = BTreeMap::new();
+        let daily: Vec<types::TreasuryDaily> = TREASURY_DAILY_STORE.with_borrow(|r| {
+            r.range(from..to)
+                .map(|entry| {
+                    let day = *entry.key();
+                    let totals_of_day = entry
+                        .value()
+                        .totals
+                        .into_iter()
+                        .map(|(kind, (count, amount))| {
+                            match (kind.is_escrow(), kind.is_inflow()) {
+                                (false, true) => income += amount,
+                                (false, false) => expense += amount,
+                                (true, true) => escrow_in += amount,
+                                (true, false) => escrow_out += amount,
+                            }
+                            let total = totals.entry(kind).or_default();
+                            total.count += count;
+                            total.amount += amount;
+                            (kind, types::TreasuryTotal { count, amount })
+                        })
+                        .collect();
+                    types::TreasuryDaily {
+                        day,
+                        totals: totals_of_day,
+                    }
+                })
+                .collect()
+        });
 
-            state::with_mut(|s| {
-                s.incoming_total += amount as u128;
-            });
-        }
+        let (incoming_total, transfer_out_total) =
+            state::with(|s| (s.incoming_total, s.transfer_out_total));
+        Ok(types::TreasuryReport {
+            from,
+            to,
+            income,
+            expense,
+            escrow_in,
+            escrow_out,
+            totals,
+            daily,
+            incoming_total,
+            transfer_out_total,
+        })
+    }
+}
 
-        input.paid = amount;
-        let miner = input.get_miner();
-        let res: Result<ChannelInfo, String> =
-            call(channel_canister, "admin_create_channel", (input,), 0).await?;
+#[cfg(test)]
+mod test {
+    use super::*;
+    use types::{TreasuryKind, TOKEN_1, TOKEN_FEE};
 
-        // try to mint $DMSG for the miner
-        if res.is_ok() {
-            if let Some(miner) = miner {
-                if user::has_username(&miner) {
-                    let _: Result<bool, String> =
-                        call(MINTER_CANISTER, "try_prepare", (miner, caller), 0).await;
-                }
-            }
+    // paid records a payout of the escrowed amount, as `payout::pay_escrow` does
+    fn paid(kind: TreasuryKind, to: Principal, amount: u64, now_ms: u64) {
+        let p = Payout {
+            to,
+            amount: amount - TOKEN_FEE,
+            memo: "".to_string(),
+            created_at_time: 0,
+            attempts: 0,
+            error: None,
+            created_at: now_ms,
+            updated_at: now_ms,
+            kind: Some(kind),
+            uncertain: false,
+        };
+        payout::record(p, Some(Nat::from(1u64)), now_ms);
+    }
+
+    #[test]
+    fn test_treasury_report() {
+        let now_ms = 1_700_000_000_000;
+        let seller = Principal::from_slice(&[1]);
+        let buyer = Principal::from_slice(&[2]);
+        let bidder = Principal::from_slice(&[3]);
+        let memo = || "".to_string();
+        let blk = || Some(Nat::from(1u64));
+
+        // a buy: the price is escrowed, the fee goes to the treasury, the rest to the seller
+        let price = 100 * TOKEN_1;
+        let fee = price * types::USERNAME_MARKET_FEE_PERCENT / 100;
+        treasury::record(
+            TreasuryKind::MarketPurchase,
+            buyer,
+            price,
+            blk(),
+            memo(),
+            now_ms,
+        );
+        treasury::release(TreasuryKind::MarketFee, buyer, fee, memo(), now_ms);
+        paid(TreasuryKind::MarketPayout, seller, price - fee, now_ms);
+
+        // an offer that is refunded
+        let offer = 10 * TOKEN_1;
+        treasury::record(
+            TreasuryKind::MarketOffer,
+            buyer,
+            offer,
+            blk(),
+            memo(),
+            now_ms,
+        );
+        paid(TreasuryKind::MarketRefund, buyer, offer, now_ms);
+
+        // an auction won with a bid of 40, lost with 30 and forfeited with 20
+        for (deposit, who) in [(50, bidder), (30, buyer), (20, seller)] {
+            let deposit = deposit * TOKEN_1;
+            treasury::record(
+                TreasuryKind::AuctionDeposit,
+                who,
+                deposit,
+                blk(),
+                memo(),
+                now_ms,
+            );
         }
+        treasury::release(
+            TreasuryKind::AuctionForfeit,
+            seller,
+            20 * TOKEN_1,
+            memo(),
+            now_ms,
+        );
+        treasury::record(
+            TreasuryKind::NameRegistration,
+            bidder,
+            40 * TOKEN_1,
+            None,
+            memo(),
+            now_ms,
+        );
+        treasury::record(
+            TreasuryKind::EscrowRelease,
+            bidder,
+            40 * TOKEN_1,
+            None,
+            memo(),
+            now_ms,
+        );
+        paid(TreasuryKind::AuctionRefund, bidder, 10 * TOKEN_1, now_ms);
+        paid(TreasuryKind::AuctionRefund, buyer, 30 * TOKEN_1, now_ms);
 
-        res
+        let report = treasury::report(now_ms, now_ms + 1).unwrap();
+        assert_eq!(report.escrow_in, (price + offer + 100 * TOKEN_1) as u128);
+        assert_eq!(report.escrow_in, report.escrow_out);
+        assert_eq!(report.income, (fee + 60 * TOKEN_1) as u128);
+        assert_eq!(report.expense, 0);
+        assert_eq!(report.daily.len(), 1);
+        assert_eq!(report.totals[&TreasuryKind::EscrowRelease].count, 3);
+        assert_eq!(report.totals[&TreasuryKind::AuctionRefund].count, 2);
+        assert_eq!(
+            report.totals[&TreasuryKind::MarketPayout].amount,
+            (price - fee) as u128
+        );
+        let (incoming_total, transfer_out_total) =
+            state::with(|s| (s.incoming_total, s.transfer_out_total));
+        assert_eq!(incoming_total, report.income);
+        assert_eq!(transfer_out_total, 0);
     }
 
-    pub async fn topup_channel(
-        caller: Principal,
-        mut input: ChannelTopupInput,
-    ) -> Result<ChannelInfo, String> {
-        input.payer = caller;
-        state::with(|s| {
-            if !s.channel_canisters.contains(&input.canister)
-                && !s.matured_channel_canisters.contains(&input.canister)
-            {
-                Err("channel canister not found".to_string())
-            } else {
-                Ok(())
-            }
-        })?;
-        let amount = input.amount.saturating_sub(types::TOKEN_FEE);
-        token_transfer_from(caller, amount.into(), "TC".to_string()).await?;
-        state::with_mut(|s| {
-            s.incoming_total += amount as u128;
+    #[test]
+    fn test_resolver() {
+        let now_ms = 1_700_000_000_000;
+        let registry = Principal::from_slice(&[9]);
+        let caller = Principal::from_slice(&[4]);
+        let ln = "resolver".to_string();
+        NAME_STORE.with_borrow_mut(|r| r.insert(ln.clone(), caller));
+        USER_STORE.with_borrow_mut(|r| {
+            r.insert(
+                caller,
+                User {
+                    name: "Resolver".to_string(),
+                    image: "".to_string(),
+                    profile_canister: Principal::anonymous(),
+                    cose_canister: None,
+                    username: Some("Resolver".to_string()),
+                },
+            )
         });
-        let res: Result<ChannelInfo, String> =
-            call(input.canister, "admin_topup_channel", (input,), 0).await?;
-        res
+
+        let sk = ic_ed25519::PrivateKey::deserialize_raw(&[7u8; 32]).unwrap();
+        let addr = bs58::encode(sk.public_key().serialize_raw()).into_string();
+        resolver::update(
+            caller,
+            types::UpdateResolverInput {
+                upsert: vec![
+                    types::ResolverRecord::Sol(addr.clone()),
+                    types::ResolverRecord::Url("https://panda.fans".to_string()),
+                ],
+                remove: vec![],
+            },
+            now_ms,
+        )
+        .unwrap();
+        assert!(resolver::address_message(&caller, "url", &registry, now_ms).is_err());
+
+        let msg = resolver::address_message(&caller, "sol", &registry, now_ms).unwrap();
+        assert!(msg.message.contains(&addr));
+        assert!(msg.message.contains(&registry.to_text()));
+        assert!(msg.message.contains("Nonce: 0"));
+        assert_eq!(msg.expires_at, now_ms + types::ADDRESS_MESSAGE_TTL_MS);
+        let sig = sk.sign_message(msg.message.as_bytes());
+
+        // bound to the registry and the issued time
+        let other = Principal::from_slice(&[10]);
+        assert!(
+            resolver::verify_address(caller, "sol".into(), &sig, other, now_ms, now_ms).is_err()
+        );
+        assert!(
+            resolver::verify_address(caller, "sol".into(), &sig, registry, now_ms + 1, now_ms)
+                .is_err()
+        );
+        // stale
+        let late = msg.expires_at + 1;
+        assert_eq!(
+            resolver::verify_address(caller, "sol".into(), &sig, registry, now_ms, late),
+            Err("address message expired".to_string())
+        );
+
+        resolver::verify_address(caller, "sol".into(), &sig, registry, now_ms, now_ms + 1000)
+            .unwrap();
+        let resolved = resolver::resolve("Resolver", vec!["sol".to_string()], now_ms).unwrap();
+        assert_eq!(resolved.owner, caller);
+        assert_eq!(resolved.records.len(), 1);
+        assert_eq!(resolved.records[0].verified_at, Some(now_ms + 1000));
+
+        // a signed message can not be replayed
+        assert!(resolver::verify_address(
+            caller,
+            "sol".into(),
+            &sig,
+            registry,
+            now_ms,
+            now_ms + 2000
+        )
+        .is_err());
+        let msg = resolver::address_message(&caller, "sol", &registry, now_ms).unwrap();
+        assert!(msg.message.contains("Nonce: 1"));
+
+        // an expired name is not resolved
+        NAME_EXPIRY_STORE.with_borrow_mut(|r| r.insert(ln.clone(), now_ms + 5000));
+        assert!(resolver::resolve("resolver", vec![], now_ms + 5000).is_ok());
+        assert_eq!(
+            resolver::resolve("resolver", vec![], now_ms + 5001).unwrap_err(),
+            "username expired"
+        );
     }
 
-    pub async fn save_channel_kek(caller: Principal, input: ChannelKEKInput) -> Result<(), String> {
-        let cose_canister = USER_STORE
-            .with_borrow(|r| r.get(&caller).map(|u| u.cose_canister))
-            .ok_or_else(|| "user not found".to_string())?;
-        let cose_canister = cose_canister.ok_or_else(|| "user has no COSE service".to_string())?;
-        let mut sp = SettingPath {
-            ns: caller.to_text().replace("-", "_"),
-            user_owned: false,
-            subject: Some(caller),
-            key: channel_kek_key(&input.canister, input.id),
-            version: 0,
-        };
-        let res: Result<SettingInfo, String> =
-            call(cose_canister, "setting_get_info", (sp.clone(),), 0).await?;
-        let res = match res {
-            Ok(info) => {
-                sp.version = info.version;
-                let res: Result<UpdateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_update_payload",
-                    (
-                        sp,
-                        UpdateSettingPayloadInput {
-                            payload: None,
-                            status: None,
-                            deprecate_current: None,
-                            dek: Some(input.kek),
-                        },
-                    ),
-                    0,
-                )
-                .await?;
-                res.map(|_| ())
-            }
-            Err(_) => {
-                let res: Result<CreateSettingOutput, String> = call(
-                    cose_canister,
-                    "setting_create",
-                    (
-                        sp,
-                        CreateSettingInput {
-                            payload: None,
-                            desc: None,
-                            status: None,
-                            tags: None,
-                            dek: Some(input.kek),
-                        },
-                    ),
-                    0,
-                )
-                .await?;
-                res.map(|_| ())
-            }
+    #[test]
+    fn test_wasm_upload() {
+        let kind = types::CanisterKind::Profile;
+        let wasm: Vec<u8> = b"\0asm"
+            .iter()
+            .copied()
+            .chain((0..2500u32).map(|i| i as u8))
+            .collect();
+        let input = |hash: [u8; 32]| types::WasmUploadInput {
+            kind,
+            hash: hash.into(),
+            size: wasm.len() as u64,
         };
 
-        res.map(|_| ())
+        upgrade::begin_upload(input(lib_panda::sha256(&wasm))).unwrap();
+        assert!(upgrade::check_commit(kind).is_err());
+        let mut uploaded = 0;
+        for chunk in wasm.chunks(1000) {
+            uploaded = upgrade::upload_chunk(kind, chunk.to_vec()).unwrap();
+        }
+        assert_eq!(uploaded, wasm.len() as u64);
+        assert!(upgrade::upload_chunk(kind, vec![0]).is_err());
+        assert_eq!(WASM_CHUNK_STORE.with_borrow(|r| r.len()), 3);
+
+        let hash = upgrade::commit_upload(kind).unwrap();
+        assert_eq!(*hash, lib_panda::sha256(&wasm));
+        assert_eq!(
+            WASM_STORE.with_borrow(|r| r.get(&"profile".to_string())),
+            Some(wasm.clone())
+        );
+        assert_eq!(WASM_CHUNK_STORE.with_borrow(|r| r.len()), 0);
+        assert!(upgrade::commit_upload(kind).is_err());
+
+        // a new upload discards the unfinished one, a wrong hash is rejected
+        upgrade::begin_upload(input([0u8; 32])).unwrap();
+        upgrade::upload_chunk(kind, wasm[..1000].to_vec()).unwrap();
+        upgrade::begin_upload(input([0u8; 32])).unwrap();
+        assert_eq!(WASM_CHUNK_STORE.with_borrow(|r| r.len()), 0);
+        upgrade::upload_chunk(kind, wasm.clone()).unwrap();
+        assert_eq!(
+            upgrade::commit_upload(kind).unwrap_err(),
+            "wasm hash mismatch"
+        );
     }
 }
@@ -1,13 +1,54 @@
 use candid::{CandidType, Principal};
+use icrc_ledger_types::icrc1::account::Account;
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteArray;
+use serde_bytes::{ByteArray, ByteBuf};
 use std::collections::{BTreeMap, BTreeSet};
 
 pub const TOKEN_1: u64 = 100_000_000;
 pub const TOKEN_FEE: u64 = 10_000; // 0.0001 token
 pub const MIN_NAME_PRICE: u64 = TOKEN_1;
+pub const MAX_RESERVED_NAMES_PER_CALL: usize = 1000;
+pub const MAX_RESERVED_REASON_SIZE: usize = 256;
+pub const MAX_DISPUTE_CLAIM_SIZE: usize = 2048;
+pub const MAX_OPEN_DISPUTES_PER_USER: usize = 3;
+pub const MAX_PENDING_ATTEMPTS: u32 = 30; // failed operations stay for manual retry after this
+pub const MAX_SIGN_MESSAGE_SIZE: usize = 4096;
+pub const MAX_KV_NAMESPACE_SIZE: usize = 32;
+pub const MAX_KV_KEY_SIZE: usize = 64;
+pub const MAX_KV_PAYLOAD_SIZE: u64 = 32 * 1024; // encoded KVs of a namespace
+pub const COSE_NAMESPACE_PAYLOAD_SIZE: u64 = 1024; // payload limit of a user's COSE namespace
+pub const MIN_PROVISION_CYCLES: u128 = 1_000_000_000_000; // 1T cycles
+pub const MAX_WASM_SIZE: usize = 10 * 1024 * 1024;
+pub const MAX_WASM_CHUNK_SIZE: usize = 1024 * 1024;
+pub const MAX_UPGRADE_BATCH_SIZE: u32 = 20;
+pub const MAX_TOP_UP_CYCLES: u128 = 100_000_000_000_000; // 100T cycles
+pub const MAX_MONITORED_CANISTERS: usize = 50;
+pub const MAX_CYCLES_ALERTS: u64 = 1000;
+pub const PRICE_CURRENCIES: [&str; 2] = ["USD", "ICP"];
+pub const MIN_QUOTE_TTL_MS: u64 = 5 * 60 * 1000; // 5 minutes
+pub const MAX_QUOTE_TTL_MS: u64 = 24 * 3600 * 1000; // one day
 pub const MAX_DISPLAY_NAME_SIZE: usize = 32;
 pub const MAX_USER_NAME_SIZE: usize = 20;
+pub const USERNAME_MARKET_FEE_PERCENT: u64 = 5; // taken from the sale for the DAO
+pub const MAX_USERNAME_OFFERS: usize = 100;
+pub const NAME_TERM_MS: u64 = 365 * 24 * 3600 * 1000; // one year
+pub const NAME_GRACE_MS: u64 = 30 * 24 * 3600 * 1000; // 30 days to renew after expiry
+pub const MAX_RENEWAL_YEARS: u64 = 10;
+pub const MAX_EXPIRED_NAMES_PER_SWEEP: usize = 100;
+pub const MAX_AUCTION_BIDS: usize = 200;
+pub const MAX_SUBNAMES_PER_NAME: usize = 1000;
+pub const ARCHIVE_TRIGGER_BLOCKS: u64 = 20_000; // archive when the local log exceeds this
+pub const ARCHIVE_KEEP_BLOCKS: u64 = 10_000; // latest blocks kept in the local log
+pub const ARCHIVE_BATCH_BLOCKS: u64 = 500;
+pub const ARCHIVE_MAX_BLOCKS: u64 = 50_000_000; // blocks per archive canister
+pub const MAX_RESOLVER_TEXT_RECORDS: usize = 20;
+pub const TREASURY_DAY_MS: u64 = 24 * 3600 * 1000; // daily aggregates are bucketed by UTC day
+pub const MAX_TREASURY_REPORT_DAYS: u64 = 366;
+pub const MAX_RESOLVER_KEY_SIZE: usize = 32;
+pub const MAX_RESOLVER_VALUE_SIZE: usize = 1024;
+pub const ADDRESS_MESSAGE_TTL_MS: u64 = 10 * 60 * 1000; // a signed address message expires in 10 minutes
+pub const MIN_AUCTION_PHASE_MS: u64 = 3600 * 1000; // one hour
+pub const MAX_AUCTION_PHASE_MS: u64 = 30 * 24 * 3600 * 1000; // 30 days
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct StateInfo {
@@ -24,10 +65,18 @@ pub struct StateInfo {
     pub transfer_out_total: u128,
     pub next_block_height: u64,
     pub next_block_phash: ByteArray<32>,
+    pub typed_blocks_from: Option<u64>,
+    pub indexed_blocks: u64,
     pub latest_usernames: Vec<String>,
+    pub auction: AuctionConfig,
+    pub pricing: PricingConfig,
+    pub signing: SigningConfig,
+    pub provisioning: ProvisioningConfig,
+    pub canister_loads: BTreeMap<Principal, CanisterLoad>,
+    pub cycles_monitor: CyclesMonitorConfig,
 }
 
-#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+#[derive(CandidType, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
 pub struct Price {
     pub channel: u64, // price to create a channel
     pub name_l1: u64, // price to register a namespace in COSE service
@@ -35,6 +84,8 @@ pub struct Price {
     pub name_l3: u64,
     pub name_l5: u64,
     pub name_l7: u64,
+    #[serde(default)]
+    pub renewal_percent: u64, // annual renewal fee in percent of the name price, 0 for perpetual names
 }
 
 impl Price {
@@ -47,9 +98,108 @@ impl Price {
             _ => self.name_l7,
         }
     }
+
+    // renewal returns the annual renewal fee of a name with the given length.
+    pub fn renewal(&self, level: usize) -> u64 {
+        self.get(level) * self.renewal_percent / 100
+    }
+
+    // convert converts prices in a currency to PANDA prices with the rate of 1 PANDA
+    // in the currency, both with 8 decimals. It fails when a price overflows u64.
+    pub fn convert(&self, rate: u64) -> Result<Self, String> {
+        if rate == 0 {
+            return Err("invalid rate: 0".to_string());
+        }
+        let conv = |v: u64| {
+            let p = v as u128 * TOKEN_1 as u128 / rate as u128;
+            u64::try_from(p).map_err(|_| format!("price overflow: {}", p))
+        };
+        Ok(Price {
+            channel: conv(self.channel)?,
+            name_l1: conv(self.name_l1)?.max(MIN_NAME_PRICE),
+            name_l2: conv(self.name_l2)?.max(MIN_NAME_PRICE),
+            name_l3: conv(self.name_l3)?.max(MIN_NAME_PRICE),
+            name_l5: conv(self.name_l5)?.max(MIN_NAME_PRICE),
+            name_l7: conv(self.name_l7)?.max(MIN_NAME_PRICE),
+            renewal_percent: self.renewal_percent,
+        })
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct PricingConfig {
+    pub currency: Option<String>, // "USD" or "ICP", None for fixed PANDA prices
+    pub source: Option<Principal>, // price source canister with the `get_exchange_rate` method
+    pub base: Price,              // prices in the currency with 8 decimals
+    pub min_rate: u64,            // bounds of 1 PANDA in the currency with 8 decimals
+    pub max_rate: u64,
+    pub ttl_ms: u64, // validity window of a quote
+}
+
+impl PricingConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        let currency = match self.currency {
+            None => return Ok(()),
+            Some(ref currency) => currency,
+        };
+        if !PRICE_CURRENCIES.contains(&currency.as_str()) {
+            return Err(format!("unsupported currency: {}", currency));
+        }
+        if self.source.is_none() {
+            return Err("price source canister is required".to_string());
+        }
+        if self.min_rate == 0 || self.min_rate > self.max_rate {
+            return Err(format!(
+                "invalid rate bounds: [{}, {}]",
+                self.min_rate, self.max_rate
+            ));
+        }
+        if !(MIN_QUOTE_TTL_MS..=MAX_QUOTE_TTL_MS).contains(&self.ttl_ms) {
+            return Err(format!("invalid ttl_ms: {}", self.ttl_ms));
+        }
+        let base = &self.base;
+        if base.name_l7 == 0
+            || base.name_l5 <= base.name_l7
+            || base.name_l3 <= base.name_l5
+            || base.name_l2 <= base.name_l3
+            || base.name_l1 <= base.name_l2
+        {
+            return Err("base name prices must be positive and decrease with length".to_string());
+        }
+        if base.renewal_percent > 100 {
+            return Err(format!(
+                "renewal percent too high: {}",
+                base.renewal_percent
+            ));
+        }
+        Ok(())
+    }
 }
 
-#[derive(CandidType, Copy, Clone, Debug, Deserialize, Serialize)]
+// the price source interface, a stand-in for the exchange rate canister
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct GetExchangeRateRequest {
+    pub base_asset: String,
+    pub quote_asset: String,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ExchangeRate {
+    pub rate: u64, // quote asset per base asset
+    pub decimals: u32,
+    pub timestamp: u64, // seconds
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct PriceQuote {
+    pub currency: Option<String>,
+    pub rate: Option<u64>, // 1 PANDA in the currency with 8 decimals
+    pub price: Price,      // prices in PANDA
+    pub valid_from: u64,
+    pub valid_until: Option<u64>, // None for fixed PANDA prices
+}
+
+#[derive(CandidType, Copy, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
 pub enum CanisterKind {
     Cose,
     Profile,
@@ -64,6 +214,7 @@ pub struct UpdatePriceInput {
     pub name_l3: Option<u64>,
     pub name_l5: Option<u64>,
     pub name_l7: Option<u64>,
+    pub renewal_percent: Option<u64>,
 }
 
 impl UpdatePriceInput {
@@ -93,6 +244,11 @@ impl UpdatePriceInput {
                 return Err(format!("name price too low: {}", price));
             }
         }
+        if let Some(percent) = self.renewal_percent {
+            if percent > 100 {
+                return Err(format!("renewal percent too high: {}", percent));
+            }
+        }
         Ok(())
     }
 }
@@ -116,3 +272,879 @@ pub struct NamespaceInfo {
     pub settings_total: Option<u64>,   // settings created by managers for users
     pub user_settings_total: Option<u64>, // settings created by users
 }
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UsernameListing {
+    pub name: String,
+    pub seller: Principal,
+    pub price: u64,
+    pub created_at: u64,
+    pub offers: Vec<UsernameOffer>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UsernameOffer {
+    pub buyer: Principal,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UsernameTerm {
+    pub name: String,
+    pub owner: Principal,
+    pub expires_at: Option<u64>, // None for perpetual names
+    pub grace_until: Option<u64>,
+    pub renewal_fee: u64, // annual renewal fee
+}
+
+// Names with these lengths can only be registered by sealed-bid auctions.
+// A bidder commits `sha3_256(name || amount || salt || bidder)` with a deposit in the commit phase,
+// then reveals the amount and salt in the reveal phase. The highest revealed bid wins and pays its amount,
+// other revealed bids are refunded, unrevealed deposits are forfeited.
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct AuctionConfig {
+    pub lengths: BTreeSet<u8>, // empty to disable auctions
+    pub commit_ms: u64,
+    pub reveal_ms: u64,
+}
+
+impl AuctionConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for len in &self.lengths {
+            if *len == 0 || *len as usize > MAX_USER_NAME_SIZE {
+                return Err(format!("invalid name length: {}", len));
+            }
+        }
+        if !(MIN_AUCTION_PHASE_MS..=MAX_AUCTION_PHASE_MS).contains(&self.commit_ms) {
+            return Err(format!("invalid commit_ms: {}", self.commit_ms));
+        }
+        if !(MIN_AUCTION_PHASE_MS..=MAX_AUCTION_PHASE_MS).contains(&self.reveal_ms) {
+            return Err(format!("invalid reveal_ms: {}", self.reveal_ms));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UsernameAuction {
+    pub name: String,
+    pub reserve_price: u64,
+    pub started_at: u64,
+    pub commit_end: u64,
+    pub reveal_end: u64,
+    pub bids: Vec<AuctionBid>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct AuctionBid {
+    pub bidder: Principal,
+    pub deposit: u64,        // public, an upper bound of the sealed amount
+    pub amount: Option<u64>, // revealed amount
+    pub created_at: u64,
+}
+
+// ResolverRecord is a typed record that a username resolves to,
+// the keys are "account", "evm", "sol", "url" and the text record keys.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum ResolverRecord {
+    Account(Account), // ICRC-1 account
+    Evm(String),      // hex address with 0x prefix
+    Sol(String),      // base58 address
+    Url(String),
+    Text(String, String),
+}
+
+impl ResolverRecord {
+    pub fn key(&self) -> String {
+        match self {
+            ResolverRecord::Account(_) => "account".to_string(),
+            ResolverRecord::Evm(_) => "evm".to_string(),
+            ResolverRecord::Sol(_) => "sol".to_string(),
+            ResolverRecord::Url(_) => "url".to_string(),
+            ResolverRecord::Text(key, _) => key.clone(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            ResolverRecord::Account(_) => Ok(()),
+            ResolverRecord::Evm(addr) => {
+                evm_address(addr)?;
+                Ok(())
+            }
+            ResolverRecord::Sol(addr) => {
+                sol_address(addr)?;
+                Ok(())
+            }
+            ResolverRecord::Url(url) => {
+                if url.len() > MAX_RESOLVER_VALUE_SIZE {
+                    return Err("url is too long".to_string());
+                }
+                if !url.starts_with("https://") {
+                    return Err("url should start with https://".to_string());
+                }
+                Ok(())
+            }
+            ResolverRecord::Text(key, value) => {
+                if key.is_empty() || key.len() > MAX_RESOLVER_KEY_SIZE {
+                    return Err(format!("invalid text key: {:?}", key));
+                }
+                if matches!(key.as_str(), "account" | "evm" | "sol" | "url") {
+                    return Err(format!("reserved text key: {}", key));
+                }
+                if value.len() > MAX_RESOLVER_VALUE_SIZE {
+                    return Err(format!("text value of {} is too long", key));
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub fn evm_address(addr: &str) -> Result<[u8; 20], String> {
+    if !addr.starts_with("0x") {
+        return Err("evm address should start with 0x".to_string());
+    }
+    let data = hex::decode(&addr[2..]).map_err(|_| "invalid evm address".to_string())?;
+    data.try_into()
+        .map_err(|_| "invalid evm address".to_string())
+}
+
+pub fn sol_address(addr: &str) -> Result<[u8; 32], String> {
+    let data = bs58::decode(addr)
+        .into_vec()
+        .map_err(|_| "invalid solana address".to_string())?;
+    data.try_into()
+        .map_err(|_| "invalid solana address".to_string())
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateResolverInput {
+    pub upsert: Vec<ResolverRecord>,
+    pub remove: Vec<String>, // record keys
+}
+
+impl UpdateResolverInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.upsert.len() + self.remove.len() > MAX_RESOLVER_TEXT_RECORDS + 4 {
+            return Err("too many records".to_string());
+        }
+        for record in &self.upsert {
+            record.validate()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ResolvedName {
+    pub name: String,
+    pub owner: Principal,
+    pub records: Vec<ResolvedRecord>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ResolvedRecord {
+    pub record: ResolverRecord,
+    pub verified_at: Option<u64>, // for evm and sol addresses signed by their keys
+}
+
+// AddressMessage is the message that an evm or sol address signs to be linked to a username.
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct AddressMessage {
+    pub message: String,
+    pub issued_at: u64, // passed back with the signature
+    pub expires_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct SubnameInfo {
+    pub name: String, // full name, e.g. "alice.acme"
+    pub user: Principal,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct NameHistory {
+    pub height: u64,
+    pub btype: String,
+    pub name: String,
+    pub user: Principal,
+    pub from: Option<Principal>,
+    pub value: u64,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ReservedName {
+    pub name: String,
+    pub reason: String, // brand, trademark claim or system name
+    pub allocated_to: Option<Principal>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ReserveNameInput {
+    pub name: String,
+    pub reason: String,
+}
+
+impl ReserveNameInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.name.is_empty() || self.name.len() > MAX_USER_NAME_SIZE {
+            return Err(format!("invalid name: {}", self.name));
+        }
+        if self.reason.len() > MAX_RESERVED_REASON_SIZE {
+            return Err("reason is too long".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum DisputeStatus {
+    Open,
+    Upheld,
+    Rejected,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct NameDispute {
+    pub id: u64,
+    pub name: String,
+    pub claimant: Principal,
+    pub holder: Option<Principal>,
+    pub claim: String,
+    pub status: DisputeStatus,
+    pub resolution: String,
+    pub created_at: u64,
+    pub resolved_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct PendingOperation {
+    pub id: u64,
+    pub user: Principal,
+    pub kind: String,
+    pub username: String,
+    pub pending_steps: Vec<String>,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct PendingPayout {
+    pub id: u64,
+    pub to: Principal,
+    pub amount: u64,
+    pub memo: String,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub needs_review: bool, // resolved by admin_resolve_payout after checking the ledger
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SigningConfig {
+    pub fee: u64,                // PANDA fee per signature, 0 for free
+    pub max_per_hour: u32,       // signatures per user per hour
+    pub max_total_per_hour: u32, // signatures of all users per hour
+}
+
+impl Default for SigningConfig {
+    fn default() -> Self {
+        SigningConfig {
+            fee: TOKEN_1,
+            max_per_hour: 10,
+            max_total_per_hour: 1000,
+        }
+    }
+}
+
+impl SigningConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_per_hour == 0 || self.max_per_hour > 1000 {
+            return Err(format!("invalid max_per_hour: {}", self.max_per_hour));
+        }
+        if self.max_total_per_hour < self.max_per_hour || self.max_total_per_hour > 100_000 {
+            return Err(format!(
+                "invalid max_total_per_hour: {}",
+                self.max_total_per_hour
+            ));
+        }
+        if self.fee > 0 && self.fee <= TOKEN_FEE {
+            return Err(format!("fee should be greater than {}", TOKEN_FEE));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub enum KVValue {
+    Text(String),
+    Int(i64),
+    Bool(bool),
+    Bytes(ByteBuf),
+    Encrypted(ByteBuf), // COSE_Encrypt0 encrypted by the client with the user's DEK
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateKVsInput {
+    pub namespace: String,
+    pub version: u32, // the version read by the client, 0 for a new namespace
+    pub upsert: BTreeMap<String, KVValue>,
+    pub remove: BTreeSet<String>,
+}
+
+impl UpdateKVsInput {
+    pub fn validate(&self) -> Result<(), String> {
+        validate_kv_namespace(&self.namespace)?;
+        if self.upsert.is_empty() && self.remove.is_empty() {
+            return Err("empty upsert and remove".to_string());
+        }
+        for key in self.upsert.keys().chain(self.remove.iter()) {
+            if key.is_empty() || key.len() > MAX_KV_KEY_SIZE {
+                return Err(format!("invalid key: {}", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+pub fn validate_kv_namespace(namespace: &str) -> Result<(), String> {
+    if namespace.is_empty()
+        || namespace.len() > MAX_KV_NAMESPACE_SIZE
+        || !namespace
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    {
+        return Err(format!("invalid namespace: {}", namespace));
+    }
+    Ok(())
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct KVsOutput {
+    pub namespace: String,
+    pub version: u32, // 0 if the namespace does not exist
+    pub kv: BTreeMap<String, KVValue>,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CanisterLoad {
+    pub items_total: u64, // channels or profiles
+    pub messages_total: u64,
+    pub stable_memory_size: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct ProvisioningConfig {
+    pub max_channels: u64, // a channel canister matures when reaching any of the limits, 0 for no limit
+    pub max_messages: u64,
+    pub max_memory_size: u64,
+    pub max_profiles: u64, // a profile canister is full when reaching it, 0 for no limit
+    pub min_open_channel_canisters: u32, // new canisters are created below it, 0 to disable auto provisioning
+    pub cycles: u128,                    // cycles for a new canister
+    pub controllers: BTreeSet<Principal>, // controllers of new canisters besides this canister
+}
+
+impl ProvisioningConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_open_channel_canisters > 10 {
+            return Err(format!(
+                "invalid min_open_channel_canisters: {}",
+                self.min_open_channel_canisters
+            ));
+        }
+        if self.min_open_channel_canisters > 0 && self.cycles < MIN_PROVISION_CYCLES {
+            return Err(format!(
+                "cycles should be at least {}",
+                MIN_PROVISION_CYCLES
+            ));
+        }
+        if self.controllers.contains(&Principal::anonymous()) {
+            return Err("anonymous controller is not allowed".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_channel_full(&self, load: &CanisterLoad) -> bool {
+        (self.max_channels > 0 && load.items_total >= self.max_channels)
+            || (self.max_messages > 0 && load.messages_total >= self.max_messages)
+            || (self.max_memory_size > 0 && load.stable_memory_size >= self.max_memory_size)
+    }
+
+    pub fn is_profile_full(&self, load: &CanisterLoad) -> bool {
+        (self.max_profiles > 0 && load.items_total >= self.max_profiles)
+            || (self.max_memory_size > 0 && load.stable_memory_size >= self.max_memory_size)
+    }
+}
+
+pub fn validate_canister_wasm(wasm: &[u8]) -> Result<(), String> {
+    if wasm.len() > MAX_WASM_SIZE {
+        return Err(format!(
+            "wasm size should be at most {} bytes",
+            MAX_WASM_SIZE
+        ));
+    }
+    // raw wasm module or gzip compressed one
+    if !wasm.starts_with(b"\0asm") && !wasm.starts_with(&[0x1f, 0x8b]) {
+        return Err("invalid wasm module".to_string());
+    }
+    Ok(())
+}
+
+// the subset of the channel and profile canister states used for provisioning
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ChannelCanisterState {
+    pub name: String,
+    pub managers: BTreeSet<Principal>,
+    pub ic_oss_cluster: Option<Principal>,
+    pub ic_oss_buckets: Vec<Principal>,
+    pub profile_canisters: Vec<Principal>,
+    pub channels_total: u64,
+    pub messages_total: u64,
+    pub stable_memory_size: Option<u64>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct ProfileCanisterState {
+    pub name: String,
+    pub managers: BTreeSet<Principal>,
+    pub ic_oss_cluster: Option<Principal>,
+    pub ic_oss_buckets: Vec<Principal>,
+    pub profiles_total: u64,
+    pub stable_memory_size: Option<u64>,
+}
+
+// the init and upgrade args of the channel and profile canisters
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum CanisterChainArgs {
+    Init(CanisterInitArgs),
+    Upgrade(CanisterUpgradeArgs),
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CanisterInitArgs {
+    pub name: String,
+    pub managers: BTreeSet<Principal>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct CanisterUpgradeArgs {
+    pub name: Option<String>,
+    pub managers: Option<BTreeSet<Principal>>,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize)]
+pub enum ChannelCanisterKind {
+    OssCluster,
+    OssBucket,
+    Profile,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, Deserialize)]
+pub enum ProfileCanisterKind {
+    OssCluster,
+    OssBucket,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct WasmInfo {
+    pub kind: CanisterKind,
+    pub hash: ByteArray<32>, // SHA-256 of the wasm module
+    pub size: u64,
+    pub prev_hash: Option<ByteArray<32>>, // the replaced wasm, kept for rollback
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct WasmUploadInput {
+    pub kind: CanisterKind,
+    pub hash: ByteArray<32>, // expected SHA-256 of the whole wasm module
+    pub size: u64,           // expected size of the whole wasm module
+}
+
+impl WasmUploadInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.size == 0 || self.size > MAX_WASM_SIZE as u64 {
+            return Err(format!("invalid wasm size: {}", self.size));
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct StartUpgradeInput {
+    pub kind: CanisterKind,
+    pub wasm_hash: ByteArray<32>, // the current or the previous wasm of the kind
+    pub batch_size: u32,          // canisters upgraded one by one in a timer round
+    pub arg: Option<ByteBuf>,     // candid encoded upgrade args, empty args by default
+}
+
+impl StartUpgradeInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.batch_size == 0 || self.batch_size > MAX_UPGRADE_BATCH_SIZE {
+            return Err(format!("invalid batch_size: {}", self.batch_size));
+        }
+        if self
+            .arg
+            .as_ref()
+            .is_some_and(|a| a.len() > MAX_WASM_CHUNK_SIZE)
+        {
+            return Err("arg is too large".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum UpgradeStatus {
+    Running,
+    Completed,
+    Halted,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub enum CanisterUpgradeStatus {
+    Pending,
+    Upgraded,
+    Failed,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct CanisterUpgrade {
+    pub canister: Principal,
+    pub status: CanisterUpgradeStatus,
+    pub prev_module_hash: Option<ByteBuf>, // the installed module before upgrading, for rollback
+    pub error: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct FleetUpgrade {
+    pub id: u64,
+    pub kind: CanisterKind,
+    pub wasm_hash: ByteArray<32>,
+    pub rollback_wasm_hash: Option<ByteArray<32>>, // the wasm to upgrade to for a rollback
+    pub batch_size: u32,
+    pub status: UpgradeStatus,
+    pub canisters: Vec<CanisterUpgrade>,
+    pub error: Option<String>,
+    pub created_by: Principal,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CyclesMonitorConfig {
+    pub min_cycles: u128, // canisters below it are topped up, 0 to disable the monitor
+    pub top_up_cycles: u128, // cycles of each top-up
+    pub reserve_cycles: u128, // this canister keeps at least it when topping up others
+    pub icp_top_up_e8s: u64, // ICP converted by the CMC when cycles are not enough, 0 to disable
+    pub canisters: BTreeSet<Principal>, // monitored besides the registered canisters
+}
+
+impl CyclesMonitorConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.min_cycles > 0
+            && (self.top_up_cycles == 0 || self.top_up_cycles > MAX_TOP_UP_CYCLES)
+        {
+            return Err(format!("invalid top_up_cycles: {}", self.top_up_cycles));
+        }
+        if self.canisters.len() > MAX_MONITORED_CANISTERS {
+            return Err(format!(
+                "canisters should be at most {}",
+                MAX_MONITORED_CANISTERS
+            ));
+        }
+        if self.canisters.contains(&Principal::anonymous()) {
+            return Err("anonymous canister is not allowed".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct CyclesAlert {
+    pub id: u64,
+    pub canister: Principal,
+    pub cycles_balance: Option<u128>, // None if the balance is unknown
+    pub message: String,
+    pub created_at: u64,
+}
+
+// the arguments and errors of the cycles minting canister's notify_top_up
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub struct NotifyTopUpArg {
+    pub block_index: u64,
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize)]
+pub enum NotifyError {
+    Refunded {
+        block_index: Option<u64>,
+        reason: String,
+    },
+    InvalidTransaction(String),
+    Other {
+        error_message: String,
+        error_code: u64,
+    },
+    Processing,
+    TransactionTooOld(u64),
+}
+
+// the source of a treasury ledger entry, Collection moves tokens out of the treasury
+#[derive(
+    CandidType, Copy, Clone, Debug, Deserialize, PartialEq, Eq, PartialOrd, Ord, Serialize,
+)]
+pub enum TreasuryKind {
+    NameRegistration,
+    NameRenewal,
+    ChannelCreation,
+    ChannelTopup,
+    MarketFee,
+    AuctionForfeit,
+    SigningFee,
+    Collection,
+    MarketPurchase,
+    MarketOffer,
+    AuctionDeposit,
+    MarketPayout,
+    MarketRefund,
+    AuctionRefund,
+    SigningRefund,
+    EscrowRelease,
+}
+
+impl TreasuryKind {
+    // is_escrow returns true for the tokens held on behalf of traders and bidders,
+    // they are neither income nor expense of the treasury. Escrowed tokens that the
+    // treasury earns leave the escrow as EscrowRelease and are recorded again as income.
+    pub fn is_escrow(&self) -> bool {
+        matches!(
+            self,
+            TreasuryKind::MarketPurchase
+                | TreasuryKind::MarketOffer
+                | TreasuryKind::AuctionDeposit
+                | TreasuryKind::MarketPayout
+                | TreasuryKind::MarketRefund
+                | TreasuryKind::AuctionRefund
+                | TreasuryKind::EscrowRelease
+        )
+    }
+
+    // is_inflow returns true for the tokens received by the canister.
+    pub fn is_inflow(&self) -> bool {
+        !matches!(
+            self,
+            TreasuryKind::Collection
+                | TreasuryKind::MarketPayout
+                | TreasuryKind::MarketRefund
+                | TreasuryKind::AuctionRefund
+                | TreasuryKind::SigningRefund
+                | TreasuryKind::EscrowRelease
+        )
+    }
+
+    pub fn is_income(&self) -> bool {
+        self.is_inflow() && !self.is_escrow()
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TreasuryEntry {
+    pub id: u64,
+    pub kind: TreasuryKind,
+    pub account: Principal, // the payer, or the recipient of a collection or payout
+    pub amount: u64,
+    pub block: Option<u64>, // the $PANDA ledger block index if the entry has its own transfer
+    pub memo: String,
+    pub timestamp: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct TreasuryTotal {
+    pub count: u64,
+    pub amount: u128,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TreasuryDaily {
+    pub day: u64, // the start of the UTC day in milliseconds
+    pub totals: BTreeMap<TreasuryKind, TreasuryTotal>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TreasuryReport {
+    pub from: u64, // rounded down to the start of the day, inclusive
+    pub to: u64,   // rounded up to the start of the next day, exclusive
+    pub income: u128,
+    pub expense: u128,
+    pub escrow_in: u128,
+    pub escrow_out: u128,
+    pub totals: BTreeMap<TreasuryKind, TreasuryTotal>,
+    pub daily: Vec<TreasuryDaily>,
+    pub incoming_total: u128, // lifetime counters, including the time before itemization
+    pub transfer_out_total: u128,
+}
+
+// treasury_day returns the start of the UTC day of the time in milliseconds.
+pub fn treasury_day(ts_ms: u64) -> u64 {
+    ts_ms - ts_ms % TREASURY_DAY_MS
+}
+
+// treasury_days rounds [from_ms, to_ms) to whole days and checks the length of the range.
+pub fn treasury_days(from_ms: u64, to_ms: u64) -> Result<(u64, u64), String> {
+    let from = treasury_day(from_ms);
+    let to = to_ms
+        .div_ceil(TREASURY_DAY_MS)
+        .saturating_mul(TREASURY_DAY_MS);
+    if from >= to {
+        return Err("invalid report range".to_string());
+    }
+    if (to - from) / TREASURY_DAY_MS > MAX_TREASURY_REPORT_DAYS {
+        return Err(format!(
+            "report range should be at most {} days",
+            MAX_TREASURY_REPORT_DAYS
+        ));
+    }
+    Ok((from, to))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_price_convert() {
+        let base = Price {
+            channel: 2 * TOKEN_1,
+            name_l1: 1000 * TOKEN_1,
+            name_l2: 100 * TOKEN_1,
+            name_l3: 10 * TOKEN_1,
+            name_l5: TOKEN_1,
+            name_l7: TOKEN_1 / 10,
+            renewal_percent: 10,
+        };
+
+        // 1 PANDA = 0.5 in the currency
+        let price = base.convert(TOKEN_1 / 2).unwrap();
+        assert_eq!(price.channel, 4 * TOKEN_1);
+        assert_eq!(price.name_l1, 2000 * TOKEN_1);
+        assert_eq!(price.name_l5, 2 * TOKEN_1);
+        // name prices are at least MIN_NAME_PRICE
+        assert_eq!(price.name_l7, MIN_NAME_PRICE);
+        assert_eq!(price.renewal_percent, 10);
+
+        assert!(base.convert(0).is_err());
+        // prices overflowing u64 are rejected
+        let huge = Price {
+            name_l1: u64::MAX,
+            ..base.clone()
+        };
+        assert!(huge.convert(TOKEN_1).is_ok());
+        assert!(huge.convert(TOKEN_1 / 2).is_err());
+        let price = base.convert(TOKEN_1).unwrap();
+        assert_eq!(price.name_l1, base.name_l1);
+    }
+
+    #[test]
+    fn test_provisioning_is_full() {
+        let cfg = ProvisioningConfig {
+            max_channels: 100,
+            max_messages: 1000,
+            max_memory_size: 1 << 30,
+            max_profiles: 10,
+            ..Default::default()
+        };
+        let load = CanisterLoad {
+            items_total: 99,
+            messages_total: 999,
+            stable_memory_size: (1 << 30) - 1,
+            updated_at: 0,
+        };
+        assert!(!cfg.is_channel_full(&load));
+        assert!(cfg.is_channel_full(&CanisterLoad {
+            items_total: 100,
+            ..load.clone()
+        }));
+        assert!(cfg.is_channel_full(&CanisterLoad {
+            messages_total: 1000,
+            ..load.clone()
+        }));
+        assert!(cfg.is_channel_full(&CanisterLoad {
+            stable_memory_size: 1 << 30,
+            ..load.clone()
+        }));
+
+        // 0 means no limit
+        let unlimited = ProvisioningConfig::default();
+        assert!(!unlimited.is_channel_full(&CanisterLoad {
+            items_total: u64::MAX,
+            messages_total: u64::MAX,
+            stable_memory_size: u64::MAX,
+            updated_at: 0,
+        }));
+
+        assert!(!cfg.is_profile_full(&CanisterLoad {
+            items_total: 9,
+            ..load.clone()
+        }));
+        assert!(cfg.is_profile_full(&CanisterLoad {
+            items_total: 10,
+            ..load
+        }));
+    }
+
+    #[test]
+    fn test_treasury_days() {
+        let day = TREASURY_DAY_MS;
+        assert_eq!(treasury_day(0), 0);
+        assert_eq!(treasury_day(day - 1), 0);
+        assert_eq!(treasury_day(day), day);
+        assert_eq!(treasury_day(3 * day + 1), 3 * day);
+
+        assert_eq!(treasury_days(day + 1, 2 * day + 1).unwrap(), (day, 3 * day));
+        assert_eq!(treasury_days(day, 2 * day).unwrap(), (day, 2 * day));
+        assert_eq!(treasury_days(day, day + 1).unwrap(), (day, 2 * day));
+        assert!(treasury_days(day, day).is_err());
+        assert!(treasury_days(2 * day, day).is_err());
+        assert!(treasury_days(0, MAX_TREASURY_REPORT_DAYS * day).is_ok());
+        assert!(treasury_days(0, MAX_TREASURY_REPORT_DAYS * day + 1).is_err());
+    }
+
+    #[test]
+    fn test_treasury_kind() {
+        assert!(TreasuryKind::NameRegistration.is_income());
+        assert!(TreasuryKind::MarketFee.is_income());
+        assert!(!TreasuryKind::Collection.is_income());
+        assert!(!TreasuryKind::Collection.is_inflow());
+        assert!(
+            !TreasuryKind::SigningRefund.is_inflow() && !TreasuryKind::SigningRefund.is_escrow()
+        );
+        for kind in [
+            TreasuryKind::MarketPurchase,
+            TreasuryKind::MarketOffer,
+            TreasuryKind::AuctionDeposit,
+        ] {
+            assert!(kind.is_escrow() && kind.is_inflow() && !kind.is_income());
+        }
+        for kind in [
+            TreasuryKind::MarketPayout,
+            TreasuryKind::MarketRefund,
+            TreasuryKind::AuctionRefund,
+            TreasuryKind::EscrowRelease,
+        ] {
+            assert!(kind.is_escrow() && !kind.is_inflow() && !kind.is_income());
+        }
+    }
+}
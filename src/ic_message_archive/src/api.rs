@@ -0,0 +1,26 @@
+use icrc_ledger_types::icrc3::{
+    archive::{GetArchivesArgs, GetArchivesResult},
+    blocks::{GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock},
+};
+
+use crate::{is_registry, store};
+
+#[ic_cdk::query]
+fn get_state() -> Result<store::StateInfo, String> {
+    Ok(store::state::info())
+}
+
+#[ic_cdk::update(guard = "is_registry")]
+fn append_blocks(start: u64, blocks: Vec<ICRC3GenericBlock>) -> Result<u64, String> {
+    store::blocks::append(start, blocks)
+}
+
+#[ic_cdk::query]
+fn icrc3_get_archives(_args: GetArchivesArgs) -> GetArchivesResult {
+    vec![]
+}
+
+#[ic_cdk::query]
+fn icrc3_get_blocks(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    store::blocks::get(args)
+}
@@ -0,0 +1,28 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+
+use crate::store;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct InitArgs {
+    registry: Principal, // the ic_message canister that appends blocks
+    max_blocks: u64,     // the archive is full when it holds max_blocks
+}
+
+#[ic_cdk::init]
+fn init(args: InitArgs) {
+    store::state::with_mut(|s| {
+        s.registry = args.registry;
+        s.max_blocks = args.max_blocks;
+    });
+}
+
+#[ic_cdk::pre_upgrade]
+fn pre_upgrade() {
+    store::state::save();
+}
+
+#[ic_cdk::post_upgrade]
+fn post_upgrade() {
+    store::state::load();
+}
@@ -0,0 +1,21 @@
+use icrc_ledger_types::icrc3::{
+    archive::{GetArchivesArgs, GetArchivesResult},
+    blocks::{GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock},
+};
+
+mod api;
+mod api_init;
+mod store;
+
+use api_init::InitArgs;
+
+fn is_registry() -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if store::state::with(|s| s.registry == caller) {
+        Ok(())
+    } else {
+        Err("caller is not the registry canister".to_string())
+    }
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,253 @@
+use candid::{CandidType, Nat, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableCell, StableLog,
+};
+use icrc_ledger_types::icrc3::blocks::{
+    BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3GenericBlock,
+};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct State {
+    pub registry: Principal,
+    pub max_blocks: u64,
+    pub start: u64, // height of the first block in this archive
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            registry: Principal::anonymous(),
+            max_blocks: 0,
+            start: 0,
+        }
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct StateInfo {
+    pub registry: Principal,
+    pub max_blocks: u64,
+    pub start: u64,
+    pub end: u64, // exclusive
+}
+
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const BLOCKS_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const BLOCKS_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static STATE_STORE: RefCell<StableCell<Vec<u8>, Memory>> = RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(STATE_MEMORY_ID)),
+            Vec::new()
+        )
+    );
+
+    // candid encoded ICRC3GenericBlock
+    static BLOCKS: RefCell<StableLog<Vec<u8>, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(BLOCKS_DATA_MEMORY_ID)),
+        )
+    );
+}
+
+pub mod state {
+    use super::*;
+
+    pub fn with<R>(f: impl FnOnce(&State) -> R) -> R {
+        STATE.with_borrow(f)
+    }
+
+    pub fn with_mut<R>(f: impl FnOnce(&mut State) -> R) -> R {
+        STATE.with_borrow_mut(f)
+    }
+
+    pub fn info() -> StateInfo {
+        let len = BLOCKS.with_borrow(|r| r.len());
+        STATE.with_borrow(|s| StateInfo {
+            registry: s.registry,
+            max_blocks: s.max_blocks,
+            start: s.start,
+            end: s.start + len,
+        })
+    }
+
+    pub fn load() {
+        STATE_STORE.with_borrow(|r| {
+            STATE.with_borrow_mut(|h| {
+                let v: State =
+                    from_reader(&r.get()[..]).expect("failed to decode STATE_STORE data");
+                *h = v;
+            });
+        });
+    }
+
+    pub fn save() {
+        STATE.with_borrow(|h| {
+            STATE_STORE.with_borrow_mut(|r| {
+                let mut buf = vec![];
+                into_writer(h, &mut buf).expect("failed to encode STATE_STORE data");
+                r.set(buf);
+            });
+        });
+    }
+}
+
+pub mod blocks {
+    use super::*;
+
+    const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+    // append appends blocks that start at the given height, returns the next height.
+    // It is idempotent: the blocks already stored are skipped, so the registry can resend
+    // a batch whose reply was lost.
+    pub fn append(start: u64, blocks: Vec<ICRC3GenericBlock>) -> Result<u64, String> {
+        STATE.with_borrow_mut(|s| {
+            BLOCKS.with_borrow_mut(|r| {
+                let len = r.len();
+                if len == 0 {
+                    s.start = start;
+                } else if start < s.start || start > s.start + len {
+                    return Err(format!(
+                        "expected blocks start at {}, got {}",
+                        s.start + len,
+                        start
+                    ));
+                }
+
+                let mut blocks = blocks.into_iter();
+                for height in start..(s.start + len) {
+                    let Some(blk) = blocks.next() else {
+                        break;
+                    };
+                    let data = candid::encode_one(&blk).map_err(|err| err.to_string())?;
+                    if r.get(height - s.start).as_ref() != Some(&data) {
+                        return Err(format!("block {} mismatch", height));
+                    }
+                }
+
+                let blocks: Vec<ICRC3GenericBlock> = blocks.collect();
+                if len + blocks.len() as u64 > s.max_blocks {
+                    return Err("archive is full".to_string());
+                }
+                for blk in blocks {
+                    let data = candid::encode_one(&blk).map_err(|err| err.to_string())?;
+                    r.append(&data)
+                        .map_err(|err| format!("failed to append block: {:?}", err))?;
+                }
+                Ok(s.start + r.len())
+            })
+        })
+    }
+
+    pub fn get(args: Vec<GetBlocksRequest>) -> GetBlocksResult {
+        let start_height = STATE.with_borrow(|s| s.start);
+        BLOCKS.with_borrow(|r| {
+            let end_height = start_height + r.len();
+            let mut blocks = vec![];
+            for arg in args {
+                let (start, length) = arg
+                    .as_start_and_length()
+                    .unwrap_or_else(|msg| ic_cdk::api::trap(&msg));
+
+                let max_length = MAX_BLOCKS_PER_RESPONSE.saturating_sub(blocks.len() as u64);
+                if max_length == 0 {
+                    break;
+                }
+
+                let start = start.max(start_height);
+                let end = start.saturating_add(length.min(max_length)).min(end_height);
+                for height in start..end {
+                    if let Some(data) = r.get(height - start_height) {
+                        let block: ICRC3GenericBlock =
+                            candid::decode_one(&data).expect("failed to decode block");
+                        blocks.push(BlockWithId {
+                            id: Nat::from(height),
+                            block,
+                        });
+                    }
+                }
+            }
+
+            GetBlocksResult {
+                log_length: Nat::from(end_height),
+                blocks,
+                archived_blocks: vec![],
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn block(i: u64) -> ICRC3GenericBlock {
+        ICRC3GenericBlock::Nat(Nat::from(i))
+    }
+
+    fn blocks(start: u64, end: u64) -> Vec<ICRC3GenericBlock> {
+        (start..end).map(block).collect()
+    }
+
+    fn get(start: u64, length: u64) -> Vec<u64> {
+        blocks::get(vec![GetBlocksRequest {
+            start: Nat::from(start),
+            length: Nat::from(length),
+        }])
+        .blocks
+        .into_iter()
+        .map(|b| u64::try_from(&b.id.0).unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn test_append_blocks() {
+        state::with_mut(|s| s.max_blocks = 10);
+
+        assert_eq!(blocks::append(100, blocks(100, 103)).unwrap(), 103);
+        assert_eq!(state::info().start, 100);
+        assert_eq!(state::info().end, 103);
+
+        // a gap or a height before the archive is rejected
+        assert!(blocks::append(104, blocks(104, 105)).is_err());
+        assert!(blocks::append(99, blocks(99, 101)).is_err());
+
+        // a resent batch is skipped, and the new blocks after it are appended
+        assert_eq!(blocks::append(100, blocks(100, 103)).unwrap(), 103);
+        assert_eq!(blocks::append(101, blocks(101, 105)).unwrap(), 105);
+        assert_eq!(state::info().end, 105);
+        assert_eq!(get(100, 10), vec![100, 101, 102, 103, 104]);
+
+        // the resent blocks must match the stored ones
+        assert!(blocks::append(104, vec![block(0), block(105)]).is_err());
+        assert_eq!(state::info().end, 105);
+
+        // only the new blocks count towards the capacity
+        assert!(blocks::append(103, blocks(103, 111)).is_err());
+        assert_eq!(blocks::append(103, blocks(103, 110)).unwrap(), 110);
+        assert!(blocks::append(110, blocks(110, 111)).is_err());
+        assert_eq!(blocks::append(108, blocks(108, 110)).unwrap(), 110);
+
+        let res = blocks::get(vec![GetBlocksRequest {
+            start: Nat::from(0u64),
+            length: Nat::from(200u64),
+        }]);
+        assert_eq!(res.log_length, Nat::from(110u64));
+        assert_eq!(res.blocks.len(), 10);
+        assert_eq!(res.blocks[0].block, block(100));
+        assert_eq!(get(108, 5), vec![108, 109]);
+    }
+}
@@ -1,51 +1,72 @@
 use candid::Principal;
 use ic_cose_types::{validate_principals, MILLISECONDS};
+use lib_panda::audit;
+use serde_bytes::ByteArray;
 use std::collections::BTreeSet;
 
 use crate::{is_controller, store, types};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    let mut args = args;
-    store::state::with_mut(|r| {
-        r.managers.append(&mut args);
-        Ok(())
+    store::AUDIT.with("admin_add_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        let mut args = args;
+        store::state::with_mut(|r| {
+            r.managers.append(&mut args);
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_remove_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    store::state::with_mut(|r| {
-        r.managers.retain(|p| !args.contains(p));
-        Ok(())
+    store::AUDIT.with("admin_remove_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        store::state::with_mut(|r| {
+            r.managers.retain(|p| !args.contains(p));
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_canister(kind: types::CanisterKind, id: Principal) -> Result<(), String> {
-    validate_admin_add_canister(kind, id)?;
-    store::state::with_mut(|s| {
-        match kind {
-            types::CanisterKind::OssCluster => {
-                s.ic_oss_cluster = Some(id);
-            }
-            types::CanisterKind::OssBucket => {
-                s.ic_oss_buckets.push(id);
+    store::AUDIT.with("admin_add_canister", audit::digest((&kind, &id)), || {
+        validate_admin_add_canister(kind, id)?;
+        store::state::with_mut(|s| {
+            match kind {
+                types::CanisterKind::OssCluster => {
+                    s.ic_oss_cluster = Some(id);
+                }
+                types::CanisterKind::OssBucket => {
+                    s.ic_oss_buckets.push(id);
+                }
+                types::CanisterKind::Profile => {
+                    s.profile_canisters.push(id);
+                }
             }
-        }
-        Ok(())
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update]
-fn admin_create_channel(input: types::CreateChannelInput) -> Result<types::ChannelInfo, String> {
+async fn admin_create_channel(
+    input: types::CreateChannelInput,
+) -> Result<types::ChannelInfo, String> {
     input.validate()?;
 
     let caller = ic_cdk::api::msg_caller();
-    let now_ms = ic_cdk::api::time() / MILLISECONDS;
     store::state::is_manager(&caller)?;
+    let others: BTreeSet<Principal> = input
+        .managers
+        .keys()
+        .filter(|p| *p != &input.created_by)
+        .cloned()
+        .collect();
+    store::state::check_blocked(others, input.created_by).await?;
+
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
     store::channel::create(caller, input, now_ms)
 }
 
@@ -57,6 +78,17 @@ fn admin_topup_channel(input: types::ChannelTopupInput) -> Result<types::Channel
     store::channel::topup(input.payer, input.id, input.amount, now_ms)
 }
 
+#[ic_cdk::update]
+fn admin_remove_devices(
+    user: Principal,
+    ids: Vec<u32>,
+    ecdh_pubs: Vec<ByteArray<32>>,
+) -> Result<(), String> {
+    store::state::is_profile_canister(&ic_cdk::api::msg_caller())?;
+    store::channel::remove_devices(&user, &ids, &ecdh_pubs);
+    Ok(())
+}
+
 #[ic_cdk::update]
 fn validate_admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
@@ -95,6 +127,11 @@ fn validate_admin_add_canister(kind: types::CanisterKind, id: Principal) -> Resu
                     Err("OSS bucket canister is already added".to_string())?;
                 }
             }
+            types::CanisterKind::Profile => {
+                if s.profile_canisters.contains(&id) {
+                    Err("Profile canister is already added".to_string())?;
+                }
+            }
         }
         Ok("ok".to_string())
     })
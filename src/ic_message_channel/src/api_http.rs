@@ -0,0 +1,52 @@
+use ic_http_certification::HttpRequest;
+use lib_panda::{HttpResponse, MetricsWriter};
+
+use crate::store;
+
+#[ic_cdk::query(hidden = true)]
+fn http_request(request: HttpRequest<'static>) -> HttpResponse {
+    let path = request.get_path().unwrap_or_default();
+    lib_panda::http_metrics(request.method().as_str(), &path, metrics)
+}
+
+fn metrics() -> Vec<u8> {
+    let mut w = MetricsWriter::new();
+    w.gauge(
+        "ic_message_channel_cycles_balance",
+        "Cycles balance of the canister.",
+        ic_cdk::api::canister_cycle_balance(),
+    )
+    .gauge(
+        "ic_message_channel_stable_memory_bytes",
+        "Size of the stable memory in bytes.",
+        ic_cdk::stable::stable_size() * 65536,
+    )
+    .gauge(
+        "ic_message_channel_heap_memory_bytes",
+        "Size of the heap memory in bytes.",
+        lib_panda::heap_memory_size(),
+    );
+    store::state::with(|s| {
+        w.counter(
+            "ic_message_channel_incoming_gas_total",
+            "Gas received by the channels.",
+            s.incoming_gas,
+        )
+        .counter(
+            "ic_message_channel_burned_gas_total",
+            "Gas burned by the channels.",
+            s.burned_gas,
+        );
+    });
+    w.gauge(
+        "ic_message_channel_channels",
+        "Channels in the canister.",
+        store::channel::channels_total(),
+    )
+    .gauge(
+        "ic_message_channel_messages",
+        "Messages in the canister.",
+        store::channel::messages_total(),
+    );
+    w.into_bytes()
+}
@@ -1,5 +1,6 @@
 use ic_cdk::management_canister::{canister_status, CanisterStatusArgs, CanisterStatusResult};
-use ic_cose_types::format_error;
+use ic_cose_types::{format_error, MILLISECONDS};
+use lib_panda::{heap_memory_size, CanisterMetrics};
 use std::collections::BTreeSet;
 
 use crate::{is_authenticated, store, types};
@@ -11,14 +12,26 @@ fn get_state() -> Result<types::StateInfo, String> {
         managers: s.managers.clone(),
         ic_oss_cluster: s.ic_oss_cluster,
         ic_oss_buckets: s.ic_oss_buckets.clone(),
+        profile_canisters: s.profile_canisters.clone(),
         channel_id: s.channel_id,
         incoming_gas: s.incoming_gas,
         burned_gas: s.burned_gas,
         channels_total: store::channel::channels_total(),
         messages_total: store::channel::messages_total(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
     }))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> CanisterMetrics {
+    CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: heap_memory_size(),
+        timestamp: ic_cdk::api::time() / MILLISECONDS,
+    }
+}
+
 #[ic_cdk::query]
 async fn get_canister_status() -> Result<CanisterStatusResult, String> {
     store::state::is_manager(&ic_cdk::api::msg_caller())?;
@@ -94,3 +107,13 @@ fn list_messages(
     let caller = ic_cdk::api::msg_caller();
     store::channel::list_messages(caller, channel, start.unwrap_or(0), end.unwrap_or(0))
 }
+
+#[ic_cdk::query]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller()).or_else(|_| crate::is_controller())?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
+}
@@ -2,7 +2,7 @@ use candid::Principal;
 use ic_cose_types::MILLISECONDS;
 use ic_message_types::profile::UploadImageInput;
 use ic_oss_types::MapValue;
-use std::collections::hash_map::Entry;
+use std::collections::{hash_map::Entry, BTreeSet};
 
 use crate::{
     is_authenticated,
@@ -52,12 +52,20 @@ async fn update_storage(input: types::UpdateChannelStorageInput) -> Result<types
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
-fn update_manager(
+async fn update_manager(
     input: types::UpdateChannelMemberInput,
 ) -> Result<(u64, Option<types::Message>), String> {
     input.validate()?;
 
     let caller = ic_cdk::api::msg_caller();
+    if !store::channel::has_member(input.id, &input.member) {
+        store::state::check_blocked(BTreeSet::from([input.member]), caller).await?;
+    }
+    if !input.devices.is_empty() {
+        let ecdh_pubs = input.devices.iter().map(|d| d.0).collect();
+        store::state::check_devices(input.member, ecdh_pubs).await?;
+    }
+
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
     store::channel::manager_with_mut(caller, input.id, |c| {
         let is_new = match c.managers.entry(input.member) {
@@ -90,6 +98,9 @@ fn update_manager(
         if c.managers.len() > types::MAX_CHANNEL_MANAGERS {
             Err("too many managers".to_string())?;
         }
+        if let Some(s) = c.managers.get_mut(&input.member) {
+            s.set_device_keks(input.devices)?;
+        }
 
         c.updated_at = now_ms;
         if is_new {
@@ -122,12 +133,20 @@ fn update_manager(
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
-fn update_member(
+async fn update_member(
     input: types::UpdateChannelMemberInput,
 ) -> Result<(u64, Option<types::Message>), String> {
     input.validate()?;
 
     let caller = ic_cdk::api::msg_caller();
+    if !store::channel::has_member(input.id, &input.member) {
+        store::state::check_blocked(BTreeSet::from([input.member]), caller).await?;
+    }
+    if !input.devices.is_empty() {
+        let ecdh_pubs = input.devices.iter().map(|d| d.0).collect();
+        store::state::check_devices(input.member, ecdh_pubs).await?;
+    }
+
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
     store::channel::manager_with_mut(caller, input.id, |c| {
         if c.managers.contains_key(&input.member) {
@@ -153,6 +172,9 @@ fn update_member(
         if c.members.len() > types::MAX_CHANNEL_MEMBERS {
             Err("too many members".to_string())?;
         }
+        if let Some(s) = c.members.get_mut(&input.member) {
+            s.set_device_keks(input.devices)?;
+        }
 
         c.updated_at = now_ms;
         if is_new {
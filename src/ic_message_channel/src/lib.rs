@@ -1,9 +1,12 @@
 use candid::{utils::ArgumentEncoder, Principal};
 use ic_cdk::management_canister::CanisterStatusResult;
 use ic_cose_types::ANONYMOUS;
+use lib_panda::CanisterMetrics;
+use serde_bytes::ByteArray;
 use std::collections::BTreeSet;
 
 mod api_admin;
+mod api_http;
 mod api_init;
 mod api_query;
 mod api_update;
@@ -16,6 +19,8 @@ use crate::api_init::ChainArgs;
 static DAO_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 2, 0, 0, 166, 1, 1]);
 // "ql553-iqaaa-aaaap-anuyq-cai" dMsg minter canister id
 static MINTER_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 1, 224, 109, 49, 1, 1]);
+// "nscli-qiaaa-aaaaj-qa4pa-cai" ICPanda Message canister id
+static MESSAGE_CANISTER: Principal = Principal::from_slice(&[0, 0, 0, 0, 1, 48, 7, 30, 1, 1]);
 
 fn is_controller() -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
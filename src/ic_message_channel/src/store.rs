@@ -1,6 +1,7 @@
 use candid::Principal;
 use ciborium::{from_reader, from_reader_with_buffer, into_writer};
 use ic_cose_types::to_cbor_bytes;
+use ic_message_types::profile::UserInfo;
 use ic_oss_types::{
     cose::Token,
     file::{CreateFileInput, CreateFileOutput},
@@ -10,8 +11,9 @@ use ic_oss_types::{
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
+use lib_panda::audit::{AuditLog, AuditLogs};
 use serde::{Deserialize, Serialize};
 use serde_bytes::{ByteArray, ByteBuf};
 use std::{
@@ -20,7 +22,7 @@ use std::{
     collections::{BTreeMap, BTreeSet, HashMap},
 };
 
-use crate::{call, types};
+use crate::{call, types, MESSAGE_CANISTER};
 
 const MESSAGE_PER_USER_GAS: u64 = 10000;
 const MESSAGE_PER_BYTE_GAS: u64 = 1000;
@@ -43,6 +45,8 @@ pub struct State {
     pub ic_oss_cluster: Option<Principal>,
     #[serde(default)]
     pub ic_oss_buckets: Vec<Principal>,
+    #[serde(default)]
+    pub profile_canisters: Vec<Principal>,
 }
 
 impl Storable for State {
@@ -124,6 +128,7 @@ impl Channel {
                     ecdh_pub: None,
                     ecdh_remote: None,
                     updated_at: 0,
+                    devices: vec![],
                 },
                 false,
             )
@@ -134,13 +139,27 @@ impl Channel {
         > = HashMap::new();
         if is_manager {
             for (p, s) in self.managers.iter() {
-                if s.ecdh_pub.is_some() {
-                    ecdh_request.insert(*p, (s.ecdh_pub.unwrap(), s.ecdh_remote.clone()));
+                if let Some(ecdh_pub) = s.ecdh_pub {
+                    ecdh_request.insert(*p, (ecdh_pub, s.ecdh_remote.clone()));
                 }
             }
             for (p, s) in self.members.iter() {
-                if s.ecdh_pub.is_some() {
-                    ecdh_request.insert(*p, (s.ecdh_pub.unwrap(), s.ecdh_remote.clone()));
+                if let Some(ecdh_pub) = s.ecdh_pub {
+                    ecdh_request.insert(*p, (ecdh_pub, s.ecdh_remote.clone()));
+                }
+            }
+        }
+        let mut ecdh_device_request: HashMap<Principal, Vec<ByteArray<32>>> = HashMap::new();
+        if is_manager {
+            for (p, s) in self.managers.iter().chain(self.members.iter()) {
+                let pending: Vec<ByteArray<32>> = s
+                    .devices
+                    .iter()
+                    .filter(|d| d.1.is_none())
+                    .map(|d| d.0)
+                    .collect();
+                if !pending.is_empty() {
+                    ecdh_device_request.insert(*p, pending);
                 }
             }
         }
@@ -166,6 +185,7 @@ impl Channel {
             deleted_messages: self.deleted_messages,
             my_setting,
             ecdh_request,
+            ecdh_device_request,
             files_state: if let Some(storage) = self.file_storage {
                 Some(types::ChannelFilesState {
                     file_storage: storage,
@@ -194,6 +214,8 @@ pub struct ChannelSetting {
     pub ecdh_remote: Option<(ByteArray<32>, ByteBuf)>,
     #[serde(default, rename = "ua")]
     pub updated_at: u64,
+    #[serde(default, rename = "ds")]
+    pub devices: Vec<(ByteArray<32>, Option<(ByteArray<32>, ByteBuf)>)>, // (device ecdh_pub, ecdh_remote)
 }
 
 impl From<ChannelSetting> for types::ChannelSetting {
@@ -205,6 +227,7 @@ impl From<ChannelSetting> for types::ChannelSetting {
             ecdh_pub: s.ecdh_pub,
             ecdh_remote: s.ecdh_remote,
             updated_at: s.updated_at,
+            devices: s.devices,
         }
     }
 }
@@ -218,7 +241,39 @@ impl ChannelSetting {
             ecdh_pub: s.ecdh_pub,
             ecdh_remote: s.ecdh_remote,
             updated_at: now_ms,
+            devices: vec![],
+        }
+    }
+
+    pub fn request_device(&mut self, ecdh_pub: ByteArray<32>) -> Result<(), String> {
+        if !self.devices.iter().any(|d| d.0 == ecdh_pub) {
+            if self.devices.len() >= types::MAX_MEMBER_DEVICES {
+                Err("too many devices".to_string())?;
+            }
+            self.devices.push((ecdh_pub, None));
         }
+        Ok(())
+    }
+
+    // remove_devices removes the devices and the DEK wrapped for them, returns true if any removed.
+    pub fn remove_devices(&mut self, ecdh_pubs: &[ByteArray<32>]) -> bool {
+        let len = self.devices.len();
+        self.devices.retain(|d| !ecdh_pubs.contains(&d.0));
+        self.devices.len() != len
+    }
+
+    // set_device_keks saves the DEK wrapped by a manager for the requested devices.
+    pub fn set_device_keks(
+        &mut self,
+        keks: Vec<(ByteArray<32>, (ByteArray<32>, ByteBuf))>,
+    ) -> Result<(), String> {
+        for (ecdh_pub, remote) in keks {
+            match self.devices.iter_mut().find(|d| d.0 == ecdh_pub) {
+                Some(d) => d.1 = Some(remote),
+                None => Err("device ECDH request not found".to_string())?,
+            }
+        }
+        Ok(())
     }
 }
 
@@ -318,6 +373,8 @@ impl Storable for MessageId {
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
 const CHANNEL_MEMORY_ID: MemoryId = MemoryId::new(1);
 const MESSAGE_MEMORY_ID: MemoryId = MemoryId::new(2);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -343,8 +400,17 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(MESSAGE_MEMORY_ID)),
         )
     );
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
 }
 
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
 pub mod state {
     use super::*;
 
@@ -385,6 +451,89 @@ pub mod state {
         });
     }
 
+    // check_blocked returns an error if any of the users has blocked the caller.
+    // It asks only the profile canisters that hold the users' profiles, and fails
+    // if a profile canister is not known to this canister.
+    pub async fn check_blocked(
+        users: BTreeSet<Principal>,
+        caller: Principal,
+    ) -> Result<(), String> {
+        if users.is_empty() {
+            return Ok(());
+        }
+
+        let profile_canisters = with(|s| s.profile_canisters.clone());
+        if profile_canisters.is_empty() {
+            return Err("no profile canister".to_string());
+        }
+
+        // users without a profile have not blocked anyone
+        let res: Result<Vec<UserInfo>, String> =
+            call(MESSAGE_CANISTER, "batch_get_users", (users,), 0).await?;
+        let mut owners: BTreeMap<Principal, BTreeSet<Principal>> = BTreeMap::new();
+        for user in res? {
+            if !profile_canisters.contains(&user.profile_canister) {
+                return Err(format!(
+                    "unknown profile canister {}",
+                    user.profile_canister.to_text()
+                ));
+            }
+            owners
+                .entry(user.profile_canister)
+                .or_default()
+                .insert(user.id);
+        }
+
+        for (canister, users) in owners {
+            let res: Result<Vec<Principal>, String> =
+                call(canister, "admin_check_blocked", (users, caller), 0).await?;
+            if let Some(user) = res?.first() {
+                return Err(format!("caller is blocked by {}", user.to_text()));
+            }
+        }
+        Ok(())
+    }
+
+    // check_devices returns an error if any of the keys is not an active device of the user,
+    // it asks the profile canister that owns the user's profile only.
+    pub async fn check_devices(
+        user: Principal,
+        ecdh_pubs: Vec<ByteArray<32>>,
+    ) -> Result<(), String> {
+        let profile_canisters = with(|s| s.profile_canisters.clone());
+        let res: Result<Vec<UserInfo>, String> = call(
+            MESSAGE_CANISTER,
+            "batch_get_users",
+            (BTreeSet::from([user]),),
+            0,
+        )
+        .await?;
+        let canister = res?
+            .first()
+            .map(|u| u.profile_canister)
+            .ok_or_else(|| "profile not found".to_string())?;
+        if !profile_canisters.contains(&canister) {
+            return Err(format!("unknown profile canister {}", canister.to_text()));
+        }
+
+        let res: Result<bool, String> =
+            call(canister, "admin_check_devices", (user, ecdh_pubs), 0).await?;
+        if !res? {
+            return Err("device is not registered or revoked".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn is_profile_canister(caller: &Principal) -> Result<(), String> {
+        with(|s| {
+            if s.profile_canisters.contains(caller) {
+                Ok(())
+            } else {
+                Err("caller is not a profile canister".to_string())
+            }
+        })
+    }
+
     pub fn load() {
         let mut scratch = [0; 4096];
         STATE_STORE.with(|r| {
@@ -419,6 +568,35 @@ pub mod channel {
         MESSAGE_STORE.with(|r| r.borrow().len())
     }
 
+    pub fn has_member(id: u32, user: &Principal) -> bool {
+        CHANNEL_STORE.with(|r| {
+            r.borrow()
+                .get(&id)
+                .is_some_and(|v| v.managers.contains_key(user) || v.members.contains_key(user))
+        })
+    }
+
+    // remove_devices removes the user's devices from the channels.
+    pub fn remove_devices(user: &Principal, ids: &[u32], ecdh_pubs: &[ByteArray<32>]) {
+        CHANNEL_STORE.with(|r| {
+            let mut m = r.borrow_mut();
+            for id in ids {
+                if let Some(mut c) = m.get(id) {
+                    let removed = match c.managers.get_mut(user) {
+                        Some(s) => s.remove_devices(ecdh_pubs),
+                        None => c
+                            .members
+                            .get_mut(user)
+                            .is_some_and(|s| s.remove_devices(ecdh_pubs)),
+                    };
+                    if removed {
+                        m.insert(*id, c);
+                    }
+                }
+            }
+        })
+    }
+
     pub fn manager_with<R>(
         caller: Principal,
         id: u32,
@@ -594,6 +772,10 @@ pub mod channel {
         input: types::UpdateMySettingInput,
         now_ms: u64,
     ) -> Result<types::ChannelSetting, String> {
+        if let Some(ecdh_pub) = input.device_ecdh_pub {
+            state::check_devices(caller, vec![ecdh_pub]).await?;
+        }
+
         let (try_mint_payer, setting) = CHANNEL_STORE.with(|r| {
             let mut m = r.borrow_mut();
             match m.get(&input.id) {
@@ -623,6 +805,10 @@ pub mod channel {
                         setting.mute = mute;
                     }
 
+                    if let Some(ecdh_pub) = input.device_ecdh_pub {
+                        setting.request_device(ecdh_pub)?;
+                    }
+
                     let mut try_mint_payer: Option<Principal> = None;
                     if let Some(ecdh) = input.ecdh {
                         setting.ecdh_pub = ecdh.ecdh_pub;
@@ -10,15 +10,18 @@ pub struct StateInfo {
     pub managers: BTreeSet<Principal>,
     pub ic_oss_cluster: Option<Principal>,
     pub ic_oss_buckets: Vec<Principal>,
+    pub profile_canisters: Vec<Principal>,
     pub channel_id: u32,
     pub channels_total: u64,
     pub messages_total: u64,
     pub incoming_gas: u128,
     pub burned_gas: u128,
+    pub stable_memory_size: u64, // bytes
 }
 
 #[derive(CandidType, Copy, Clone, Debug, Deserialize, Serialize)]
 pub enum CanisterKind {
     OssCluster,
     OssBucket,
+    Profile,
 }
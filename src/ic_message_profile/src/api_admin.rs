@@ -1,5 +1,6 @@
 use candid::Principal;
 use ic_cose_types::{validate_principals, MILLISECONDS};
+use lib_panda::{audit, bytes32_from_base64, VerifyingKey};
 use serde_bytes::ByteArray;
 use std::collections::BTreeSet;
 
@@ -7,36 +8,53 @@ use crate::{is_controller, store, types};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    let mut args = args;
-    store::state::with_mut(|r| {
-        r.managers.append(&mut args);
-        Ok(())
+    store::AUDIT.with("admin_add_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        let mut args = args;
+        store::state::with_mut(|r| {
+            r.managers.append(&mut args);
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_remove_managers(args: BTreeSet<Principal>) -> Result<(), String> {
-    validate_principals(&args)?;
-    store::state::with_mut(|r| {
-        r.managers.retain(|p| !args.contains(p));
-        Ok(())
+    store::AUDIT.with("admin_remove_managers", audit::digest((&args,)), || {
+        validate_principals(&args)?;
+        store::state::with_mut(|r| {
+            r.managers.retain(|p| !args.contains(p));
+            Ok(())
+        })
     })
 }
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_add_canister(kind: types::CanisterKind, id: Principal) -> Result<(), String> {
-    validate_admin_add_canister(kind, id)?;
-    store::state::with_mut(|s| {
-        match kind {
-            types::CanisterKind::OssCluster => {
-                s.ic_oss_cluster = Some(id);
-            }
-            types::CanisterKind::OssBucket => {
-                s.ic_oss_buckets.push(id);
+    store::AUDIT.with("admin_add_canister", audit::digest((&kind, &id)), || {
+        validate_admin_add_canister(kind, id)?;
+        store::state::with_mut(|s| {
+            match kind {
+                types::CanisterKind::OssCluster => {
+                    s.ic_oss_cluster = Some(id);
+                }
+                types::CanisterKind::OssBucket => {
+                    s.ic_oss_buckets.push(id);
+                }
             }
-        }
-        Ok(())
+            Ok(())
+        })
+    })
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn admin_set_link_verifier(key: String) -> Result<(), String> {
+    store::AUDIT.with("admin_set_link_verifier", audit::digest((&key,)), || {
+        let key = validate_link_verifier(&key)?;
+        store::state::with_mut(|s| {
+            s.link_verifier = Some(key.into());
+            Ok(())
+        })
     })
 }
 
@@ -56,6 +74,28 @@ fn admin_update_profile_ecdh_pub(user: Principal, ecdh_pub: ByteArray<32>) -> Re
     store::profile::update_profile_ecdh_pub(user, now_ms, ecdh_pub)
 }
 
+#[ic_cdk::update]
+fn admin_update_profile_names(names: Vec<(Principal, String)>) -> Result<(), String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller())?;
+    store::profile::update_names(names);
+    Ok(())
+}
+
+#[ic_cdk::query]
+fn admin_check_devices(user: Principal, ecdh_pubs: Vec<ByteArray<32>>) -> Result<bool, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller())?;
+    store::profile::check_devices(&user, &ecdh_pubs)
+}
+
+#[ic_cdk::query]
+fn admin_check_blocked(
+    owners: BTreeSet<Principal>,
+    user: Principal,
+) -> Result<Vec<Principal>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller())?;
+    Ok(store::profile::check_blocked(owners, user))
+}
+
 #[ic_cdk::update]
 fn validate_admin_add_managers(args: BTreeSet<Principal>) -> Result<(), String> {
     validate_principals(&args)?;
@@ -98,3 +138,21 @@ fn validate_admin_add_canister(kind: types::CanisterKind, id: Principal) -> Resu
         Ok("ok".to_string())
     })
 }
+
+#[ic_cdk::update]
+fn validate_admin_set_link_verifier(key: String) -> Result<(), String> {
+    validate_link_verifier(&key)?;
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn validate2_admin_set_link_verifier(key: String) -> Result<String, String> {
+    validate_link_verifier(&key)?;
+    Ok("ok".to_string())
+}
+
+fn validate_link_verifier(key: &str) -> Result<[u8; 32], String> {
+    let key = bytes32_from_base64(key)?;
+    VerifyingKey::from_bytes(&key).map_err(|_| "invalid Ed25519 public key".to_string())?;
+    Ok(key)
+}
@@ -0,0 +1,35 @@
+use ic_http_certification::HttpRequest;
+use lib_panda::{HttpResponse, MetricsWriter};
+
+use crate::store;
+
+#[ic_cdk::query(hidden = true)]
+fn http_request(request: HttpRequest<'static>) -> HttpResponse {
+    let path = request.get_path().unwrap_or_default();
+    lib_panda::http_metrics(request.method().as_str(), &path, metrics)
+}
+
+fn metrics() -> Vec<u8> {
+    let mut w = MetricsWriter::new();
+    w.gauge(
+        "ic_message_profile_cycles_balance",
+        "Cycles balance of the canister.",
+        ic_cdk::api::canister_cycle_balance(),
+    )
+    .gauge(
+        "ic_message_profile_stable_memory_bytes",
+        "Size of the stable memory in bytes.",
+        ic_cdk::stable::stable_size() * 65536,
+    )
+    .gauge(
+        "ic_message_profile_heap_memory_bytes",
+        "Size of the heap memory in bytes.",
+        lib_panda::heap_memory_size(),
+    )
+    .gauge(
+        "ic_message_profile_profiles",
+        "Profiles in the canister.",
+        store::profile::profiles_total(),
+    );
+    w.into_bytes()
+}
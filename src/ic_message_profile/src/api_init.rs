@@ -1,8 +1,9 @@
 use candid::{CandidType, Principal};
+use ic_cose_types::MILLISECONDS;
 use serde::Deserialize;
-use std::collections::BTreeSet;
+use std::{collections::BTreeSet, time::Duration};
 
-use crate::store;
+use crate::{store, types};
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
 pub enum ChainArgs {
@@ -38,6 +39,8 @@ fn init(args: Option<ChainArgs>) {
             );
         }
     }
+
+    set_timers();
 }
 
 #[ic_cdk::pre_upgrade]
@@ -67,4 +70,23 @@ fn post_upgrade(args: Option<ChainArgs>) {
         }
         _ => {}
     }
+
+    set_timers();
+}
+
+// set_timers registers the background jobs, timers are cleared by an upgrade.
+fn set_timers() {
+    ic_cdk_timers::set_timer(
+        Duration::from_secs(0),
+        store::state::init_challenge_secret(),
+    );
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || async {
+        store::token::refresh_queued(ic_cdk::api::time() / MILLISECONDS).await;
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || async {
+        store::search::backfill(types::SEARCH_BACKFILL_BATCH);
+    });
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(60), || async {
+        store::device::propagate_revocations().await;
+    });
 }
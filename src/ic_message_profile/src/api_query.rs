@@ -1,8 +1,11 @@
 use candid::Principal;
 use ic_cdk::management_canister::{canister_status, CanisterStatusArgs, CanisterStatusResult};
-use ic_cose_types::format_error;
+use ic_cose_types::{format_error, MILLISECONDS};
+use lib_panda::Challenge;
+use lib_panda::{heap_memory_size, CanisterMetrics};
+use serde_bytes::ByteBuf;
 
-use crate::{store, types};
+use crate::{is_authenticated, store, types};
 
 #[ic_cdk::query]
 fn get_state() -> Result<types::StateInfo, String> {
@@ -11,10 +14,22 @@ fn get_state() -> Result<types::StateInfo, String> {
         managers: s.managers.clone(),
         ic_oss_cluster: s.ic_oss_cluster,
         ic_oss_buckets: s.ic_oss_buckets.clone(),
+        link_verifier: s.link_verifier,
         profiles_total: store::profile::profiles_total(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
     }))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> CanisterMetrics {
+    CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: heap_memory_size(),
+        timestamp: ic_cdk::api::time() / MILLISECONDS,
+    }
+}
+
 #[ic_cdk::query]
 async fn get_canister_status() -> Result<CanisterStatusResult, String> {
     store::state::is_manager(&ic_cdk::api::msg_caller())?;
@@ -31,5 +46,51 @@ async fn get_canister_status() -> Result<CanisterStatusResult, String> {
 fn get_profile(user: Option<Principal>) -> Result<types::ProfileInfo, String> {
     let caller = ic_cdk::api::msg_caller();
     let user = user.unwrap_or(caller);
-    store::profile::get(user, caller == user)
+    store::profile::get(user, caller)
+}
+
+#[ic_cdk::query]
+fn get_profile_tokens(user: Option<Principal>) -> Result<Vec<types::ProfileToken>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let user = user.unwrap_or(caller);
+    store::profile::get_tokens(user, caller)
+}
+
+#[ic_cdk::query]
+fn search_profiles(
+    query: String,
+    prev: Option<u64>,
+    take: Option<u32>,
+    prev_id: Option<Principal>,
+) -> Result<Vec<types::ProfileInfo>, String> {
+    if query.len() > 256 {
+        return Err("query is too long".to_string());
+    }
+    let take = take.unwrap_or(20).min(100) as usize;
+    Ok(store::profile::search(&query, prev, prev_id, take))
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn link_challenge(uri: String) -> Result<ByteBuf, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !store::profile::has_link(&caller, &uri) {
+        return Err("link not found".to_string());
+    }
+
+    let now_sec = ic_cdk::api::time() / MILLISECONDS / 1000;
+    let secret = store::state::challenge_secret()?;
+    Ok(ByteBuf::from((caller, &uri).challenge(
+        secret.as_slice(),
+        now_sec + types::LINK_CHALLENGE_EXPIRE_SEC,
+    )))
+}
+
+#[ic_cdk::query]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    store::state::is_manager(&ic_cdk::api::msg_caller()).or_else(|_| crate::is_controller())?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
 }
@@ -1,8 +1,11 @@
-use candid::Principal;
+use candid::{Nat, Principal};
 use ic_cose_types::MILLISECONDS;
-use serde_bytes::ByteArray;
+use lib_panda::{Challenge, ChallengeState, Ed25519Message, VerifyingKey};
+use num_traits::cast::ToPrimitive;
+use serde_bytes::{ByteArray, ByteBuf};
+use std::collections::{BTreeMap, BTreeSet};
 
-use crate::{store, types};
+use crate::{is_authenticated, store, types};
 
 #[ic_cdk::update]
 fn update_profile(input: types::UpdateProfileInput) -> Result<types::ProfileInfo, String> {
@@ -14,10 +17,50 @@ fn update_profile(input: types::UpdateProfileInput) -> Result<types::ProfileInfo
 }
 
 #[ic_cdk::update]
-fn update_profile_ecdh_pub(ecdh_pub: ByteArray<32>) -> Result<(), String> {
+fn update_profile_ecdh_pub(
+    ecdh_pub: ByteArray<32>,
+    device: Option<types::UpdateDeviceInput>,
+) -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::profile::update_profile_ecdh_pub(caller, now_ms, ecdh_pub)
+    match device {
+        None => store::profile::update_profile_ecdh_pub(caller, now_ms, ecdh_pub),
+        Some(input) => {
+            input.validate()?;
+            store::profile::update_device(caller, now_ms, ecdh_pub, input)
+        }
+    }
+}
+
+#[ic_cdk::update]
+fn update_privacy(privacy: u8) -> Result<(), String> {
+    if privacy > types::PROFILE_PRIVACY_PRIVATE {
+        return Err("invalid privacy".to_string());
+    }
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::profile::with_mut(caller, |p| {
+        p.privacy = privacy;
+        p.active_at = now_ms;
+        Ok(())
+    })
+}
+
+#[ic_cdk::update]
+fn block_users(users: BTreeSet<Principal>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if users.contains(&caller) {
+        return Err("cannot block self".to_string());
+    }
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::profile::block_users(caller, now_ms, users)
+}
+
+#[ic_cdk::update]
+fn unblock_users(users: BTreeSet<Principal>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::profile::unblock_users(caller, now_ms, users)
 }
 
 #[ic_cdk::update]
@@ -30,11 +73,28 @@ fn update_links(links: Vec<types::Link>) -> Result<(), String> {
     }
     let caller = ic_cdk::api::msg_caller();
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::profile::with_mut(caller, |p| {
-        p.links = links;
-        p.active_at = now_ms;
-        Ok(())
-    })
+    store::profile::update_links(caller, now_ms, links)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn verify_link(input: types::VerifyLinkInput) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    let now_sec = now_ms / 1000;
+    let secret = store::state::challenge_secret()?;
+    let verifier = store::state::with(|s| s.link_verifier);
+    let verifier = verifier.ok_or_else(|| "link verifier not set".to_string())?;
+    let verifier =
+        VerifyingKey::from_bytes(&verifier).map_err(|_| "invalid link verifier".to_string())?;
+
+    (caller, &input.uri).verify(secret.as_slice(), now_sec, &input.challenge)?;
+    let att: ChallengeState<(String, ByteBuf)> =
+        Ed25519Message::verify(&verifier, &input.attestation)?;
+    if !att.is_valid(&caller, &(input.uri.clone(), input.challenge), now_sec) {
+        return Err("invalid link attestation".to_string());
+    }
+
+    store::profile::verify_link(caller, now_ms, &input.uri)
 }
 
 #[ic_cdk::update]
@@ -56,11 +116,25 @@ fn update_tokens(tokens: Vec<Principal>) -> Result<(), String> {
 
     let caller = ic_cdk::api::msg_caller();
     let now_ms = ic_cdk::api::time() / MILLISECONDS;
-    store::profile::with_mut(caller, |p| {
-        p.tokens = tokens;
-        p.active_at = now_ms;
-        Ok(())
-    })
+    store::profile::update_tokens(caller, now_ms, tokens.clone())?;
+    store::token::enqueue_stale(&tokens, now_ms);
+    Ok(())
+}
+
+#[ic_cdk::update]
+fn update_token_balances(balances: Vec<(Principal, Nat)>) -> Result<(), String> {
+    if balances.len() > types::MAX_PROFILE_TOKENS {
+        return Err("too many tokens".to_string());
+    }
+    let mut snapshot = BTreeMap::new();
+    for (ledger, balance) in balances {
+        let balance = balance.0.to_u128().ok_or("invalid token balance")?;
+        snapshot.insert(ledger, balance);
+    }
+
+    let caller = ic_cdk::api::msg_caller();
+    let now_ms = ic_cdk::api::time() / MILLISECONDS;
+    store::profile::update_token_balances(caller, now_ms, snapshot)
 }
 
 #[ic_cdk::update]
@@ -1,10 +1,12 @@
-use candid::{utils::ArgumentEncoder, Principal};
+use candid::{utils::ArgumentEncoder, Nat, Principal};
 use ic_cdk::management_canister::CanisterStatusResult;
 use ic_cose_types::ANONYMOUS;
-use serde_bytes::ByteArray;
+use lib_panda::CanisterMetrics;
+use serde_bytes::{ByteArray, ByteBuf};
 use std::collections::BTreeSet;
 
 mod api_admin;
+mod api_http;
 mod api_init;
 mod api_query;
 mod api_update;
@@ -25,7 +27,6 @@ fn is_controller() -> Result<(), String> {
     }
 }
 
-#[allow(dead_code)]
 fn is_authenticated() -> Result<(), String> {
     if ic_cdk::api::msg_caller() == ANONYMOUS {
         Err("anonymous user is not allowed".to_string())
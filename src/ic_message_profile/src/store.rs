@@ -8,17 +8,18 @@ use ic_oss_types::{
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
+use lib_panda::audit::{AuditLog, AuditLogs};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
 use std::{
     borrow::Cow,
     cell::RefCell,
-    collections::{BTreeSet, HashMap},
+    collections::{BTreeMap, BTreeSet, HashMap},
 };
 
-use crate::{call, types};
+use crate::{call, types, ANONYMOUS};
 
 type Memory = VirtualMemory<DefaultMemoryImpl>;
 
@@ -30,6 +31,18 @@ pub struct State {
     pub ic_oss_cluster: Option<Principal>,
     #[serde(default)]
     pub ic_oss_buckets: Vec<Principal>,
+    #[serde(default)]
+    pub challenge_secret: ByteArray<32>,
+    #[serde(default)]
+    pub link_verifier: Option<ByteArray<32>>, // Ed25519 public key of the off-chain link verifier
+    #[serde(default)]
+    pub token_queue: BTreeSet<Principal>, // token ledgers waiting for metadata resolution
+    #[serde(default)]
+    pub search_backfilled: bool, // whether the profiles before the indexes are indexed
+    #[serde(default)]
+    pub search_backfill_prev: Option<Principal>, // the last profile indexed by the backfill
+    #[serde(default)]
+    pub device_revocations: Vec<(Principal, ByteArray<32>)>, // revoked devices to remove from channels
 }
 
 impl Storable for State {
@@ -72,31 +85,98 @@ pub struct Profile {
     pub links: Vec<types::Link>,
     #[serde(default, rename = "t")]
     pub tokens: Vec<Principal>, // token ledger canister
+    #[serde(default, rename = "bl")]
+    pub blocked: BTreeSet<Principal>,
+    #[serde(default, rename = "pv")]
+    pub privacy: u8, // 0: public; 1: following; 2: private
+    #[serde(default, rename = "tb")]
+    pub token_balances: BTreeMap<Principal, (u128, u64)>, // ledger -> (balance, snapshot time)
+    #[serde(default, rename = "n")]
+    pub name: String, // display name synced from ic_message, only used for search
+    #[serde(default, rename = "ds")]
+    pub devices: Vec<Device>,
+    #[serde(default, rename = "ix")]
+    pub indexed: bool, // whether the name and bio are in the search index
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Device {
+    #[serde(rename = "i")]
+    pub id: u32,
+    #[serde(rename = "ep")]
+    pub ecdh_pub: ByteArray<32>,
+    #[serde(rename = "l")]
+    pub label: String,
+    #[serde(rename = "a")]
+    pub added_at: u64,
+    #[serde(rename = "r")]
+    pub revoked: bool,
+}
+
+impl From<Device> for types::ProfileDevice {
+    fn from(d: Device) -> Self {
+        types::ProfileDevice {
+            id: d.id,
+            ecdh_pub: d.ecdh_pub,
+            label: d.label,
+            added_at: d.added_at,
+            revoked: d.revoked,
+        }
+    }
 }
 
 impl Profile {
+    // is_visible_to returns true if the viewer can see the profile's links, tokens and active_at.
+    pub fn is_visible_to(&self, id: &Principal, viewer: &Principal) -> bool {
+        if id == viewer {
+            return true;
+        }
+        match self.privacy {
+            types::PROFILE_PRIVACY_PUBLIC => true,
+            types::PROFILE_PRIVACY_FOLLOWING => self.following.contains(viewer),
+            _ => false,
+        }
+    }
+
     pub fn into_info(
         self,
-        caller: Principal,
+        id: Principal,
         canister: Principal,
-        is_caller: bool,
+        viewer: Principal,
     ) -> types::ProfileInfo {
+        let is_owner = id == viewer;
+        if !is_owner && self.blocked.contains(&viewer) {
+            return types::ProfileInfo {
+                id,
+                canister,
+                bio: "".to_string(),
+                active_at: 0,
+                created_at: self.created_at,
+                image_file: None,
+                links: vec![],
+                tokens: vec![],
+                ecdh_pub: None,
+                following: None,
+                channels: None,
+                privacy: None,
+                blocked: None,
+                devices: None,
+            };
+        }
+
+        let visible = self.is_visible_to(&id, &viewer);
         types::ProfileInfo {
-            id: caller,
+            id,
             canister,
             bio: self.bio,
-            active_at: self.active_at,
+            active_at: if visible { self.active_at } else { 0 },
             created_at: self.created_at,
             image_file: self.image_file,
-            links: self.links,
-            tokens: self.tokens,
+            links: if visible { self.links } else { vec![] },
+            tokens: if visible { self.tokens } else { vec![] },
             ecdh_pub: self.ecdh_pub,
-            following: if is_caller {
-                Some(self.following)
-            } else {
-                None
-            },
-            channels: if is_caller {
+            following: if is_owner { Some(self.following) } else { None },
+            channels: if is_owner {
                 Some(
                     self.channels
                         .into_iter()
@@ -115,6 +195,13 @@ impl Profile {
             } else {
                 None
             },
+            privacy: if is_owner { Some(self.privacy) } else { None },
+            blocked: if is_owner { Some(self.blocked) } else { None },
+            devices: if is_owner {
+                Some(self.devices.into_iter().map(|d| d.into()).collect())
+            } else {
+                None
+            },
         }
     }
 }
@@ -149,8 +236,94 @@ impl Storable for Profile {
     }
 }
 
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct TokenMeta {
+    #[serde(rename = "n")]
+    pub name: String,
+    #[serde(rename = "s")]
+    pub symbol: String,
+    #[serde(rename = "d")]
+    pub decimals: u8,
+    #[serde(rename = "f")]
+    pub fee: u128,
+    #[serde(rename = "l")]
+    pub logo: Option<String>,
+    #[serde(rename = "u")]
+    pub updated_at: u64,
+}
+
+impl TokenMeta {
+    pub fn into_metadata(self) -> types::TokenMetadata {
+        types::TokenMetadata {
+            name: self.name,
+            symbol: self.symbol,
+            decimals: self.decimals,
+            fee: self.fee.into(),
+            logo: self.logo,
+            updated_at: self.updated_at,
+        }
+    }
+}
+
+impl Storable for TokenMeta {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode TokenMeta data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode TokenMeta data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode TokenMeta data")
+    }
+}
+
+// (token, profile id), encoded as token bytes + 0x00 + principal bytes
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SearchKey(pub String, pub Principal);
+
+impl Storable for SearchKey {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let id = self.1.as_slice();
+        let mut buf = Vec::with_capacity(self.0.len() + 1 + id.len());
+        buf.extend_from_slice(self.0.as_bytes());
+        buf.push(0);
+        buf.extend_from_slice(id);
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        let i = bytes
+            .iter()
+            .position(|b| *b == 0)
+            .expect("failed to decode SearchKey data");
+        SearchKey(
+            String::from_utf8(bytes[..i].to_vec()).expect("failed to decode SearchKey data"),
+            Principal::from_slice(&bytes[i + 1..]),
+        )
+    }
+}
+
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
 const PROFILE_MEMORY_ID: MemoryId = MemoryId::new(1);
+const TOKEN_MEMORY_ID: MemoryId = MemoryId::new(2);
+const SEARCH_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(4);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(5);
+const RECENT_INDEX_MEMORY_ID: MemoryId = MemoryId::new(6);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -172,8 +345,36 @@ thread_local! {
         )
     );
 
+    static TOKEN_STORE: RefCell<StableBTreeMap<Principal, TokenMeta, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(TOKEN_MEMORY_ID)),
+        )
+    );
+
+    static SEARCH_INDEX: RefCell<StableBTreeMap<SearchKey, (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(SEARCH_INDEX_MEMORY_ID)),
+        )
+    );
+
+    // (active_at, user), profiles in the order of activity
+    static RECENT_INDEX: RefCell<StableBTreeMap<(u64, Principal), (), Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(RECENT_INDEX_MEMORY_ID)),
+        )
+    );
+
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
 }
 
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
 pub mod state {
     use super::*;
 
@@ -192,6 +393,32 @@ pub mod state {
         })
     }
 
+    // challenge_secret returns an error until the secret is initialized by the init timer.
+    pub fn challenge_secret() -> Result<ByteArray<32>, String> {
+        let secret = with(|s| s.challenge_secret);
+        if secret.iter().all(|b| *b == 0) {
+            return Err("challenge secret is not initialized".to_string());
+        }
+        Ok(secret)
+    }
+
+    pub async fn init_challenge_secret() {
+        if with(|s| s.challenge_secret.iter().any(|b| *b != 0)) {
+            return;
+        }
+
+        let mut data = ic_cdk::management_canister::raw_rand()
+            .await
+            .expect("failed to generate challenge secret");
+        data.truncate(32);
+        let secret: [u8; 32] = data
+            .try_into()
+            .expect("failed to generate challenge secret");
+        STATE.with_borrow_mut(|r| {
+            r.challenge_secret = secret.into();
+        });
+    }
+
     pub fn load() {
         let mut scratch = [0; 4096];
         STATE_STORE.with(|r| {
@@ -231,13 +458,27 @@ pub mod profile {
             let mut m = r.borrow_mut();
             match m.get(&user) {
                 None => Err("profile not found".to_string()),
-                Some(mut p) => f(&mut p).inspect(|_r| {
-                    m.insert(user, p);
-                }),
+                Some(mut p) => {
+                    let active_at = p.active_at;
+                    f(&mut p).inspect(|_r| {
+                        save(&mut m, user, Some(active_at), p);
+                    })
+                }
             }
         })
     }
 
+    // save stores the profile and moves it in the recency index.
+    fn save(
+        m: &mut StableBTreeMap<Principal, Profile, Memory>,
+        user: Principal,
+        prev_active_at: Option<u64>,
+        p: Profile,
+    ) {
+        search::touch(user, prev_active_at, p.active_at);
+        m.insert(user, p);
+    }
+
     pub fn upsert(
         user: Principal,
         now_ms: u64,
@@ -247,6 +488,7 @@ pub mod profile {
             let mut m = r.borrow_mut();
             match m.get(&user) {
                 Some(mut p) => {
+                    let active_at = p.active_at;
                     p.active_at = now_ms;
                     if let Some(cid) = channel {
                         let max_pin = p.channels.values().map(|v| v.pin).max().unwrap_or_default();
@@ -259,7 +501,7 @@ pub mod profile {
                             },
                         );
                     }
-                    m.insert(user, p);
+                    save(&mut m, user, Some(active_at), p);
                 }
                 None => {
                     let mut p = Profile {
@@ -270,7 +512,7 @@ pub mod profile {
                     if let Some(cid) = channel {
                         p.channels.insert(cid, ChannelSetting::default());
                     }
-                    m.insert(user, p);
+                    save(&mut m, user, None, p);
                 }
             }
 
@@ -287,9 +529,10 @@ pub mod profile {
             let mut m = r.borrow_mut();
             match m.get(&user) {
                 Some(mut p) => {
+                    let active_at = p.active_at;
                     p.ecdh_pub = Some(ecdh_pub);
                     p.active_at = now_ms;
-                    m.insert(user, p);
+                    save(&mut m, user, Some(active_at), p);
                     Ok(())
                 }
                 None => Err("profile not found".to_string()),
@@ -297,6 +540,82 @@ pub mod profile {
         })
     }
 
+    // update_device adds the ECDH public key as a new device, or updates the existing one.
+    // The first device key becomes the profile's ecdh_pub, and a revoked ecdh_pub is replaced
+    // by the latest active device key. A revoked device is queued for removal from channels.
+    pub fn update_device(
+        user: Principal,
+        now_ms: u64,
+        ecdh_pub: ByteArray<32>,
+        input: types::UpdateDeviceInput,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            match p.devices.iter_mut().find(|d| d.ecdh_pub == ecdh_pub) {
+                Some(d) => {
+                    if d.revoked {
+                        return Err("device is revoked".to_string());
+                    }
+                    d.label = input.label;
+                    d.revoked = input.revoked;
+                }
+                None => {
+                    if input.revoked {
+                        return Err("device not found".to_string());
+                    }
+                    if p.devices.iter().filter(|d| !d.revoked).count() >= types::MAX_PROFILE_DEVICES
+                    {
+                        return Err("devices limit exceeded".to_string());
+                    }
+                    let id = p.devices.iter().map(|d| d.id).max().unwrap_or_default() + 1;
+                    // keep the latest revoked devices only
+                    let revoked = p.devices.iter().filter(|d| d.revoked).count();
+                    if revoked >= types::MAX_PROFILE_DEVICES {
+                        if let Some(i) = p.devices.iter().position(|d| d.revoked) {
+                            p.devices.remove(i);
+                        }
+                    }
+                    p.devices.push(Device {
+                        id,
+                        ecdh_pub,
+                        label: input.label,
+                        added_at: now_ms,
+                        revoked: false,
+                    });
+                }
+            }
+
+            if p.ecdh_pub.is_none() || (input.revoked && p.ecdh_pub == Some(ecdh_pub)) {
+                p.ecdh_pub = p
+                    .devices
+                    .iter()
+                    .rev()
+                    .find(|d| !d.revoked)
+                    .map(|d| d.ecdh_pub);
+            }
+            p.active_at = now_ms;
+            Ok(())
+        })?;
+
+        if input.revoked {
+            state::with_mut(|s| {
+                if !s.device_revocations.contains(&(user, ecdh_pub)) {
+                    s.device_revocations.push((user, ecdh_pub));
+                }
+            });
+        }
+        Ok(())
+    }
+
+    // check_devices returns true if all the keys are the user's active devices.
+    pub fn check_devices(user: &Principal, ecdh_pubs: &[ByteArray<32>]) -> Result<bool, String> {
+        PROFILE_STORE.with(|r| match r.borrow().get(user) {
+            None => Err("profile not found".to_string()),
+            Some(p) => Ok(ecdh_pubs
+                .iter()
+                .all(|k| p.devices.iter().any(|d| !d.revoked && &d.ecdh_pub == k))),
+        })
+    }
+
     pub fn update(
         user: Principal,
         now_ms: u64,
@@ -306,10 +625,6 @@ pub mod profile {
             let mut m = r.borrow_mut();
             match m.get(&user) {
                 Some(mut p) => {
-                    if let Some(bio) = input.bio {
-                        p.bio = bio;
-                    }
-
                     if !input.unfollow.is_empty() {
                         for u in input.unfollow {
                             p.following.remove(&u);
@@ -340,15 +655,72 @@ pub mod profile {
                         }
                     }
 
+                    if let Some(bio) = input.bio {
+                        search::reindex(
+                            user,
+                            p.indexed.then_some((&p.name, &p.bio)),
+                            (&p.name, &bio),
+                        );
+                        p.bio = bio;
+                        p.indexed = true;
+                    }
+
+                    let active_at = p.active_at;
                     p.active_at = now_ms;
-                    m.insert(user, p.clone());
-                    Ok(p.into_info(user, ic_cdk::api::canister_self(), true))
+                    save(&mut m, user, Some(active_at), p.clone());
+                    Ok(p.into_info(user, ic_cdk::api::canister_self(), user))
                 }
                 None => Err("profile not found".to_string()),
             }
         })
     }
 
+    pub fn update_links(
+        user: Principal,
+        now_ms: u64,
+        links: Vec<types::Link>,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            // keep the verification of links whose uri is unchanged
+            let verified: HashMap<String, u64> = p
+                .links
+                .iter()
+                .filter_map(|l| l.verified_at.map(|t| (l.uri.clone(), t)))
+                .collect();
+            p.links = links
+                .into_iter()
+                .map(|mut l| {
+                    l.verified_at = verified.get(&l.uri).cloned();
+                    l
+                })
+                .collect();
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn verify_link(user: Principal, now_ms: u64, uri: &str) -> Result<(), String> {
+        with_mut(user, |p| {
+            let link = p
+                .links
+                .iter_mut()
+                .find(|l| l.uri == uri)
+                .ok_or_else(|| "link not found".to_string())?;
+            link.verified_at = Some(now_ms);
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn has_link(user: &Principal, uri: &str) -> bool {
+        PROFILE_STORE.with(|r| {
+            r.borrow()
+                .get(user)
+                .map(|p| p.links.iter().any(|l| l.uri == uri))
+                .unwrap_or(false)
+        })
+    }
+
     pub async fn upload_image_token(
         user: Principal,
         now_ms: u64,
@@ -453,10 +825,460 @@ pub mod profile {
         })
     }
 
-    pub fn get(user: Principal, is_caller: bool) -> Result<types::ProfileInfo, String> {
+    pub fn get(user: Principal, viewer: Principal) -> Result<types::ProfileInfo, String> {
         PROFILE_STORE.with(|r| match r.borrow_mut().get(&user) {
-            Some(v) => Ok(v.into_info(user, ic_cdk::api::canister_self(), is_caller)),
+            Some(v) => Ok(v.into_info(user, ic_cdk::api::canister_self(), viewer)),
             None => Err("profile not found".to_string()),
         })
     }
+
+    pub fn block_users(
+        user: Principal,
+        now_ms: u64,
+        users: BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            for u in users {
+                p.following.remove(&u);
+                p.blocked.insert(u);
+            }
+            if p.blocked.len() > types::MAX_PROFILE_BLOCKED {
+                return Err("blocked limit exceeded".to_string());
+            }
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn unblock_users(
+        user: Principal,
+        now_ms: u64,
+        users: BTreeSet<Principal>,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            p.blocked.retain(|u| !users.contains(u));
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn update_tokens(
+        user: Principal,
+        now_ms: u64,
+        tokens: Vec<Principal>,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            p.token_balances.retain(|k, _| tokens.contains(k));
+            p.tokens = tokens;
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn update_token_balances(
+        user: Principal,
+        now_ms: u64,
+        balances: BTreeMap<Principal, u128>,
+    ) -> Result<(), String> {
+        with_mut(user, |p| {
+            for ledger in balances.keys() {
+                if !p.tokens.contains(ledger) {
+                    return Err(format!("token {} is not in the profile", ledger));
+                }
+            }
+            p.token_balances = balances
+                .into_iter()
+                .map(|(k, v)| (k, (v, now_ms)))
+                .collect();
+            p.active_at = now_ms;
+            Ok(())
+        })
+    }
+
+    pub fn get_tokens(
+        user: Principal,
+        viewer: Principal,
+    ) -> Result<Vec<types::ProfileToken>, String> {
+        let p = PROFILE_STORE
+            .with(|r| r.borrow().get(&user))
+            .ok_or_else(|| "profile not found".to_string())?;
+        if (user != viewer && p.blocked.contains(&viewer)) || !p.is_visible_to(&user, &viewer) {
+            return Ok(vec![]);
+        }
+
+        Ok(p.tokens
+            .into_iter()
+            .map(|ledger| {
+                let balance = p.token_balances.get(&ledger);
+                types::ProfileToken {
+                    ledger,
+                    metadata: token::get(&ledger).map(|m| m.into_metadata()),
+                    balance: balance.map(|b| b.0.into()),
+                    balance_at: balance.map(|b| b.1),
+                }
+            })
+            .collect())
+    }
+
+    pub fn update_names(names: Vec<(Principal, String)>) {
+        PROFILE_STORE.with(|r| {
+            let mut m = r.borrow_mut();
+            for (user, name) in names {
+                if let Some(mut p) = m.get(&user) {
+                    // profiles indexed for the first time get their full token set
+                    search::reindex(
+                        user,
+                        p.indexed.then_some((&p.name, &p.bio)),
+                        (&name, &p.bio),
+                    );
+                    p.name = name;
+                    p.indexed = true;
+                    m.insert(user, p);
+                }
+            }
+        })
+    }
+
+    // search returns the public profiles that match the query, ordered by (active_at, id)
+    // descending, starting below the (prev, prev_id) cursor.
+    pub fn search(
+        query: &str,
+        prev: Option<u64>,
+        prev_id: Option<Principal>,
+        take: usize,
+    ) -> Vec<types::ProfileInfo> {
+        let tokens = search::tokenize(query, types::MAX_QUERY_SEARCH_TOKENS);
+        if tokens.is_empty() {
+            return vec![];
+        }
+        let cursor = (
+            prev.unwrap_or(u64::MAX),
+            prev_id.unwrap_or(Principal::management_canister()),
+        );
+        let canister = ic_cdk::api::canister_self();
+        let matched =
+            |p: &Profile| p.privacy == types::PROFILE_PRIVACY_PUBLIC && search::matches(&tokens, p);
+
+        let res: Vec<(Principal, Profile)> = match search::lookup(&tokens) {
+            Some(ids) => {
+                let mut res: Vec<(Principal, Profile)> = PROFILE_STORE.with(|r| {
+                    let m = r.borrow();
+                    ids.into_iter()
+                        .filter_map(|id| {
+                            m.get(&id)
+                                .filter(|p| (p.active_at, id) < cursor && matched(p))
+                                .map(|p| (id, p))
+                        })
+                        .collect()
+                });
+                res.sort_by_key(|(id, p)| std::cmp::Reverse((p.active_at, *id)));
+                res.truncate(take);
+                res
+            }
+            // every query token is too common, scan the most recently active profiles
+            None => RECENT_INDEX.with(|ri| {
+                PROFILE_STORE.with(|r| {
+                    let m = r.borrow();
+                    ri.borrow()
+                        .range(..cursor)
+                        .rev()
+                        .take(types::MAX_SEARCH_CANDIDATES)
+                        .filter_map(|e| {
+                            let id = e.key().1;
+                            m.get(&id).filter(&matched).map(|p| (id, p))
+                        })
+                        .take(take)
+                        .collect()
+                })
+            }),
+        };
+
+        res.into_iter()
+            .map(|(id, p)| p.into_info(id, canister, ANONYMOUS))
+            .collect()
+    }
+
+    // check_blocked returns the owners (with profiles in this canister) who have blocked the user.
+    pub fn check_blocked(owners: BTreeSet<Principal>, user: Principal) -> Vec<Principal> {
+        PROFILE_STORE.with(|r| {
+            let m = r.borrow();
+            owners
+                .into_iter()
+                .filter(|owner| m.get(owner).is_some_and(|p| p.blocked.contains(&user)))
+                .collect()
+        })
+    }
+}
+
+pub mod device {
+    use super::*;
+
+    const REVOCATIONS_PER_ROUND: usize = 10;
+
+    // propagate_revocations removes the DEKs wrapped for revoked devices from the user's
+    // channels, it runs on a timer. Failed revocations stay queued for the next round.
+    pub async fn propagate_revocations() {
+        let batch: Vec<(Principal, ByteArray<32>)> = state::with(|s| {
+            s.device_revocations
+                .iter()
+                .take(REVOCATIONS_PER_ROUND)
+                .cloned()
+                .collect()
+        });
+
+        for (user, ecdh_pub) in batch {
+            let channels: BTreeMap<Principal, Vec<u32>> = PROFILE_STORE.with(|r| {
+                let mut channels: BTreeMap<Principal, Vec<u32>> = BTreeMap::new();
+                if let Some(p) = r.borrow().get(&user) {
+                    for (canister, id) in p.channels.keys() {
+                        channels.entry(*canister).or_default().push(*id as u32);
+                    }
+                }
+                channels
+            });
+
+            let mut done = true;
+            for (canister, ids) in channels {
+                let res: Result<Result<(), String>, String> = call(
+                    canister,
+                    "admin_remove_devices",
+                    (user, ids, vec![ecdh_pub]),
+                    0,
+                )
+                .await;
+                if let Err(err) = res.and_then(|r| r) {
+                    ic_cdk::api::debug_print(format!(
+                        "failed to revoke device of {} in {}: {}",
+                        user.to_text(),
+                        canister.to_text(),
+                        err
+                    ));
+                    done = false;
+                }
+            }
+
+            if done {
+                state::with_mut(|s| s.device_revocations.retain(|v| v != &(user, ecdh_pub)));
+            }
+        }
+    }
+}
+
+pub mod token {
+    use icrc_ledger_types::icrc::generic_metadata_value::MetadataValue;
+    use num_traits::cast::ToPrimitive;
+
+    use super::*;
+
+    pub fn get(ledger: &Principal) -> Option<TokenMeta> {
+        TOKEN_STORE.with(|r| r.borrow().get(ledger))
+    }
+
+    // enqueue_stale queues the tokens that are not cached or expired for the refresh timer,
+    // so that user calls never wait on the token ledgers.
+    pub fn enqueue_stale(tokens: &[Principal], now_ms: u64) {
+        let (stale, cache_full) = TOKEN_STORE.with(|r| {
+            let m = r.borrow();
+            let stale: Vec<(Principal, bool)> = tokens
+                .iter()
+                .filter_map(|t| match m.get(t) {
+                    None => Some((*t, false)),
+                    Some(v) if v.updated_at + types::TOKEN_METADATA_TTL_MS < now_ms => {
+                        Some((*t, true))
+                    }
+                    _ => None,
+                })
+                .collect();
+            (stale, m.len() >= types::MAX_CACHED_TOKENS)
+        });
+
+        state::with_mut(|s| {
+            for (ledger, cached) in stale {
+                if s.token_queue.len() >= types::MAX_TOKEN_QUEUE {
+                    break;
+                }
+                // new tokens are not cached when the cache is full
+                if cached || !cache_full {
+                    s.token_queue.insert(ledger);
+                }
+            }
+        });
+    }
+
+    // refresh_queued resolves a batch of the queued tokens, it runs on a timer.
+    // Failures are logged, the tokens are queued again on the next update by a user.
+    pub async fn refresh_queued(now_ms: u64) {
+        let batch: Vec<Principal> = state::with_mut(|s| {
+            let mut batch = Vec::new();
+            while batch.len() < types::TOKEN_REFRESH_BATCH {
+                match s.token_queue.pop_first() {
+                    Some(ledger) => batch.push(ledger),
+                    None => break,
+                }
+            }
+            batch
+        });
+
+        for ledger in batch {
+            if let Err(err) = refresh(ledger, now_ms).await {
+                ic_cdk::api::debug_print(format!("failed to refresh token {ledger}: {err}"));
+            }
+        }
+    }
+
+    pub async fn refresh(ledger: Principal, now_ms: u64) -> Result<TokenMeta, String> {
+        let res: Vec<(String, MetadataValue)> = call(ledger, "icrc1_metadata", (), 0).await?;
+        let mut meta = TokenMeta {
+            updated_at: now_ms,
+            ..Default::default()
+        };
+        for (key, value) in res {
+            match (key.as_str(), value) {
+                ("icrc1:name", MetadataValue::Text(v)) => meta.name = v,
+                ("icrc1:symbol", MetadataValue::Text(v)) => meta.symbol = v,
+                ("icrc1:decimals", MetadataValue::Nat(v)) => {
+                    meta.decimals = v.0.to_u8().ok_or("invalid token decimals")?;
+                }
+                ("icrc1:fee", MetadataValue::Nat(v)) => {
+                    meta.fee = v.0.to_u128().ok_or("invalid token fee")?;
+                }
+                ("icrc1:logo", MetadataValue::Text(v)) if v.len() <= types::MAX_TOKEN_LOGO_SIZE => {
+                    meta.logo = Some(v);
+                }
+                _ => {}
+            }
+        }
+        if meta.symbol.is_empty() {
+            return Err("token symbol not found".to_string());
+        }
+
+        TOKEN_STORE.with(|r| {
+            let mut m = r.borrow_mut();
+            if !m.contains_key(&ledger) && m.len() >= types::MAX_CACHED_TOKENS {
+                return Err("token cache is full".to_string());
+            }
+            m.insert(ledger, meta.clone());
+            Ok(())
+        })?;
+        Ok(meta)
+    }
+}
+
+pub mod search {
+    use super::*;
+
+    // tokenize splits the text into lowercase alphanumeric tokens.
+    // Single ASCII characters are ignored, but a single CJK character is a token.
+    pub fn tokenize(text: &str, max: usize) -> BTreeSet<String> {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|t| t.len() >= 2 && t.len() <= types::MAX_SEARCH_TOKEN_SIZE)
+            .map(|t| t.to_lowercase())
+            .take(max)
+            .collect()
+    }
+
+    fn profile_tokens(name: &str, bio: &str) -> BTreeSet<String> {
+        let mut tokens = tokenize(name, types::MAX_PROFILE_SEARCH_TOKENS);
+        let max = types::MAX_PROFILE_SEARCH_TOKENS.saturating_sub(tokens.len());
+        tokens.append(&mut tokenize(bio, max));
+        tokens
+    }
+
+    // reindex updates the index entries of the profile from the old (name, bio) to the new one.
+    // The old tokens are None if the profile is not indexed yet.
+    pub fn reindex(user: Principal, old: Option<(&str, &str)>, new: (&str, &str)) {
+        let old = old
+            .map(|(name, bio)| profile_tokens(name, bio))
+            .unwrap_or_default();
+        let new = profile_tokens(new.0, new.1);
+        SEARCH_INDEX.with(|r| {
+            let mut m = r.borrow_mut();
+            for t in old.difference(&new) {
+                m.remove(&SearchKey(t.clone(), user));
+            }
+            for t in new.difference(&old) {
+                m.insert(SearchKey(t.clone(), user), ());
+            }
+        })
+    }
+
+    // touch moves the profile in the recency index.
+    pub fn touch(user: Principal, prev_active_at: Option<u64>, active_at: u64) {
+        if prev_active_at == Some(active_at) {
+            return;
+        }
+        RECENT_INDEX.with(|r| {
+            let mut m = r.borrow_mut();
+            if let Some(prev) = prev_active_at {
+                m.remove(&(prev, user));
+            }
+            m.insert((active_at, user), ());
+        })
+    }
+
+    // matches returns true if every query token is a prefix of a profile token.
+    pub fn matches(tokens: &BTreeSet<String>, p: &Profile) -> bool {
+        let pt = profile_tokens(&p.name, &p.bio);
+        tokens.iter().all(|t| pt.iter().any(|v| v.starts_with(t)))
+    }
+
+    // lookup returns the candidate profiles for the query tokens: the intersection of the
+    // tokens that match at most MAX_SEARCH_CANDIDATES profiles. Returns None if every token
+    // matches more, the caller should then scan profiles by recency instead.
+    pub fn lookup(tokens: &BTreeSet<String>) -> Option<BTreeSet<Principal>> {
+        let mut ids: Option<BTreeSet<Principal>> = None;
+        SEARCH_INDEX.with(|r| {
+            let m = r.borrow();
+            for t in tokens {
+                let matched: BTreeSet<Principal> = m
+                    .range(SearchKey(t.clone(), Principal::management_canister())..)
+                    .take_while(|e| e.key().0.starts_with(t))
+                    .take(types::MAX_SEARCH_CANDIDATES + 1)
+                    .map(|e| e.key().1)
+                    .collect();
+                if matched.len() > types::MAX_SEARCH_CANDIDATES {
+                    continue;
+                }
+                ids = Some(match ids.take() {
+                    None => matched,
+                    Some(ids) => ids.intersection(&matched).cloned().collect(),
+                });
+            }
+        });
+        ids
+    }
+
+    // backfill indexes a batch of the profiles that existed before the search and recency
+    // indexes, it runs on a timer until all profiles are indexed.
+    pub fn backfill(take: usize) {
+        let prev = match state::with(|s| (!s.search_backfilled).then_some(s.search_backfill_prev)) {
+            None => return,
+            Some(prev) => prev,
+        };
+        let batch: Vec<(Principal, Profile)> = PROFILE_STORE.with(|r| {
+            let m = r.borrow();
+            let iter = match prev {
+                Some(prev) => m.range(prev..),
+                None => m.range(..),
+            };
+            iter.filter(|e| Some(*e.key()) != prev)
+                .take(take)
+                .map(|e| (*e.key(), e.value()))
+                .collect()
+        });
+
+        let last = batch.last().map(|(id, _)| *id);
+        for (user, mut p) in batch {
+            RECENT_INDEX.with(|r| r.borrow_mut().insert((p.active_at, user), ()));
+            if !p.indexed {
+                reindex(user, None, (&p.name, &p.bio));
+                p.indexed = true;
+                PROFILE_STORE.with(|r| r.borrow_mut().insert(user, p));
+            }
+        }
+        state::with_mut(|s| match last {
+            Some(last) => s.search_backfill_prev = Some(last),
+            None => s.search_backfilled = true,
+        });
+    }
 }
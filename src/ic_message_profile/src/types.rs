@@ -1,16 +1,31 @@
 use candid::{CandidType, Principal};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteArray;
 use std::collections::BTreeSet;
 
 pub use ic_message_types::profile::*;
 
+// the challenge should be published and verified within 10 minutes
+pub const LINK_CHALLENGE_EXPIRE_SEC: u64 = 600;
+
+pub const MAX_SEARCH_TOKEN_SIZE: usize = 32;
+pub const MAX_PROFILE_SEARCH_TOKENS: usize = 100; // tokens indexed per profile
+pub const MAX_QUERY_SEARCH_TOKENS: usize = 5;
+pub const MAX_SEARCH_CANDIDATES: usize = 10000; // profiles scanned per query token
+pub const SEARCH_BACKFILL_BATCH: usize = 500; // profiles indexed per timer tick
+pub const MAX_CACHED_TOKENS: u64 = 10000;
+pub const MAX_TOKEN_QUEUE: usize = 1000;
+pub const TOKEN_REFRESH_BATCH: usize = 10; // tokens resolved per timer tick
+
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct StateInfo {
     pub name: String,
     pub managers: BTreeSet<Principal>,
     pub ic_oss_cluster: Option<Principal>,
     pub ic_oss_buckets: Vec<Principal>,
+    pub link_verifier: Option<ByteArray<32>>,
     pub profiles_total: u64,
+    pub stable_memory_size: u64, // bytes
 }
 
 #[derive(CandidType, Copy, Clone, Debug, Deserialize, Serialize)]
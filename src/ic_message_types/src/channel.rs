@@ -8,6 +8,7 @@ pub const MAX_CHANNEL_MANAGERS: usize = 5;
 pub const MAX_CHANNEL_MEMBERS: usize = 995;
 pub const MAX_CHANNEL_MESSAGES: u32 = 10000;
 pub const MAX_USER_CHANNELS: usize = 1000;
+pub const MAX_MEMBER_DEVICES: usize = 20;
 pub const MAX_MESSAGE_SIZE: usize = 1024 * 32; // 32KB
 pub const MIN_TOPUP_AMOUNT: u64 = 100_000_000; // 1 token
 
@@ -42,6 +43,8 @@ pub struct ChannelInfo {
     pub ecdh_request: HashMap<Principal, (ByteArray<32>, Option<(ByteArray<32>, ByteBuf)>)>,
     #[serde(default)]
     pub files_state: Option<ChannelFilesState>,
+    #[serde(default)]
+    pub ecdh_device_request: HashMap<Principal, Vec<ByteArray<32>>>, // devices waiting for the DEK
 }
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -75,6 +78,8 @@ pub struct ChannelSetting {
     pub ecdh_pub: Option<ByteArray<32>>,
     pub ecdh_remote: Option<(ByteArray<32>, ByteBuf)>,
     pub updated_at: u64,
+    #[serde(default)]
+    pub devices: Vec<(ByteArray<32>, Option<(ByteArray<32>, ByteBuf)>)>, // (device ecdh_pub, ecdh_remote)
 }
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -217,11 +222,19 @@ pub struct UpdateChannelMemberInput {
     pub id: u32,
     pub member: Principal,
     pub ecdh: ChannelECDHInput,
+    #[serde(default)]
+    pub devices: Vec<(ByteArray<32>, (ByteArray<32>, ByteBuf))>, // DEK wrapped for member's devices
 }
 
 impl UpdateChannelMemberInput {
     pub fn validate(&self) -> Result<(), String> {
         self.ecdh.validate()?;
+        if self.devices.len() > MAX_MEMBER_DEVICES {
+            Err("too many devices".to_string())?;
+        }
+        for (_, (_, kek)) in &self.devices {
+            try_decode_encrypt0(kek)?;
+        }
         Ok(())
     }
 }
@@ -247,6 +260,8 @@ pub struct UpdateMySettingInput {
     pub last_read: Option<u32>, // message id
     pub mute: Option<bool>,
     pub ecdh: Option<ChannelECDHInput>,
+    #[serde(default)]
+    pub device_ecdh_pub: Option<ByteArray<32>>, // request the DEK for a device registered on the profile
 }
 
 impl UpdateMySettingInput {
@@ -1,10 +1,62 @@
-use candid::Principal;
+use candid::{Nat, Principal};
+use icrc_ledger_types::icrc::generic_value::ICRC3Value;
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteArray;
+use serde_bytes::{ByteArray, ByteBuf};
+use std::collections::BTreeMap;
 
 pub mod channel;
 pub mod profile;
 
+// ICRC-3 block types of the name registry
+pub const BTYPE_NAME_REGISTER: &str = "name_register";
+pub const BTYPE_NAME_TRANSFER: &str = "name_transfer";
+pub const BTYPE_NAME_RENEW: &str = "name_renew";
+pub const BTYPE_NAME_RELEASE: &str = "name_release";
+pub const BTYPE_SUBNAME_ISSUE: &str = "subname_issue";
+pub const BTYPE_SUBNAME_REVOKE: &str = "subname_revoke";
+
+// NameOp is the operation recorded by a NameBlock.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum NameOp {
+    #[serde(rename = "rg")]
+    Register,
+    #[serde(rename = "tr")]
+    Transfer,
+    #[serde(rename = "rn")]
+    Renew,
+    #[serde(rename = "rl")]
+    Release,
+    #[serde(rename = "si")]
+    SubnameIssue,
+    #[serde(rename = "sr")]
+    SubnameRevoke,
+}
+
+impl NameOp {
+    pub fn btype(&self) -> &'static str {
+        match self {
+            NameOp::Register => BTYPE_NAME_REGISTER,
+            NameOp::Transfer => BTYPE_NAME_TRANSFER,
+            NameOp::Renew => BTYPE_NAME_RENEW,
+            NameOp::Release => BTYPE_NAME_RELEASE,
+            NameOp::SubnameIssue => BTYPE_SUBNAME_ISSUE,
+            NameOp::SubnameRevoke => BTYPE_SUBNAME_REVOKE,
+        }
+    }
+
+    pub fn from_btype(btype: &str) -> Option<Self> {
+        match btype {
+            BTYPE_NAME_REGISTER => Some(NameOp::Register),
+            BTYPE_NAME_TRANSFER => Some(NameOp::Transfer),
+            BTYPE_NAME_RENEW => Some(NameOp::Renew),
+            BTYPE_NAME_RELEASE => Some(NameOp::Release),
+            BTYPE_SUBNAME_ISSUE => Some(NameOp::SubnameIssue),
+            BTYPE_SUBNAME_REVOKE => Some(NameOp::SubnameRevoke),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct NameBlock {
     #[serde(rename = "h")]
@@ -21,4 +73,238 @@ pub struct NameBlock {
     pub value: u64,
     #[serde(rename = "t")]
     pub timestamp: u64, // milliseconds
+    #[serde(default, rename = "o")]
+    pub op: Option<NameOp>, // None in the blocks appended before the op was recorded
+}
+
+impl NameBlock {
+    // btype returns the ICRC-3 block type of the op. It is derived from the block fields
+    // for the blocks without an op.
+    pub fn btype(&self) -> &'static str {
+        if let Some(op) = self.op {
+            return op.btype();
+        }
+        let subname = self.name.contains('.');
+        match self.from {
+            None => BTYPE_NAME_REGISTER,
+            Some(_) if self.user == Principal::anonymous() => {
+                if subname {
+                    BTYPE_SUBNAME_REVOKE
+                } else {
+                    BTYPE_NAME_RELEASE
+                }
+            }
+            Some(_) if subname => BTYPE_SUBNAME_ISSUE,
+            Some(from) if from == self.user => BTYPE_NAME_RENEW,
+            Some(_) => BTYPE_NAME_TRANSFER,
+        }
+    }
+
+    // to_value encodes the block as an ICRC-3 Value:
+    // { btype, ts (nanoseconds), phash (omitted in the first block), tx: { name, to, from?, amt? } }
+    // where accounts are encoded as ICRC-3 arrays of the principal bytes.
+    pub fn to_value(&self) -> ICRC3Value {
+        let mut tx = BTreeMap::new();
+        tx.insert("name".to_string(), ICRC3Value::Text(self.name.clone()));
+        tx.insert("to".to_string(), account_value(&self.user));
+        if let Some(from) = &self.from {
+            tx.insert("from".to_string(), account_value(from));
+        }
+        if self.value > 0 {
+            tx.insert("amt".to_string(), ICRC3Value::Nat(Nat::from(self.value)));
+        }
+
+        let mut blk = BTreeMap::new();
+        blk.insert(
+            "btype".to_string(),
+            ICRC3Value::Text(self.btype().to_string()),
+        );
+        blk.insert(
+            "ts".to_string(),
+            ICRC3Value::Nat(Nat::from(self.timestamp) * 1_000_000u64),
+        );
+        if self.height > 0 {
+            blk.insert(
+                "phash".to_string(),
+                ICRC3Value::Blob(ByteBuf::from(self.phash.to_vec())),
+            );
+        }
+        blk.insert("tx".to_string(), ICRC3Value::Map(tx));
+        ICRC3Value::Map(blk)
+    }
+
+    // from_value decodes a block encoded by `to_value`.
+    pub fn from_value(height: u64, value: &ICRC3Value) -> Result<Self, String> {
+        let blk = as_map(value)?;
+        let tx = as_map(blk.get("tx").ok_or("missing tx")?)?;
+        let phash = match blk.get("phash") {
+            Some(ICRC3Value::Blob(b)) => {
+                let phash: [u8; 32] = b.as_slice().try_into().map_err(|_| "invalid phash")?;
+                phash.into()
+            }
+            Some(_) => return Err("invalid phash".to_string()),
+            None => [0u8; 32].into(),
+        };
+        let name = match tx.get("name") {
+            Some(ICRC3Value::Text(name)) => name.clone(),
+            _ => return Err("invalid name".to_string()),
+        };
+        let user = account_from_value(tx.get("to").ok_or("missing to")?)?;
+        let from = tx.get("from").map(account_from_value).transpose()?;
+        let value = tx.get("amt").map(nat_from_value).transpose()?.unwrap_or(0);
+        let ts = nat_from_value(blk.get("ts").ok_or("missing ts")?)?;
+        let op = match blk.get("btype") {
+            Some(ICRC3Value::Text(btype)) => {
+                Some(NameOp::from_btype(btype).ok_or("invalid btype")?)
+            }
+            _ => return Err("invalid btype".to_string()),
+        };
+
+        Ok(NameBlock {
+            height,
+            phash,
+            name,
+            user,
+            from,
+            value,
+            timestamp: ts / 1_000_000,
+            op,
+        })
+    }
+}
+
+fn account_value(owner: &Principal) -> ICRC3Value {
+    ICRC3Value::Array(vec![ICRC3Value::Blob(ByteBuf::from(owner.as_slice()))])
+}
+
+fn account_from_value(value: &ICRC3Value) -> Result<Principal, String> {
+    match value {
+        ICRC3Value::Array(arr) => match arr.first() {
+            Some(ICRC3Value::Blob(b)) => {
+                Principal::try_from_slice(b.as_slice()).map_err(|_| "invalid account".to_string())
+            }
+            _ => Err("invalid account".to_string()),
+        },
+        _ => Err("invalid account".to_string()),
+    }
+}
+
+fn nat_from_value(value: &ICRC3Value) -> Result<u64, String> {
+    match value {
+        ICRC3Value::Nat(n) => u64::try_from(&n.0).map_err(|_| "invalid nat".to_string()),
+        _ => Err("invalid nat".to_string()),
+    }
+}
+
+fn as_map(value: &ICRC3Value) -> Result<&BTreeMap<String, ICRC3Value>, String> {
+    match value {
+        ICRC3Value::Map(map) => Ok(map),
+        _ => Err("invalid block".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn name_block(height: u64, phash: [u8; 32]) -> NameBlock {
+        NameBlock {
+            height,
+            phash: phash.into(),
+            name: "panda".to_string(),
+            user: Principal::management_canister(),
+            from: None,
+            value: 0,
+            timestamp: 1_700_000_000_000,
+            op: Some(NameOp::Register),
+        }
+    }
+
+    #[test]
+    fn test_name_block_to_value() {
+        let blk = name_block(0, [0u8; 32]);
+        let value = blk.to_value();
+        let ICRC3Value::Map(m) = &value else {
+            panic!("block should be a map");
+        };
+        assert_eq!(
+            m.get("btype"),
+            Some(&ICRC3Value::Text(BTYPE_NAME_REGISTER.to_string()))
+        );
+        assert_eq!(
+            m.get("ts"),
+            Some(&ICRC3Value::Nat(candid::Nat::from(
+                1_700_000_000_000_000_000u64
+            )))
+        );
+        // the first block has no phash, and a zero value has no amt
+        assert!(m.get("phash").is_none());
+        let Some(ICRC3Value::Map(tx)) = m.get("tx") else {
+            panic!("tx should be a map");
+        };
+        assert_eq!(tx.get("name"), Some(&ICRC3Value::Text("panda".to_string())));
+        assert!(tx.get("from").is_none());
+        assert!(tx.get("amt").is_none());
+
+        let mut blk = name_block(1, [1u8; 32]);
+        blk.from = Some(Principal::anonymous());
+        blk.value = 100_000_000;
+        blk.op = Some(NameOp::Transfer);
+        let value = blk.to_value();
+        let ICRC3Value::Map(m) = &value else {
+            panic!("block should be a map");
+        };
+        assert_eq!(
+            m.get("phash"),
+            Some(&ICRC3Value::Blob(ByteBuf::from(vec![1u8; 32])))
+        );
+        assert_eq!(
+            m.get("btype"),
+            Some(&ICRC3Value::Text(BTYPE_NAME_TRANSFER.to_string()))
+        );
+
+        let decoded = NameBlock::from_value(1, &value).unwrap();
+        assert_eq!(decoded.phash, blk.phash);
+        assert_eq!(decoded.name, blk.name);
+        assert_eq!(decoded.user, blk.user);
+        assert_eq!(decoded.from, blk.from);
+        assert_eq!(decoded.value, blk.value);
+        assert_eq!(decoded.timestamp, blk.timestamp);
+        assert_eq!(decoded.op, blk.op);
+    }
+
+    #[test]
+    fn test_name_block_hash() {
+        let b0 = name_block(0, [0u8; 32]);
+        let h0 = b0.to_value().hash();
+        assert_eq!(h0, name_block(0, [0u8; 32]).to_value().hash());
+
+        // the hash covers every field of the block
+        let mut other = name_block(0, [0u8; 32]);
+        other.value = 1;
+        assert_ne!(other.to_value().hash(), h0);
+        let mut other = name_block(0, [0u8; 32]);
+        other.timestamp += 1;
+        assert_ne!(other.to_value().hash(), h0);
+
+        // each block carries the hash of the previous one
+        let b1 = name_block(1, h0);
+        let value = b1.to_value();
+        let decoded = NameBlock::from_value(1, &value).unwrap();
+        assert_eq!(*decoded.phash, h0);
+        assert_eq!(decoded.to_value().hash(), value.clone().hash());
+        assert_ne!(value.hash(), h0);
+    }
+
+    #[test]
+    fn test_name_block_hash_vector() {
+        let mut blk = name_block(1, [1u8; 32]);
+        blk.from = Some(Principal::anonymous());
+        blk.value = 100_000_000;
+        blk.op = Some(NameOp::Transfer);
+        assert_eq!(
+            hex::encode(blk.to_value().hash()),
+            "4235f66a333c2212c3325726a29324de6c173b990f305f8c8f1faf3026f297d9"
+        );
+    }
 }
@@ -1,4 +1,4 @@
-use candid::{CandidType, Principal};
+use candid::{CandidType, Nat, Principal};
 use ic_oss_types::file::CHUNK_SIZE;
 use serde::{Deserialize, Serialize};
 use serde_bytes::{ByteArray, ByteBuf};
@@ -11,6 +11,16 @@ pub const MAX_PROFILE_TOKENS: usize = 100;
 pub const MAX_PROFILE_CHANNEL_ALIAS_LEN: usize = 20;
 pub const MAX_PROFILE_CHANNEL_TAGS_LEN: usize = 5;
 pub const MAX_PROFILE_CHANNEL_TAG_LEN: usize = 20;
+pub const MAX_PROFILE_BLOCKED: usize = 2048;
+pub const MAX_PROFILE_DEVICES: usize = 20; // active devices, as many revoked ones are kept
+pub const MAX_DEVICE_LABEL_SIZE: usize = 64;
+pub const MAX_TOKEN_LOGO_SIZE: usize = 32 * 1024; // 32KB, larger logos are not cached
+pub const TOKEN_METADATA_TTL_MS: u64 = 7 * 24 * 3600 * 1000; // 7 days
+
+// Who can see the profile's links, tokens and active_at
+pub const PROFILE_PRIVACY_PUBLIC: u8 = 0; // everyone
+pub const PROFILE_PRIVACY_FOLLOWING: u8 = 1; // users followed by the profile owner
+pub const PROFILE_PRIVACY_PRIVATE: u8 = 2; // only the profile owner
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
 pub struct UserInfo {
@@ -35,6 +45,54 @@ pub struct ProfileInfo {
     pub following: Option<BTreeSet<Principal>>,
     pub channels: Option<HashMap<(Principal, u64), ChannelSetting>>,
     pub ecdh_pub: Option<ByteArray<32>>,
+    #[serde(default)]
+    pub privacy: Option<u8>,
+    #[serde(default)]
+    pub blocked: Option<BTreeSet<Principal>>,
+    #[serde(default)]
+    pub devices: Option<Vec<ProfileDevice>>,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ProfileDevice {
+    pub id: u32,
+    pub ecdh_pub: ByteArray<32>,
+    pub label: String,
+    pub added_at: u64,
+    pub revoked: bool,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct UpdateDeviceInput {
+    pub label: String,
+    pub revoked: bool,
+}
+
+impl UpdateDeviceInput {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.label.len() > MAX_DEVICE_LABEL_SIZE {
+            return Err("device label is too long".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u8,
+    pub fee: Nat,
+    pub logo: Option<String>,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct ProfileToken {
+    pub ledger: Principal,
+    pub metadata: Option<TokenMetadata>, // None if the ICRC-1 metadata is not resolved yet
+    pub balance: Option<Nat>,            // balance snapshot attached by the profile owner
+    pub balance_at: Option<u64>,
 }
 
 #[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
@@ -108,6 +166,8 @@ pub struct Link {
     pub title: String,
     pub uri: String,
     pub image: Option<String>,
+    #[serde(default)]
+    pub verified_at: Option<u64>, // set by the profile canister, ignored in input
 }
 
 impl Link {
@@ -122,6 +182,13 @@ impl Link {
     }
 }
 
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct VerifyLinkInput {
+    pub uri: String,
+    pub challenge: ByteBuf,   // issued by the profile canister with `link_challenge`
+    pub attestation: ByteBuf, // Ed25519 message signed by the link verifier
+}
+
 #[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
 pub struct UploadImageInput {
     pub size: u64,            // should <= 256KB
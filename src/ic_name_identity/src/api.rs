@@ -4,6 +4,7 @@ use ic_auth_types::{Delegation, SignInResponse, SignedDelegation};
 use ic_auth_verifier::{user_public_key_from_der, verify_basic_sig};
 use ic_canister_sig_creation::{delegation_signature_msg, CanisterSigPublicKey};
 use ic_message_types::profile::UserInfo;
+use lib_panda::{heap_memory_size, CanisterMetrics};
 use serde_bytes::ByteBuf;
 
 use crate::types::{Delegator, NameAccount};
@@ -16,6 +17,16 @@ fn get_state() -> Result<store::State, String> {
     Ok(store::state::with(|s| s.clone()))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> CanisterMetrics {
+    CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: heap_memory_size(),
+        timestamp: ic_cdk::api::time() / MILLISECONDS,
+    }
+}
+
 #[ic_cdk::query]
 fn whoami() -> Result<Principal, String> {
     Ok(ic_cdk::api::msg_caller())
@@ -155,3 +166,13 @@ fn get_delegation(
         signature: signature.into(),
     })
 }
+
+#[ic_cdk::query]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    crate::is_controller()?;
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
+}
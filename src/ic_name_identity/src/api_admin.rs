@@ -1,12 +1,19 @@
 use candid::Principal;
+use lib_panda::audit;
 use std::collections::BTreeSet;
 
 use crate::{is_controller, store};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_reset_name(name: String, delegators: BTreeSet<Principal>) -> Result<(), String> {
-    let name = name.to_ascii_lowercase();
-    store::state::reset_delegators(&name, delegators)
+    store::AUDIT.with(
+        "admin_reset_name",
+        audit::digest((&name, &delegators)),
+        || {
+            let name = name.to_ascii_lowercase();
+            store::state::reset_delegators(&name, delegators)
+        },
+    )
 }
 
 #[ic_cdk::update]
@@ -1,4 +1,5 @@
 use candid::{utils::ArgumentEncoder, Principal};
+use lib_panda::CanisterMetrics;
 use serde_bytes::ByteBuf;
 use std::collections::BTreeSet;
 
@@ -9,8 +9,9 @@ use ic_certification::labeled_hash;
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     storable::Bound,
-    DefaultMemoryImpl, StableBTreeMap, StableCell, Storable,
+    DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, Storable,
 };
+use lib_panda::audit::{AuditLog, AuditLogs};
 use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, cell::RefCell, collections::BTreeSet};
 
@@ -144,6 +145,8 @@ impl Storable for Names {
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
 const NAME_DELEGATIONS_MEMORY_ID: MemoryId = MemoryId::new(1);
 const MY_NAMES_MEMORY_ID: MemoryId = MemoryId::new(2);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(4);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -171,8 +174,17 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(MY_NAMES_MEMORY_ID)),
         )
     );
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
 }
 
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
 pub mod state {
     use super::*;
 
@@ -0,0 +1,77 @@
+use ic_http_certification::HttpRequest;
+use lib_panda::{HttpResponse, MetricsWriter};
+
+use crate::store;
+
+#[ic_cdk::query(hidden = true)]
+fn http_request(request: HttpRequest<'static>) -> HttpResponse {
+    let path = request.get_path().unwrap_or_default();
+    lib_panda::http_metrics(request.method().as_str(), &path, metrics)
+}
+
+fn metrics() -> Vec<u8> {
+    let mut w = MetricsWriter::new();
+    w.gauge(
+        "luckypool_cycles_balance",
+        "Cycles balance of the canister.",
+        ic_cdk::api::canister_cycle_balance(),
+    )
+    .gauge(
+        "luckypool_stable_memory_bytes",
+        "Size of the stable memory in bytes.",
+        ic_cdk::stable::stable_size() * 65536,
+    )
+    .gauge(
+        "luckypool_heap_memory_bytes",
+        "Size of the heap memory in bytes.",
+        lib_panda::heap_memory_size(),
+    );
+    store::state::with(|s| {
+        w.gauge(
+            "luckypool_airdrop_balance",
+            "PANDA tokens left for airdrops, in e8s.",
+            s.airdrop_balance,
+        )
+        .counter(
+            "luckypool_airdrop_tokens_total",
+            "PANDA tokens airdropped, in e8s.",
+            s.total_airdrop,
+        )
+        .counter(
+            "luckypool_airdrops_total",
+            "Airdrops claimed.",
+            s.total_airdrop_count,
+        )
+        .counter(
+            "luckypool_luckydraw_tokens_total",
+            "PANDA tokens won by lucky draws, in e8s.",
+            s.total_luckydraw,
+        )
+        .counter(
+            "luckypool_luckydraw_icp_total",
+            "ICP paid for lucky draws.",
+            s.total_luckydraw_icp,
+        )
+        .counter(
+            "luckypool_luckydraws_total",
+            "Lucky draws.",
+            s.total_luckydraw_count,
+        )
+        .counter(
+            "luckypool_prize_tokens_total",
+            "PANDA tokens claimed from prizes, in e8s.",
+            s.total_prize.unwrap_or_default(),
+        )
+        .counter(
+            "luckypool_prize_claims_total",
+            "Prizes claimed.",
+            s.total_prize_count.unwrap_or_default(),
+        )
+        .counter(
+            "luckypool_prizes_total",
+            "Prizes created.",
+            s.total_prizes_count.unwrap_or_default(),
+        );
+    });
+    w.into_bytes()
+}
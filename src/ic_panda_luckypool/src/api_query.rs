@@ -18,6 +18,16 @@ fn state() -> Result<types::State, ()> {
     Ok(store::state::with(|r| r.to_info()))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> lib_panda::CanisterMetrics {
+    lib_panda::CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: lib_panda::heap_memory_size(),
+        timestamp: ic_cdk::api::time() / 1_000_000,
+    }
+}
+
 #[ic_cdk::query]
 async fn name_of(owner: Option<Principal>) -> Result<Option<types::NameOutput>, ()> {
     let owner = owner.unwrap_or(ic_cdk::api::msg_caller());
@@ -243,3 +253,16 @@ async fn principal_by_luckycode(luckycode: String) -> Result<Principal, String>
     let code = utils::luckycode_from_string(&luckycode)?;
     store::luckycode::get(code).ok_or("lucky code not found".to_string())
 }
+
+#[ic_cdk::query]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !store::state::is_manager(&caller) {
+        crate::is_controller()?;
+    }
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
+}
@@ -13,6 +13,7 @@ use std::collections::BTreeSet;
 use std::convert::Into;
 
 mod api_admin;
+mod api_http;
 mod api_init;
 mod api_query;
 mod api_update;
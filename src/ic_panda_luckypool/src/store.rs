@@ -6,6 +6,7 @@ use ic_stable_structures::{
     DefaultMemoryImpl, StableBTreeMap, StableCell, StableLog, StableMinHeap, Storable,
 };
 use icrc_ledger_types::icrc1::account::Account;
+use lib_panda::audit::{AuditLog, AuditLogs};
 use lib_panda::{mac_256, Cryptogram};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
@@ -570,6 +571,8 @@ const PRIZE_INFO_MEMORY_ID: MemoryId = MemoryId::new(13);
 const PRIZE_REC_MEMORY_ID: MemoryId = MemoryId::new(14);
 const PRIZE_LOG_MEMORY_ID: MemoryId = MemoryId::new(15);
 const PRIZE_REFUND_MEMORY_ID: MemoryId = MemoryId::new(16);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(17);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(18);
 
 thread_local! {
     static CAPTCHA_SECRET: RefCell<[u8; 32]> = const { RefCell::new([0; 32]) };
@@ -680,8 +683,17 @@ thread_local! {
             MEMORY_MANAGER.with_borrow(|m| m.get(PRIZE_REFUND_MEMORY_ID)),
         )
     );
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
 }
 
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
 pub mod keys {
     use super::*;
 
@@ -6,13 +6,26 @@ use ic_canister_sig_creation::delegation_signature_msg;
 use lib_panda::mac_256;
 use serde_bytes::ByteBuf;
 
-use crate::{helper::msg_caller, store};
+use crate::{
+    helper::{msg_caller, MILLISECONDS},
+    store,
+};
 
 #[ic_cdk::query]
 fn info() -> Result<store::StateInfo, String> {
     Ok(store::state::with(|s| s.into()))
 }
 
+#[ic_cdk::query]
+fn get_canister_metrics() -> lib_panda::CanisterMetrics {
+    lib_panda::CanisterMetrics {
+        cycles_balance: ic_cdk::api::canister_cycle_balance(),
+        stable_memory_size: ic_cdk::stable::stable_size() * 65536,
+        heap_memory_size: lib_panda::heap_memory_size(),
+        timestamp: ic_cdk::api::time() / MILLISECONDS,
+    }
+}
+
 #[ic_cdk::query]
 fn whoami() -> Result<Principal, String> {
     Ok(ic_cdk::api::msg_caller())
@@ -1,14 +1,21 @@
+use lib_panda::audit;
 use url::Url;
 
 use crate::{helper::pretty_format, store};
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_update_domain(domain: String, uri: String) -> Result<(), String> {
-    validate_admin_update_domain(domain.clone(), uri.clone())?;
-    store::state::with_mut(|s| {
-        s.domains.insert(domain, uri);
-        Ok(())
-    })
+    store::AUDIT.with(
+        "admin_update_domain",
+        audit::digest((&domain, &uri)),
+        || {
+            validate_admin_update_domain(domain.clone(), uri.clone())?;
+            store::state::with_mut(|s| {
+                s.domains.insert(domain, uri);
+                Ok(())
+            })
+        },
+    )
 }
 
 #[ic_cdk::update]
@@ -25,10 +32,12 @@ fn validate_admin_update_domain(domain: String, uri: String) -> Result<String, S
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_remove_domain(domain: String) -> Result<(), String> {
-    validate_admin_remove_domain(domain.clone())?;
-    store::state::with_mut(|s| {
-        s.domains.remove(&domain);
-        Ok(())
+    store::AUDIT.with("admin_remove_domain", audit::digest((&domain,)), || {
+        validate_admin_remove_domain(domain.clone())?;
+        store::state::with_mut(|s| {
+            s.domains.remove(&domain);
+            Ok(())
+        })
     })
 }
 
@@ -48,14 +57,20 @@ fn validate_admin_remove_domain(domain: String) -> Result<String, String> {
 
 #[ic_cdk::update(guard = "is_controller")]
 fn admin_update_statement(statement: String) -> Result<(), String> {
-    if statement.is_empty() {
-        return Err("statement cannot be empty".to_string());
-    }
+    store::AUDIT.with(
+        "admin_update_statement",
+        audit::digest((&statement,)),
+        || {
+            if statement.is_empty() {
+                return Err("statement cannot be empty".to_string());
+            }
 
-    store::state::with_mut(|s| {
-        s.statement = statement;
-        Ok(())
-    })
+            store::state::with_mut(|s| {
+                s.statement = statement;
+                Ok(())
+            })
+        },
+    )
 }
 
 #[ic_cdk::update]
@@ -67,6 +82,15 @@ fn validate_admin_update_statement(statement: String) -> Result<String, String>
     pretty_format(&(statement,))
 }
 
+#[ic_cdk::query(guard = "is_controller")]
+fn list_audit_logs(
+    prev: Option<u64>,
+    take: Option<u32>,
+) -> Result<Vec<lib_panda::audit::AuditLogInfo>, String> {
+    let take = take.unwrap_or(20).min(100);
+    Ok(store::AUDIT.list(prev, take as usize))
+}
+
 fn is_controller() -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
     if ic_cdk::api::is_controller(&caller)
@@ -1,7 +1,7 @@
 use ic_auth_types::{ByteArrayB64, ByteBufB64, BytesB64, SignInResponse};
-use ic_auth_verifier::{keccak256, sha3_256, user_public_key_from_der, verify_basic_sig};
+use ic_auth_verifier::{sha3_256, user_public_key_from_der, verify_basic_sig};
 use ic_canister_sig_creation::{delegation_signature_msg, CanisterSigPublicKey};
-use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use lib_panda::recover_eth_address;
 use time::{macros::format_description, OffsetDateTime};
 
 use crate::{
@@ -175,29 +175,3 @@ impl std::fmt::Display for SiweMessage {
         )
     }
 }
-
-fn recover_eth_address(message: &str, signature: &[u8]) -> Result<[u8; 20], String> {
-    let message_hash = eip191_hash(message);
-
-    let recovery_id = RecoveryId::try_from(signature[64] % 27)
-        .map_err(|_| String::from("Invalid recovery ID"))?;
-
-    let signature =
-        Signature::from_slice(&signature[..64]).map_err(|_| String::from("Invalid signature"))?;
-
-    let verifying_key = VerifyingKey::recover_from_prehash(&message_hash, &signature, recovery_id)
-        .map_err(|_| String::from("Public key recovery failure"))?;
-    let mut addr: [u8; 20] = [0; 20];
-    addr.copy_from_slice(&keccak256(&verifying_key.to_encoded_point(false).as_bytes()[1..])[12..]);
-    Ok(addr)
-}
-
-/// Hashes a message using the EIP-191 standard. See [EIP-191 spec](https://eips.ethereum.org/EIPS/eip-191) for more information.
-fn eip191_hash(message: &str) -> [u8; 32] {
-    keccak256(&eip191_bytes(message))
-}
-
-/// Formats a message according to the EIP-191 standard. See [EIP-191 spec](https://eips.ethereum.org/EIPS/eip-191) for for more information.
-fn eip191_bytes(message: &str) -> Vec<u8> {
-    format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message).into_bytes()
-}
@@ -1,7 +1,7 @@
-use ed25519_dalek::{Signature, VerifyingKey};
 use ic_auth_types::{ByteArrayB64, ByteBufB64, BytesB64, SignInResponse};
 use ic_auth_verifier::{sha3_256, user_public_key_from_der, verify_basic_sig};
 use ic_canister_sig_creation::{delegation_signature_msg, CanisterSigPublicKey};
+use lib_panda::verify_sol_signature;
 use time::{macros::format_description, OffsetDateTime};
 
 use crate::{
@@ -35,13 +35,7 @@ fn sign_in_with_solana(
         return Err("signed message does not match expected message".to_string());
     }
 
-    // Create a PublicKey from the Solana public key
-    let vk = VerifyingKey::from_bytes(&pubkey).map_err(|_| "invalid public key")?;
-    // Create a Signature from the Solana signature
-    let signature = Signature::from_bytes(&message_sig);
-    // Verify the signature
-    vk.verify_strict(message.as_bytes(), &signature)
-        .map_err(|_| "verification failed".to_string())?;
+    verify_sol_signature(&pubkey, message.as_bytes(), &message_sig)?;
 
     let (alg, pk) = user_public_key_from_der(session_pubkey.as_slice())
         .map_err(|err| format!("invalid public key: {:?}", err))?;
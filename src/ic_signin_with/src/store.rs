@@ -12,8 +12,9 @@ use ic_http_certification::{
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
-    DefaultMemoryImpl, StableCell,
+    DefaultMemoryImpl, StableCell, StableLog,
 };
+use lib_panda::audit::{AuditLog, AuditLogs};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteArray;
 use std::{cell::RefCell, collections::BTreeMap};
@@ -49,6 +50,8 @@ impl From<&State> for StateInfo {
 }
 
 const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const AUDIT_LOG_INDEX_MEMORY_ID: MemoryId = MemoryId::new(1);
+const AUDIT_LOG_DATA_MEMORY_ID: MemoryId = MemoryId::new(2);
 
 thread_local! {
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -65,8 +68,17 @@ thread_local! {
             Vec::new()
         )
     );
+
+    static AUDIT_LOGS: RefCell<StableLog<AuditLog, Memory, Memory>> = RefCell::new(
+        StableLog::init(
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_INDEX_MEMORY_ID)),
+            MEMORY_MANAGER.with_borrow(|m| m.get(AUDIT_LOG_DATA_MEMORY_ID)),
+        )
+    );
 }
 
+pub const AUDIT: AuditLogs<Memory> = AuditLogs::new(&AUDIT_LOGS);
+
 pub mod state {
     use super::*;
     use lazy_static::lazy_static;
@@ -0,0 +1,168 @@
+use candid::{utils::ArgumentEncoder, CandidType, Principal};
+use ciborium::{from_reader, into_writer};
+use ic_stable_structures::{storable::Bound, Memory, StableLog, Storable};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteArray;
+use std::{borrow::Cow, cell::RefCell, future::Future, thread::LocalKey};
+
+const MAX_ERROR_SIZE: usize = 256;
+
+#[derive(CandidType, Clone, Debug, Deserialize, Serialize)]
+pub struct AuditLogInfo {
+    pub id: u64,
+    pub caller: Principal,
+    pub method: String,
+    pub args_digest: ByteArray<32>, // SHA-256 of the candid encoded arguments
+    pub error: Option<String>,      // None if the call succeeded
+    pub timestamp: u64,             // milliseconds
+}
+
+// AuditLog records an admin or manager call.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AuditLog {
+    #[serde(rename = "c")]
+    pub caller: Principal,
+    #[serde(rename = "m")]
+    pub method: String,
+    #[serde(rename = "d")]
+    pub args_digest: ByteArray<32>, // SHA-256 of the candid encoded arguments
+    #[serde(rename = "e")]
+    pub error: Option<String>, // None if the call succeeded
+    #[serde(rename = "t")]
+    pub timestamp: u64,
+}
+
+impl AuditLog {
+    pub fn into_info(self, id: u64) -> AuditLogInfo {
+        AuditLogInfo {
+            id,
+            caller: self.caller,
+            method: self.method,
+            args_digest: self.args_digest,
+            error: self.error,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+impl Storable for AuditLog {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        let mut buf = vec![];
+        into_writer(&self, &mut buf).expect("failed to encode AuditLog data");
+        buf
+    }
+
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut buf = vec![];
+        into_writer(self, &mut buf).expect("failed to encode AuditLog data");
+        Cow::Owned(buf)
+    }
+
+    fn from_bytes(bytes: Cow<'_, [u8]>) -> Self {
+        from_reader(&bytes[..]).expect("failed to decode AuditLog data")
+    }
+}
+
+// digest returns the SHA-256 of the candid encoded arguments.
+pub fn digest(args: impl ArgumentEncoder) -> ByteArray<32> {
+    crate::sha256(&candid::encode_args(args).unwrap_or_default()).into()
+}
+
+// AuditLogs appends the admin and manager calls of a canister to its stable log.
+pub struct AuditLogs<M: Memory + 'static>(&'static LocalKey<RefCell<StableLog<AuditLog, M, M>>>);
+
+impl<M: Memory + 'static> AuditLogs<M> {
+    pub const fn new(logs: &'static LocalKey<RefCell<StableLog<AuditLog, M, M>>>) -> Self {
+        Self(logs)
+    }
+
+    pub fn record<T>(&self, method: &str, args_digest: ByteArray<32>, res: &Result<T, String>) {
+        self.append(ic_cdk::api::msg_caller(), method, args_digest, res);
+    }
+
+    // with runs an admin or manager call and records it.
+    pub fn with<T>(
+        &self,
+        method: &str,
+        args_digest: ByteArray<32>,
+        f: impl FnOnce() -> Result<T, String>,
+    ) -> Result<T, String> {
+        let res = f();
+        self.record(method, args_digest, &res);
+        res
+    }
+
+    // with_async runs an async admin or manager call and records it when it completes.
+    pub async fn with_async<T>(
+        &self,
+        method: &str,
+        args_digest: ByteArray<32>,
+        f: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        let caller = ic_cdk::api::msg_caller();
+        let res = f.await;
+        self.append(caller, method, args_digest, &res);
+        res
+    }
+
+    // list returns the logs before `prev` in reverse order.
+    pub fn list(&self, prev: Option<u64>, take: usize) -> Vec<AuditLogInfo> {
+        self.0.with_borrow(|r| {
+            let end = prev.unwrap_or(u64::MAX).min(r.len());
+            let start = end.saturating_sub(take as u64);
+            (start..end)
+                .rev()
+                .filter_map(|i| r.get(i).map(|log| log.into_info(i)))
+                .collect()
+        })
+    }
+
+    fn append<T>(
+        &self,
+        caller: Principal,
+        method: &str,
+        args_digest: ByteArray<32>,
+        res: &Result<T, String>,
+    ) {
+        let log = AuditLog {
+            caller,
+            method: method.to_string(),
+            args_digest,
+            error: res
+                .as_ref()
+                .err()
+                .map(|err| err.chars().take(MAX_ERROR_SIZE).collect()),
+            timestamp: ic_cdk::api::time() / 1_000_000,
+        };
+        self.0.with_borrow_mut(|r| {
+            r.append(&log).expect("failed to append AuditLog");
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_audit_log() {
+        let log = AuditLog {
+            caller: Principal::anonymous(),
+            method: "admin_add_managers".to_string(),
+            args_digest: digest((&1u64,)),
+            error: Some("not allowed".to_string()),
+            timestamp: 1000,
+        };
+        let data = log.to_bytes();
+        let info = AuditLog::from_bytes(data).into_info(7);
+        assert_eq!(info.id, 7);
+        assert_eq!(info.caller, Principal::anonymous());
+        assert_eq!(info.method, "admin_add_managers");
+        assert_eq!(info.args_digest, digest((&1u64,)));
+        assert_ne!(info.args_digest, digest((&2u64,)));
+        assert_eq!(info.error.as_deref(), Some("not allowed"));
+        assert_eq!(info.timestamp, 1000);
+    }
+}
@@ -1,13 +1,36 @@
 use base64::{engine::general_purpose, Engine};
-use candid::Principal;
+use candid::{CandidType, Principal};
 use ciborium::{from_reader, into_writer};
 use hmac::{Hmac, Mac};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_bytes::ByteBuf;
-use sha3::{Digest, Sha3_256};
+use sha3::{Digest, Keccak256, Sha3_256};
+
+pub mod audit;
 
 pub use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
 
+// heap_memory_size returns the size of the wasm heap memory in bytes.
+pub fn heap_memory_size() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * 65536
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+// cycles and memory usage reported by a canister
+#[derive(CandidType, Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CanisterMetrics {
+    pub cycles_balance: u128,
+    pub stable_memory_size: u64, // bytes
+    pub heap_memory_size: u64,   // bytes
+    pub timestamp: u64,          // milliseconds
+}
+
 pub fn sha256(data: &[u8]) -> [u8; 32] {
     let mut hasher = sha2::Sha256::new();
     hasher.update(data);
@@ -20,6 +43,12 @@ pub fn sha3_256(data: &[u8]) -> [u8; 32] {
     hasher.finalize().into()
 }
 
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
 pub fn mac_256(key: &[u8], add: &[u8]) -> [u8; 32] {
     let mut mac = Hmac::<Sha3_256>::new_from_slice(key).expect("HMAC can take key of any size");
     mac.update(add);
@@ -208,6 +237,165 @@ where
     }
 }
 
+/// Formats a message according to the EIP-191 standard. See [EIP-191 spec](https://eips.ethereum.org/EIPS/eip-191) for more information.
+pub fn eip191_bytes(message: &str) -> Vec<u8> {
+    format!("\x19Ethereum Signed Message:\n{}{}", message.len(), message).into_bytes()
+}
+
+/// Hashes a message using the EIP-191 standard.
+pub fn eip191_hash(message: &str) -> [u8; 32] {
+    keccak256(&eip191_bytes(message))
+}
+
+// recover_eth_address recovers the Ethereum address from an EIP-191 signed message.
+pub fn recover_eth_address(message: &str, signature: &[u8]) -> Result<[u8; 20], String> {
+    if signature.len() != 65 {
+        return Err(String::from("Invalid signature length"));
+    }
+    let message_hash = eip191_hash(message);
+
+    let recovery_id = k256::ecdsa::RecoveryId::try_from(signature[64] % 27)
+        .map_err(|_| String::from("Invalid recovery ID"))?;
+
+    let signature = k256::ecdsa::Signature::from_slice(&signature[..64])
+        .map_err(|_| String::from("Invalid signature"))?;
+
+    let verifying_key =
+        k256::ecdsa::VerifyingKey::recover_from_prehash(&message_hash, &signature, recovery_id)
+            .map_err(|_| String::from("Public key recovery failure"))?;
+    let mut addr: [u8; 20] = [0; 20];
+    addr.copy_from_slice(&keccak256(&verifying_key.to_encoded_point(false).as_bytes()[1..])[12..]);
+    Ok(addr)
+}
+
+// verify_sol_signature verifies a Solana signed message with the Ed25519 public key of the address.
+pub fn verify_sol_signature(
+    pubkey: &[u8; 32],
+    message: &[u8],
+    signature: &[u8; 64],
+) -> Result<(), String> {
+    let vk = VerifyingKey::from_bytes(pubkey).map_err(|_| "invalid public key")?;
+    let signature = Signature::from_bytes(signature);
+    vk.verify_strict(message, &signature)
+        .map_err(|_| "verification failed".to_string())
+}
+
+// NameAttestation states that `owner` owns `username` in the `registry` canister at `issued_at`.
+// It is signed by the registry's threshold Ed25519 key as an Ed25519Message.
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct NameAttestation {
+    #[serde(rename = "n")]
+    pub username: String,
+    #[serde(rename = "o")]
+    pub owner: Principal,
+    #[serde(rename = "r")]
+    pub registry: Principal,
+    #[serde(rename = "i")]
+    pub issued_at: u64, // milliseconds
+    #[serde(rename = "e")]
+    pub expires_at: Option<u64>, // the name expiry in milliseconds, None for perpetual names
+}
+
+// verify_name_attestation verifies an attestation signed by the attestation key of the
+// `registry`, not older than `max_age_ms` at `now_ms` and of a name not expired at `now_ms`.
+pub fn verify_name_attestation(
+    public_key: &[u8; 32],
+    registry: &Principal,
+    attestation: &[u8],
+    now_ms: u64,
+    max_age_ms: u64,
+) -> Result<NameAttestation, String> {
+    let key = VerifyingKey::from_bytes(public_key).map_err(|_| "invalid public key")?;
+    let att: NameAttestation = Ed25519Message::verify(&key, attestation)?;
+    if &att.registry != registry {
+        return Err("the attestation is from another registry".to_string());
+    }
+    if att.issued_at.saturating_add(max_age_ms) < now_ms {
+        return Err("the attestation is expired".to_string());
+    }
+    if att.expires_at.is_some_and(|t| t < now_ms) {
+        return Err("the name is expired".to_string());
+    }
+    Ok(att)
+}
+
+// MetricsWriter writes metrics in the Prometheus text exposition format.
+#[derive(Default)]
+pub struct MetricsWriter {
+    buf: String,
+}
+
+impl MetricsWriter {
+    pub const CONTENT_TYPE: &'static str = "text/plain; version=0.0.4";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn gauge(&mut self, name: &str, help: &str, value: impl std::fmt::Display) -> &mut Self {
+        self.write(name, "gauge", help, value)
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, value: impl std::fmt::Display) -> &mut Self {
+        self.write(name, "counter", help, value)
+    }
+
+    fn write(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        value: impl std::fmt::Display,
+    ) -> &mut Self {
+        use std::fmt::Write;
+        let _ = writeln!(self.buf, "# HELP {} {}", name, help);
+        let _ = writeln!(self.buf, "# TYPE {} {}", name, kind);
+        let _ = writeln!(self.buf, "{} {}", name, value);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf.into_bytes()
+    }
+}
+
+#[derive(CandidType, Deserialize, Serialize, Clone, Default)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    pub upgrade: Option<bool>,
+}
+
+// http_metrics serves the canister metrics on `GET /metrics` and 404 on other requests.
+// Metrics change with every update and are not certified, scrape them from the raw domain:
+// https://<canister_id>.raw.icp0.io/metrics
+pub fn http_metrics(method: &str, path: &str, metrics: impl FnOnce() -> Vec<u8>) -> HttpResponse {
+    match (method, path) {
+        ("GET", "/metrics") => {
+            let body = metrics();
+            HttpResponse {
+                status_code: 200,
+                headers: vec![
+                    (
+                        "content-type".to_string(),
+                        MetricsWriter::CONTENT_TYPE.to_string(),
+                    ),
+                    ("content-length".to_string(), body.len().to_string()),
+                ],
+                body: body.into(),
+                upgrade: None,
+            }
+        }
+        (method, path) => HttpResponse {
+            status_code: 404,
+            headers: vec![("content-type".to_string(), "text/plain".to_string())],
+            body: format!("method {method}, path: {path}").into_bytes().into(),
+            upgrade: None,
+        },
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -264,4 +452,89 @@ mod test {
         let state2 = ChallengeState::verify_from(&pk, &msg).unwrap();
         assert_eq!(state, state2);
     }
+
+    #[test]
+    fn test_recover_eth_address() {
+        let sk = k256::ecdsa::SigningKey::from_slice(&sha3_256(b"secret key")).unwrap();
+        let pk = sk.verifying_key().to_encoded_point(false);
+        let addr: [u8; 20] = keccak256(&pk.as_bytes()[1..])[12..].try_into().unwrap();
+
+        let message = "hello ICPanda";
+        let (sig, recid) = sk.sign_prehash_recoverable(&eip191_hash(message)).unwrap();
+        let mut signature = sig.to_bytes().to_vec();
+        signature.push(27 + recid.to_byte());
+        assert_eq!(recover_eth_address(message, &signature).unwrap(), addr);
+        assert_ne!(recover_eth_address("hello", &signature).unwrap(), addr);
+        assert!(recover_eth_address(message, &signature[1..]).is_err());
+    }
+
+    #[test]
+    fn test_verify_name_attestation() {
+        let sk = SigningKey::from_bytes(&sha3_256(b"secret key"));
+        let pk = VerifyingKey::from(&sk).to_bytes();
+        let registry = Principal::management_canister();
+        let att = NameAttestation {
+            username: "panda".to_string(),
+            owner: Principal::anonymous(),
+            registry,
+            issued_at: 1000,
+            expires_at: None,
+        };
+        let msg = att.sign(&sk);
+        assert_eq!(
+            verify_name_attestation(&pk, &registry, &msg, 2000, 1000).unwrap(),
+            att
+        );
+        assert!(verify_name_attestation(&pk, &registry, &msg, 2001, 1000).is_err());
+        assert!(verify_name_attestation(&pk, &registry, &msg[1..], 2000, 1000).is_err());
+        assert!(verify_name_attestation(&pk, &Principal::anonymous(), &msg, 2000, 1000).is_err());
+
+        let other = VerifyingKey::from(&SigningKey::from_bytes(&sha3_256(b"other"))).to_bytes();
+        assert!(verify_name_attestation(&other, &registry, &msg, 2000, 1000).is_err());
+
+        let att = NameAttestation {
+            expires_at: Some(1500),
+            ..att
+        };
+        let msg = att.sign(&sk);
+        assert!(verify_name_attestation(&pk, &registry, &msg, 1500, 1000).is_ok());
+        assert!(verify_name_attestation(&pk, &registry, &msg, 1501, 1000).is_err());
+    }
+
+    #[test]
+    fn test_verify_sol_signature() {
+        let sk = SigningKey::from_bytes(&sha3_256(b"secret key"));
+        let pk = VerifyingKey::from(&sk).to_bytes();
+        let sig = sk.sign(b"hello ICPanda").to_bytes();
+        assert!(verify_sol_signature(&pk, b"hello ICPanda", &sig).is_ok());
+        assert!(verify_sol_signature(&pk, b"hello", &sig).is_err());
+    }
+
+    #[test]
+    fn test_metrics_writer() {
+        let mut w = MetricsWriter::new();
+        w.gauge("cycles_balance", "Cycles balance.", 1000u128)
+            .counter("users_total", "Total users.", 42u64);
+        let text = String::from_utf8(w.into_bytes()).unwrap();
+        assert_eq!(
+            text,
+            "# HELP cycles_balance Cycles balance.\n# TYPE cycles_balance gauge\ncycles_balance 1000\n\
+             # HELP users_total Total users.\n# TYPE users_total counter\nusers_total 42\n"
+        );
+    }
+
+    #[test]
+    fn test_http_metrics() {
+        let res = http_metrics("GET", "/metrics", || b"users 1\n".to_vec());
+        assert_eq!(res.status_code, 200);
+        assert_eq!(res.body.as_slice(), b"users 1\n");
+        assert!(res
+            .headers
+            .contains(&("content-length".to_string(), "8".to_string())));
+
+        let res = http_metrics("POST", "/metrics", || unreachable!());
+        assert_eq!(res.status_code, 404);
+        let res = http_metrics("GET", "/", || unreachable!());
+        assert_eq!(res.status_code, 404);
+    }
 }
@@ -22,6 +22,9 @@ pub struct AppState {
     pub http_client: Arc<Client>,
     pub state_secret: Vec<u8>,
     pub challenge_secret: SigningKey,
+    pub link_client: Arc<Client>,
+    pub link_signer: SigningKey, // derived from the challenge secret, only signs link attestations
+    pub link_hosts: Arc<Vec<String>>,
     pub twitter: AuthConfig,
     pub ic_redirect_uri: String,
     pub test_redirect_uri: String,
@@ -0,0 +1,157 @@
+use crate::cbor::Cbor;
+use crate::context::{unix_ms, ReqContext};
+use crate::erring::{HTTPError, SuccessResponse};
+use axum::{extract::State, response::IntoResponse, Extension};
+use base64::{engine::general_purpose, Engine};
+use candid::Principal;
+use http::header;
+use lib_panda::{ChallengeState, Ed25519Message};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::sync::Arc;
+use url::Url;
+
+use crate::api;
+
+const MAX_PAGE_SIZE: usize = 1024 * 1024; // 1MB
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct VerifyLinkInput {
+    pub principal: Principal,
+    pub uri: String,
+    pub challenge: ByteBuf, // issued by the profile canister with `link_challenge`
+}
+
+// verify_link fetches the linked page and checks that the challenge is published on it,
+// then signs an attestation that the profile canister checks in `verify_link`.
+pub async fn verify_link(
+    Extension(ctx): Extension<Arc<ReqContext>>,
+    State(app): State<api::AppState>,
+    Cbor(input): Cbor<VerifyLinkInput>,
+) -> Result<impl IntoResponse, HTTPError> {
+    ctx.set_kvs(vec![
+        ("action", "verify_link".into()),
+        ("principal", input.principal.to_string().into()),
+        ("uri", input.uri.clone().into()),
+    ])
+    .await;
+
+    let url = Url::parse(&input.uri).map_err(|_| HTTPError::new(400, "invalid uri".to_string()))?;
+    if !is_allowed_url(&app.link_hosts, &url) {
+        return Err(HTTPError::new(400, "uri is not allowed".to_string()));
+    }
+
+    let mut res = match app
+        .link_client
+        .get(url)
+        .header(header::USER_AGENT, api::USER_AGENT)
+        .send()
+        .await
+    {
+        Ok(res) => {
+            if res.status().is_success() {
+                res
+            } else {
+                let status = res.status().as_u16();
+                ctx.set("error", format!("get link: {}", status).into())
+                    .await;
+                return Err(HTTPError::new(
+                    400,
+                    format!("failed to get link: {}", status),
+                ));
+            }
+        }
+        Err(err) => {
+            ctx.set("error", format!("get link: {:?}", err).into())
+                .await;
+            return Err(HTTPError::new(500, "failed to get link".to_string()));
+        }
+    };
+
+    if res.content_length().unwrap_or_default() > MAX_PAGE_SIZE as u64 {
+        return Err(HTTPError::new(400, "link content too large".to_string()));
+    }
+    // the content length may be absent or wrong, so the limit is checked while reading
+    let mut body: Vec<u8> = Vec::new();
+    loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                if body.len() + chunk.len() > MAX_PAGE_SIZE {
+                    return Err(HTTPError::new(400, "link content too large".to_string()));
+                }
+                body.extend_from_slice(&chunk);
+            }
+            Ok(None) => break,
+            Err(err) => {
+                ctx.set("error", format!("read link: {:?}", err).into())
+                    .await;
+                return Err(HTTPError::new(500, "failed to read link".to_string()));
+            }
+        }
+    }
+
+    let challenge = general_purpose::URL_SAFE_NO_PAD.encode(&input.challenge);
+    if !String::from_utf8_lossy(&body).contains(&challenge) {
+        return Err(HTTPError::new(
+            404,
+            "challenge not found in link".to_string(),
+        ));
+    }
+
+    let state = ChallengeState((
+        input.principal,
+        (input.uri, input.challenge),
+        60 + unix_ms() / 1000,
+    ));
+    Ok(Cbor(SuccessResponse::new(ByteBuf::from(
+        state.sign(&app.link_signer),
+    ))))
+}
+
+// is_allowed_url checks that the url is https on the default port and that its host
+// is one of the allowed hosts or their subdomains.
+pub fn is_allowed_url(hosts: &[String], url: &Url) -> bool {
+    if url.scheme() != "https"
+        || url.port().is_some()
+        || !url.username().is_empty()
+        || url.password().is_some()
+    {
+        return false;
+    }
+    match url.host() {
+        Some(url::Host::Domain(host)) => {
+            let host = host.trim_end_matches('.').to_ascii_lowercase();
+            hosts.iter().any(|h| {
+                host == *h
+                    || host
+                        .strip_suffix(h.as_str())
+                        .is_some_and(|prefix| prefix.ends_with('.'))
+            })
+        }
+        // IP addresses are never allowed
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed_url() {
+        let hosts = vec!["github.com".to_string(), "x.com".to_string()];
+        let allowed = |s: &str| is_allowed_url(&hosts, &Url::parse(s).unwrap());
+
+        assert!(allowed("https://github.com/ldclabs"));
+        assert!(allowed("https://gist.github.com/ldclabs/abc"));
+        assert!(allowed("https://X.com/ICPandaDAO"));
+        assert!(!allowed("http://github.com/ldclabs"));
+        assert!(!allowed("https://github.com:8443/ldclabs"));
+        assert!(!allowed("https://user@github.com/ldclabs"));
+        assert!(!allowed("https://evilgithub.com/ldclabs"));
+        assert!(!allowed("https://github.com.evil.io/ldclabs"));
+        assert!(!allowed("https://127.0.0.1/"));
+        assert!(!allowed("https://[::1]/"));
+        assert!(!allowed("https://localhost/"));
+    }
+}
@@ -1,8 +1,9 @@
 use axum::{middleware, routing, Router};
+use base64::{engine::general_purpose, Engine};
 use dotenvy::dotenv;
 use http::StatusCode;
-use lib_panda::{bytes32_from_base64, SigningKey};
-use reqwest::ClientBuilder;
+use lib_panda::{bytes32_from_base64, mac_256, SigningKey};
+use reqwest::{redirect::Policy, ClientBuilder};
 use std::net::SocketAddr;
 use std::{sync::Arc, time::Duration};
 use structured_logger::{async_json::new_writer, get_env_level, Builder};
@@ -16,6 +17,7 @@ use tower_http::{
 };
 
 mod api;
+mod api_link;
 mod api_twitter;
 mod cbor;
 mod context;
@@ -41,11 +43,44 @@ async fn main() {
         .build()
         .unwrap();
 
+    let link_hosts: Arc<Vec<String>> = Arc::new(
+        std::env::var("LINK_HOSTS")
+            .unwrap_or_default()
+            .split(',')
+            .map(|s| s.trim().to_ascii_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect(),
+    );
+    let redirect_hosts = link_hosts.clone();
+    let link_client = ClientBuilder::new()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(8))
+        .gzip(true)
+        .redirect(Policy::custom(move |attempt| {
+            if attempt.previous().len() >= 3 {
+                attempt.stop()
+            } else if api_link::is_allowed_url(&redirect_hosts, attempt.url()) {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        }))
+        .build()
+        .unwrap();
+
     let secret = bytes32_from_base64(&std::env::var("CHALLENGE_SECRET").unwrap()).unwrap();
+    let link_signer = SigningKey::from_bytes(&mac_256(&secret, b"ICPanda link verifier"));
+    log::warn!(target: "server",
+        "link verifier public key: {}",
+        general_purpose::URL_SAFE_NO_PAD.encode(link_signer.verifying_key().to_bytes())
+    );
     let app_state = api::AppState {
         http_client: Arc::new(http_client),
         state_secret: std::env::var("STATE_SECRET").unwrap().into_bytes(),
         challenge_secret: SigningKey::from_bytes(&secret),
+        link_client: Arc::new(link_client),
+        link_signer,
+        link_hosts,
         ic_redirect_uri: std::env::var("IC_REDIRECT_URI").unwrap(),
         test_redirect_uri: std::env::var("TEST_REDIRECT_URI").unwrap(),
         local_redirect_uri: std::env::var("LOCAL_REDIRECT_URI").unwrap(),
@@ -125,7 +160,11 @@ async fn main() {
         )
         .route(
             "/challenge/:kind",
-            routing::post(api::challenge).layer(GovernorLayer::new(challenge_limiter_conf)),
+            routing::post(api::challenge).layer(GovernorLayer::new(challenge_limiter_conf.clone())),
+        )
+        .route(
+            "/verify_link",
+            routing::post(api_link::verify_link).layer(GovernorLayer::new(challenge_limiter_conf)),
         )
         .layer((
             CatchPanicLayer::new(),